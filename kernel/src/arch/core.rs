@@ -20,6 +20,11 @@ pub fn archctl(cmd: usize, arg: usize) -> EResult<usize> {
     internal::core::archctl(cmd, arg)
 }
 
+/// Returns a random number from a hardware source, or [`None`] if there is none.
+pub fn get_entropy() -> Option<u64> {
+    internal::core::get_entropy()
+}
+
 /// Stop all other CPUs.
 pub fn halt_others() {
     internal::core::halt_others()
@@ -1,3 +1,4 @@
+use super::irq;
 use crate::{
     arch::irq::wait_for_irq,
     {
@@ -7,15 +8,18 @@ use crate::{
 };
 use core::arch::naked_asm;
 
-// TODO
-fn stvec() {
-    panic!("exception!");
-}
-
 pub fn setup_bsp() {
     unsafe {
         core::arch::asm!("la tp, {percpu}", percpu = sym crate::percpu::LD_PERCPU_START);
-        core::arch::asm!("csrw stvec, {stvec}", stvec = in(reg) stvec);
+        // The kernel runs with `sscratch` cleared, see [`irq::trap_entry`].
+        // The F and D registers are also used by user tasks, so they have to be accessible.
+        core::arch::asm!(
+            "csrw sscratch, zero",
+            "csrw stvec, {stvec}",
+            "csrs sstatus, {fs}",
+            stvec = in(reg) irq::trap_vector(),
+            fs = in(reg) irq::SSTATUS_FS_INITIAL,
+        );
     }
 }
 
@@ -42,6 +46,11 @@ pub fn archctl(cmd: usize, arg: usize) -> EResult<usize> {
     }
 }
 
+pub fn get_entropy() -> Option<u64> {
+    // TODO: Use the seed CSR from Zkr.
+    None
+}
+
 pub fn halt() -> ! {
     loop {
         wait_for_irq();
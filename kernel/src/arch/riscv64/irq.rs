@@ -1,4 +1,12 @@
-use core::arch::asm;
+use super::sched::Context;
+use crate::{
+    irq::lock::IrqLock, memory::fault::PageFaultInfo, percpu::CpuData, process::signal::Signal,
+    uapi::signal, util::align_up,
+};
+use core::{
+    arch::{asm, naked_asm},
+    mem::offset_of,
+};
 
 pub unsafe fn set_irq_state(value: bool) -> bool {
     let old: u64;
@@ -34,3 +42,327 @@ pub fn wait_for_irq() {
         asm!("wfi");
     }
 }
+
+/// Set in `sstatus` while interrupts are enabled.
+pub(super) const SSTATUS_SIE: u64 = 1 << 1;
+/// The interrupt enable bit to restore on `sret`.
+pub(super) const SSTATUS_SPIE: u64 = 1 << 5;
+/// Set if the trap was taken from supervisor mode, clear for user mode.
+pub(super) const SSTATUS_SPP: u64 = 1 << 8;
+/// The F and D registers may be used and have their initial values.
+pub(super) const SSTATUS_FS_INITIAL: u64 = 1 << 13;
+
+/// Set in `scause` if the trap was caused by an interrupt.
+const SCAUSE_INTERRUPT: u64 = 1 << 63;
+
+const CAUSE_INSTRUCTION_MISALIGNED: u64 = 0;
+const CAUSE_INSTRUCTION_ACCESS: u64 = 1;
+const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+const CAUSE_BREAKPOINT: u64 = 3;
+const CAUSE_LOAD_MISALIGNED: u64 = 4;
+const CAUSE_LOAD_ACCESS: u64 = 5;
+const CAUSE_STORE_MISALIGNED: u64 = 6;
+const CAUSE_STORE_ACCESS: u64 = 7;
+const CAUSE_USER_ECALL: u64 = 8;
+const CAUSE_INSTRUCTION_PAGE_FAULT: u64 = 12;
+const CAUSE_LOAD_PAGE_FAULT: u64 = 13;
+const CAUSE_STORE_PAGE_FAULT: u64 = 15;
+
+/// What a trap saves on the stack. A pointer to the frame also points to its context.
+/// The stack pointer has to stay aligned to 16 bytes.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub(super) struct TrapFrame {
+    pub context: Context,
+    pub sstatus: u64,
+}
+
+/// Entry point of all traps. `stvec` has to point to the aligned start, see [`trap_vector`].
+/// While running in user mode, `sscratch` holds the per-CPU block, otherwise it's zero.
+#[unsafe(naked)]
+unsafe extern "C" fn trap_entry() {
+    naked_asm!(
+        ".balign 4",
+        "csrrw tp, sscratch, tp",
+        "bnez tp, 2f",
+        // Coming from the kernel, keep using the current stack.
+        "csrrw tp, sscratch, zero",
+        "addi sp, sp, -{size}",
+        "sd t0, {t0}(sp)",
+        "addi t0, sp, {size}",
+        "sd t0, {sp}(sp)",
+        "sd tp, {tp}(sp)",
+        "j 3f",
+        // Coming from user mode, switch to the kernel stack of the task.
+        "2:",
+        "sd sp, {user_stack}(tp)",
+        "ld sp, {kernel_stack}(tp)",
+        "addi sp, sp, -{size}",
+        "sd t0, {t0}(sp)",
+        "ld t0, {user_stack}(tp)",
+        "sd t0, {sp}(sp)",
+        "csrrw t0, sscratch, zero",
+        "sd t0, {tp}(sp)",
+        "3:",
+        "sd ra, {ra}(sp)",
+        "sd gp, {gp}(sp)",
+        "sd t1, {t1}(sp)",
+        "sd t2, {t2}(sp)",
+        "sd s0, {s0}(sp)",
+        "sd s1, {s1}(sp)",
+        "sd a0, {a0}(sp)",
+        "sd a1, {a1}(sp)",
+        "sd a2, {a2}(sp)",
+        "sd a3, {a3}(sp)",
+        "sd a4, {a4}(sp)",
+        "sd a5, {a5}(sp)",
+        "sd a6, {a6}(sp)",
+        "sd a7, {a7}(sp)",
+        "sd s2, {s2}(sp)",
+        "sd s3, {s3}(sp)",
+        "sd s4, {s4}(sp)",
+        "sd s5, {s5}(sp)",
+        "sd s6, {s6}(sp)",
+        "sd s7, {s7}(sp)",
+        "sd s8, {s8}(sp)",
+        "sd s9, {s9}(sp)",
+        "sd s10, {s10}(sp)",
+        "sd s11, {s11}(sp)",
+        "sd t3, {t3}(sp)",
+        "sd t4, {t4}(sp)",
+        "sd t5, {t5}(sp)",
+        "sd t6, {t6}(sp)",
+        "csrr t0, sepc",
+        "sd t0, {pc}(sp)",
+        "csrr t0, sstatus",
+        "sd t0, {sstatus}(sp)",
+        // Zero out the frame pointer since we can't trust it.
+        "mv fp, zero",
+        "mv a0, sp",
+        "call {trap_handler}",
+        "j {trap_return}",
+        size = const size_of::<TrapFrame>(),
+        user_stack = const offset_of!(CpuData, user_stack),
+        kernel_stack = const offset_of!(CpuData, kernel_stack),
+        sstatus = const offset_of!(TrapFrame, sstatus),
+        ra = const offset_of!(Context, ra),
+        sp = const offset_of!(Context, sp),
+        gp = const offset_of!(Context, gp),
+        tp = const offset_of!(Context, tp),
+        t0 = const offset_of!(Context, t0),
+        t1 = const offset_of!(Context, t1),
+        t2 = const offset_of!(Context, t2),
+        s0 = const offset_of!(Context, s0),
+        s1 = const offset_of!(Context, s1),
+        a0 = const offset_of!(Context, a0),
+        a1 = const offset_of!(Context, a1),
+        a2 = const offset_of!(Context, a2),
+        a3 = const offset_of!(Context, a3),
+        a4 = const offset_of!(Context, a4),
+        a5 = const offset_of!(Context, a5),
+        a6 = const offset_of!(Context, a6),
+        a7 = const offset_of!(Context, a7),
+        s2 = const offset_of!(Context, s2),
+        s3 = const offset_of!(Context, s3),
+        s4 = const offset_of!(Context, s4),
+        s5 = const offset_of!(Context, s5),
+        s6 = const offset_of!(Context, s6),
+        s7 = const offset_of!(Context, s7),
+        s8 = const offset_of!(Context, s8),
+        s9 = const offset_of!(Context, s9),
+        s10 = const offset_of!(Context, s10),
+        s11 = const offset_of!(Context, s11),
+        t3 = const offset_of!(Context, t3),
+        t4 = const offset_of!(Context, t4),
+        t5 = const offset_of!(Context, t5),
+        t6 = const offset_of!(Context, t6),
+        pc = const offset_of!(Context, pc),
+        trap_handler = sym trap_handler,
+        trap_return = sym trap_return,
+    );
+}
+
+/// Returns the address for `stvec`, which has to be aligned to 4 bytes.
+pub(super) fn trap_vector() -> usize {
+    align_up(trap_entry as *const () as usize, 4)
+}
+
+/// Restores the [`TrapFrame`] at the stack pointer and returns from the trap.
+#[unsafe(naked)]
+pub(super) unsafe extern "C" fn trap_return() {
+    naked_asm!(
+        // Nothing may trap until `sscratch` and `sstatus` match the context to return to.
+        "csrci sstatus, {sie}",
+        "ld t0, {sstatus}(sp)",
+        "andi t1, t0, {spp}",
+        "bnez t1, 2f",
+        "csrw sscratch, tp",
+        "2:",
+        "csrw sstatus, t0",
+        "ld t0, {pc}(sp)",
+        "csrw sepc, t0",
+        "ld ra, {ra}(sp)",
+        "ld gp, {gp}(sp)",
+        "ld tp, {tp}(sp)",
+        "ld t0, {t0}(sp)",
+        "ld t1, {t1}(sp)",
+        "ld t2, {t2}(sp)",
+        "ld s0, {s0}(sp)",
+        "ld s1, {s1}(sp)",
+        "ld a0, {a0}(sp)",
+        "ld a1, {a1}(sp)",
+        "ld a2, {a2}(sp)",
+        "ld a3, {a3}(sp)",
+        "ld a4, {a4}(sp)",
+        "ld a5, {a5}(sp)",
+        "ld a6, {a6}(sp)",
+        "ld a7, {a7}(sp)",
+        "ld s2, {s2}(sp)",
+        "ld s3, {s3}(sp)",
+        "ld s4, {s4}(sp)",
+        "ld s5, {s5}(sp)",
+        "ld s6, {s6}(sp)",
+        "ld s7, {s7}(sp)",
+        "ld s8, {s8}(sp)",
+        "ld s9, {s9}(sp)",
+        "ld s10, {s10}(sp)",
+        "ld s11, {s11}(sp)",
+        "ld t3, {t3}(sp)",
+        "ld t4, {t4}(sp)",
+        "ld t5, {t5}(sp)",
+        "ld t6, {t6}(sp)",
+        "ld sp, {sp}(sp)",
+        "sret",
+        sie = const SSTATUS_SIE,
+        spp = const SSTATUS_SPP,
+        sstatus = const offset_of!(TrapFrame, sstatus),
+        ra = const offset_of!(Context, ra),
+        sp = const offset_of!(Context, sp),
+        gp = const offset_of!(Context, gp),
+        tp = const offset_of!(Context, tp),
+        t0 = const offset_of!(Context, t0),
+        t1 = const offset_of!(Context, t1),
+        t2 = const offset_of!(Context, t2),
+        s0 = const offset_of!(Context, s0),
+        s1 = const offset_of!(Context, s1),
+        a0 = const offset_of!(Context, a0),
+        a1 = const offset_of!(Context, a1),
+        a2 = const offset_of!(Context, a2),
+        a3 = const offset_of!(Context, a3),
+        a4 = const offset_of!(Context, a4),
+        a5 = const offset_of!(Context, a5),
+        a6 = const offset_of!(Context, a6),
+        a7 = const offset_of!(Context, a7),
+        s2 = const offset_of!(Context, s2),
+        s3 = const offset_of!(Context, s3),
+        s4 = const offset_of!(Context, s4),
+        s5 = const offset_of!(Context, s5),
+        s6 = const offset_of!(Context, s6),
+        s7 = const offset_of!(Context, s7),
+        s8 = const offset_of!(Context, s8),
+        s9 = const offset_of!(Context, s9),
+        s10 = const offset_of!(Context, s10),
+        s11 = const offset_of!(Context, s11),
+        t3 = const offset_of!(Context, t3),
+        t4 = const offset_of!(Context, t4),
+        t5 = const offset_of!(Context, t5),
+        t6 = const offset_of!(Context, t6),
+        pc = const offset_of!(Context, pc),
+    );
+}
+
+/// Invoked by [`trap_entry`].
+extern "C" fn trap_handler(frame: *mut TrapFrame) {
+    let frame = unsafe { frame.as_mut().unwrap() };
+    let to_user = frame.sstatus & SSTATUS_SPP == 0;
+
+    let (scause, stval): (u64, u64);
+    unsafe { asm!("csrr {}, scause", "csrr {}, stval", out(reg) scause, out(reg) stval) };
+
+    if scause == CAUSE_USER_ECALL {
+        syscall_handler(&mut frame.context);
+    } else {
+        let old = IrqLock::set_interrupted(true);
+        match scause {
+            x if x & SCAUSE_INTERRUPT != 0 => {
+                panic!("Unhandled interrupt {}", x & !SCAUSE_INTERRUPT)
+            }
+            CAUSE_INSTRUCTION_PAGE_FAULT | CAUSE_LOAD_PAGE_FAULT | CAUSE_STORE_PAGE_FAULT => {
+                page_fault_handler(frame, scause, stval)
+            }
+            CAUSE_ILLEGAL_INSTRUCTION => {
+                try_signal_or_die(frame, scause, Signal::SIGILL, signal::ILL_ILLOPN)
+            }
+            CAUSE_BREAKPOINT => {
+                try_signal_or_die(frame, scause, Signal::SIGTRAP, signal::TRAP_BRKPT)
+            }
+            CAUSE_INSTRUCTION_MISALIGNED | CAUSE_LOAD_MISALIGNED | CAUSE_STORE_MISALIGNED => {
+                try_signal_or_die(frame, scause, Signal::SIGBUS, signal::BUS_ADRALN)
+            }
+            CAUSE_INSTRUCTION_ACCESS | CAUSE_LOAD_ACCESS | CAUSE_STORE_ACCESS => {
+                try_signal_or_die(frame, scause, Signal::SIGBUS, signal::BUS_ADRERR)
+            }
+            _ => {
+                error!("{:?}", frame);
+                panic!("Got an exception {}", scause);
+            }
+        }
+        IrqLock::set_interrupted(old);
+
+        // This can't be done from a nested trap.
+        if !old {
+            CpuData::get().scheduler.preempt(to_user);
+        }
+    }
+
+    // Deliver pending signals before returning to user mode.
+    if to_user {
+        crate::process::signal::dispatch_pending(&mut frame.context);
+    }
+}
+
+fn syscall_handler(context: &mut Context) {
+    // Continue after the ecall instruction.
+    context.pc += 4;
+
+    // The number is passed in a7, the arguments in a0 to a5.
+    crate::syscall::dispatch(
+        context,
+        context.a7 as usize,
+        context.a0 as usize,
+        context.a1 as usize,
+        context.a2 as usize,
+        context.a3 as usize,
+        context.a4 as usize,
+        context.a5 as usize,
+    );
+
+    // A task which was woken up during the call might have to run first.
+    CpuData::get().scheduler.preempt(true);
+}
+
+fn page_fault_handler(frame: &mut TrapFrame, scause: u64, stval: u64) {
+    let info = PageFaultInfo {
+        // The cause doesn't tell if the page was present.
+        page_was_present: false,
+        caused_by_write: scause == CAUSE_STORE_PAGE_FAULT,
+        caused_by_fetch: scause == CAUSE_INSTRUCTION_PAGE_FAULT,
+        caused_by_user: frame.sstatus & SSTATUS_SPP == 0,
+        ip: (frame.context.pc as usize).into(),
+        addr: (stval as usize).into(),
+    };
+
+    if let Some(resume) = crate::memory::virt::fault::handler(&info) {
+        frame.context.pc = resume.value() as u64;
+    }
+}
+
+/// Try to send a signal to the user-space program or panic if the trap is caused by the kernel.
+fn try_signal_or_die(frame: &TrapFrame, scause: u64, signal: Signal, code: u32) {
+    if frame.sstatus & SSTATUS_SPP != 0 {
+        error!("{:?}", frame);
+        panic!("Got an exception {} in kernel mode", scause);
+    }
+
+    crate::process::signal::send_fault(signal, code as _, (frame.context.pc as usize).into());
+}
@@ -1,10 +1,17 @@
+use super::irq::{self, SSTATUS_FS_INITIAL, SSTATUS_SIE, SSTATUS_SPIE, SSTATUS_SPP, TrapFrame};
 use crate::{
     irq::lock::IrqGuard,
-    memory::{VirtAddr, virt::KERNEL_STACK_SIZE},
-    posix::errno::EResult,
+    memory::{VirtAddr, user::UserPtr},
+    posix::errno::{EResult, Errno},
     process::task::Task,
+    sched::Scheduler,
+    uapi::signal::{siginfo_t, sigset_t},
+    util::align_down,
+};
+use core::{
+    arch::{asm, naked_asm},
+    mem::offset_of,
 };
-use core::{arch::naked_asm, mem::offset_of};
 
 #[repr(C)]
 #[derive(Debug, Default, Clone, Copy)]
@@ -40,6 +47,7 @@ pub struct Context {
     pub t4: u64,
     pub t5: u64,
     pub t6: u64,
+    pub pc: u64,
 }
 
 impl Context {
@@ -49,11 +57,51 @@ impl Context {
     }
 }
 
+/// The registers of the F and D extensions.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpuState {
+    pub f: [u64; 32],
+    pub fcsr: u64,
+}
+
+/// Saves the F and D registers to `state`.
+#[unsafe(naked)]
+unsafe extern "C" fn fpu_save(state: *mut FpuState) {
+    naked_asm!(
+        ".irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+        "fsd f\\n, {f} + \\n * 8(a0)",
+        ".endr",
+        "frcsr t0",
+        "sd t0, {fcsr}(a0)",
+        "ret",
+        f = const offset_of!(FpuState, f),
+        fcsr = const offset_of!(FpuState, fcsr),
+    );
+}
+
+/// Loads the F and D registers from `state`.
+#[unsafe(naked)]
+unsafe extern "C" fn fpu_restore(state: *const FpuState) {
+    naked_asm!(
+        ".irp n, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+        "fld f\\n, {f} + \\n * 8(a0)",
+        ".endr",
+        "ld t0, {fcsr}(a0)",
+        "fscsr t0",
+        "ret",
+        f = const offset_of!(FpuState, f),
+        fcsr = const offset_of!(FpuState, fcsr),
+    );
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Clone)]
 pub struct TaskContext {
     pub sp: u64,
     pub tp: u64,
+    /// The F and D registers of a user task while it's not running.
+    pub fpu: FpuState,
 }
 
 #[repr(C)]
@@ -91,10 +139,18 @@ pub unsafe fn switch(from: *const Task, to: *const Task, irq_guard: IrqGuard) {
         let mut from_context = from.task_context.lock();
         let to_context = to.task_context.lock();
 
+        if from.is_user() {
+            fpu_save(&raw mut from_context.fpu);
+        }
+        if to.is_user() {
+            fpu_restore(&raw const to_context.fpu);
+        }
+
         let old_sp = &raw mut from_context.sp;
         let new_sp = to_context.sp;
 
-        drop(irq_guard);
+        // Interrupts have to stay disabled until the switch is finished.
+        core::mem::forget(irq_guard);
         perform_switch(old_sp, new_sp);
     }
 }
@@ -163,12 +219,12 @@ pub fn init_task(
     entry: extern "C" fn(usize, usize),
     arg1: usize,
     arg2: usize,
-    stack_start: VirtAddr,
+    stack_top: VirtAddr,
     is_user: bool,
 ) -> EResult<()> {
     // Prepare a dummy stack with an entry point function to return to.
     unsafe {
-        let frame = ((stack_start.value() + KERNEL_STACK_SIZE) as *mut TaskFrame).sub(1);
+        let frame = (stack_top.value() as *mut TaskFrame).sub(1);
         (*frame).s0 = entry as u64;
         (*frame).s1 = arg1 as u64;
         (*frame).s2 = arg2 as u64;
@@ -194,12 +250,109 @@ unsafe extern "C" fn task_entry_thunk() -> ! {
     );
 }
 
-pub unsafe fn jump_to_user(ip: VirtAddr, sp: VirtAddr) {
-    let _ = (ip, sp);
-    todo!()
+pub fn new_user_context(ip: VirtAddr, sp: VirtAddr, arg: usize) -> Context {
+    Context {
+        pc: ip.value() as u64,
+        sp: sp.value() as u64,
+        a0: arg as u64,
+        ..Context::default()
+    }
 }
 
-pub unsafe fn jump_to_context(context: *mut Context) {
-    let _ = context;
-    todo!()
+pub fn set_tls(context: &mut TaskContext, tls: VirtAddr) {
+    context.tp = tls.value() as u64;
+}
+
+pub unsafe fn jump_to_user(ip: VirtAddr, sp: VirtAddr) -> ! {
+    assert!(
+        Scheduler::get_current().is_user(),
+        "Attempted to perform a user jump on a kernel task!"
+    );
+
+    let mut context = new_user_context(ip, sp, 0);
+    unsafe { jump_to_context(&raw mut context) }
+}
+
+pub unsafe fn jump_to_context(context: *mut Context) -> ! {
+    // Return to user mode with interrupts enabled.
+    let sstatus: u64;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) };
+    let mut frame = TrapFrame {
+        context: unsafe { *context },
+        sstatus: (sstatus & !(SSTATUS_SPP | SSTATUS_SIE)) | SSTATUS_SPIE | SSTATUS_FS_INITIAL,
+    };
+
+    unsafe {
+        asm!(
+            "mv sp, {frame}",
+            "j {trap_return}",
+            frame = in(reg) &raw mut frame,
+            trap_return = sym irq::trap_return,
+            options(noreturn),
+        );
+    }
+}
+
+/// Layout of the data pushed onto the user stack when delivering a signal.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    info: siginfo_t,
+    /// The interrupted context.
+    context: Context,
+    /// The signal mask that was active before the signal was delivered.
+    mask: sigset_t,
+    /// The F and D registers of the interrupted context.
+    fpu: FpuState,
+}
+
+pub fn push_signal_frame(
+    context: &mut Context,
+    handler: VirtAddr,
+    restorer: VirtAddr,
+    info: &siginfo_t,
+    mask: sigset_t,
+) -> EResult<()> {
+    // The stack pointer has to be aligned to 16 bytes.
+    let frame_addr = (context.sp as usize)
+        .checked_sub(size_of::<SignalFrame>())
+        .map(|x| align_down(x, 16))
+        .ok_or(Errno::EFAULT)?;
+
+    // The registers still contain the state of the interrupted user context.
+    let mut fpu = FpuState::default();
+    unsafe { fpu_save(&raw mut fpu) };
+
+    let frame = SignalFrame {
+        info: *info,
+        context: *context,
+        mask,
+        fpu,
+    };
+    if !UserPtr::new(frame_addr.into()).write(frame) {
+        return Err(Errno::EFAULT);
+    }
+
+    // Call the handler with (signo, &info, &context) and return to the restorer.
+    context.pc = handler.value() as u64;
+    context.ra = restorer.value() as u64;
+    context.sp = frame_addr as u64;
+    context.a0 = info.si_signo as u64;
+    context.a1 = (frame_addr + offset_of!(SignalFrame, info)) as u64;
+    context.a2 = (frame_addr + offset_of!(SignalFrame, context)) as u64;
+
+    Ok(())
+}
+
+pub fn pop_signal_frame(context: &mut Context) -> EResult<sigset_t> {
+    // The handler returned with the stack pointer pointing at the frame.
+    let frame_addr = context.sp as usize;
+
+    let frame = UserPtr::<SignalFrame>::new(frame_addr.into())
+        .read()
+        .ok_or(Errno::EFAULT)?;
+    *context = frame.context;
+    unsafe { fpu_restore(&raw const frame.fpu) };
+
+    Ok(frame.mask)
 }
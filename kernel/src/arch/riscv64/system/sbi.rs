@@ -2,8 +2,15 @@ use crate::log::{self, LoggerSink};
 use alloc::boxed::Box;
 
 const DBCN: u64 = 0x4442434E;
+/// The remote fence extension.
+pub const RFNC: u64 = 0x52464E43;
 
 pub fn call(eid: u64, fid: u64, a0: u64) -> (u64, u64) {
+    call4(eid, fid, [a0, 0, 0, 0])
+}
+
+/// Same as [`call`], but with four arguments.
+pub fn call4(eid: u64, fid: u64, args: [u64; 4]) -> (u64, u64) {
     unsafe {
         let mut result0;
         let mut result1;
@@ -11,9 +18,10 @@ pub fn call(eid: u64, fid: u64, a0: u64) -> (u64, u64) {
             "ecall",
             in("a7") eid,
             in("a6") fid,
-            in("a0") a0,
-            lateout("a0") result0,
-            lateout("a1") result1,
+            inlateout("a0") args[0] => result0,
+            inlateout("a1") args[1] => result1,
+            in("a2") args[2],
+            in("a3") args[3],
         );
         (result0, result1)
     }
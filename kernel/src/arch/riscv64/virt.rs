@@ -1,9 +1,20 @@
-use crate::memory::{
-    PhysAddr, VirtAddr,
-    virt::{PteFlags, mmu::PageTable},
+use crate::{
+    arch::riscv64::system::sbi,
+    memory::{
+        PhysAddr, VirtAddr,
+        virt::{
+            PteFlags,
+            mmu::{self, PageTable},
+        },
+    },
+    percpu::CpuData,
+    system::dt,
 };
 use bitflags::bitflags;
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 #[repr(transparent)]
 #[derive(Clone, Copy)]
@@ -24,6 +35,16 @@ impl core::fmt::Debug for PageTableEntry {
 /// Masks only the address bits of a PTE.
 const PPN_MASK: u64 = ((1 << 44) - 1) << 10;
 
+// Page-based memory types of the Svpbmt extension.
+/// Non-cacheable, idempotent, weakly-ordered main memory.
+const PBMT_NC: u64 = 1 << 61;
+/// Non-cacheable, non-idempotent, strongly-ordered I/O memory.
+const PBMT_IO: u64 = 2 << 61;
+const PBMT_MASK: u64 = 3 << 61;
+
+/// Set if all harts implement Svpbmt. The PBMT bits are reserved otherwise.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
 bitflags! {
     #[repr(transparent)]
     #[derive(Debug)]
@@ -36,6 +57,8 @@ bitflags! {
         const Global = 1 << 5;
         const Access = 1 << 6;
         const Dirty = 1 << 7;
+        /// Reserved for software. Marks a swap entry if the PTE isn't valid.
+        const Swap = 1 << 8;
     }
 }
 
@@ -63,11 +86,48 @@ impl PageTableEntry {
             if flags.contains(PteFlags::Exec) {
                 result |= PageFlags::Execute.bits();
             }
+
+            // There is no write-through type, uncached memory is the closest match.
+            if flags.intersects(PteFlags::WriteCombining.union(PteFlags::WriteThrough)) {
+                result |= PBMT_NC;
+            } else if flags.contains(PteFlags::Uncached) {
+                result |= PBMT_IO;
+            }
         }
 
         Self { inner: result }
     }
 
+    /// Returns the flags of a present leaf PTE.
+    pub fn flags(&self, level: usize) -> PteFlags {
+        let flags = PageFlags::from_bits_truncate(self.inner);
+        let mut result = PteFlags::empty();
+
+        if flags.contains(PageFlags::Read) {
+            result |= PteFlags::Read;
+        }
+        if flags.contains(PageFlags::Write) {
+            result |= PteFlags::Write;
+        }
+        if flags.contains(PageFlags::Execute) {
+            result |= PteFlags::Exec;
+        }
+        if flags.contains(PageFlags::UserMode) {
+            result |= PteFlags::User;
+        }
+        // Every leaf above the lowest level is a large page.
+        if level > 0 {
+            result |= PteFlags::Large;
+        }
+
+        result
+            | match self.inner & PBMT_MASK {
+                PBMT_NC => PteFlags::WriteCombining,
+                PBMT_IO => PteFlags::Uncached,
+                _ => PteFlags::empty(),
+            }
+    }
+
     pub const fn inner(&self) -> usize {
         self.inner as usize
     }
@@ -86,6 +146,45 @@ impl PageTableEntry {
         PageFlags::from_bits_truncate(self.inner).contains(PageFlags::Dirty)
     }
 
+    /// Clears the dirty bit and returns whether it was set.
+    /// This is atomic, because the MMU may set the bit at the same time.
+    pub fn clear_dirty(&mut self) -> bool {
+        let inner = unsafe { AtomicU64::from_ptr(&raw mut self.inner) };
+        inner.fetch_and(!PageFlags::Dirty.bits(), Ordering::AcqRel) & PageFlags::Dirty.bits() != 0
+    }
+
+    /// Replaces the PTE with an empty one and returns the old value.
+    /// This is atomic, so a dirty bit which the MMU sets at the same time isn't lost.
+    pub fn take(&mut self) -> Self {
+        let inner = unsafe { AtomicU64::from_ptr(&raw mut self.inner) };
+        Self {
+            inner: inner.swap(0, Ordering::AcqRel),
+        }
+    }
+
+    /// Clears the accessed bit and returns whether it was set.
+    pub fn clear_accessed(&mut self) -> bool {
+        let inner = unsafe { AtomicU64::from_ptr(&raw mut self.inner) };
+        inner.fetch_and(!PageFlags::Access.bits(), Ordering::AcqRel) & PageFlags::Access.bits() != 0
+    }
+
+    /// Returns a non-present PTE which records that the page was moved to swap space.
+    pub const fn new_swap(entry: usize) -> Self {
+        Self {
+            inner: ((entry as u64) << 10) & PPN_MASK | PageFlags::Swap.bits(),
+        }
+    }
+
+    /// Returns the swap entry stored in a PTE created with [`Self::new_swap`].
+    pub fn swap_entry(&self) -> Option<usize> {
+        match !self.is_present()
+            && PageFlags::from_bits_truncate(self.inner).contains(PageFlags::Swap)
+        {
+            true => Some(((self.inner & PPN_MASK) >> 10) as usize),
+            false => None,
+        }
+    }
+
     pub fn address(&self) -> PhysAddr {
         ((self.inner & PPN_MASK) << 2).into()
     }
@@ -97,6 +196,14 @@ pub(in crate::arch) fn flush_tlb(addr: VirtAddr) {
     }
 }
 
+pub(in crate::arch) fn send_shootdown(cpu: &'static CpuData) {
+    // There is no interrupt handler for IPIs, so let the firmware flush the TLBs instead.
+    // The hart IDs aren't known, so this goes to all harts and flushes everything.
+    let (error, _) = sbi::call4(sbi::RFNC, 1, [0, u64::MAX, 0, u64::MAX]);
+    assert_eq!(error, 0, "Remote sfence.vma failed with SBI error {error}");
+    mmu::acknowledge_shootdown(cpu);
+}
+
 pub(in crate::arch) unsafe fn set_page_table(pt: &PageTable) {
     let satp = (pt.get_head_addr().value() >> 12)
         | (match pt.root_level() {
@@ -106,7 +213,7 @@ pub(in crate::arch) unsafe fn set_page_table(pt: &PageTable) {
             _ => panic!("Root level out of range"),
         } << 60);
     unsafe {
-        asm!("csrw satp, {satp}", satp = in(reg) satp);
+        asm!("csrw satp, {satp}", "sfence.vma", satp = in(reg) satp);
     }
 }
 
@@ -137,3 +244,94 @@ pub(in crate::arch) fn get_pfndb_base() -> VirtAddr {
 pub(in crate::arch) fn get_map_base() -> VirtAddr {
     VirtAddr::new(0xFFFF_C000_0000_0000)
 }
+
+pub(in crate::arch) fn supports_cache_types() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
+}
+
+/// Permits the kernel to access user pages.
+const SSTATUS_SUM: usize = 1 << 18;
+
+/// Copies byte by byte. A fault on either access resumes at the end, with the bytes left over
+/// still in `len`.
+pub(in crate::arch) unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    let remaining: usize;
+    unsafe {
+        asm!(
+            "csrs sstatus, {sum}",
+            "2:",
+            "beqz {len}, 5f",
+            "3:",
+            "lb {tmp}, 0({src})",
+            "4:",
+            "sb {tmp}, 0({dst})",
+            "addi {src}, {src}, 1",
+            "addi {dst}, {dst}, 1",
+            "addi {len}, {len}, -1",
+            "j 2b",
+            "5:",
+            "csrc sstatus, {sum}",
+            ".pushsection .user_fixup, \"a\"",
+            ".balign 4",
+            ".long 3b - .",
+            ".long 5b - .",
+            ".long 4b - .",
+            ".long 5b - .",
+            ".popsection",
+            sum = in(reg) SSTATUS_SUM,
+            len = inout(reg) len => remaining,
+            src = inout(reg) src => _,
+            dst = inout(reg) dst => _,
+            tmp = out(reg) _,
+            options(nostack),
+        );
+    }
+    remaining == 0
+}
+
+/// Returns true if the device tree node of a hart lists the Svpbmt extension.
+fn hart_has_svpbmt(cpu: &dt::Node) -> bool {
+    for prop in cpu.properties() {
+        match prop.name() {
+            b"riscv,isa-extensions" => {
+                if prop.as_str().any(|x| x == b"svpbmt") {
+                    return true;
+                }
+            }
+            // Multi-letter extensions follow the base ISA, separated by underscores.
+            b"riscv,isa" => {
+                if let Some(isa) = prop.as_str().next()
+                    && isa.split(|x| *x == b'_').skip(1).any(|x| x == b"svpbmt")
+                {
+                    return true;
+                }
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+#[initgraph::task(
+    name = "arch.riscv64.detect-svpbmt",
+    depends = [crate::system::dt::TREE_STAGE],
+    entails = [crate::arch::INIT_STAGE],
+)]
+fn SVPBMT_STAGE() {
+    let Some(tree) = dt::TREE.get() else {
+        return;
+    };
+    let Some(cpus) = tree.find_node(b"/cpus") else {
+        return;
+    };
+
+    // Until now, the PMAs decided the memory types, which is what devices expect anyways.
+    let mut harts = cpus
+        .nodes()
+        .filter(|x| x.name().starts_with(b"cpu@"))
+        .peekable();
+    if harts.peek().is_some() && harts.all(|x| hart_has_svpbmt(&x)) {
+        SVPBMT.store(true, Ordering::Relaxed);
+        log!("Svpbmt is supported, using page-based memory types");
+    }
+}
@@ -3,6 +3,7 @@ use crate::irq::lock::IrqGuard;
 use crate::memory::VirtAddr;
 use crate::posix::errno::EResult;
 use crate::process::task::Task;
+use crate::uapi::signal::{siginfo_t, sigset_t};
 use core::fmt::Debug;
 
 pub use internal::sched::Context;
@@ -33,6 +34,8 @@ pub unsafe fn preempt_enable() -> bool {
 }
 
 /// Switches the current CPU context from one task to another.
+/// The implementation must leak `irq_guard`, so interrupts stay disabled until the switch is
+/// finished by the scheduler.
 /// # Safety
 /// The caller must ensure that `from` and `to` are both valid tasks and
 /// that both arguments do not point to the same task.
@@ -53,10 +56,21 @@ pub fn init_task(
     entry: extern "C" fn(usize, usize),
     arg1: usize,
     arg2: usize,
-    stack_start: VirtAddr,
+    stack_top: VirtAddr,
     is_user: bool,
 ) -> EResult<()> {
-    internal::sched::init_task(task, entry, arg1, arg2, stack_start, is_user)
+    internal::sched::init_task(task, entry, arg1, arg2, stack_top, is_user)
+}
+
+/// Creates a context which enters user mode at `ip` with the stack pointer set to `sp`.
+/// `arg` is passed to the entry point as the first argument.
+pub fn new_user_context(ip: VirtAddr, sp: VirtAddr, arg: usize) -> Context {
+    internal::sched::new_user_context(ip, sp, arg)
+}
+
+/// Sets the thread pointer of a user task, which is used to access thread local storage.
+pub fn set_tls(context: &mut TaskContext, tls: VirtAddr) {
+    internal::sched::set_tls(context, tls)
 }
 
 /// Transitions to user mode at a specified IP and SP.
@@ -73,6 +87,25 @@ pub unsafe fn jump_to_context(context: *mut Context) {
     unsafe { internal::sched::jump_to_context(context) };
 }
 
+/// Pushes a signal frame onto the user stack of `context` and modifies it so that returning
+/// to user mode will invoke `handler`. Once the handler returns, it jumps to `restorer`.
+/// `mask` is the signal mask to restore with [`pop_signal_frame`].
+pub fn push_signal_frame(
+    context: &mut Context,
+    handler: VirtAddr,
+    restorer: VirtAddr,
+    info: &siginfo_t,
+    mask: sigset_t,
+) -> EResult<()> {
+    internal::sched::push_signal_frame(context, handler, restorer, info, mask)
+}
+
+/// Restores `context` from a signal frame previously pushed by [`push_signal_frame`].
+/// Returns the signal mask that was active before the signal was delivered.
+pub fn pop_signal_frame(context: &mut Context) -> EResult<sigset_t> {
+    internal::sched::pop_signal_frame(context)
+}
+
 // # Note
 // This module is only used to ensure the API is correctly implemented,
 // since associated functions are more complicated. Not to be used directly.
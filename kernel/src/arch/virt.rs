@@ -1,5 +1,8 @@
 use super::internal;
-use crate::memory::{VirtAddr, virt::mmu::PageTable};
+use crate::{
+    memory::{VirtAddr, virt::mmu::PageTable},
+    percpu::CpuData,
+};
 pub use internal::virt::PageTableEntry;
 
 /// Gets the page size for a given level.
@@ -7,6 +10,11 @@ pub fn get_page_size() -> usize {
     1 << get_page_bits()
 }
 
+/// Gets the amount of memory a single PTE at `level` covers.
+pub fn get_level_size(level: usize) -> usize {
+    1 << (get_page_bits() + get_level_bits() * level)
+}
+
 /// Gets the highest possible shift for a canonical virtual address.
 pub fn get_highest_bit_shift() -> usize {
     get_level_bits() * get_num_levels() + get_page_bits()
@@ -33,6 +41,7 @@ pub fn get_num_levels() -> usize {
     internal::virt::get_num_levels()
 }
 
+/// Gets the start of the region the HHDM is placed in. The HHDM itself may start higher up.
 pub fn get_hhdm_base() -> VirtAddr {
     internal::virt::get_hhdm_base()
 }
@@ -45,6 +54,20 @@ pub fn get_map_base() -> VirtAddr {
     internal::virt::get_map_base()
 }
 
+/// Returns true if PTEs can select a [`crate::memory::virt::VmCacheType`].
+/// Otherwise, all mappings use the memory type the platform assigns to an address.
+pub fn supports_cache_types() -> bool {
+    internal::virt::supports_cache_types()
+}
+
+/// Copies `len` bytes from `src` to `dst`, one of which is in user memory. Returns false if an
+/// access faulted, see [`crate::memory::user`].
+/// # Safety
+/// The kernel side has to be valid for `len` bytes. The user side must not reach into kernel memory.
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    unsafe { internal::virt::copy_user(dst, src, len) }
+}
+
 /// Sets a given page table as the active one on this CPU.
 ///
 /// # Safety
@@ -59,6 +82,12 @@ pub fn flush_tlb(addr: VirtAddr) {
     internal::virt::flush_tlb(addr);
 }
 
+/// Interrupts another CPU so it handles a TLB shootdown.
+/// See [`crate::memory::virt::mmu::handle_shootdown`].
+pub fn send_shootdown(cpu: &'static CpuData) {
+    internal::virt::send_shootdown(cpu);
+}
+
 // # Note
 // This module is only used to ensure the API is correctly implemented,
 // since associated functions are more complicated. Not to be used directly.
@@ -74,7 +103,7 @@ mod api {
     }
 
     /// Returns a new PTE with a set address.
-    const fn pte_new(address: PhysAddr, flags: PteFlags, level: usize) -> PageTableEntry {
+    fn pte_new(address: PhysAddr, flags: PteFlags, level: usize) -> PageTableEntry {
         PageTableEntry::new(address, flags, level)
     }
 
@@ -97,6 +126,37 @@ mod api {
         pte.is_dirty()
     }
 
+    /// Clears the dirty bit of the PTE and returns whether it was set.
+    fn pte_clear_dirty(pte: &mut PageTableEntry) -> bool {
+        pte.clear_dirty()
+    }
+
+    /// Empties the PTE and returns its old value.
+    fn pte_take(pte: &mut PageTableEntry) -> PageTableEntry {
+        pte.take()
+    }
+
+    /// Clears the accessed bit of the PTE and returns whether it was set.
+    fn pte_clear_accessed(pte: &mut PageTableEntry) -> bool {
+        pte.clear_accessed()
+    }
+
+    /// Returns a non-present PTE which holds a swap entry.
+    const fn pte_new_swap(entry: usize) -> PageTableEntry {
+        PageTableEntry::new_swap(entry)
+    }
+
+    /// Returns the swap entry of a non-present PTE.
+    fn pte_swap_entry(pte: &PageTableEntry) -> Option<usize> {
+        pte.swap_entry()
+    }
+
+    /// Returns the flags of a present leaf PTE at the given level.
+    /// This includes [`PteFlags::Large`] and the flags of the caching type.
+    fn pte_flags(pte: &PageTableEntry, level: usize) -> PteFlags {
+        pte.flags(level)
+    }
+
     /// Returns the contained address pointed to by the PTE.
     fn pte_address(pte: &PageTableEntry) -> PhysAddr {
         pte.address()
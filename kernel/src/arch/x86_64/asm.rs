@@ -60,6 +60,17 @@ pub unsafe fn wrxcr(msr: u32, value: u64) {
     }
 }
 
+/// Reads an unsigned 64-bit value from the model-specific XCR register.
+#[inline]
+pub unsafe fn rdxcr(msr: u32) -> u64 {
+    unsafe {
+        let eax: u32;
+        let edx: u32;
+        asm!("xgetbv", out("eax") eax, out("edx") edx, in("ecx") msr);
+        return (eax as u64) | ((edx as u64) << 32);
+    }
+}
+
 /// Reads an unsigned 64-bit value from a model-specific register.
 #[inline]
 pub unsafe fn rdmsr(msr: u32) -> u64 {
@@ -123,6 +123,8 @@ pub const MSR_EFER_LMSLE: u32 = 1 << 13;
 pub const MSR_EFER_FFXSR: u32 = 1 << 14;
 /// Translation Cache Extension
 pub const MSR_EFER_TCE: u32 = 1 << 15;
+/// Page Attribute Table
+pub const MSR_PAT: u32 = 0x277;
 pub const MSR_STAR: u32 = 0xC0000081;
 pub const MSR_LSTAR: u32 = 0xC0000082;
 pub const MSR_CSTAR: u32 = 0xC0000083;
@@ -255,6 +257,10 @@ pub const CPUID_7D_AVX512FP16: u32 = 1 << 23;
 pub const CPUID_7D_AMX_TILE: u32 = 1 << 24;
 pub const CPUID_7D_AMX_INT8: u32 = 1 << 25;
 
+// CPUID Leaf 0x8000_0001 EDX
+pub const CPUID_80000001D_NX: u32 = 1 << 20;
+pub const CPUID_80000001D_PDPE1GB: u32 = 1 << 26;
+
 pub const IDT_DE: u8 = 0x0;
 pub const IDT_DB: u8 = 0x1;
 pub const IDT_NMI: u8 = 0x2;
@@ -282,3 +288,4 @@ pub const IDT_SX: u8 = 0x1E;
 pub const IDT_IPI_PANIC: u8 = 0xFF;
 pub const IDT_IPI_RESCHED: u8 = 0xFE;
 pub const IDT_IPI_SHOOTDOWN: u8 = 0xFD;
+pub const IDT_TIMER: u8 = 0xFC;
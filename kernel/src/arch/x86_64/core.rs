@@ -8,6 +8,7 @@ use crate::{
             apic::{self, LAPIC, LocalApic},
             gdt, idt,
         },
+        virt,
     },
     clock,
     percpu::{CpuData, LD_PERCPU_START},
@@ -96,10 +97,16 @@ pub(super) fn setup_core(context: &'static CpuData) {
     // Note: The IDT itself is global, but still needs to be loaded for each CPU.
     idt::set_idt();
 
-    // Enable the `syscall` extension.
+    // Set up the memory types selectable by PTEs.
+    virt::init_pat();
+
+    // Enable the `syscall` extension and non-executable pages.
     unsafe {
-        let msr = super::asm::rdmsr(consts::MSR_EFER);
-        super::asm::wrmsr(consts::MSR_EFER, msr | consts::MSR_EFER_SCE as u64);
+        let mut efer = super::asm::rdmsr(consts::MSR_EFER) | consts::MSR_EFER_SCE as u64;
+        if virt::has_nx() {
+            efer |= consts::MSR_EFER_NXE as u64;
+        }
+        super::asm::wrmsr(consts::MSR_EFER, efer);
         // Bits 32-47 are kernel segment base, Bits 48-63 are user segment base. Lower 32 bits (EIP) are unused.
         super::asm::wrmsr(
             consts::MSR_STAR,
@@ -174,14 +181,16 @@ pub(super) fn setup_core(context: &'static CpuData) {
     //     cr4 |= consts::CR4_UMIP;
     // }
 
-    // if cpuid7.ebx & consts::CPUID_7B_SMEP != 0 {
-    //     cr4 |= consts::CR4_SMEP;
-    // }
+    // Never execute user code in kernel mode.
+    if cpuid7.ebx & consts::CPUID_7B_SMEP != 0 {
+        cr4 |= consts::CR4_SMEP;
+    }
 
-    // if cpuid7.ebx & consts::CPUID_7B_SMAP != 0 {
-    //     cr4 |= consts::CR4_SMAP;
-    //     cpu.can_smap.store(true, Ordering::Relaxed);
-    // }
+    // Never access user memory in kernel mode, except through the user copy routines.
+    if cpuid7.ebx & consts::CPUID_7B_SMAP != 0 {
+        cr4 |= consts::CR4_SMAP;
+        cpu.can_smap.store(true, Ordering::Relaxed);
+    }
 
     if cpuid7.ebx & consts::CPUID_7B_FSGSBASE != 0 {
         cr4 |= consts::CR4_FSGSBASE;
@@ -213,6 +222,31 @@ pub(in crate::arch) fn halt_others() {
     clock::block_ns(10000).unwrap();
 }
 
+pub(in crate::arch) fn get_entropy() -> Option<u64> {
+    if super::asm::cpuid(1, 0).ecx & consts::CPUID_1C_RDRAND == 0 {
+        return None;
+    }
+
+    // RDRAND fails if the generator is drained, which usually doesn't last for long.
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
 pub(in crate::arch) fn halt() -> ! {
     loop {
         unsafe {
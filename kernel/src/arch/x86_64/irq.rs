@@ -6,14 +6,18 @@ use crate::{
         system::{apic::LAPIC, gdt::Gdt},
     },
     irq::{IrqLine, lock::IrqLock},
-    memory::fault::PageFaultInfo,
+    memory::{fault::PageFaultInfo, virt::vmalloc},
     percpu::CpuData,
+    process::signal::Signal,
+    sched::Scheduler,
+    uapi::signal,
     util::mutex::spin::SpinMutex,
 };
 use alloc::sync::Arc;
 use core::{
     arch::{asm, naked_asm},
     mem::offset_of,
+    sync::atomic::Ordering,
 };
 use seq_macro::seq;
 
@@ -80,6 +84,8 @@ pub unsafe extern "C" fn amd64_syscall_stub() {
         "mov rdi, rsp",               // Put the trap frame struct as first argument.
         "call {syscall_handler}",     // Call syscall handler
         "cli",
+        "test al, al",                // If the context was modified, we can't use sysret.
+        "jnz {interrupt_return}",
         "pop r15",
         "pop r14",
         "pop r13",
@@ -101,6 +107,7 @@ pub unsafe extern "C" fn amd64_syscall_stub() {
         "sysretq",                    // Return to user mode.
 
         syscall_handler = sym syscall_handler,
+        interrupt_return = sym interrupt_return,
         user_stack = const offset_of!(CpuData, user_stack),
         kernel_stack = const offset_of!(CpuData, kernel_stack),
         user_code64 = const offset_of!(Gdt, user_code64) | CPL_USER as usize,
@@ -109,13 +116,14 @@ pub unsafe extern "C" fn amd64_syscall_stub() {
 }
 
 /// Invoked by either the interrupt or syscall stub.
-extern "C" fn syscall_handler(frame: *mut Context) {
+/// Returns true if the context can't be restored using `sysret` and needs a full interrupt return instead.
+extern "C" fn syscall_handler(frame: *mut Context) -> bool {
     unsafe {
         let frame = frame.as_mut().unwrap();
 
         // Arguments use the SYSV C ABI.
         // Except for a3, since RCX is needed for sysret, we need a different register.
-        let result = crate::syscall::dispatch(
+        crate::syscall::dispatch(
             frame,
             frame.rax as usize,
             frame.rdi as usize,
@@ -125,8 +133,15 @@ extern "C" fn syscall_handler(frame: *mut Context) {
             frame.r8 as usize,
             frame.r9 as usize,
         );
-        frame.rax = result.0 as u64;
-        frame.rdx = result.1 as u64;
+
+        // A task which was woken up during the call might have to run first.
+        CpuData::get().scheduler.preempt(true);
+        crate::process::signal::dispatch_pending(frame);
+
+        // sysret takes RIP from RCX, RFLAGS from R11 and RSP from the per-CPU block.
+        frame.rcx != frame.rip
+            || frame.r11 != frame.rflags
+            || frame.rsp != CpuData::get().user_stack.load(Ordering::Relaxed) as u64
     }
 }
 
@@ -232,16 +247,38 @@ per_cpu! {
 }
 
 /// Invoked by an interrupt stub.
-unsafe extern "C" fn idt_handler(context: *const Context) {
+unsafe extern "C" fn idt_handler(context: *mut Context) {
     let old = IrqLock::set_interrupted(true);
-    let context = unsafe { context.as_ref().unwrap() };
+    let context = unsafe { context.as_mut().unwrap() };
     let isr = context.isr;
 
+    // The interrupted code may have set the AC flag, which would let the handler access user
+    // memory. `iretq` restores it afterwards.
+    if super::ARCH_DATA.get().can_smap.load(Ordering::Relaxed) {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+
     match isr as u8 {
         // Exceptions.
         consts::IDT_PF => {
             page_fault_handler(context);
         }
+        consts::IDT_DE => try_signal_or_die(context, Signal::SIGFPE, signal::FPE_INTDIV),
+        consts::IDT_DB => try_signal_or_die(context, Signal::SIGTRAP, signal::TRAP_TRACE),
+        consts::IDT_BP => try_signal_or_die(context, Signal::SIGTRAP, signal::TRAP_BRKPT),
+        consts::IDT_OF | consts::IDT_BR | consts::IDT_GP => {
+            try_signal_or_die(context, Signal::SIGSEGV, signal::SI_KERNEL)
+        }
+        consts::IDT_UD => try_signal_or_die(context, Signal::SIGILL, signal::ILL_ILLOPN),
+        consts::IDT_NM => try_signal_or_die(context, Signal::SIGFPE, signal::FPE_FLTINV),
+        consts::IDT_NP | consts::IDT_SS => {
+            try_signal_or_die(context, Signal::SIGBUS, signal::BUS_ADRERR)
+        }
+        consts::IDT_MF | consts::IDT_XF => {
+            try_signal_or_die(context, Signal::SIGFPE, signal::FPE_FLTINV)
+        }
+        consts::IDT_AC => try_signal_or_die(context, Signal::SIGBUS, signal::BUS_ADRALN),
+        consts::IDT_DF => double_fault_handler(context),
         // Unhandled exceptions.
         0x00..0x20 => {
             error!("{:?}", context);
@@ -252,11 +289,16 @@ unsafe extern "C" fn idt_handler(context: *const Context) {
             halt();
         }
         consts::IDT_IPI_RESCHED => {
-            unsafe { crate::arch::sched::preempt_disable() };
-            if unsafe { crate::arch::sched::preempt_enable() } {
-                LAPIC.get().eoi();
-                CpuData::get().scheduler.reschedule();
-            }
+            LAPIC.get().eoi();
+            CpuData::get().scheduler.request_reschedule();
+        }
+        consts::IDT_IPI_SHOOTDOWN => {
+            LAPIC.get().eoi();
+            crate::memory::virt::mmu::handle_shootdown();
+        }
+        consts::IDT_TIMER => {
+            LAPIC.get().eoi();
+            CpuData::get().scheduler.tick();
         }
         // Any other ISR is an IRQ with a dynamic handler.
         _ => {
@@ -268,12 +310,49 @@ unsafe extern "C" fn idt_handler(context: *const Context) {
     }
 
     IrqLock::set_interrupted(old);
+
+    // Switch tasks if the time slice of the current one is used up.
+    // This can't be done from a nested interrupt.
+    let to_user = context.cs & consts::CPL_USER as u64 == consts::CPL_USER as u64;
+    if !old {
+        CpuData::get().scheduler.preempt(to_user);
+    }
+
+    // Deliver pending signals before returning to user mode.
+    if to_user {
+        crate::process::signal::dispatch_pending(context);
+    }
+}
+
+/// Try to send a signal to the user-space program or panic if the interrupt is caused by the kernel.
+fn try_signal_or_die(context: &Context, signal: Signal, code: u32) {
+    if context.cs & consts::CPL_USER as u64 != consts::CPL_USER as u64 {
+        error!("{:?}", context);
+        panic!("Got an exception {} in kernel mode", context.isr);
+    }
+
+    crate::process::signal::send_fault(signal, code as _, (context.rip as usize).into());
 }
 
-// /// Try to send a signal to the user-space program or panic if the interrupt is caused by the kernel.
-// fn try_signal_or_die(context: &Context, signal: u32, code: u32) {}
+/// Runs on its own stack, see [`super::system::idt::init`].
+fn double_fault_handler(context: &Context) -> ! {
+    // The page fault which ran into the guard page couldn't be delivered on the same stack.
+    let mut cr2: usize;
+    unsafe { asm!("mov {cr2}, cr2", cr2 = out(reg) cr2) };
+
+    error!("{:?}", context);
+    if vmalloc::is_guard_page(cr2.into()) || vmalloc::is_guard_page((context.rsp as usize).into()) {
+        panic!(
+            "Kernel stack overflow in task {} (IP: {:#x}, address: {:#x})",
+            Scheduler::get_current().get_id(),
+            context.rip,
+            cr2
+        );
+    }
+    panic!("Got a double fault (IP: {:#x})", context.rip);
+}
 
-fn page_fault_handler(context: &Context) {
+fn page_fault_handler(context: &mut Context) {
     let mut cr2: usize;
     unsafe { asm!("mov {cr2}, cr2", cr2 = out(reg) cr2) };
 
@@ -287,5 +366,7 @@ fn page_fault_handler(context: &Context) {
         addr: cr2.into(),
     };
 
-    crate::memory::virt::fault::handler(&info);
+    if let Some(resume) = crate::memory::virt::fault::handler(&info) {
+        context.rip = resume.value() as u64;
+    }
 }
@@ -1,6 +1,6 @@
 use super::{
     ARCH_DATA,
-    asm::{rdmsr, wrmsr},
+    asm::{rdmsr, rdxcr, wrmsr},
     consts::{self},
     core::get_per_cpu,
     system::{apic, gdt::Gdt},
@@ -12,18 +12,20 @@ use crate::{
     memory::{
         VirtAddr,
         pmm::{AllocFlags, KernelAlloc, PageAllocator},
-        virt::KERNEL_STACK_SIZE,
+        user::{self, UserPtr},
     },
     percpu::CpuData,
-    posix::errno::EResult,
+    posix::errno::{EResult, Errno},
     process::task::Task,
     sched::Scheduler,
+    uapi::signal::{siginfo_t, sigset_t},
+    util::{align_down, align_up},
 };
+use alloc::{vec, vec::Vec};
 use core::{
     arch::{asm, naked_asm},
     fmt::Write,
     mem::offset_of,
-    sync::atomic::Ordering,
 };
 
 #[repr(C)]
@@ -128,7 +130,7 @@ pub(in crate::arch) unsafe fn switch(from: *const Task, to: *const Task, irq_gua
         let to_context = to.task_context.lock();
 
         let cpu = ARCH_DATA.get();
-        TSS.get().lock().rsp0 = (to.kernel_stack.load(Ordering::Relaxed) + KERNEL_STACK_SIZE) as _;
+        TSS.get().lock().rsp0 = to.kernel_stack.top().value() as _;
 
         if from.is_user() {
             cpu.fpu_save.get()(from_context.fpu_region);
@@ -162,7 +164,8 @@ pub(in crate::arch) unsafe fn switch(from: *const Task, to: *const Task, irq_gua
 
         drop(from_context);
         drop(to_context);
-        drop(irq_guard);
+        // Interrupts have to stay disabled until the switch is finished.
+        core::mem::forget(irq_guard);
         perform_switch(old_rsp, new_rsp);
     }
 }
@@ -201,13 +204,13 @@ pub(in crate::arch) fn init_task(
     entry: extern "C" fn(usize, usize),
     arg1: usize,
     arg2: usize,
-    stack_start: VirtAddr,
+    stack_top: VirtAddr,
     is_user: bool,
 ) -> EResult<()> {
     let cpu = ARCH_DATA.get();
     // Prepare a dummy stack with an entry point function to return to.
     unsafe {
-        let frame = ((stack_start.value() + KERNEL_STACK_SIZE) as *mut TaskFrame).sub(1);
+        let frame = (stack_top.value() as *mut TaskFrame).sub(1);
         (*frame).rbx = entry as *const () as u64;
         (*frame).r12 = arg1 as u64;
         (*frame).r13 = arg2 as u64;
@@ -280,6 +283,22 @@ pub unsafe fn remote_reschedule(cpu: u32) {
     );
 }
 
+pub(in crate::arch) fn new_user_context(ip: VirtAddr, sp: VirtAddr, arg: usize) -> Context {
+    Context {
+        rip: ip.value() as u64,
+        rsp: sp.value() as u64,
+        rdi: arg as u64,
+        rflags: 0x202,
+        cs: offset_of!(Gdt, user_code64) as u64 | consts::CPL_USER as u64,
+        ss: offset_of!(Gdt, user_data) as u64 | consts::CPL_USER as u64,
+        ..Context::default()
+    }
+}
+
+pub(in crate::arch) fn set_tls(context: &mut TaskContext, tls: VirtAddr) {
+    context.fsbase = tls.value() as u64;
+}
+
 pub(in crate::arch) unsafe fn jump_to_user(ip: VirtAddr, sp: VirtAddr) -> ! {
     assert!(
         Scheduler::get_current().is_user(),
@@ -287,14 +306,7 @@ pub(in crate::arch) unsafe fn jump_to_user(ip: VirtAddr, sp: VirtAddr) -> ! {
     );
 
     // Create a new context for the user jump.
-    let mut context = Context {
-        rip: ip.value() as u64,
-        rsp: sp.value() as u64,
-        rflags: 0x202,
-        cs: offset_of!(Gdt, user_code64) as u64 | consts::CPL_USER as u64,
-        ss: offset_of!(Gdt, user_data) as u64 | consts::CPL_USER as u64,
-        ..Context::default()
-    };
+    let mut context = new_user_context(ip, sp, 0);
 
     // Clear segment registers. Because this also clears GSBASE, we have to restore it immediately.
     unsafe {
@@ -325,3 +337,151 @@ pub(in crate::arch) unsafe fn jump_to_context(context: *mut Context) -> ! {
         unreachable!();
     }
 }
+
+/// Layout of the data pushed onto the user stack when delivering a signal.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    /// Return address of the signal handler.
+    restorer: u64,
+    info: siginfo_t,
+    /// The interrupted context.
+    context: Context,
+    /// The signal mask that was active before the signal was delivered.
+    mask: sigset_t,
+    /// Address of the saved FPU state.
+    fpu: u64,
+}
+
+/// The System V ABI allows leaf functions to use 128 bytes below the stack pointer.
+const RED_ZONE_SIZE: usize = 128;
+
+/// The RFLAGS bits which user space is allowed to modify.
+const USER_RFLAGS: usize = consts::RFLAGS_CF
+    | consts::RFLAGS_PF
+    | consts::RFLAGS_AF
+    | consts::RFLAGS_ZF
+    | consts::RFLAGS_SF
+    | consts::RFLAGS_TF
+    | consts::RFLAGS_DF
+    | consts::RFLAGS_OF
+    | consts::RFLAGS_AC;
+
+/// Returns true if `addr` is a canonical lower half address.
+fn is_user_canonical(addr: u64) -> bool {
+    addr < 0x0000_8000_0000_0000
+}
+
+/// Allocates a zeroed kernel buffer for the FPU state. XSAVE requires the area to be aligned
+/// to 64 bytes, so it starts at the returned offset into the buffer.
+fn fpu_buffer(fpu_size: usize) -> (Vec<u8>, usize) {
+    let buffer = vec![0u8; fpu_size + 63];
+    let offset = buffer.as_ptr().align_offset(64);
+    (buffer, offset)
+}
+
+pub(in crate::arch) fn push_signal_frame(
+    context: &mut Context,
+    handler: VirtAddr,
+    restorer: VirtAddr,
+    info: &siginfo_t,
+    mask: sigset_t,
+) -> EResult<()> {
+    let cpu = ARCH_DATA.get();
+    let fpu_size = *cpu.fpu_size.get();
+
+    // Don't clobber the red zone. XSAVE requires the FPU area to be aligned to 64 bytes.
+    let fpu_addr = (context.rsp as usize)
+        .checked_sub(RED_ZONE_SIZE + align_up(fpu_size, 64))
+        .map(|x| align_down(x, 64))
+        .ok_or(Errno::EFAULT)?;
+
+    // On entry to the handler, the stack must look like a call was just made,
+    // meaning (rsp + 8) has to be aligned to 16 bytes.
+    let frame_addr = fpu_addr
+        .checked_sub(size_of::<SignalFrame>() + size_of::<u64>())
+        .map(|x| align_down(x, 16) + size_of::<u64>())
+        .ok_or(Errno::EFAULT)?;
+
+    // The FPU still contains the state of the interrupted user context. It's saved to the kernel
+    // first, because the user stack may fault.
+    let (mut buffer, offset) = fpu_buffer(fpu_size);
+    let fpu = &mut buffer[offset..][..fpu_size];
+    unsafe { cpu.fpu_save.get()(fpu.as_mut_ptr()) };
+    user::copy_to_user(fpu_addr.into(), fpu)?;
+
+    let frame = SignalFrame {
+        restorer: restorer.value() as u64,
+        info: *info,
+        context: *context,
+        mask,
+        fpu: fpu_addr as u64,
+    };
+    if !UserPtr::new(frame_addr.into()).write(frame) {
+        return Err(Errno::EFAULT);
+    }
+
+    // Call the handler with (signo, &info, &context).
+    context.rip = handler.value() as u64;
+    context.rsp = frame_addr as u64;
+    context.rdi = info.si_signo as u64;
+    context.rsi = (frame_addr + offset_of!(SignalFrame, info)) as u64;
+    context.rdx = (frame_addr + offset_of!(SignalFrame, context)) as u64;
+    context.rax = 0;
+    // The ABI requires the direction flag to be clear on function entry.
+    context.rflags &= !(consts::RFLAGS_DF as u64);
+
+    Ok(())
+}
+
+pub(in crate::arch) fn pop_signal_frame(context: &mut Context) -> EResult<sigset_t> {
+    let cpu = ARCH_DATA.get();
+    let fpu_size = *cpu.fpu_size.get();
+
+    // The return address has already been popped by the time the restorer is running.
+    let frame_addr = (context.rsp as usize)
+        .checked_sub(size_of::<u64>())
+        .ok_or(Errno::EFAULT)?;
+
+    let frame = UserPtr::<SignalFrame>::new(frame_addr.into())
+        .read()
+        .ok_or(Errno::EFAULT)?;
+
+    let saved = frame.context;
+    if !is_user_canonical(saved.rip) || !is_user_canonical(saved.rsp) {
+        return Err(Errno::EFAULT);
+    }
+
+    // Everything is read before the context is changed, so a bad frame leaves it untouched.
+    let (mut buffer, offset) = fpu_buffer(fpu_size);
+    let fpu = &mut buffer[offset..][..fpu_size];
+    user::copy_from_user(fpu, (frame.fpu as usize).into())?;
+
+    // Never trust privileged state coming from user space.
+    *context = Context {
+        isr: context.isr,
+        error: context.error,
+        cs: offset_of!(Gdt, user_code64) as u64 | consts::CPL_USER as u64,
+        ss: offset_of!(Gdt, user_data) as u64 | consts::CPL_USER as u64,
+        rflags: (saved.rflags & USER_RFLAGS as u64) | consts::RFLAGS_IF as u64 | 0x2,
+        ..saved
+    };
+
+    let region = fpu.as_mut_ptr();
+    unsafe {
+        // Reserved MXCSR bits cause a #GP when loaded.
+        let mxcsr = region.add(24) as *mut u32;
+        mxcsr.write(mxcsr.read() & 0xFFFF);
+
+        // Same goes for state components which are not enabled and the reserved header fields.
+        if fpu_size > 512 {
+            let header = region.add(512) as *mut u64;
+            header.write(header.read() & rdxcr(0));
+            core::ptr::write_bytes(header.add(1), 0, 7);
+        }
+
+        cpu.fpu_restore.get()(region);
+    }
+
+    Ok(frame.mask)
+}
@@ -1,7 +1,7 @@
 use crate::{
     arch::x86_64::{
         asm,
-        consts::{self, IDT_IPI_RESCHED},
+        consts::{self, IDT_TIMER},
         irq::IRQ_LINES,
     },
     clock,
@@ -23,10 +23,12 @@ pub struct LocalApic {
     /// If [`Some`], points to the xAPIC MMIO space.
     /// Otherwise, it's an x2APIC.
     xapic_regs: SpinMutex<Option<MmioView>>,
+    /// The ID of this LAPIC, so other CPUs can address it.
+    apic_id: AtomicU32,
 }
 
 per_cpu! {
-    pub static LAPIC: LocalApic = LocalApic { ticks_per_10ms: AtomicU32::new(0), xapic_regs: SpinMutex::new(None) };
+    pub static LAPIC: LocalApic = LocalApic { ticks_per_10ms: AtomicU32::new(0), xapic_regs: SpinMutex::new(None), apic_id: AtomicU32::new(0) };
 }
 
 #[allow(unused)]
@@ -123,7 +125,16 @@ impl LocalApic {
         // Enable APIC bit in the SIVR.
         lapic.write_reg(lapic_regs::SIVR, lapic.read_reg(lapic_regs::SIVR) | 0x100);
 
-        if lapic.xapic_regs.lock().is_some() {
+        let is_xapic = lapic.xapic_regs.lock().is_some();
+
+        // The xAPIC keeps its ID in the upper byte of the register.
+        let id = match is_xapic {
+            true => lapic.id() >> 24,
+            false => lapic.id(),
+        };
+        lapic.apic_id.store(id, Ordering::Relaxed);
+
+        if is_xapic {
             lapic.write_reg(lapic_regs::DFR, 0xF000_0000);
             // Logical destination = LAPIC ID.
             lapic.write_reg(lapic_regs::LDR, lapic.read_reg(lapic_regs::ID));
@@ -147,7 +158,7 @@ impl LocalApic {
         );
 
         // Finally, run the periodic timer interrupt.
-        lapic.write_reg(lapic_regs::LVT_TR, IDT_IPI_RESCHED as u64 | 0x20000);
+        lapic.write_reg(lapic_regs::LVT_TR, IDT_TIMER as u64 | 0x20000);
         lapic.write_reg(lapic_regs::DCR, 3);
         lapic.write_reg(
             lapic_regs::ICR_TIMER,
@@ -190,6 +201,12 @@ impl LocalApic {
         return self.read_reg(lapic_regs::ID) as u32;
     }
 
+    /// Returns the ID which is used to send IPIs to this LAPIC.
+    /// Unlike [`Self::id`], this can be called for the LAPIC of any CPU.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
     /// Signals an end of interrupt to the LAPIC.
     pub fn eoi(&self) {
         self.write_reg(lapic_regs::EOI, 0);
@@ -1,12 +1,14 @@
 use crate::{
     arch::x86_64::consts::{CPL_KERNEL, CPL_USER, MSR_GS_BASE},
-    memory::virt::KERNEL_STACK_SIZE,
+    memory::virt::stack::KernelStack,
     util::mutex::spin::SpinMutex,
 };
-use alloc::boxed::Box;
 use bitflags::bitflags;
 use bytemuck::{Pod, Zeroable};
-use core::{arch::asm, mem::offset_of};
+use core::{
+    arch::asm,
+    mem::{self, offset_of},
+};
 
 /// Global Descriptor Table.
 /// These entries are ordered exactly like this because the SYSRET instruction expects it.
@@ -339,11 +341,15 @@ pub fn init() {
     let mut gdt = GDT.get().lock();
     let mut tss = TSS.get().lock();
 
-    // Allocate an initial stack for the TSS.
-    // TODO: Use kernel stack struct.
-    let stack = unsafe { Box::new_zeroed_slice(KERNEL_STACK_SIZE).assume_init() };
-    let val = Box::leak(stack).as_mut_ptr() as *mut u8 as u64 + KERNEL_STACK_SIZE as u64;
-    tss.rsp0 = val;
+    // Allocate an initial stack for the TSS, until the scheduler switches to a task.
+    let stack = KernelStack::new().expect("Unable to allocate the initial kernel stack");
+    tss.rsp0 = stack.top().value() as u64;
+    mem::forget(stack);
+
+    // A double fault gets its own stack, since the old one might have overflown.
+    let stack = KernelStack::new().expect("Unable to allocate the double fault stack");
+    tss.ist1 = stack.top().value() as u64;
+    mem::forget(stack);
 
     *gdt = BASE_GDT;
     gdt.tss.set_base(unsafe { TSS.get().raw_inner() } as u64);
@@ -1,10 +1,13 @@
 use super::gdt::Gdt;
-use crate::memory::VirtAddr;
+use crate::{arch::x86_64::consts::IDT_DF, memory::VirtAddr};
 use core::{arch::asm, mem::offset_of};
 use seq_macro::seq;
 
 pub const IDT_SIZE: usize = 256;
 
+/// The interrupt stack table entry used by the double fault handler, see [`super::gdt::init`].
+const DOUBLE_FAULT_IST: u8 = 1;
+
 // Temporary storage to hold the limit and base of the IDT.
 #[repr(C, packed)]
 pub struct IdtRegister {
@@ -36,6 +39,13 @@ pub fn init() {
         seq!(N in 0..256 {
             (*idt).routines[N] = IdtEntry::new((crate::arch::internal::irq::interrupt_stub~N as *const () as usize).into(), 0, GateType::Interrupt);
         });
+
+        // A double fault is usually caused by a kernel stack overflow, so it can't use that stack.
+        (*idt).routines[IDT_DF as usize] = IdtEntry::new(
+            (crate::arch::internal::irq::interrupt_stub8 as *const () as usize).into(),
+            DOUBLE_FAULT_IST,
+            GateType::Interrupt,
+        );
     }
 }
 
@@ -1,6 +1,6 @@
 use crate::{
     arch::{
-        virt::{PageTableEntry, get_level_bits, get_max_leaf_level, get_page_bits},
+        virt::{PageTableEntry, get_level_bits, get_page_bits},
         x86_64::{
             consts::{CR0_ET, CR0_PE, CR0_PG, CR4_PAE, MSR_EFER, MSR_EFER_LME, MSR_EFER_NXE},
             system::{
@@ -57,6 +57,7 @@ SMP_TRAMPOLINE_DATA:
 .set farjmp_offset, (data_offset + {farjmp_offset})
 .set temp_stack_offset, (data_offset + {temp_stack_offset})
 .set temp_cr3_offset, (data_offset + {temp_cr3_offset})
+.set efer_offset, (data_offset + {efer_offset})
 .set entry_offset, (data_offset + {entry_offset})
 .set hhdm_offset, (data_offset + {hhdm_offset})
 
@@ -97,7 +98,7 @@ mode32:
     mov cr4, eax
 
     mov ecx, {msr_efer}
-    mov eax, {efer_lme}
+    mov eax, dword ptr [ebx + efer_offset]
     xor edx, edx
     wrmsr
 
@@ -123,11 +124,6 @@ mode64:
     mov gs, ax
     mov ss, ax
 
-    mov ecx, {msr_efer}
-    rdmsr
-    or eax, {efer_nxe}
-    wrmsr
-
     xor ebp, ebp
 
     lea rdi, [rbx + data_offset]
@@ -146,6 +142,7 @@ SMP_TRAMPOLINE_END:",
     farjmp_offset = const FARJMP_OFFSET,
     temp_stack_offset = const TEMP_STACK_OFFSET,
     temp_cr3_offset = const TEMP_CR3_OFFSET,
+    efer_offset = const EFER_OFFSET,
     entry_offset = const ENTRY_OFFSET,
     hhdm_offset = const HHDM_OFFSET,
 
@@ -159,8 +156,6 @@ SMP_TRAMPOLINE_END:",
     cr4_pae = const CR4_PAE,
 
     msr_efer = const MSR_EFER,
-    efer_lme = const MSR_EFER_LME,
-    efer_nxe = const MSR_EFER_NXE,
 );
 
 const INFO_SIZE: usize = size_of::<InfoData>();
@@ -168,6 +163,7 @@ const GDTR_OFFSET: usize = offset_of!(InfoData, gdtr_limit);
 const FARJMP_OFFSET: usize = offset_of!(InfoData, farjmp_offset);
 const TEMP_STACK_OFFSET: usize = offset_of!(InfoData, temp_stack);
 const TEMP_CR3_OFFSET: usize = offset_of!(InfoData, temp_cr3);
+const EFER_OFFSET: usize = offset_of!(InfoData, efer);
 const ENTRY_OFFSET: usize = offset_of!(InfoData, entry);
 const HHDM_OFFSET: usize = offset_of!(InfoData, hhdm_offset);
 
@@ -187,6 +183,9 @@ struct InfoData {
     temp_stack: u32,
     hhdm_offset: u64,
     temp_cr3: u32,
+    /// EFER value to enter long mode with.
+    /// The page tables may only contain non-executable pages if NXE is already set.
+    efer: u32,
     entry: u64,
     lapic_id: u32,
     booted: u8,
@@ -271,6 +270,10 @@ fn start_ap(temp_cr3: u32, id: u32) {
         temp_stack: temp_stack as u32,
         hhdm_offset: PhysAddr::null().as_hhdm() as *mut u8 as _,
         temp_cr3,
+        efer: match super::super::virt::has_nx() {
+            true => MSR_EFER_LME | MSR_EFER_NXE,
+            false => MSR_EFER_LME,
+        },
         entry: ap_entry as *const fn() as _,
         lapic_id: id,
         booted: 0,
@@ -393,7 +396,8 @@ fn INIT_APS_STAGE() {
             temp_l3_buffer.add(i).write(
                 PageTableEntry::new(
                     PhysAddr::new(
-                        i * (1 << (get_page_bits() + get_max_leaf_level() * get_level_bits())),
+                        // These are 1 GiB pages.
+                        i * (1 << (get_page_bits() + 2 * get_level_bits())),
                     ),
                     PteFlags::Read | PteFlags::Write | PteFlags::Exec | PteFlags::Large,
                     3,
@@ -1,9 +1,19 @@
-use crate::memory::{
-    PhysAddr, VirtAddr,
-    virt::{PteFlags, mmu::PageTable},
+use crate::{
+    arch::x86_64::{
+        ARCH_DATA, consts,
+        system::apic::{self, LAPIC},
+    },
+    memory::{
+        PhysAddr, VirtAddr,
+        virt::{PteFlags, mmu::PageTable},
+    },
+    percpu::CpuData,
 };
 use bitflags::bitflags;
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 
 #[repr(transparent)]
 #[derive(Clone, Copy)]
@@ -24,6 +34,17 @@ impl core::fmt::Debug for PageTableEntry {
 /// Masks only the address bits of a PTE.
 const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 
+// Memory types which can be stored in the PAT.
+const PAT_UC: u64 = 0x00;
+const PAT_WC: u64 = 0x01;
+const PAT_WT: u64 = 0x04;
+const PAT_WB: u64 = 0x06;
+
+/// The PAT entries are selected by the PCD and PWT bits of a PTE.
+/// The PAT bit itself is never set, so the upper half repeats the lower one.
+/// Entries 0 and 3 match the power-on defaults, so mappings made before [`init_pat`] are fine.
+const PAT_LAYOUT: u64 = (PAT_WB | PAT_WC << 8 | PAT_WT << 16 | PAT_UC << 24) * 0x1_0000_0001;
+
 bitflags! {
     #[repr(transparent)]
     #[derive(Debug)]
@@ -49,7 +70,7 @@ impl PageTableEntry {
         return Self { inner: 0 };
     }
 
-    pub const fn new(address: PhysAddr, flags: PteFlags, _level: usize) -> Self {
+    pub fn new(address: PhysAddr, flags: PteFlags, _level: usize) -> Self {
         let mut result = (address.value() as u64 & ADDR_MASK) | PageFlags::Present.bits();
 
         if flags.contains(PteFlags::User) {
@@ -63,18 +84,56 @@ impl PageTableEntry {
                 result |= PageFlags::ReadWrite.bits();
             }
 
-            if !flags.contains(PteFlags::Exec) {
+            if !flags.contains(PteFlags::Exec) && has_nx() {
                 result |= PageFlags::ExecuteDisable.bits();
             }
 
             if flags.contains(PteFlags::Large) {
                 result |= PageFlags::Size.bits();
             }
+
+            if flags.contains(PteFlags::WriteCombining) {
+                result |= PageFlags::WriteThrough.bits();
+            } else if flags.contains(PteFlags::WriteThrough) {
+                result |= PageFlags::CacheDisable.bits();
+            } else if flags.contains(PteFlags::Uncached) {
+                result |= PageFlags::CacheDisable.bits() | PageFlags::WriteThrough.bits();
+            }
         }
 
         Self { inner: result }
     }
 
+    /// Returns the flags of a present leaf PTE. The caching type is decoded with [`PAT_LAYOUT`].
+    pub fn flags(&self, level: usize) -> PteFlags {
+        let flags = PageFlags::from_bits_retain(self.inner);
+        let mut result = PteFlags::Read;
+
+        if flags.contains(PageFlags::UserMode) {
+            result |= PteFlags::User;
+        }
+        if flags.contains(PageFlags::ReadWrite) {
+            result |= PteFlags::Write;
+        }
+        if !flags.contains(PageFlags::ExecuteDisable) {
+            result |= PteFlags::Exec;
+        }
+        if level > 0 && flags.contains(PageFlags::Size) {
+            result |= PteFlags::Large;
+        }
+
+        result
+            | match (
+                flags.contains(PageFlags::CacheDisable),
+                flags.contains(PageFlags::WriteThrough),
+            ) {
+                (false, false) => PteFlags::empty(),
+                (false, true) => PteFlags::WriteCombining,
+                (true, false) => PteFlags::WriteThrough,
+                (true, true) => PteFlags::Uncached,
+            }
+    }
+
     pub const fn inner(&self) -> usize {
         self.inner as usize
     }
@@ -91,6 +150,46 @@ impl PageTableEntry {
         PageFlags::from_bits_retain(self.inner).contains(PageFlags::Dirty)
     }
 
+    /// Clears the dirty bit and returns whether it was set.
+    /// This is atomic, because the MMU may set the bit at the same time.
+    pub fn clear_dirty(&mut self) -> bool {
+        let inner = unsafe { AtomicU64::from_ptr(&raw mut self.inner) };
+        inner.fetch_and(!PageFlags::Dirty.bits(), Ordering::AcqRel) & PageFlags::Dirty.bits() != 0
+    }
+
+    /// Replaces the PTE with an empty one and returns the old value.
+    /// This is atomic, so a dirty bit which the MMU sets at the same time isn't lost.
+    pub fn take(&mut self) -> Self {
+        let inner = unsafe { AtomicU64::from_ptr(&raw mut self.inner) };
+        Self {
+            inner: inner.swap(0, Ordering::AcqRel),
+        }
+    }
+
+    /// Clears the accessed bit and returns whether it was set.
+    pub fn clear_accessed(&mut self) -> bool {
+        let inner = unsafe { AtomicU64::from_ptr(&raw mut self.inner) };
+        inner.fetch_and(!PageFlags::Accessed.bits(), Ordering::AcqRel) & PageFlags::Accessed.bits()
+            != 0
+    }
+
+    /// Returns a non-present PTE which records that the page was moved to swap space.
+    pub const fn new_swap(entry: usize) -> Self {
+        Self {
+            inner: ((entry as u64) << 12) & ADDR_MASK | PageFlags::Available.bits(),
+        }
+    }
+
+    /// Returns the swap entry stored in a PTE created with [`Self::new_swap`].
+    pub fn swap_entry(&self) -> Option<usize> {
+        match !self.is_present()
+            && PageFlags::from_bits_retain(self.inner).contains(PageFlags::Available)
+        {
+            true => Some(((self.inner & ADDR_MASK) >> 12) as usize),
+            false => None,
+        }
+    }
+
     pub fn address(&self) -> PhysAddr {
         (self.inner & ADDR_MASK).into()
     }
@@ -102,6 +201,18 @@ pub(in crate::arch) fn flush_tlb(addr: VirtAddr) {
     }
 }
 
+pub(in crate::arch) fn send_shootdown(cpu: &'static CpuData) {
+    LAPIC.get().send_ipi(
+        apic::IpiTarget::Specific(LAPIC.get_for(cpu).apic_id()),
+        consts::IDT_IPI_SHOOTDOWN,
+        apic::DeliveryMode::Fixed,
+        apic::DestinationMode::Physical,
+        apic::DeliveryStatus::Idle,
+        apic::Level::Assert,
+        apic::TriggerMode::Edge,
+    );
+}
+
 pub(in crate::arch) unsafe fn set_page_table(pt: &PageTable) {
     unsafe {
         asm!("mov cr3, {addr}", addr = in(reg) pt.get_head_addr().value());
@@ -112,8 +223,39 @@ pub(in crate::arch) const fn get_page_bits() -> usize {
     12
 }
 
-pub(in crate::arch) const fn get_max_leaf_level() -> usize {
-    2
+/// Cached result of [`get_max_leaf_level`], or 0 if it wasn't determined yet.
+static MAX_LEAF_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+pub(in crate::arch) fn get_max_leaf_level() -> usize {
+    match MAX_LEAF_LEVEL.load(Ordering::Relaxed) {
+        0 => {
+            // 2 MiB pages are always available, 1 GiB pages are optional.
+            let level =
+                match super::asm::cpuid(0x8000_0001, 0).edx & consts::CPUID_80000001D_PDPE1GB {
+                    0 => 1,
+                    _ => 2,
+                };
+            MAX_LEAF_LEVEL.store(level, Ordering::Relaxed);
+            level
+        }
+        x => x,
+    }
+}
+
+/// Cached result of [`has_nx`]: 0 if it wasn't determined yet, 1 if unsupported, 2 if supported.
+static HAS_NX: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns true if the CPU can mark pages as not executable.
+/// Without support, [`PageFlags::ExecuteDisable`] is a reserved bit and must never be set.
+pub(super) fn has_nx() -> bool {
+    match HAS_NX.load(Ordering::Relaxed) {
+        0 => {
+            let supported = super::asm::cpuid(0x8000_0001, 0).edx & consts::CPUID_80000001D_NX != 0;
+            HAS_NX.store(supported as usize + 1, Ordering::Relaxed);
+            supported
+        }
+        x => x == 2,
+    }
 }
 
 pub(in crate::arch) const fn get_level_bits() -> usize {
@@ -135,3 +277,94 @@ pub(in crate::arch) fn get_pfndb_base() -> VirtAddr {
 pub(in crate::arch) fn get_map_base() -> VirtAddr {
     VirtAddr::new(0xFFFF_C000_0000_0000)
 }
+
+/// Cached result of [`supports_cache_types`]: 0 if it wasn't determined yet, 1 if unsupported,
+/// 2 if supported.
+static HAS_PAT: AtomicUsize = AtomicUsize::new(0);
+
+/// Without the PAT, [`init_pat`] leaves the PCD and PWT bits with their legacy meaning, which
+/// can't express write-combining.
+pub(in crate::arch) fn supports_cache_types() -> bool {
+    match HAS_PAT.load(Ordering::Relaxed) {
+        0 => {
+            let supported = super::asm::cpuid(1, 0).edx & consts::CPUID_1D_PAT != 0;
+            HAS_PAT.store(supported as usize + 1, Ordering::Relaxed);
+            supported
+        }
+        x => x == 2,
+    }
+}
+
+/// Runs `f` with access to user pages. With SMAP enabled, the kernel may only touch them while
+/// the AC flag is set, anything else faults.
+fn user_access<R>(f: impl FnOnce() -> R) -> R {
+    // Not `nomem`, the accesses in `f` must not be moved outside of the window.
+    let smap = ARCH_DATA.get().can_smap.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+    result
+}
+
+/// Copies with `rep movsb`. A fault resumes right after it, with the bytes left over in `rcx`.
+pub(in crate::arch) unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    let remaining = user_access(|| unsafe {
+        let remaining: usize;
+        asm!(
+            "2:",
+            "rep movsb",
+            "3:",
+            ".pushsection .user_fixup, \"a\"",
+            ".balign 4",
+            ".long 2b - .",
+            ".long 3b - .",
+            ".popsection",
+            inout("rcx") len => remaining,
+            inout("rdi") dst => _,
+            inout("rsi") src => _,
+            options(nostack, preserves_flags),
+        );
+        remaining
+    });
+    remaining == 0
+}
+
+/// Programs the PAT of this CPU with [`PAT_LAYOUT`]. All CPUs have to use the same layout.
+///
+/// The MTRRs are left as the firmware set them up. Write-combining and uncached PAT entries take
+/// precedence over any MTRR type, so MMIO and framebuffers are mapped correctly either way.
+pub(super) fn init_pat() {
+    if super::asm::cpuid(1, 0).edx & consts::CPUID_1D_PAT == 0 {
+        warn!("CPU doesn't support the PAT, write-combining is not available");
+        return;
+    }
+
+    // Changing memory types requires the caches to be disabled and flushed first.
+    unsafe {
+        let cr0: usize;
+        asm!("mov {cr0}, cr0", cr0 = out(reg) cr0, options(nostack));
+        asm!(
+            "mov cr0, {cr0}",
+            "wbinvd",
+            cr0 = in(reg) (cr0 | consts::CR0_CD) & !consts::CR0_NW,
+            options(nostack)
+        );
+
+        super::asm::wrmsr(consts::MSR_PAT, PAT_LAYOUT);
+
+        // Reloading CR3 flushes all non-global TLB entries.
+        asm!(
+            "wbinvd",
+            "mov {tmp}, cr3",
+            "mov cr3, {tmp}",
+            "mov cr0, {cr0}",
+            tmp = out(reg) _,
+            cr0 = in(reg) cr0,
+            options(nostack)
+        );
+    }
+}
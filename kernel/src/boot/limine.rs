@@ -32,6 +32,7 @@ pub static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 #[unsafe(link_section = ".boot")]
 pub static PAGING_REQUEST: PagingModeRequest = PagingModeRequest::new();
 
+/// The kernel is relocatable, so Limine loads it at a random address unless `kaslr: no` is set.
 #[unsafe(link_section = ".boot")]
 pub static KERNEL_ADDR_REQUEST: ExecutableAddressRequest = ExecutableAddressRequest::new();
 
@@ -1,7 +1,7 @@
 //! Global timer management.
 // TODO: Try to get rid of some locks.
 
-use super::util::mutex::spin::SpinMutex;
+use super::{irq::lock::IrqLock, util::mutex::spin::SpinMutex};
 use alloc::boxed::Box;
 
 #[initgraph::task(name = "generic.clock")]
@@ -34,6 +34,8 @@ pub enum ClockError {
 
 /// Gets the elapsed nanoseconds since initialization of this timer.
 pub fn get_elapsed() -> usize {
+    // The clock is also read by the timer interrupt.
+    let _irq = IrqLock::lock();
     let guard = CLOCK.lock();
     match &guard.current {
         Some(x) => x.get_elapsed_ns() + guard.counter_base,
@@ -43,6 +45,8 @@ pub fn get_elapsed() -> usize {
 
 /// Switches to a new clock source if it is of higher priority.
 pub fn switch(mut new_source: Box<dyn ClockSource>) -> Result<(), ClockError> {
+    let _irq = IrqLock::lock();
+
     // Determine if we should make the switch.
     if let Some(x) = &CLOCK.lock().current {
         let prio = x.get_priority();
@@ -68,12 +72,13 @@ pub fn switch(mut new_source: Box<dyn ClockSource>) -> Result<(), ClockError> {
 }
 
 pub fn has_clock() -> bool {
+    let _irq = IrqLock::lock();
     return CLOCK.lock().current.is_some();
 }
 
 /// Blocking wait for a given amount of nanoseconds.
 pub fn block_ns(time: usize) -> Result<(), ClockError> {
-    if CLOCK.lock().current.is_none() {
+    if !has_clock() {
         error!(
             "Unable to sleep for {} nanoseconds. No clock source available, this would block forever!",
             time
@@ -2,7 +2,7 @@ use crate::{
     device::drm::object::{
         AtomicState, BufferObject, Connector, Crtc, Encoder, Framebuffer, ModeObject, Plane,
     },
-    memory::{AddressSpace, UserPtr, VirtAddr, VmFlags},
+    memory::{AddressSpace, UserPtr, VirtAddr, VmCacheType, VmFlags},
     posix::errno::{EResult, Errno},
     process::{Identity, Process},
     uapi::{
@@ -13,7 +13,7 @@ use crate::{
     util::mutex::spin::SpinMutex,
     vfs::{
         self, File,
-        file::{FileOps, MmapFlags},
+        file::{self, FileOps, MmapFlags},
         inode::Mode,
     },
 };
@@ -749,6 +749,7 @@ impl FileOps for DrmFile {
                         reserved: 0,
                     };
                     self.events.lock().push(event);
+                    file::notify_poll();
                 }
             }
             x => {
@@ -780,11 +781,13 @@ impl FileOps for DrmFile {
             .find(|x| x.id() == buffer_id)
             .ok_or(Errno::EINVAL)?;
 
+        // Buffers are only written to by the CPU and scanned out by the display.
         space.map_object(
             buffer.clone(),
             addr,
             len,
             prot,
+            VmCacheType::WriteCombining,
             offset as u32 as uapi::off_t,
         )?;
 
@@ -5,7 +5,7 @@ use crate::{
     memory::{
         PhysAddr, UserPtr, VirtAddr, free, malloc,
         pmm::KernelAlloc,
-        virt::{VmFlags, mmu::PageTable},
+        virt::{VmCacheType, VmFlags, mmu::PageTable},
     },
     posix::errno::{EResult, Errno},
     uapi::{self, termios::winsize},
@@ -64,6 +64,7 @@ impl FbCon {
             .map_memory::<KernelAlloc>(
                 fb.base,
                 VmFlags::Read | VmFlags::Write,
+                VmCacheType::WriteCombining,
                 fb.pitch * fb.height,
             )
             .unwrap();
@@ -127,8 +128,7 @@ impl Drop for FbCon {
         unsafe { flanterm_sys::flanterm_deinit(self.ctx, Some(free)) };
 
         PageTable::get_kernel()
-            .unmap_range::<KernelAlloc>(self.mem.into(), self.fb.pitch * self.fb.height)
-            .unwrap();
+            .unmap_memory::<KernelAlloc>(self.mem, self.fb.pitch * self.fb.height);
     }
 }
 
@@ -32,14 +32,11 @@ impl IrqLock {
     pub fn set_interrupted(value: bool) -> bool {
         IRQ_MUTEX.get().in_interrupt.swap(value, Ordering::Release)
     }
-}
-
-pub struct IrqGuard<'a> {
-    _p: PhantomData<&'a ()>,
-}
 
-impl<'a> Drop for IrqGuard<'a> {
-    fn drop(&mut self) {
+    /// Releases a guard which was leaked with [`core::mem::forget`].
+    /// # Safety
+    /// Every call has to match exactly one leaked guard.
+    pub unsafe fn force_unlock() {
         let cpu = IRQ_MUTEX.get();
         if !cpu.in_interrupt.load(Ordering::Acquire) {
             let old_depth = cpu.depth.fetch_sub(1, Ordering::Acquire);
@@ -51,3 +48,13 @@ impl<'a> Drop for IrqGuard<'a> {
         }
     }
 }
+
+pub struct IrqGuard<'a> {
+    _p: PhantomData<&'a ()>,
+}
+
+impl<'a> Drop for IrqGuard<'a> {
+    fn drop(&mut self) {
+        unsafe { IrqLock::force_unlock() };
+    }
+}
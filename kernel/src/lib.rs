@@ -36,6 +36,7 @@ pub mod panic;
 pub mod percpu;
 pub mod posix;
 pub mod process;
+pub mod random;
 pub mod sched;
 pub mod syscall;
 pub mod system;
@@ -110,11 +111,7 @@ pub extern "C" fn main(_: usize, _: usize) {
     envs.iter()
         .for_each(|x| log!("    {}", String::from_utf8_lossy(x)));
 
-    unsafe {
-        INIT.init(Arc::new(
-            Process::new("init".into(), None).expect("Unable to create init process"),
-        ))
-    };
+    unsafe { INIT.init(Process::new("init".into(), None).expect("Unable to create init process")) };
 
     let init_proc = INIT.get();
     // Open /dev/console for stdio for init.
@@ -1,25 +1,136 @@
 use crate::{
     arch::virt::get_page_size,
     memory::{
-        PhysAddr,
-        pmm::{AllocFlags, KernelAlloc, PageAllocator},
+        PhysAddr, oom,
+        pmm::{AllocFlags, KernelAlloc, Page, PageAllocator},
+        swap::{self, SwapEntry},
     },
-    posix::errno::EResult,
+    posix::errno::{EResult, Errno},
     uapi,
     util::mutex::spin::SpinMutex,
+    vfs::File,
+};
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    sync::Arc,
+    vec::Vec,
 };
-use alloc::{collections::btree_map::BTreeMap, sync::Arc};
 use core::{fmt::Debug, num::NonZeroUsize, slice};
 
 pub trait MemoryObject {
     /// Attempts to get the physical address of a page with a relative index into this object.
     /// Returns [`None`] if the page is out of bounds for this object.
     fn try_get_page(&self, page_index: usize) -> Option<PhysAddr>;
+
+    /// Attempts to get `num_pages` pages starting at `page_index` as one physically consecutive
+    /// block which is aligned to its size, so it can be mapped as a large page.
+    /// Returns [`None`] if the pages have to be mapped one by one.
+    fn try_get_huge_page(&self, page_index: usize, num_pages: usize) -> Option<PhysAddr> {
+        let _ = (page_index, num_pages);
+        None
+    }
+
+    /// Drops `num_pages` pages starting at `page_index`, so they have to be fetched again on the
+    /// next access. Objects which can't give up their pages ignore this.
+    fn discard(&self, page_index: usize, num_pages: usize) {
+        let _ = (page_index, num_pages);
+    }
+
+    /// Creates a new object which shares all current pages with this one.
+    /// Afterwards, both objects have to use [`Self::unshare_page`] before writing to a page.
+    /// Returns [`None`] if this object can't be copied on write.
+    fn clone_cow(&self) -> Option<Arc<dyn MemoryObject>> {
+        None
+    }
+
+    /// Like [`Self::try_get_page`], but copies the page first if it's shared with other objects.
+    fn unshare_page(&self, page_index: usize) -> Option<PhysAddr> {
+        self.try_get_page(page_index)
+    }
+
+    /// Records that a page has been modified, so the next [`Self::sync`] writes it back.
+    fn mark_dirty(&self, page_index: usize) {
+        let _ = page_index;
+    }
+
+    /// Writes all modified pages in a range back to where they were loaded from.
+    fn sync(&self, page_index: usize, num_pages: usize) -> EResult<()> {
+        let _ = (page_index, num_pages);
+        Ok(())
+    }
+
+    /// Returns a page which can be moved to swap space, with an extra reference for the caller.
+    /// Returns [`None`] if the page can't be swapped out, for example because it's shared with
+    /// another object.
+    fn prepare_swap_out(&self, page_index: usize) -> Option<PhysAddr> {
+        let _ = page_index;
+        None
+    }
+
+    /// Replaces a page returned by [`Self::prepare_swap_out`] with `entry` once it was written
+    /// to swap space, and drops the reference of the caller. Returns false if the page was used
+    /// in the meantime or `entry` is [`None`], the caller still owns `entry` in that case.
+    fn finish_swap_out(&self, page_index: usize, page: PhysAddr, entry: Option<SwapEntry>) -> bool {
+        let _ = (page_index, entry);
+        unsafe { Page::drop_ref(page) };
+        false
+    }
+
+    /// Returns true if the page at `page_index` has to be read from swap space before it can be
+    /// used. Callers which hold locks should release them and call [`Self::swap_in`] first.
+    fn is_swapped(&self, page_index: usize) -> bool {
+        let _ = page_index;
+        false
+    }
+
+    /// Reads the page at `page_index` back from swap space, if it's there.
+    /// This sleeps, so the caller may not hold any locks.
+    fn swap_in(&self, page_index: usize) -> EResult<()> {
+        let _ = page_index;
+        Ok(())
+    }
+
+    /// Reads all pages which are stored in swap area `area` back into memory.
+    fn swap_in_area(&self, area: usize) -> EResult<()> {
+        let _ = area;
+        Ok(())
+    }
+}
+
+/// Where the data of a page in a [`PagedMemoryObject`] is.
+#[derive(Debug, Clone, Copy)]
+enum PageEntry {
+    /// The page is in memory.
+    Present(PhysAddr),
+    /// The page was moved to swap space.
+    Swapped(SwapEntry),
+}
+
+impl PageEntry {
+    /// Adds an owner to the page or its swap slot.
+    fn add_ref(self) {
+        match self {
+            PageEntry::Present(addr) => Page::add_ref(addr),
+            PageEntry::Swapped(entry) => entry.add_ref(),
+        }
+    }
+
+    /// Removes an owner from the page or its swap slot.
+    /// # Safety
+    /// Same as [`Page::drop_ref`].
+    unsafe fn drop_ref(self) {
+        match self {
+            PageEntry::Present(addr) => unsafe { Page::drop_ref(addr) },
+            PageEntry::Swapped(entry) => entry.drop_ref(),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct PagedMemoryObject {
-    pages: SpinMutex<BTreeMap<usize, PhysAddr>>,
+    pages: SpinMutex<BTreeMap<usize, PageEntry>>,
+    /// Indices of pages which have to be written back to `source`.
+    dirty: SpinMutex<BTreeSet<usize>>,
     source: Arc<dyn Pager>,
 }
 
@@ -28,6 +139,7 @@ impl PagedMemoryObject {
     pub fn new(source: Arc<dyn Pager>) -> Self {
         Self {
             pages: SpinMutex::new(BTreeMap::new()),
+            dirty: SpinMutex::new(BTreeSet::new()),
             source,
         }
     }
@@ -98,6 +210,7 @@ impl dyn MemoryObject {
             let page_slice: &mut [u8] =
                 unsafe { slice::from_raw_parts_mut(page_addr.as_hhdm(), page_size) };
             page_slice[misalign..][..copy_size].copy_from_slice(&buffer[progress..][..copy_size]);
+            self.mark_dirty(page_index);
             progress += copy_size;
         }
 
@@ -105,29 +218,284 @@ impl dyn MemoryObject {
     }
 }
 
+impl PagedMemoryObject {
+    /// Returns the page at `page_index`, loading it if necessary.
+    fn get_page(&self, page_index: usize) -> Option<PhysAddr> {
+        loop {
+            let mut pages = self.pages.lock();
+            match pages.get(&page_index).copied() {
+                // If the page already exists, we can return it.
+                Some(PageEntry::Present(page)) => return Some(page),
+                // If it was swapped out, it has to be read back without holding the lock.
+                Some(PageEntry::Swapped(_)) => {
+                    drop(pages);
+                    self.swap_in(page_index).ok()?;
+                }
+                // If it does not, we need to check if it's actually available.
+                None => {
+                    let page = self.source.try_get_page(page_index).ok()?;
+                    Page::init_ref(page);
+                    pages.insert(page_index, PageEntry::Present(page));
+                    return Some(page);
+                }
+            }
+        }
+    }
+}
+
 impl MemoryObject for PagedMemoryObject {
     fn try_get_page(&self, page_index: usize) -> Option<PhysAddr> {
+        self.get_page(page_index)
+    }
+
+    fn try_get_huge_page(&self, page_index: usize, num_pages: usize) -> Option<PhysAddr> {
+        // Pages with a backing store are loaded one at a time.
+        if self.source.needs_writeback() {
+            return None;
+        }
+
+        let page_size = get_page_size();
+        let mut pages = self.pages.lock();
+        let range = page_index..page_index + num_pages;
+
+        match pages.range(range.clone()).next().map(|(_, entry)| *entry) {
+            // If none of the pages were touched yet, they can be allocated as one block.
+            // A failure just falls back to small pages, so there is no need to free memory.
+            None => {
+                let base = KernelAlloc::alloc_aligned(num_pages, AllocFlags::Zeroed).ok()?;
+                for (i, index) in range.enumerate() {
+                    let page = base + i * page_size;
+                    Page::init_ref(page);
+                    pages.insert(index, PageEntry::Present(page));
+                }
+                Some(base)
+            }
+            // Otherwise, the block might still be in one piece from an earlier allocation.
+            Some(PageEntry::Present(base)) => {
+                let contiguous = base.value() % (num_pages * page_size) == 0
+                    && pages.range(range.clone()).count() == num_pages
+                    && pages.range(range).enumerate().all(|(i, (_, entry))| {
+                        matches!(entry, PageEntry::Present(x) if *x == base + i * page_size)
+                    });
+                contiguous.then_some(base)
+            }
+            Some(PageEntry::Swapped(_)) => None,
+        }
+    }
+
+    fn discard(&self, page_index: usize, num_pages: usize) {
+        let mut pages = self.pages.lock();
+        let indices = pages
+            .range(page_index..page_index.saturating_add(num_pages))
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+
+        let mut dirty = self.dirty.lock();
+        for index in indices {
+            if let Some(entry) = pages.remove(&index) {
+                unsafe { entry.drop_ref() };
+            }
+            dirty.remove(&index);
+        }
+    }
+
+    fn clone_cow(&self) -> Option<Arc<dyn MemoryObject>> {
+        let pages = self.pages.lock().clone();
+        for entry in pages.values() {
+            entry.add_ref();
+        }
+
+        let has_swapped = pages.values().any(|x| matches!(x, PageEntry::Swapped(_)));
+        let copy: Arc<dyn MemoryObject> = Arc::new(Self {
+            pages: SpinMutex::new(pages),
+            dirty: SpinMutex::new(BTreeSet::new()),
+            source: self.source.clone(),
+        });
+
+        // The copy shares the swap slots, so it has to be found when they go away.
+        if has_swapped {
+            swap::track(&copy);
+        }
+        Some(copy)
+    }
+
+    fn unshare_page(&self, page_index: usize) -> Option<PhysAddr> {
+        loop {
+            let page = self.get_page(page_index)?;
+            let mut pages = self.pages.lock();
+
+            // The page might have been swapped out again before the lock was taken.
+            if !matches!(pages.get(&page_index), Some(PageEntry::Present(x)) if *x == page) {
+                continue;
+            }
+            if Page::ref_count(page) <= 1 {
+                return Some(page);
+            }
+
+            let copy = oom::alloc_page(AllocFlags::empty()).ok()?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    page.as_hhdm::<u8>(),
+                    copy.as_hhdm::<u8>(),
+                    get_page_size(),
+                )
+            };
+            Page::init_ref(copy);
+            pages.insert(page_index, PageEntry::Present(copy));
+
+            // The other owners keep the original.
+            unsafe { Page::drop_ref(page) };
+            return Some(copy);
+        }
+    }
+
+    fn mark_dirty(&self, page_index: usize) {
+        if self.source.needs_writeback() {
+            self.dirty.lock().insert(page_index);
+        }
+    }
+
+    fn sync(&self, page_index: usize, num_pages: usize) -> EResult<()> {
+        let indices = {
+            let mut dirty = self.dirty.lock();
+            let indices = dirty
+                .range(page_index..page_index.saturating_add(num_pages))
+                .copied()
+                .collect::<Vec<_>>();
+            for index in indices.iter() {
+                dirty.remove(index);
+            }
+            indices
+        };
+
+        for (i, &index) in indices.iter().enumerate() {
+            let Some(PageEntry::Present(addr)) = self.pages.lock().get(&index).copied() else {
+                continue;
+            };
+
+            // Keep the page alive while it's being written, it could get discarded in the meantime.
+            Page::add_ref(addr);
+            let result = self.source.try_put_page(addr, index);
+            unsafe { Page::drop_ref(addr) };
+
+            if let Err(err) = result {
+                // Whatever wasn't written is still dirty.
+                self.dirty.lock().extend(indices[i..].iter().copied());
+                return Err(err.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn prepare_swap_out(&self, page_index: usize) -> Option<PhysAddr> {
+        // Pages with a backing store are written back there instead.
+        if self.source.needs_writeback() {
+            return None;
+        }
+
+        let pages = self.pages.lock();
+        let Some(PageEntry::Present(page)) = pages.get(&page_index).copied() else {
+            return None;
+        };
+
+        // A page which is shared with a copy of this object might be mapped somewhere else.
+        if Page::ref_count(page) != 1 {
+            return None;
+        }
+
+        Page::add_ref(page);
+        Some(page)
+    }
+
+    fn finish_swap_out(&self, page_index: usize, page: PhysAddr, entry: Option<SwapEntry>) -> bool {
         let mut pages = self.pages.lock();
-        match pages.get(&page_index) {
-            // If the page already exists, we can return it.
-            Some(page) => Some(*page),
-            // If it does not, we need to check if it's actually available.
-            None => match self.source.try_get_page(page_index) {
-                Ok(x) => {
-                    pages.insert(page_index, x);
-                    Some(x)
+        let unused = matches!(pages.get(&page_index), Some(PageEntry::Present(x)) if *x == page)
+            && Page::ref_count(page) == 2;
+
+        let Some(entry) = entry.filter(|_| unused) else {
+            unsafe { Page::drop_ref(page) };
+            return false;
+        };
+
+        pages.insert(page_index, PageEntry::Swapped(entry));
+        unsafe {
+            Page::drop_ref(page);
+            Page::drop_ref(page);
+        }
+        true
+    }
+
+    fn is_swapped(&self, page_index: usize) -> bool {
+        matches!(
+            self.pages.lock().get(&page_index),
+            Some(PageEntry::Swapped(_))
+        )
+    }
+
+    fn swap_in(&self, page_index: usize) -> EResult<()> {
+        loop {
+            let entry = match self.pages.lock().get(&page_index).copied() {
+                Some(PageEntry::Swapped(entry)) => {
+                    // Keeps the slot from being reused while the pages aren't locked.
+                    entry.add_ref();
+                    entry
+                }
+                _ => return Ok(()),
+            };
+
+            let result = swap::read_page(entry);
+            let mut pages = self.pages.lock();
+
+            // Somebody else might have read or discarded the page in the meantime.
+            let unchanged =
+                matches!(pages.get(&page_index), Some(PageEntry::Swapped(x)) if *x == entry);
+            if let Ok(page) = result {
+                match unchanged {
+                    true => {
+                        Page::init_ref(page);
+                        pages.insert(page_index, PageEntry::Present(page));
+                        entry.drop_ref();
+                    }
+                    false => unsafe { KernelAlloc::dealloc(page, 1) },
                 }
-                Err(_) => None,
-            },
+            }
+            drop(pages);
+            entry.drop_ref();
+
+            result?;
+            if unchanged {
+                return Ok(());
+            }
         }
     }
+
+    fn swap_in_area(&self, area: usize) -> EResult<()> {
+        let indices = self
+            .pages
+            .lock()
+            .iter()
+            .filter_map(|(index, entry)| match entry {
+                PageEntry::Swapped(x) if x.area() == area => Some(*index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for index in indices {
+            self.swap_in(index)?;
+        }
+        Ok(())
+    }
 }
 
 impl Drop for PagedMemoryObject {
     fn drop(&mut self) {
+        // Nobody can report an error here anymore.
+        _ = self.sync(0, usize::MAX);
+
         let p = self.pages.lock();
-        for (_, &addr) in p.iter() {
-            unsafe { KernelAlloc::dealloc(addr, 1) };
+        for (_, &entry) in p.iter() {
+            unsafe { entry.drop_ref() };
         }
     }
 }
@@ -141,6 +509,12 @@ pub trait Pager: Debug {
     fn try_get_page(&self, page_index: usize) -> Result<PhysAddr, PagerError>;
     /// Attempts to write a page at an index back to the device.
     fn try_put_page(&self, address: PhysAddr, page_index: usize) -> Result<(), PagerError>;
+
+    /// Returns true if modified pages have to be written back with [`Self::try_put_page`].
+    /// Pages of pagers which don't need this are anonymous memory and can be swapped out.
+    fn needs_writeback(&self) -> bool {
+        true
+    }
 }
 
 /// Errors that can occur when reading or writing a page.
@@ -149,6 +523,18 @@ pub enum PagerError {
     IndexOutOfBounds,
     /// The pager cannot allocate pages.
     OutOfMemory,
+    /// Reading or writing the backing store failed.
+    Io(Errno),
+}
+
+impl From<PagerError> for Errno {
+    fn from(value: PagerError) -> Self {
+        match value {
+            PagerError::IndexOutOfBounds => Errno::EIO,
+            PagerError::OutOfMemory => Errno::ENOMEM,
+            PagerError::Io(x) => x,
+        }
+    }
 }
 
 /// A pager which uses kernel memory to get physical pages.
@@ -162,11 +548,71 @@ impl Pager for PhysPager {
     }
 
     fn try_get_page(&self, _: usize) -> Result<PhysAddr, PagerError> {
-        KernelAlloc::alloc(1, AllocFlags::Zeroed).map_err(|_| PagerError::OutOfMemory)
+        oom::alloc_page(AllocFlags::Zeroed).map_err(|_| PagerError::OutOfMemory)
     }
 
     fn try_put_page(&self, _: PhysAddr, _: usize) -> Result<(), PagerError> {
         // Don't do anything. There's nothing to write back to.
         Ok(())
     }
+
+    fn needs_writeback(&self) -> bool {
+        false
+    }
+}
+
+/// A pager which loads pages from a file and writes modified ones back to it.
+/// File systems on top of a block device use this for the caches of their files.
+#[derive(Debug)]
+pub struct FilePager {
+    file: Arc<File>,
+    /// Offset of the first page in the file.
+    offset: u64,
+    /// Amount of bytes which belong to the paged data.
+    len: usize,
+}
+
+impl FilePager {
+    pub fn new(file: Arc<File>, offset: u64, len: usize) -> Self {
+        Self { file, offset, len }
+    }
+
+    /// Returns the position and size of a page in the file, or [`None`] if it's out of bounds.
+    fn range(&self, page_index: usize) -> Option<(u64, usize)> {
+        let page_size = get_page_size();
+        let start = page_index.checked_mul(page_size)?;
+        if start >= self.len {
+            return None;
+        }
+        Some((self.offset + start as u64, page_size.min(self.len - start)))
+    }
+}
+
+impl Pager for FilePager {
+    fn has_page(&self, page_index: usize) -> bool {
+        self.range(page_index).is_some()
+    }
+
+    fn try_get_page(&self, page_index: usize) -> Result<PhysAddr, PagerError> {
+        let (offset, len) = self.range(page_index).ok_or(PagerError::IndexOutOfBounds)?;
+        let page = oom::alloc_page(AllocFlags::Zeroed).map_err(|_| PagerError::OutOfMemory)?;
+
+        // Anything after the end of the file stays zero.
+        let buf = unsafe { slice::from_raw_parts_mut(page.as_hhdm::<u8>(), len) };
+        if let Err(err) = self.file.pread(buf, offset) {
+            unsafe { KernelAlloc::dealloc(page, 1) };
+            return Err(PagerError::Io(err));
+        }
+        Ok(page)
+    }
+
+    fn try_put_page(&self, address: PhysAddr, page_index: usize) -> Result<(), PagerError> {
+        let (offset, len) = self.range(page_index).ok_or(PagerError::IndexOutOfBounds)?;
+        let buf = unsafe { slice::from_raw_parts(address.as_hhdm::<u8>(), len) };
+        match self.file.pwrite(buf, offset) {
+            Ok(x) if x as usize == len => Ok(()),
+            Ok(_) => Err(PagerError::Io(Errno::EIO)),
+            Err(err) => Err(PagerError::Io(err)),
+        }
+    }
 }
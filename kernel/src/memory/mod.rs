@@ -1,8 +1,10 @@
 // We don't want to use the bump allocator anywhere after initial setup.
 mod bump;
 pub mod cache;
+pub mod oom;
 pub mod pmm;
 pub mod slab;
+pub mod swap;
 pub mod user;
 pub mod view;
 pub mod virt;
@@ -225,6 +227,13 @@ pub fn MEMORY_STAGE() {
 
     unsafe { HHDM_START.init(hhdm_address) };
 
+    // Move our HHDM by a random amount. It stays aligned, so it can be mapped with 1 GiB pages.
+    let hhdm_align = arch::virt::get_level_size(2);
+    let hhdm_room = (arch::virt::get_pfndb_base() - arch::virt::get_hhdm_base())
+        .value()
+        .saturating_sub(align_up(highest_phys.value(), hhdm_align));
+    let hhdm_base = arch::virt::get_hhdm_base() + virt::aslr::kernel_offset(hhdm_room, hhdm_align);
+
     let mut memory_map = info.memory_map.lock();
 
     // Print the memory map.
@@ -275,6 +284,7 @@ pub fn MEMORY_STAGE() {
             text_start,
             PhysAddr(text_start.0 - kernel_start.0 + kernel_phys.0),
             VmFlags::Read | VmFlags::Exec,
+            VmCacheType::WriteBack,
             text_end.0 - text_start.0,
         )
         .expect("Unable to map the text segment");
@@ -285,6 +295,7 @@ pub fn MEMORY_STAGE() {
             rodata_start,
             PhysAddr(rodata_start.0 - kernel_start.0 + kernel_phys.0),
             VmFlags::Read,
+            VmCacheType::WriteBack,
             rodata_end.0 - rodata_start.0,
         )
         .expect("Unable to map the rodata segment");
@@ -295,6 +306,7 @@ pub fn MEMORY_STAGE() {
             data_start,
             PhysAddr(data_start.0 - kernel_start.0 + kernel_phys.0),
             VmFlags::Read | VmFlags::Write,
+            VmCacheType::WriteBack,
             data_end.0 - data_start.0,
         )
         .expect("Unable to map the data segment");
@@ -307,18 +319,16 @@ pub fn MEMORY_STAGE() {
         .for_each(|entry| {
             table
                 .map_range::<BumpAllocator>(
-                    arch::virt::get_hhdm_base() + entry.address.value(),
+                    hhdm_base + entry.address.value(),
                     entry.address,
                     VmFlags::Read | VmFlags::Write,
+                    VmCacheType::WriteBack,
                     entry.length,
                 )
                 .expect("Unable to map HHDM region");
         });
 
-    log!(
-        "Mapped HHDM segment at {:#018x}",
-        arch::virt::get_hhdm_base().value()
-    );
+    log!("Mapped HHDM segment at {:#018x}", hhdm_base.value());
 
     // We record metadata for every single page of available memory in a large array.
     // This array is contiguous in virtual memory, but is sparsely populated.
@@ -346,6 +356,7 @@ pub fn MEMORY_STAGE() {
                         (virt + page).into(),
                         BumpAllocator::alloc(1, AllocFlags::Zeroed).unwrap(),
                         VmFlags::Read | VmFlags::Write,
+                        VmCacheType::WriteBack,
                     )
                     .unwrap();
             }
@@ -360,7 +371,7 @@ pub fn MEMORY_STAGE() {
     unsafe { table.set_active() };
     log!("Kernel map is now active");
 
-    unsafe { HHDM_START.init(arch::virt::get_hhdm_base()) };
+    unsafe { HHDM_START.init(hhdm_base) };
 
     // Finally, make sure to mark the allocated memory as used before the real allocator looks at the memory map.
     let allocated_bytes = align_up(
@@ -385,8 +396,5 @@ pub fn MEMORY_STAGE() {
 
     // Save the page table.
     unsafe { virt::KERNEL_PAGE_TABLE.init(Arc::new(table)) };
-
-    // Set the MMAP base to right after the page table. Make sure this lands on a new PTE so we can map regular pages.
-    // TODO: Use a virtual memory allocator instead.
-    virt::KERNEL_MMAP_BASE_ADDR.store(arch::virt::get_map_base().value(), Ordering::Relaxed);
+    virt::vmalloc::init(virt::KERNEL_PAGE_TABLE.get());
 }
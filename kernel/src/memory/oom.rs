@@ -0,0 +1,163 @@
+//! Handling of memory pressure.
+//!
+//! User memory is allocated with [`alloc_page`]. When the physical memory allocator runs dry,
+//! the page fault which needed the memory waits for the swap reclaimer. If that doesn't free
+//! anything, it calls [`out_of_memory`], which kills the process that holds the most memory.
+//!
+//! The score of a process is the amount of its pages which are present or swapped out. Its
+//! `oom_score_adj` is added on top, in thousandths of the total memory. Processes with
+//! [`OOM_SCORE_ADJ_MIN`] are never killed.
+
+use crate::{
+    memory::{
+        PhysAddr,
+        pmm::{self, AllocFlags, KernelAlloc, PageAllocator},
+        virt::mmu,
+    },
+    process::{
+        Process, ProcessState,
+        signal::{self, Signal, SignalInfo},
+    },
+    uapi::{
+        self,
+        oom::{OOM_SCORE_ADJ_MAX, OOM_SCORE_ADJ_MIN},
+    },
+    util::{event::Event, mutex::spin::SpinMutex},
+};
+use alloc::{
+    alloc::AllocError,
+    sync::{Arc, Weak},
+};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+per_cpu!(
+    /// Set if [`alloc_page`] failed on this CPU since the last call to [`take_failure`].
+    static ALLOC_FAILED: AtomicBool = AtomicBool::new(false);
+);
+
+/// The process which was last killed to free memory.
+static VICTIM: SpinMutex<Weak<Process>> = SpinMutex::new(Weak::new());
+
+/// Signaled whenever a process gave up its memory.
+static RELEASED: Event = Event::new();
+
+/// Allocates a page for user memory. A failure is recorded for the page fault handler, which
+/// waits for the reclaimer before retrying.
+pub fn alloc_page(flags: AllocFlags) -> Result<PhysAddr, AllocError> {
+    KernelAlloc::alloc(1, flags).inspect_err(|_| ALLOC_FAILED.get().store(true, Ordering::Relaxed))
+}
+
+/// Returns true if [`alloc_page`] failed on this CPU since the last call.
+pub fn take_failure() -> bool {
+    ALLOC_FAILED.get().swap(false, Ordering::Relaxed)
+}
+
+/// How much killing a process would help.
+struct Badness {
+    score: usize,
+    present: usize,
+    swapped: usize,
+    adj: i32,
+}
+
+/// Returns the score of a process, or [`None`] if it can't be killed.
+fn badness(proc: &Process) -> Option<Badness> {
+    // Init and the kernel have to stay alive.
+    if proc.get_pid() <= 1 {
+        return None;
+    }
+
+    let adj = proc
+        .oom_score_adj
+        .load(Ordering::Relaxed)
+        .clamp(OOM_SCORE_ADJ_MIN, OOM_SCORE_ADJ_MAX);
+    if adj == OOM_SCORE_ADJ_MIN {
+        return None;
+    }
+
+    let table = mmu::lock_responsive(&proc.address_space).table.clone();
+    let (present, swapped) = table.count_user_pages();
+    // Killing a process without memory doesn't help.
+    if present + swapped == 0 {
+        return None;
+    }
+
+    let bonus = pmm::total_pages() as isize * adj as isize / 1000;
+    let score = ((present + swapped) as isize + bonus).max(1) as usize;
+    Some(Badness {
+        score,
+        present,
+        swapped,
+        adj,
+    })
+}
+
+/// Kills a process to free memory, unless a previous victim is still releasing its memory.
+/// Returns the process whose memory the caller should wait for, or [`None`] if no process can
+/// be killed.
+pub fn out_of_memory() -> Option<Arc<Process>> {
+    let mut victim = mmu::lock_responsive(&VICTIM);
+
+    // A process gives up its memory when it terminates, see [`Process::terminate`].
+    if let Some(proc) = victim.upgrade()
+        && !mmu::lock_responsive(&proc.address_space)
+            .mappings
+            .is_empty()
+    {
+        return Some(proc);
+    }
+
+    let mut chosen: Option<(Arc<Process>, Badness)> = None;
+    Process::for_each(|proc| {
+        // Processes which are already exiting are about to free their memory anyways.
+        if !matches!(*proc.status.lock(), ProcessState::Running) {
+            return;
+        }
+        if let Some(x) = badness(proc)
+            && chosen.as_ref().is_none_or(|(_, best)| x.score > best.score)
+        {
+            chosen = Some((proc.clone(), x));
+        }
+    });
+
+    let Some((proc, badness)) = chosen else {
+        error!("Out of memory and no process left to kill");
+        return None;
+    };
+
+    warn!(
+        "Out of memory: Killing process {} ({}) with score {}: {} pages present, {} pages swapped, oom_score_adj {}",
+        proc.get_pid(),
+        proc.get_name(),
+        badness.score,
+        badness.present,
+        badness.swapped,
+        badness.adj,
+    );
+    signal::send_to_process(
+        &proc,
+        SignalInfo::new(Signal::SIGKILL, uapi::signal::SI_KERNEL as _),
+    );
+
+    *victim = Arc::downgrade(&proc);
+    Some(proc)
+}
+
+/// Records that a terminated process gave up its memory.
+pub fn memory_released() {
+    RELEASED.wake_all();
+}
+
+/// Blocks until `victim` gave up its memory.
+pub fn wait_for_victim(victim: &Process) {
+    loop {
+        let guard = RELEASED.guard();
+        if mmu::lock_responsive(&victim.address_space)
+            .mappings
+            .is_empty()
+        {
+            return;
+        }
+        guard.wait();
+    }
+}
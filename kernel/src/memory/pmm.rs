@@ -3,7 +3,7 @@ use crate::{
     arch,
     {
         boot::{PhysMemory, PhysMemoryUsage},
-        util::{divide_up, mutex::spin::SpinMutex},
+        util::{align_up, divide_up, mutex::spin::SpinMutex},
     },
 };
 use alloc::alloc::AllocError;
@@ -12,11 +12,11 @@ use core::{
     hint::unlikely,
     ptr::{NonNull, null_mut, write_bytes},
     slice,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering},
 };
 
 bitflags! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy)]
     pub struct AllocFlags: usize {
         /// Only consider physical memory below 1MiB.
         const Kernel20 = 1 << 0;
@@ -58,7 +58,10 @@ pub trait PageAllocator {
 #[repr(C)]
 pub struct Page {
     pub next: Option<NonNull<Page>>,
-    pub count: usize,
+    /// Amount of free pages in this region, if this page is the start of a free region.
+    pub count: u32,
+    /// Amount of memory objects which own this page, see [`Page::add_ref`].
+    pub refs: AtomicU32,
 }
 
 // If this assert fails, the PFNDB can't properly allocate data.
@@ -69,6 +72,19 @@ pub static PAGE_DB_START: AtomicPtr<()> = AtomicPtr::new(null_mut());
 
 pub static PMM: SpinMutex<Option<NonNull<Page>>> = SpinMutex::new(None);
 
+/// Whether pages were freed since the free regions were last merged. Only freeing can put two
+/// free regions next to each other, so merging again before that is pointless.
+/// Only accessed while holding [`PMM`].
+static NEEDS_MERGE: AtomicBool = AtomicBool::new(true);
+
+/// Amount of usable pages in the system.
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the amount of usable pages in the system.
+pub fn total_pages() -> usize {
+    TOTAL_PAGES.load(Ordering::Relaxed)
+}
+
 pub struct KernelAlloc;
 impl PageAllocator for KernelAlloc {
     fn alloc(pages: usize, flags: AllocFlags) -> Result<PhysAddr, AllocError> {
@@ -84,42 +100,52 @@ impl PageAllocator for KernelAlloc {
         };
 
         let mut addr = None;
-        let mut it = *head;
-        let mut prev_it = None;
-        while let Some(mut x) = it {
-            let page = unsafe { x.as_mut() };
-
-            if page.get_address() + bytes >= limit {
-                prev_it = it;
-                it = page.next;
-                continue;
+        for merged in [false, true] {
+            // Merge adjacent regions if we didn't find anything.
+            if merged && !merge_regions(&mut head, &mut PAGE_DB.lock()) {
+                break;
             }
 
-            if unlikely(page.count < pages) {
-                prev_it = it;
-                it = page.next;
-                continue;
-            }
+            let mut it = *head;
+            let mut prev_it = None;
+            while let Some(mut x) = it {
+                let page = unsafe { x.as_mut() };
 
-            if unlikely(page.count == pages) {
-                addr = Some(page.get_address());
-                if let Some(mut prev) = prev_it {
-                    let prev_page = unsafe { prev.as_mut() };
-                    prev_page.next = page.next;
+                if page.get_address() + bytes >= limit {
+                    prev_it = it;
+                    it = page.next;
+                    continue;
+                }
+
+                if unlikely((page.count as usize) < pages) {
+                    prev_it = it;
+                    it = page.next;
+                    continue;
+                }
+
+                if unlikely(page.count as usize == pages) {
+                    addr = Some(page.get_address());
+                    if let Some(mut prev) = prev_it {
+                        let prev_page = unsafe { prev.as_mut() };
+                        prev_page.next = page.next;
+                    } else {
+                        *head = page.next;
+                    }
+                    page.next = None;
+                    page.count = 0;
                 } else {
-                    *head = page.next;
+                    page.count -= pages as u32;
+                    addr = Some(
+                        page.get_address() + page.count as usize * arch::virt::get_page_size(),
+                    );
                 }
-                page.next = None;
-                page.count = 0;
-            } else {
-                page.count -= pages;
-                addr = Some(page.get_address() + page.count * arch::virt::get_page_size());
+                break;
             }
-            break;
-        }
 
-        // TODO: Merge adjacent regions if we didn't find anything.
-        debug_assert!(addr.is_some());
+            if addr.is_some() {
+                break;
+            }
+        }
 
         match addr {
             Some(x) => {
@@ -145,18 +171,165 @@ impl PageAllocator for KernelAlloc {
         debug_assert!(page.count == 0);
         debug_assert!(page.next.is_none());
 
-        page.count = pages;
+        page.count = pages as u32;
         page.next = *head;
         *head = NonNull::new(page);
+        NEEDS_MERGE.store(true, Ordering::Relaxed);
+    }
+}
+
+impl KernelAlloc {
+    /// Allocates `pages` consecutive pages which start at a multiple of their total size.
+    /// This is what large pages need. Only [`AllocFlags::Zeroed`] is considered.
+    /// The pages can be deallocated one by one, they are merged again when memory runs short.
+    pub fn alloc_aligned(pages: usize, flags: AllocFlags) -> Result<PhysAddr, AllocError> {
+        Self::try_alloc_aligned(pages, flags, false)
+            .or_else(|_| Self::try_alloc_aligned(pages, flags, true))
+    }
+
+    /// Same as [`Self::alloc_aligned`], but merges adjacent free regions first if `merge` is set.
+    fn try_alloc_aligned(
+        pages: usize,
+        flags: AllocFlags,
+        merge: bool,
+    ) -> Result<PhysAddr, AllocError> {
+        let page_size = arch::virt::get_page_size();
+        let bytes = pages * page_size;
+
+        let mut head = PMM.lock();
+        let mut page_db = PAGE_DB.lock();
+        if merge && !merge_regions(&mut head, &mut page_db) {
+            return Err(AllocError);
+        }
+
+        let mut it = *head;
+        let mut prev_it: Option<NonNull<Page>> = None;
+        while let Some(mut x) = it {
+            let page = unsafe { x.as_mut() };
+            let start = page.get_address().value();
+            let end = start + page.count as usize * page_size;
+            let aligned = align_up(start, bytes);
+            if aligned + bytes > end {
+                prev_it = it;
+                it = page.next;
+                continue;
+            }
+
+            // The region keeps the part in front of the allocation.
+            if aligned == start {
+                match prev_it {
+                    Some(mut prev) => unsafe { prev.as_mut().next = page.next },
+                    None => *head = page.next,
+                }
+                page.next = None;
+                page.count = 0;
+            } else {
+                page.count = ((aligned - start) / page_size) as u32;
+            }
+
+            // The part behind it becomes a new region.
+            let tail = (end - aligned - bytes) / page_size;
+            if tail != 0 {
+                let tail_page = page_db
+                    .get_mut(Page::idx_from_addr(PhysAddr(aligned + bytes)))
+                    .unwrap();
+                tail_page.count = tail as u32;
+                tail_page.next = *head;
+                *head = NonNull::new(tail_page);
+            }
+
+            if flags.contains(AllocFlags::Zeroed) {
+                unsafe { write_bytes(PhysAddr(aligned).as_hhdm::<u8>(), 0, bytes) };
+            }
+            return Ok(PhysAddr(aligned));
+        }
+
+        Err(AllocError)
     }
 }
 
+/// Merges free regions which directly follow each other into one, so that larger allocations can
+/// succeed again. Pages which were freed one by one, like those of a huge page, stay separate
+/// regions otherwise. Only the first page of a free region has a count.
+/// Does nothing and returns false if no pages were freed since the last merge.
+fn merge_regions(head: &mut Option<NonNull<Page>>, page_db: &mut [Page]) -> bool {
+    if !NEEDS_MERGE.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+
+    // Let every region absorb the regions behind it.
+    let mut it = *head;
+    while let Some(x) = it {
+        let idx = unsafe { x.as_ref() }.get_pn();
+        loop {
+            let end = idx + page_db[idx].count as usize;
+            let Some(count) = page_db.get(end).map(|x| x.count).filter(|x| *x != 0) else {
+                break;
+            };
+            page_db[end].count = 0;
+            page_db[idx].count += count;
+        }
+        it = page_db[idx].next;
+    }
+
+    // Unlink the regions which were absorbed.
+    let mut prev: Option<usize> = None;
+    let mut it = *head;
+    while let Some(x) = it {
+        let idx = unsafe { x.as_ref() }.get_pn();
+        let next = page_db[idx].next;
+        if page_db[idx].count == 0 {
+            page_db[idx].next = None;
+            match prev {
+                Some(prev) => page_db[prev].next = next,
+                None => *head = next,
+            }
+        } else {
+            prev = Some(idx);
+        }
+        it = next;
+    }
+
+    true
+}
+
 impl Page {
     #[inline]
     pub fn idx_from_addr(address: PhysAddr) -> usize {
         address.0 / arch::virt::get_page_size()
     }
 
+    /// Returns the metadata of the page at `addr`.
+    pub fn from_addr(addr: PhysAddr) -> &'static Page {
+        let db = PAGE_DB_START.load(Ordering::Relaxed) as *const Page;
+        unsafe { &*db.add(Self::idx_from_addr(addr)) }
+    }
+
+    /// Marks the page at `addr` as owned by exactly one memory object.
+    pub fn init_ref(addr: PhysAddr) {
+        Self::from_addr(addr).refs.store(1, Ordering::Release);
+    }
+
+    /// Adds an owner to the page at `addr`, which was set up with [`Page::init_ref`].
+    /// Pages with more than one owner have to be copied before they can be written to.
+    pub fn add_ref(addr: PhysAddr) {
+        Self::from_addr(addr).refs.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Removes an owner from the page at `addr` and frees it if that was the last one.
+    /// # Safety
+    /// The caller has to be an owner of the page and may not use it afterwards.
+    pub unsafe fn drop_ref(addr: PhysAddr) {
+        if Self::from_addr(addr).refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            unsafe { KernelAlloc::dealloc(addr, 1) };
+        }
+    }
+
+    /// Returns the amount of owners of the page at `addr`.
+    pub fn ref_count(addr: PhysAddr) -> u32 {
+        Self::from_addr(addr).refs.load(Ordering::Acquire)
+    }
+
     /// Returns the page number of this page.
     fn get_pn(&self) -> usize {
         let page: *const Page = self;
@@ -188,12 +361,16 @@ pub fn init(memory_map: &[PhysMemory], pages: (*mut Page, usize)) {
         let mut pmm = PMM.lock();
         let mut page_db = PAGE_DB.lock();
         let page = page_db.get_mut(Page::idx_from_addr(entry.address)).unwrap();
-        page.count = entry.length / arch::virt::get_page_size();
+        page.count = (entry.length / arch::virt::get_page_size()) as u32;
         page.next = *pmm;
         *pmm = NonNull::new(page);
 
         total_memory += entry.length;
     }
 
+    TOTAL_PAGES.store(
+        total_memory / arch::virt::get_page_size(),
+        Ordering::Relaxed,
+    );
     log!("Total available memory: {} MiB", total_memory / 1024 / 1024);
 }
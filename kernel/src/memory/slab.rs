@@ -8,6 +8,7 @@ use crate::{
     arch,
     util::{align_down, align_up, divide_up, mutex::spin::SpinMutex},
 };
+use alloc::alloc::AllocError;
 use core::{
     alloc::{GlobalAlloc, Layout},
     hint::{likely, unlikely},
@@ -43,8 +44,8 @@ impl Slab {
         }
     }
 
-    /// Initializes a slab.
-    fn init(&self) {
+    /// Initializes a slab. Fails if there is no memory left.
+    fn init(&self) -> Result<(), AllocError> {
         unsafe {
             // Calculate the amount of bytes we need to skip in order to be able to store a reference to the slab.
             let offset = align_up(size_of::<SlabHeader>(), self.ent_size);
@@ -52,7 +53,7 @@ impl Slab {
             let available_size = arch::virt::get_page_size() - offset;
 
             // Allocate memory for this slab.
-            let mem = KernelAlloc::alloc(1, AllocFlags::empty()).expect("Out of memory");
+            let mem = KernelAlloc::alloc(1, AllocFlags::empty())?;
             let mut head = mem.as_hhdm::<*mut ()>();
 
             // Get a reference to the start of the buffer.
@@ -73,12 +74,14 @@ impl Slab {
 
             *self.head.lock() = head.into();
         }
+        Ok(())
     }
 
     fn alloc(&self) -> *mut u8 {
         // Initialize the slab if not done already.
-        if likely(*self.head.lock() == VirtAddr::null()) {
-            self.init();
+        // The global allocator reports a null pointer as an allocation failure.
+        if likely(*self.head.lock() == VirtAddr::null()) && self.init().is_err() {
+            return null_mut();
         }
 
         let mut head = self.head.lock();
@@ -0,0 +1,456 @@
+//! Swapping of anonymous memory.
+//!
+//! Pages of private mappings can be moved to a swap area, which is a block device or a regular
+//! file prepared with `mkswap` and enabled with `swapon`. When the physical memory allocator runs
+//! dry, the faulting task wakes the reclaimer task and waits for it. The reclaimer walks a clock
+//! hand over the page tables of all processes. Pages which haven't been accessed since the hand
+//! last passed them are unmapped, written to swap space without holding any locks, and freed if
+//! nobody touched them in the meantime. Mappings locked with `mlock` are skipped.
+//!
+//! The owning [`MemoryObject`] remembers where each page went, so it can be read back on the next
+//! fault. The PTE of an evicted page records the same [`SwapEntry`].
+
+use crate::{
+    arch,
+    memory::{
+        MemoryObject, PhysAddr, VirtAddr, oom,
+        pmm::{AllocFlags, KernelAlloc, PageAllocator},
+        virt::mmu,
+    },
+    posix::errno::{EResult, Errno},
+    process::{Process, task::Task},
+    sched::Scheduler,
+    util::{event::Event, mutex::spin::SpinMutex},
+    vfs::{
+        File,
+        inode::{INode, NodeOps},
+    },
+};
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    ptr, slice,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+/// Maximum amount of swap areas which can be enabled at the same time.
+pub const MAX_AREAS: usize = 32;
+
+/// Signature at the end of the first page of a swap area, written by `mkswap`.
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+/// Offset of the header in the first page of a swap area. The bytes before are left for boot code.
+const HEADER_OFFSET: usize = 1024;
+/// The only supported version of the header.
+const HEADER_VERSION: u32 = 1;
+/// Offset of the list of bad pages in the first page of a swap area.
+const BAD_PAGES_OFFSET: usize = 1536;
+
+/// Reference count of a slot which can't be used, such as the header or a bad page.
+const SLOT_RESERVED: u32 = u32::MAX;
+
+/// The location of a page in swap space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapEntry(usize);
+
+impl SwapEntry {
+    fn new(area: usize, slot: usize) -> Self {
+        Self(slot * MAX_AREAS + area)
+    }
+
+    /// Returns the entry as a plain number, which can be stored in a PTE.
+    pub fn value(self) -> usize {
+        self.0
+    }
+
+    /// Returns the index of the swap area which holds the page.
+    pub fn area(self) -> usize {
+        self.0 % MAX_AREAS
+    }
+
+    fn slot(self) -> usize {
+        self.0 / MAX_AREAS
+    }
+
+    /// Adds an owner to the slot of this entry. Used when an object is copied.
+    pub fn add_ref(self) {
+        if let Some(area) = get_area(self.area()) {
+            area.slots.lock()[self.slot()] += 1;
+        }
+    }
+
+    /// Removes an owner from the slot of this entry and frees the slot if that was the last one.
+    pub fn drop_ref(self) {
+        if let Some(area) = get_area(self.area()) {
+            area.slots.lock()[self.slot()] -= 1;
+        }
+    }
+}
+
+/// An enabled swap area.
+struct SwapArea {
+    /// The opened device or file.
+    file: Arc<File>,
+    /// Areas with a higher priority are used first.
+    priority: isize,
+    /// Amount of owners of every slot.
+    slots: SpinMutex<Vec<u32>>,
+    /// Cleared while the area is being disabled.
+    active: AtomicBool,
+}
+
+impl SwapArea {
+    /// Takes a free slot and gives it one owner.
+    fn alloc_slot(&self) -> Option<usize> {
+        let mut slots = self.slots.lock();
+        let slot = slots.iter().position(|x| *x == 0)?;
+        slots[slot] = 1;
+        Some(slot)
+    }
+
+    fn read(&self, slot: usize, page: PhysAddr) -> EResult<()> {
+        let page_size = arch::virt::get_page_size();
+        let buf = unsafe { slice::from_raw_parts_mut(page.as_hhdm::<u8>(), page_size) };
+        match self.file.pread(buf, (slot * page_size) as u64)? {
+            x if x as usize == page_size => Ok(()),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    fn write(&self, slot: usize, page: PhysAddr) -> EResult<()> {
+        let page_size = arch::virt::get_page_size();
+        let buf = unsafe { slice::from_raw_parts(page.as_hhdm::<u8>(), page_size) };
+        match self.file.pwrite(buf, (slot * page_size) as u64)? {
+            x if x as usize == page_size => Ok(()),
+            _ => Err(Errno::EIO),
+        }
+    }
+
+    /// Returns true if any slot is still in use.
+    fn is_used(&self) -> bool {
+        self.slots
+            .lock()
+            .iter()
+            .any(|x| *x != 0 && *x != SLOT_RESERVED)
+    }
+}
+
+static AREAS: SpinMutex<[Option<Arc<SwapArea>>; MAX_AREAS]> =
+    SpinMutex::new([const { None }; MAX_AREAS]);
+
+/// Objects which might hold pages in swap space. Used to get them back on `swapoff`.
+static OWNERS: SpinMutex<Vec<Weak<dyn MemoryObject>>> = SpinMutex::new(Vec::new());
+
+/// Amount of pages the reclaimer tries to free in one pass.
+const RECLAIM_BATCH: usize = 32;
+
+/// Signaled when an allocation failed and the reclaimer should make a pass.
+static RECLAIM_REQUEST: Event = Event::new();
+/// Signaled after every pass of the reclaimer.
+static RECLAIM_DONE: Event = Event::new();
+/// Set if a pass was requested since the reclaimer last started one.
+static PENDING: AtomicBool = AtomicBool::new(false);
+/// Amount of passes the reclaimer has made so far.
+static PASSES: AtomicUsize = AtomicUsize::new(0);
+/// Amount of pages freed by the last pass.
+static LAST_FREED: AtomicUsize = AtomicUsize::new(0);
+/// The reclaimer task.
+static RECLAIMER: AtomicPtr<Task> = AtomicPtr::new(ptr::null_mut());
+
+fn get_area(index: usize) -> Option<Arc<SwapArea>> {
+    AREAS.lock().get(index)?.clone()
+}
+
+/// Enables swapping to `file`, which has to contain a swap header.
+pub fn enable(file: Arc<File>, priority: Option<isize>) -> EResult<()> {
+    let inode = file.inode.clone().ok_or(Errno::EINVAL)?;
+    let is_regular = match inode.node_ops {
+        NodeOps::Regular(_) => true,
+        NodeOps::BlockDevice => false,
+        _ => return Err(Errno::EINVAL),
+    };
+    let page_size = arch::virt::get_page_size();
+
+    // Read the header from the first page.
+    let header = KernelAlloc::alloc(1, AllocFlags::Zeroed)?;
+    let slots = {
+        let buf = unsafe { slice::from_raw_parts_mut(header.as_hhdm::<u8>(), page_size) };
+        let result = file.pread(buf, 0).and_then(|_| parse_header(buf));
+        unsafe { KernelAlloc::dealloc(header, 1) };
+        result?
+    };
+
+    let mut slots = slots;
+    // A file may be shorter than its header says.
+    if is_regular {
+        slots.truncate(inode.len() / page_size);
+    }
+    if slots.iter().all(|x| *x == SLOT_RESERVED) {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut areas = AREAS.lock();
+    if areas.iter().flatten().any(|x| is_same(&x.file, &inode)) {
+        return Err(Errno::EBUSY);
+    }
+
+    // Areas without a priority are used in the order they were enabled.
+    let priority = priority
+        .unwrap_or_else(|| -1 - areas.iter().flatten().filter(|x| x.priority < 0).count() as isize);
+
+    let index = areas.iter().position(|x| x.is_none()).ok_or(Errno::EPERM)?;
+    areas[index] = Some(Arc::try_new(SwapArea {
+        file,
+        priority,
+        slots: SpinMutex::new(slots),
+        active: AtomicBool::new(true),
+    })?);
+
+    Ok(())
+}
+
+/// Disables swapping to the area backed by `inode`. All of its pages are read back into memory.
+pub fn disable(inode: &Arc<INode>) -> EResult<()> {
+    let (index, area) = AREAS
+        .lock()
+        .iter()
+        .enumerate()
+        .find_map(|(i, x)| Some((i, x.clone().filter(|x| is_same(&x.file, inode))?)))
+        .ok_or(Errno::EINVAL)?;
+
+    // No new pages may be moved to the area, but the existing ones can still be read.
+    if !area.active.swap(false, Ordering::AcqRel) {
+        // Somebody else is disabling it already.
+        return Err(Errno::EINVAL);
+    }
+
+    let result = swap_in_area(index);
+    if result.is_err() || area.is_used() {
+        area.active.store(true, Ordering::Release);
+        return Err(result.err().unwrap_or(Errno::ENOMEM));
+    }
+
+    AREAS.lock()[index] = None;
+    Ok(())
+}
+
+/// Reads every page stored in area `index` back into the objects which own it.
+fn swap_in_area(index: usize) -> EResult<()> {
+    let owners = OWNERS
+        .lock()
+        .iter()
+        .filter_map(|x| x.upgrade())
+        .collect::<Vec<_>>();
+
+    for owner in owners {
+        owner.swap_in_area(index)?;
+    }
+    Ok(())
+}
+
+/// Returns true if `file` refers to the same node as `inode`.
+fn is_same(file: &File, inode: &Arc<INode>) -> bool {
+    file.inode.as_ref().is_some_and(|x| Arc::ptr_eq(x, inode))
+}
+
+/// Reads the slots of a swap area from its first page.
+fn parse_header(page: &[u8]) -> EResult<Vec<u32>> {
+    if !page.ends_with(SWAP_MAGIC) {
+        return Err(Errno::EINVAL);
+    }
+
+    let read_u32 =
+        |offset: usize| u32::from_ne_bytes(page[offset..][..size_of::<u32>()].try_into().unwrap());
+    let version = read_u32(HEADER_OFFSET);
+    let last_page = read_u32(HEADER_OFFSET + 4) as usize;
+    let num_bad = read_u32(HEADER_OFFSET + 8) as usize;
+    if version != HEADER_VERSION {
+        return Err(Errno::EINVAL);
+    }
+
+    let mut slots = Vec::new();
+    slots
+        .try_reserve_exact(last_page + 1)
+        .map_err(|_| Errno::ENOMEM)?;
+    slots.resize(last_page + 1, 0);
+
+    // The header itself can't be used for pages.
+    slots[0] = SLOT_RESERVED;
+
+    let max_bad = (page.len() - SWAP_MAGIC.len() - BAD_PAGES_OFFSET) / size_of::<u32>();
+    for i in 0..num_bad.min(max_bad) {
+        if let Some(slot) = slots.get_mut(read_u32(BAD_PAGES_OFFSET + i * 4) as usize) {
+            *slot = SLOT_RESERVED;
+        }
+    }
+
+    Ok(slots)
+}
+
+/// Writes a page to swap space. The page itself is left alone.
+pub fn write_page(page: PhysAddr) -> EResult<SwapEntry> {
+    let mut areas = AREAS
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, x)| Some((i, x.clone()?)))
+        .filter(|(_, x)| x.active.load(Ordering::Acquire))
+        .collect::<Vec<_>>();
+    areas.sort_by_key(|(_, x)| -x.priority);
+
+    for (index, area) in areas {
+        let Some(slot) = area.alloc_slot() else {
+            continue;
+        };
+
+        if let Err(err) = area.write(slot, page) {
+            area.slots.lock()[slot] = 0;
+            return Err(err);
+        }
+        return Ok(SwapEntry::new(index, slot));
+    }
+
+    Err(Errno::ENOSPC)
+}
+
+/// Reads the page stored at `entry` into a new page. The entry keeps its owners.
+pub fn read_page(entry: SwapEntry) -> EResult<PhysAddr> {
+    let area = get_area(entry.area()).ok_or(Errno::EIO)?;
+    let page = oom::alloc_page(AllocFlags::empty())?;
+    if let Err(err) = area.read(entry.slot(), page) {
+        unsafe { KernelAlloc::dealloc(page, 1) };
+        return Err(err);
+    }
+    Ok(page)
+}
+
+/// Records that `object` might hold pages in swap space.
+pub fn track(object: &Arc<dyn MemoryObject>) {
+    let mut owners = OWNERS.lock();
+    owners.retain(|x| x.strong_count() > 0);
+    if !owners
+        .iter()
+        .any(|x| core::ptr::addr_eq(x.as_ptr(), Arc::as_ptr(object)))
+    {
+        owners.push(Arc::downgrade(object));
+    }
+}
+
+/// A page which was unmapped to be moved to swap space.
+pub struct Eviction {
+    /// The object which owns the page.
+    pub object: Arc<dyn MemoryObject>,
+    /// Index of the page in the object.
+    pub page_index: usize,
+    /// Where the page was mapped.
+    pub addr: VirtAddr,
+    /// The page itself, with an extra reference held until the eviction is finished.
+    pub page: PhysAddr,
+}
+
+/// Returns true if pages can be moved to any swap area right now.
+fn has_active_area() -> bool {
+    AREAS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|x| x.active.load(Ordering::Acquire))
+}
+
+/// Waits until the reclaimer made a pass over user memory. Returns true if that freed any pages,
+/// so the allocation which failed is worth retrying.
+pub fn wait_for_reclaim() -> bool {
+    // The reclaimer can't wait for itself.
+    if !has_active_area()
+        || ptr::eq(
+            Arc::as_ptr(&Scheduler::get_current()),
+            RECLAIMER.load(Ordering::Acquire),
+        )
+    {
+        return false;
+    }
+
+    let start = PASSES.load(Ordering::Acquire);
+    loop {
+        let guard = RECLAIM_DONE.guard();
+        if PASSES.load(Ordering::Acquire) != start {
+            return LAST_FREED.load(Ordering::Acquire) != 0;
+        }
+
+        PENDING.store(true, Ordering::Release);
+        RECLAIM_REQUEST.wake_one();
+        guard.wait();
+    }
+}
+
+/// Moves up to `target` pages of user memory to swap space. Returns the amount of freed pages.
+/// `clock` is the position of the clock hand, as a process ID and a virtual page number.
+fn reclaim(clock: &mut (usize, usize), target: usize) -> usize {
+    let mut procs = Vec::new();
+    Process::for_each(|x| procs.push(x.clone()));
+
+    // Every page gets looked at twice at most. The first time only clears its accessed bit.
+    let mut freed = 0;
+    let mut wrapped = 0;
+    while freed < target && wrapped < 3 {
+        let Some(proc) = procs.iter().find(|x| x.get_pid() >= clock.0) else {
+            *clock = (0, 0);
+            wrapped += 1;
+            continue;
+        };
+
+        let (evictions, next) =
+            mmu::lock_responsive(&proc.address_space).reclaim(clock.1, target - freed);
+
+        // The pages are unmapped, so they can be written out without holding any locks.
+        for eviction in evictions {
+            let entry = write_page(eviction.page).ok();
+            if mmu::lock_responsive(&proc.address_space).finish_eviction(eviction, entry) {
+                freed += 1;
+            }
+        }
+
+        *clock = match next {
+            Some(page) => (proc.get_pid(), page),
+            None => (proc.get_pid() + 1, 0),
+        };
+    }
+
+    freed
+}
+
+/// Main loop of the reclaimer task. Makes a pass whenever an allocation failed.
+extern "C" fn reclaimer(_: usize, _: usize) {
+    let mut clock = (0, 0);
+    loop {
+        let guard = RECLAIM_REQUEST.guard();
+        if !PENDING.swap(false, Ordering::AcqRel) {
+            guard.wait();
+            continue;
+        }
+        drop(guard);
+
+        let freed = if has_active_area() {
+            reclaim(&mut clock, RECLAIM_BATCH)
+        } else {
+            0
+        };
+        LAST_FREED.store(freed, Ordering::Release);
+        PASSES.fetch_add(1, Ordering::AcqRel);
+        RECLAIM_DONE.wake_all();
+    }
+}
+
+#[initgraph::task(
+    name = "generic.memory.reclaimer",
+    depends = [crate::sched::SCHEDULER_STAGE],
+)]
+fn RECLAIMER_STAGE() {
+    let task = Arc::new(
+        Task::new(reclaimer, 0, 0, Process::get_kernel(), false)
+            .expect("Unable to create the reclaimer task"),
+    );
+    RECLAIMER.store(Arc::as_ptr(&task) as *mut _, Ordering::Release);
+    Scheduler::add_task_to_best_cpu(task);
+}
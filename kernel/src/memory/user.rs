@@ -1,7 +1,95 @@
 //! Safe user memory reading/writing.
+//!
+//! User memory is only accessed through [`arch::virt::copy_user`]. If a user pointer is bad, the
+//! copy faults and the page fault handler resumes it at an address from the fixup table, which
+//! the copy routine put into the `.user_fixup` section. The copy then fails with [`Errno::EFAULT`].
 
 use super::VirtAddr;
-use core::marker::PhantomData;
+use crate::{
+    arch,
+    posix::errno::{EResult, Errno},
+};
+use alloc::vec::Vec;
+use core::{marker::PhantomData, mem::MaybeUninit};
+
+/// An entry of the fixup table. Both addresses are relative to the field they are stored in.
+#[repr(C)]
+struct Fixup {
+    /// The instruction which may fault.
+    fault: i32,
+    /// Where to continue instead.
+    resume: i32,
+}
+
+unsafe extern "C" {
+    unsafe static LD_USER_FIXUP_START: Fixup;
+    unsafe static LD_USER_FIXUP_END: Fixup;
+}
+
+/// Returns where to continue if the instruction at `ip` faulted while accessing user memory.
+pub fn find_fixup(ip: VirtAddr) -> Option<VirtAddr> {
+    let start = &raw const LD_USER_FIXUP_START;
+    let end = &raw const LD_USER_FIXUP_END;
+    let table = unsafe { core::slice::from_raw_parts(start, end.offset_from_unsigned(start)) };
+
+    let resolve = |field: &i32| (field as *const i32 as usize).wrapping_add_signed(*field as isize);
+    table
+        .iter()
+        .find(|x| resolve(&x.fault) == ip.value())
+        .map(|x| VirtAddr::new(resolve(&x.resume)))
+}
+
+/// Returns true if `len` bytes at `addr` are all in the user half of the address space.
+pub fn is_user_range(addr: VirtAddr, len: usize) -> bool {
+    let end = 1usize << (arch::virt::get_highest_bit_shift() - 1);
+    addr.value().checked_add(len).is_some_and(|x| x <= end)
+}
+
+/// Copies `len` bytes from `src` to `dst`, where `user` is the one of them in user memory.
+///
+/// # Safety
+/// The other one has to be valid kernel memory for `len` bytes.
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize, user: VirtAddr) -> EResult<()> {
+    if !is_user_range(user, len) || !unsafe { arch::virt::copy_user(dst, src, len) } {
+        return Err(Errno::EFAULT);
+    }
+    Ok(())
+}
+
+/// Fills `dst` with the user memory at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> EResult<()> {
+    unsafe { copy(dst.as_mut_ptr(), src.as_ptr(), dst.len(), src) }
+}
+
+/// Writes `src` to the user memory at `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> EResult<()> {
+    unsafe { copy(dst.as_ptr(), src.as_ptr(), src.len(), dst) }
+}
+
+/// Reads a NUL terminated string from user memory. The returned string doesn't include the NUL.
+/// Fails with [`Errno::ENAMETOOLONG`] if there is no NUL within `max` bytes.
+pub fn copy_cstr_from_user(addr: VirtAddr, max: usize) -> EResult<Vec<u8>> {
+    let page_size = arch::virt::get_page_size();
+    let mut result = Vec::new();
+    let mut cur = addr;
+
+    while result.len() < max {
+        // The string may end right before an unmapped page, so don't read across pages at once.
+        let start = result.len();
+        let chunk = (page_size - cur.value() % page_size).min(max - start);
+        result.try_reserve(chunk).map_err(|_| Errno::ENOMEM)?;
+        result.resize(start + chunk, 0);
+        copy_from_user(&mut result[start..], cur)?;
+
+        if let Some(nul) = result[start..].iter().position(|&x| x == 0) {
+            result.truncate(start + nul);
+            return Ok(result);
+        }
+        cur = cur + chunk;
+    }
+
+    Err(Errno::ENAMETOOLONG)
+}
 
 /// Provides safe access to a single structure from userland.
 #[repr(transparent)]
@@ -30,14 +118,36 @@ impl<T: Sized + Copy> UserPtr<T> {
         }
     }
 
+    pub const fn is_null(&self) -> bool {
+        self.addr.0 == 0
+    }
+
+    /// Reads the value, or returns [`None`] if the pointer is bad.
     pub fn read(&self) -> Option<T> {
-        // TODO: Mark the start of a user pointer access that can be checked in the PF handler.
-        Some(unsafe { self.addr.as_ptr::<T>().read_unaligned() })
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            copy(
+                value.as_mut_ptr().cast(),
+                self.addr.as_ptr(),
+                size_of::<T>(),
+                self.addr,
+            )
+            .ok()?;
+            Some(value.assume_init())
+        }
     }
 
+    /// Writes the value. Returns false if the pointer is bad.
     pub fn write(&mut self, value: T) -> bool {
-        unsafe { self.addr.as_ptr::<T>().write_unaligned(value) };
-        true
+        unsafe {
+            copy(
+                self.addr.as_ptr(),
+                (&raw const value).cast(),
+                size_of::<T>(),
+                self.addr,
+            )
+            .is_ok()
+        }
     }
 }
 
@@ -46,36 +156,3 @@ impl<T: Sized + Copy> From<usize> for UserPtr<T> {
         Self::new(value.into())
     }
 }
-
-/// Provides safe access to a memory buffer from userland.
-pub struct UserSlice<T: Sized + Copy> {
-    addr: VirtAddr,
-    len: usize,
-    _p: PhantomData<T>,
-}
-
-impl<T: Sized + Copy> UserSlice<T> {
-    pub const fn new(addr: VirtAddr, len: usize) -> Self {
-        Self {
-            addr,
-            len,
-            _p: PhantomData,
-        }
-    }
-
-    pub fn len(&self) -> usize {
-        self.len
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.len == 0
-    }
-
-    pub fn as_slice(&self) -> Option<&[T]> {
-        Some(unsafe { core::slice::from_raw_parts(self.addr.as_ptr::<T>(), self.len) })
-    }
-
-    pub fn as_mut_slice(&mut self) -> Option<&mut [T]> {
-        Some(unsafe { core::slice::from_raw_parts_mut(self.addr.as_ptr::<T>(), self.len) })
-    }
-}
@@ -1,6 +1,10 @@
 //! Helpers for structured data accesses.
 
-use super::{PhysAddr, VirtAddr, pmm::KernelAlloc, virt::VmFlags};
+use super::{
+    PhysAddr,
+    pmm::KernelAlloc,
+    virt::{VmCacheType, VmFlags},
+};
 use crate::memory::virt::mmu::PageTable;
 use core::{marker::PhantomData, ops::RangeInclusive};
 use num_traits::{FromBytes, PrimInt, ToBytes};
@@ -227,9 +231,13 @@ impl MmioView {
     /// `phys` must be pointing to the start of the device memory region.
     pub unsafe fn new(phys: PhysAddr, len: usize) -> Self {
         return Self {
-            // TODO: When adding memory type support, map this as uncacheable.
             base: PageTable::get_kernel()
-                .map_memory::<KernelAlloc>(phys, VmFlags::Read | VmFlags::Write, len)
+                .map_memory::<KernelAlloc>(
+                    phys,
+                    VmFlags::Read | VmFlags::Write,
+                    VmCacheType::Uncached,
+                    len,
+                )
                 .unwrap() as *mut (),
             len,
         };
@@ -295,9 +303,7 @@ impl MmioView {
 
 impl Drop for MmioView {
     fn drop(&mut self) {
-        PageTable::get_kernel()
-            .unmap_range::<KernelAlloc>(VirtAddr(self.base as usize), self.len)
-            .unwrap();
+        PageTable::get_kernel().unmap_memory::<KernelAlloc>(self.base as *mut u8, self.len);
     }
 }
 
@@ -0,0 +1,72 @@
+//! Address space layout randomization.
+//!
+//! In user processes, the executable, the interpreter, the stack and the start of the mmap region
+//! are each moved by a random amount of pages. Passing `aslr=off` on the command line places
+//! everything at fixed addresses again, which makes crashes reproducible.
+//!
+//! The kernel image is placed by the bootloader, which randomizes it if it's configured to do so.
+//! The kernel itself moves the HHDM and the start of the [`vmalloc`](super::vmalloc) region, unless
+//! `kaslr=off` is passed on the command line.
+
+use crate::{arch, boot::BootInfo, random};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Base address of a position independent executable.
+/// It could technically be 0, but we don't want to get anywhere near the NULL address.
+pub const EXEC_BASE: usize = 0x10000;
+/// Lowest address at which the mmap region starts.
+pub const MMAP_BASE: usize = 0x1_0000_0000;
+
+/// Random bits in the page offset of a position independent executable.
+pub const EXEC_BITS: u32 = 22;
+/// Random bits in the page offset of the interpreter.
+pub const INTERP_BITS: u32 = 28;
+/// Random bits in the page offset of the mmap region.
+pub const MMAP_BITS: u32 = 28;
+/// Random bits in the page offset of the stack.
+pub const STACK_BITS: u32 = 22;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Returns true if layouts are randomized.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Returns a random, page aligned offset of less than `1 << bits` pages.
+/// The offset is always 0 if randomization is disabled.
+pub fn random_offset(bits: u32) -> usize {
+    if !is_enabled() {
+        return 0;
+    }
+
+    let pages = random::next_u64() as usize & ((1 << bits) - 1);
+    pages * arch::virt::get_page_size()
+}
+
+/// Returns a random offset for a kernel region. It is a multiple of `align` and at most `room`.
+/// The offset is always 0 if kernel randomization is disabled.
+pub fn kernel_offset(room: usize, align: usize) -> usize {
+    // This is called while setting up memory, which is before the stage below runs.
+    if !BootInfo::get()
+        .command_line
+        .get_bool("kaslr")
+        .unwrap_or(true)
+    {
+        return 0;
+    }
+
+    (random::next_u64() as usize % (room / align + 1)) * align
+}
+
+#[initgraph::task(name = "generic.memory.aslr")]
+pub fn ASLR_STAGE() {
+    if !BootInfo::get()
+        .command_line
+        .get_bool("aslr")
+        .unwrap_or(true)
+    {
+        log!("Address space layout randomization is disabled");
+        ENABLED.store(false, Ordering::Relaxed);
+    }
+}
@@ -1,12 +1,18 @@
 use crate::{
     arch,
     memory::{
-        AddressSpace, MemoryObject, PagedMemoryObject, VirtAddr, pmm::KernelAlloc, virt::VmFlags,
+        AddressSpace, MemoryObject, VirtAddr, oom,
+        pmm::KernelAlloc,
+        swap, user,
+        virt::{MappedObject, VmFlags, mmu, vmalloc},
     },
+    posix::errno::{EResult, Errno},
+    process::{self, ProcessState, signal::Signal},
     sched::Scheduler,
+    uapi::signal,
+    util::{align_down, mutex::spin::SpinMutex},
 };
-use alloc::sync::Arc;
-use core::num::NonZeroUsize;
+use alloc::{sync::Arc, vec::Vec};
 
 /// Abstract information about a page fault.
 #[derive(Debug)]
@@ -25,9 +31,36 @@ pub struct PageFaultInfo {
     pub page_was_present: bool,
 }
 
-/// The actual page fault. This function is meant to be called by other code too.
-/// This is useful for [`crate::memory::user::UserPtr`], which avoids taking any additional locks.
-pub fn handler_inner(info: &PageFaultInfo, space: &mut AddressSpace) {
+/// Why a page fault couldn't be resolved.
+enum FaultKind {
+    /// There is no mapping at the faulting address.
+    NotMapped,
+    /// The mapping doesn't allow this kind of access.
+    AccessDenied,
+    /// The mapped object has no backing page at the faulting address.
+    NoPage,
+    /// There was no memory left to provide a page.
+    OutOfMemory,
+    /// The page has to be read from swap space first, see [`MemoryObject::swap_in`].
+    Swapped(Arc<dyn MemoryObject>, usize),
+}
+
+impl VmFlags {
+    /// Returns true if a mapping with these flags permits the access described by `info`.
+    fn allows(self, info: &PageFaultInfo) -> bool {
+        if info.caused_by_write {
+            self.contains(VmFlags::Write)
+        } else if info.caused_by_fetch {
+            self.contains(VmFlags::Exec)
+        } else {
+            self.intersects(VmFlags::Read | VmFlags::Write | VmFlags::Exec)
+        }
+    }
+}
+
+/// The actual page fault. Faults which can't be resolved are returned, so they can be handled
+/// by [`unresolved`] after `space` was unlocked. The OOM killer has to look at all address spaces.
+fn handler_inner(info: &PageFaultInfo, space: &mut AddressSpace) -> Result<(), FaultKind> {
     // Check if the current address space has a theoretical mapping at the faulting address.
     let page_size = arch::virt::get_page_size();
     let faulty_page = info.addr.value() / arch::virt::get_page_size();
@@ -38,88 +71,232 @@ pub fn handler_inner(info: &PageFaultInfo, space: &mut AddressSpace) {
             .find(|x| faulty_page >= x.start_page && faulty_page < x.end_page)
             .cloned()
     } {
-        // Do copy on write.
+        // Check if the access is allowed at all.
+        if !mapped.get_flags().allows(info) {
+            return Err(FaultKind::AccessDenied);
+        }
+
+        // Only allocations made on behalf of this fault are of interest.
+        oom::take_failure();
+
         let mut map_flags = mapped.get_flags();
-        let wants_cow = map_flags.contains(VmFlags::CopyOnWrite);
-        let mut mapped_obj = mapped.object.clone();
+        let page_index = faulty_page - mapped.start_page + mapped.offset_page;
 
-        if wants_cow && info.caused_by_write {
+        // Pages of copy on write mappings may be shared with other objects.
+        // They stay read only until they get written to, then they're copied.
+        let unshare = map_flags.contains(VmFlags::CopyOnWrite) && info.caused_by_write;
+        if map_flags.contains(VmFlags::CopyOnWrite) {
             map_flags &= !VmFlags::CopyOnWrite;
-
-            let num_pages = mapped.end_page - mapped.start_page;
-            let region_len = NonZeroUsize::new(num_pages * page_size).unwrap();
-            let region_addr = (mapped.start_page * page_size).into();
-            let region_offset = (mapped.offset_page * page_size) as _;
-
-            if Arc::strong_count(&mapped.object) == 1 {
-                space
-                    .map_object(
-                        mapped.object.clone(),
-                        region_addr,
-                        region_len,
-                        map_flags,
-                        region_offset,
-                    )
-                    .unwrap();
-                mapped_obj = mapped.object.clone();
-            } else {
-                let new_obj: Arc<dyn MemoryObject> = Arc::new(PagedMemoryObject::new_phys());
-
-                // Copy the data from the old mapping into the new private object.
-                let mut buf = vec![0u8; page_size];
-                for page in 0..num_pages {
-                    mapped
-                        .object
-                        .read(&mut buf, (mapped.offset_page + page) * page_size);
-                    new_obj.write(&buf, (mapped.offset_page + page) * page_size);
-                }
-
-                space
-                    .map_object(
-                        new_obj.clone(),
-                        region_addr,
-                        region_len,
-                        map_flags,
-                        region_offset,
-                    )
-                    .unwrap();
-                mapped_obj = new_obj;
+            if !info.caused_by_write {
+                map_flags &= !VmFlags::Write;
             }
-        } else if wants_cow {
-            map_flags &= !VmFlags::Write;
         }
 
-        if let Some(phys) =
-            mapped_obj.try_get_page((faulty_page - mapped.start_page) + mapped.offset_page)
-        {
+        // Copies are made one page at a time.
+        if !unshare && map_huge(space, &mapped, faulty_page, map_flags) {
+            return Ok(());
+        }
+
+        // Reading from swap space sleeps, so it's done without holding `space`.
+        if mapped.object.is_swapped(page_index) {
+            return Err(FaultKind::Swapped(mapped.object.clone(), page_index));
+        }
+
+        let phys = match unshare {
+            true => mapped.object.unshare_page(page_index),
+            false => mapped.object.try_get_page(page_index),
+        };
+
+        // Tell a missing page apart from an allocation which failed.
+        let no_memory = oom::take_failure();
+
+        if let Some(phys) = phys {
             // If we get here, the accessed address is valid. Map it in the actual page table and return.
+            let page_addr = VirtAddr::from(faulty_page * page_size);
+            let was_mapped = space.table.is_mapped(page_addr);
             space
                 .table
-                .map_single::<KernelAlloc>(info.addr, phys, map_flags)
-                .expect("Failed to map a demand-loaded page");
-            return;
+                .map_single::<KernelAlloc>(page_addr, phys, map_flags, mapped.cache)
+                .map_err(|_| FaultKind::OutOfMemory)?;
+
+            // Other CPUs might still see the page that was replaced.
+            if was_mapped {
+                space.table.flush_range(page_addr, page_size);
+            }
+            return Ok(());
+        }
+
+        // The mapping exists, but the object can't provide a page at this offset.
+        return Err(match no_memory {
+            true => FaultKind::OutOfMemory,
+            false => FaultKind::NoPage,
+        });
+    }
+
+    Err(FaultKind::NotMapped)
+}
+
+/// Tries to map the huge page around `page` at once. Returns false if that's not possible, for
+/// example because the mapping doesn't cover all of it.
+fn map_huge(space: &AddressSpace, mapped: &MappedObject, page: usize, flags: VmFlags) -> bool {
+    let pages = 1 << arch::virt::get_level_bits();
+    let first = align_down(page, pages);
+    if first < mapped.start_page || first + pages > mapped.end_page {
+        return false;
+    }
+
+    // The object has to be aligned just like the address space.
+    let index = first - mapped.start_page + mapped.offset_page;
+    if index % pages != 0 {
+        return false;
+    }
+
+    let Some(phys) = mapped.object.try_get_huge_page(index, pages) else {
+        return false;
+    };
+    space
+        .table
+        .map_large::<KernelAlloc>(
+            VirtAddr::from(first * arch::virt::get_page_size()),
+            phys,
+            flags,
+            mapped.cache,
+            1,
+        )
+        .is_ok()
+}
+
+/// Faults in every mapped page between `start_page` and `end_page`, as if each of them was
+/// accessed. Pages of writable private mappings are copied right away, so writing to them later
+/// doesn't need any memory. Like a page fault, this only locks `space` for one page at a time.
+pub(super) fn populate(
+    space: &SpinMutex<AddressSpace>,
+    start_page: usize,
+    end_page: usize,
+) -> EResult<()> {
+    let ranges = space
+        .lock()
+        .mappings
+        .iter()
+        .filter(|x| start_page < x.end_page && x.start_page < end_page)
+        .map(|x| (start_page.max(x.start_page), end_page.min(x.end_page)))
+        .collect::<Vec<_>>();
+
+    let page_size = arch::virt::get_page_size();
+    for page in ranges.into_iter().flat_map(|(start, end)| start..end) {
+        let addr = VirtAddr::from(page * page_size);
+        let mut locked = space.lock();
+
+        // The mapping might have changed since the ranges were collected.
+        let Some(flags) = locked
+            .mappings
+            .iter()
+            .find(|x| page >= x.start_page && page < x.end_page)
+            .map(|x| x.get_flags())
+        else {
+            continue;
+        };
+
+        // Pages which can't be accessed at all are only reserved.
+        if !flags.intersects(VmFlags::Read | VmFlags::Write | VmFlags::Exec) {
+            continue;
+        }
+        let unshare = flags.contains(VmFlags::CopyOnWrite | VmFlags::Write);
+        if !unshare && locked.table.is_mapped(addr) {
+            continue;
+        }
+
+        let info = PageFaultInfo {
+            ip: VirtAddr::null(),
+            addr,
+            caused_by_user: true,
+            caused_by_write: flags.contains(VmFlags::Write),
+            caused_by_fetch: false,
+            page_was_present: false,
+        };
+        let mut result = handler_inner(&info, &mut locked);
+        if let Err(FaultKind::Swapped(object, page_index)) = result {
+            drop(locked);
+            object.swap_in(page_index)?;
+            result = handler_inner(&info, &mut space.lock());
+        }
+
+        match result {
+            // Pages past the end of a file can't be faulted in, but they can't be swapped either.
+            Ok(()) | Err(FaultKind::NoPage) => (),
+            Err(_) => return Err(Errno::EAGAIN),
+        }
+    }
+
+    Ok(())
+}
+
+/// Handles a page fault which couldn't be resolved by mapping a page.
+/// Returns where to resume if the kernel faulted while copying user memory.
+fn unresolved(info: &PageFaultInfo, fault: FaultKind) -> Option<VirtAddr> {
+    let proc = Scheduler::get_current().get_process();
+
+    // Threads of an exiting process may still run for a moment after its memory was freed.
+    // Retrying would fault forever. The process already let go of the thread, so it only has to
+    // stop running.
+    if info.caused_by_user && !matches!(*proc.status.lock(), ProcessState::Running) {
+        drop(proc);
+        Scheduler::kill_current();
+    }
+
+    // Retry the access once the page is back in memory.
+    let fault = match fault {
+        FaultKind::Swapped(object, page_index) => match object.swap_in(page_index) {
+            Ok(()) => return None,
+            Err(Errno::ENOMEM) => FaultKind::OutOfMemory,
+            Err(_) => FaultKind::NoPage,
+        },
+        x => x,
+    };
+
+    // Retry the access if the reclaimer could free some pages.
+    if let FaultKind::OutOfMemory = fault
+        && swap::wait_for_reclaim()
+    {
+        return None;
+    }
+
+    // Retry the access once the victim gave up its memory. If the victim is the current process,
+    // it dies as soon as it returns to user space, but the kernel can't wait for that.
+    if let FaultKind::OutOfMemory = fault
+        && let Some(victim) = oom::out_of_memory()
+    {
+        if !Arc::ptr_eq(&victim, &proc) {
+            oom::wait_for_victim(&victim);
+            return None;
+        }
+        if info.caused_by_user {
+            return None;
         }
     }
 
     if info.caused_by_user {
-        // TODO: Send SIGSEGV and reschedule.
-        // Kill process.
-        // Force immediate reschedule.
+        // Let the process handle the fault, or get killed by it.
+        let (sig, code) = match fault {
+            FaultKind::NotMapped => (Signal::SIGSEGV, signal::SEGV_MAPERR),
+            FaultKind::AccessDenied => (Signal::SIGSEGV, signal::SEGV_ACCERR),
+            FaultKind::NoPage | FaultKind::OutOfMemory | FaultKind::Swapped(..) => {
+                (Signal::SIGBUS, signal::BUS_ADRERR)
+            }
+        };
+        process::signal::send_fault(sig, code as _, info.addr);
+        return None;
+    }
+
+    // A bad pointer from user space makes the copy fail instead of the kernel.
+    if let Some(resume) = user::find_fixup(info.ip) {
+        return Some(resume);
+    }
+
+    if vmalloc::is_guard_page(info.addr) {
         panic!(
-            "User process caused a segmentation fault! Attempted to {} a {} page at {:#x} (IP: {:#x})",
-            if info.caused_by_write {
-                "write to"
-            } else if info.caused_by_fetch {
-                "execute on"
-            } else {
-                "read from"
-            },
-            if info.page_was_present {
-                "present"
-            } else {
-                "non-present"
-            },
-            info.addr.0,
+            "Kernel stack overflow in task {} (IP: {:#x})",
+            Scheduler::get_current().get_id(),
             info.ip.0
         );
     }
@@ -145,7 +322,9 @@ pub fn handler_inner(info: &PageFaultInfo, space: &mut AddressSpace) {
 }
 
 /// Generic page fault handler for MMU-generated faults.
-pub fn handler(info: &PageFaultInfo) {
+/// If this returns an address, the faulting context has to continue there.
+pub fn handler(info: &PageFaultInfo) -> Option<VirtAddr> {
     let proc = Scheduler::get_current().get_process();
-    handler_inner(info, &mut proc.address_space.lock());
+    let result = handler_inner(info, &mut mmu::lock_responsive(&proc.address_space));
+    result.err().and_then(|fault| unresolved(info, fault))
 }
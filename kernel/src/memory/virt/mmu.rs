@@ -1,16 +1,31 @@
 use crate::{
     arch::{self, virt::PageTableEntry},
     {
+        irq::lock::IrqLock,
         memory::{
             PhysAddr, VirtAddr,
             pmm::{AllocFlags, KernelAlloc, PageAllocator},
-            virt::{KERNEL_MMAP_BASE_ADDR, KERNEL_PAGE_TABLE, PageTableError, PteFlags, VmFlags},
+            virt::{KERNEL_PAGE_TABLE, PageTableError, PteFlags, VmCacheType, VmFlags, vmalloc},
+        },
+        percpu::CpuData,
+        sched::cpuset::CpuSet,
+        util::{
+            align_down, align_up,
+            mutex::spin::{SpinMutex, SpinMutexGuard},
         },
-        util::{align_up, mutex::spin::SpinMutex},
     },
 };
-use alloc::{alloc::AllocError, slice};
-use core::sync::atomic::Ordering;
+use alloc::{alloc::AllocError, slice, vec::Vec};
+use core::{
+    hint,
+    ptr::{self, null_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+/// The flags which select a [`VmCacheType`].
+const CACHE_FLAGS: PteFlags = PteFlags::WriteCombining
+    .union(PteFlags::Uncached)
+    .union(PteFlags::WriteThrough);
 
 /// Represents a virtual address space.
 #[derive(Debug)]
@@ -21,6 +36,79 @@ pub struct PageTable {
     root_level: usize,
     /// `true`, if this is a user page table.
     is_user: bool,
+    /// CPUs which might have entries of this page table in their TLB.
+    active_cpus: SpinMutex<CpuSet>,
+}
+
+per_cpu!(
+    /// The page table which was last activated on this CPU.
+    static ACTIVE_TABLE: AtomicPtr<PageTable> = AtomicPtr::new(null_mut());
+    /// Set if this CPU still has to handle the current TLB shootdown.
+    static SHOOTDOWN_PENDING: AtomicBool = AtomicBool::new(false);
+);
+
+/// Only one TLB shootdown can be in flight at a time.
+static SHOOTDOWN_LOCK: SpinMutex<()> = SpinMutex::new(());
+/// The page table of the current TLB shootdown.
+static SHOOTDOWN_TABLE: AtomicPtr<PageTable> = AtomicPtr::new(null_mut());
+/// The start address of the current TLB shootdown.
+static SHOOTDOWN_START: AtomicUsize = AtomicUsize::new(0);
+/// The length in bytes of the current TLB shootdown.
+static SHOOTDOWN_LEN: AtomicUsize = AtomicUsize::new(0);
+/// Amount of CPUs which didn't handle the current TLB shootdown yet.
+static SHOOTDOWN_REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Invalidates a range of addresses in the TLB of this CPU.
+fn flush_local(virt: VirtAddr, length: usize) {
+    let step = arch::virt::get_page_size();
+    for offset in (0..align_up(length, step)).step_by(step) {
+        arch::virt::flush_tlb(VirtAddr(virt.0 + offset));
+    }
+}
+
+/// Handles the TLB shootdown requested from this CPU, if there is one.
+/// This is called by the shootdown IPI, but also by CPUs which wait for a lock with interrupts
+/// disabled, so they can't block a CPU which is waiting for them.
+pub fn handle_shootdown() {
+    if !SHOOTDOWN_PENDING.get().swap(false, Ordering::AcqRel) {
+        return;
+    }
+
+    let table = SHOOTDOWN_TABLE.load(Ordering::Acquire);
+    // The initiator keeps the table alive until every CPU is done.
+    let table_ref = unsafe { &*table };
+    if !table_ref.is_user || ACTIVE_TABLE.get().load(Ordering::Acquire) == table {
+        flush_local(
+            VirtAddr(SHOOTDOWN_START.load(Ordering::Acquire)),
+            SHOOTDOWN_LEN.load(Ordering::Acquire),
+        );
+    } else {
+        // Switching away from the page table already flushed all of its entries.
+        table_ref.active_cpus.lock().remove(CpuData::get().id);
+    }
+
+    SHOOTDOWN_REMAINING.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Completes the pending shootdown of `cpu` on its behalf. This is used by architectures which
+/// can invalidate the TLB of another CPU directly, without interrupting it.
+pub fn acknowledge_shootdown(cpu: &'static CpuData) {
+    if SHOOTDOWN_PENDING.get_for(cpu).swap(false, Ordering::AcqRel) {
+        SHOOTDOWN_REMAINING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Locks `mutex` while answering TLB shootdowns.
+/// This has to be used instead of [`SpinMutex::lock`] if interrupts are disabled and the lock
+/// could be held by a CPU which is waiting for a shootdown.
+pub fn lock_responsive<T: ?Sized>(mutex: &SpinMutex<T>) -> SpinMutexGuard<'_, T> {
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            return guard;
+        }
+        handle_shootdown();
+        hint::spin_loop();
+    }
 }
 
 impl PageTable {
@@ -41,6 +129,7 @@ impl PageTable {
             head: SpinMutex::new(user_l1),
             root_level: KERNEL_PAGE_TABLE.get().root_level,
             is_user: true,
+            active_cpus: SpinMutex::new(CpuSet::empty()),
         }
     }
 
@@ -50,6 +139,7 @@ impl PageTable {
             head: SpinMutex::new(P::alloc(1, flags | AllocFlags::Zeroed).unwrap()),
             root_level,
             is_user: false,
+            active_cpus: SpinMutex::new(CpuSet::empty()),
         }
     }
 
@@ -58,22 +148,34 @@ impl PageTable {
     }
 
     /// Maps physical memory to a free area in virtual address space.
+    /// The mapping has to be removed with [`Self::unmap_memory`].
     pub fn map_memory<P: PageAllocator>(
         &self,
         phys: PhysAddr,
         flags: VmFlags,
+        cache: VmCacheType,
         length: usize,
     ) -> Result<*mut u8, AllocError> {
         let aligned_len = align_up(length, arch::virt::get_page_size());
-
-        // Increase mapping base.
-        // TODO: Use actual virtual address allocator.
-        let virt = KERNEL_MMAP_BASE_ADDR.fetch_add(aligned_len, Ordering::SeqCst);
+        let virt = vmalloc::alloc(aligned_len)?;
 
         // Map memory.
-        self.map_range::<P>(VirtAddr(virt), phys, flags, aligned_len)
-            .map_err(|_| AllocError)?;
-        return Ok(virt as *mut u8);
+        if self
+            .map_range::<P>(virt, phys, flags, cache, aligned_len)
+            .is_err()
+        {
+            _ = self.unmap_range::<P>(virt, aligned_len);
+            vmalloc::free(virt);
+            return Err(AllocError);
+        }
+        return Ok(virt.as_ptr());
+    }
+
+    /// Removes a mapping created by [`Self::map_memory`] and frees its address space.
+    pub fn unmap_memory<P: PageAllocator>(&self, virt: *mut u8, length: usize) {
+        let virt = VirtAddr::from(virt);
+        _ = self.unmap_range::<P>(virt, length);
+        vmalloc::free(virt);
     }
 }
 
@@ -98,17 +200,87 @@ impl PageTable {
         }
     }
 
+    /// Sets this page table as the active one and records that this CPU may now cache its
+    /// entries, so it takes part in TLB shootdowns. This has to be used by the scheduler.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`Self::set_active`]. Interrupts have to be disabled.
+    pub unsafe fn activate(&self) {
+        // This CPU has to be visible to shootdowns before it can load any entries.
+        self.active_cpus.lock().insert(CpuData::get().id);
+        ACTIVE_TABLE
+            .get()
+            .store(ptr::from_ref(self).cast_mut(), Ordering::Release);
+        unsafe { self.set_active() };
+    }
+
+    /// Invalidates a range of this page table in the TLB of every CPU which might have cached it.
+    /// Returns once all of them are done.
+    pub fn flush_range(&self, virt: VirtAddr, length: usize) {
+        let _irq = IrqLock::lock();
+        flush_local(virt, length);
+
+        // Kernel mappings are shared by all page tables.
+        let this = CpuData::get().id;
+        let mut targets = match self.is_user {
+            true => *self.active_cpus.lock(),
+            false => CpuSet::online(),
+        };
+        targets.remove(this);
+        if targets.is_empty() {
+            return;
+        }
+
+        let _guard = lock_responsive(&SHOOTDOWN_LOCK);
+        SHOOTDOWN_TABLE.store(ptr::from_ref(self).cast_mut(), Ordering::Release);
+        SHOOTDOWN_START.store(virt.0, Ordering::Release);
+        SHOOTDOWN_LEN.store(length, Ordering::Release);
+
+        let cpus = || {
+            CpuData::iter().filter(|x| targets.contains(x.id) && x.online.load(Ordering::Acquire))
+        };
+        SHOOTDOWN_REMAINING.store(cpus().count(), Ordering::Release);
+        for cpu in cpus() {
+            SHOOTDOWN_PENDING
+                .get_for(cpu)
+                .store(true, Ordering::Release);
+            arch::virt::send_shootdown(cpu);
+        }
+
+        while SHOOTDOWN_REMAINING.load(Ordering::Acquire) != 0 {
+            hint::spin_loop();
+        }
+    }
+
     /// Gets the page table entry pointed to by `virt`.
-    /// Allocates new levels if necessary and requested.
+    /// Allocates new levels if necessary and requested. Large pages on the way are split up in
+    /// that case too. Otherwise, the returned PTE may be a large page on a higher level.
     pub fn get_pte<P: PageAllocator>(
         &self,
         virt: VirtAddr,
         allocate: bool,
     ) -> Result<*mut PageTableEntry, PageTableError> {
+        self.walk::<P>(virt, 0, allocate).map(|(pte, _)| pte)
+    }
+
+    /// Walks the page table down to the PTE which maps `virt` on `target_level`.
+    /// If `allocate` is false, the walk stops early at large pages. Returns the PTE and its level.
+    fn walk<P: PageAllocator>(
+        &self,
+        virt: VirtAddr,
+        target_level: usize,
+        allocate: bool,
+    ) -> Result<(*mut PageTableEntry, usize), PageTableError> {
         let head = self.head.lock();
         let mut current_head: *mut PageTableEntry = head.as_hhdm();
-        let mut index = 0;
-        let mut do_break = false;
+
+        let dir_flags = PteFlags::Directory
+            | if self.is_user {
+                PteFlags::User
+            } else {
+                PteFlags::empty()
+            };
 
         // Traverse the page table (from highest to lowest level).
         for level in (0..self.root_level).rev() {
@@ -119,33 +291,22 @@ impl PageTable {
             let addr_shift = arch::virt::get_page_bits() + (arch::virt::get_level_bits() * level);
 
             // Get the index for this level by masking the relevant address part.
-            index = (virt.0 >> addr_shift) & addr_bits;
-
-            // The last level is used to access the actual PTE, so break the loop then.
-            if level == 0 || do_break {
-                break;
+            let pte = unsafe { current_head.add((virt.0 >> addr_shift) & addr_bits) };
+            if level == target_level {
+                return Ok((pte, level));
             }
 
             unsafe {
-                let pte = current_head.add(index);
-
-                let mut pte_flags = PteFlags::Directory
-                    | if self.is_user {
-                        PteFlags::User
-                    } else {
-                        PteFlags::empty()
-                    };
-
-                if (*pte).is_present() {
-                    // If this PTE is a large page, it already contains the final address. Don't continue.
-                    if !(*pte).is_directory(level) {
-                        pte_flags |= PteFlags::Large;
-                        do_break = true;
-                    } else {
-                        // If the PTE is not large, go one level deeper.
-                        current_head = (*pte).address().as_hhdm();
+                if (*pte).is_present() && (*pte).is_directory(level) {
+                    // Go one level deeper.
+                    *pte = PageTableEntry::new((*pte).address(), dir_flags, level);
+                    current_head = (*pte).address().as_hhdm();
+                } else if (*pte).is_present() {
+                    // If this PTE is a large page, it already contains the final address.
+                    if !allocate {
+                        return Ok((pte, level));
                     }
-                    *pte = PageTableEntry::new((*pte).address(), pte_flags, level);
+                    current_head = Self::split::<P>(pte, level, dir_flags)?;
                 } else {
                     // PTE isn't present, but we have to allocate a new level now.
                     if !allocate {
@@ -162,7 +323,7 @@ impl PageTable {
                         VirtAddr::from(next_head)
                             .as_hhdm()
                             .ok_or(PageTableError::PageTableEntryMissing)?,
-                        pte_flags,
+                        dir_flags,
                         level,
                     );
                     current_head = next_head;
@@ -170,7 +331,80 @@ impl PageTable {
             }
         }
 
-        Ok(unsafe { current_head.add(index) })
+        Err(PageTableError::PageTableEntryMissing)
+    }
+
+    /// Replaces the large page in `pte` with a new level that maps the same memory with pages of
+    /// the next smaller size. Returns the new level.
+    ///
+    /// The translation stays the same, so the TLB doesn't have to be flushed before the new
+    /// entries are changed. Dirty bits of the large page are lost.
+    unsafe fn split<P: PageAllocator>(
+        pte: *mut PageTableEntry,
+        level: usize,
+        dir_flags: PteFlags,
+    ) -> Result<*mut PageTableEntry, PageTableError> {
+        let old = unsafe { pte.read_volatile() };
+        let next = P::alloc(1, AllocFlags::empty()).map_err(|_| PageTableError::OutOfMemory)?;
+        let next_head: *mut PageTableEntry = next.as_hhdm();
+
+        let mut flags = old.flags(level);
+        flags.set(PteFlags::Large, level > 1);
+        let size = arch::virt::get_level_size(level - 1);
+        for i in 0..1 << arch::virt::get_level_bits() {
+            unsafe {
+                next_head.add(i).write(PageTableEntry::new(
+                    old.address() + i * size,
+                    flags,
+                    level - 1,
+                ))
+            };
+        }
+
+        unsafe { pte.write_volatile(PageTableEntry::new(next, dir_flags, level)) };
+        Ok(next_head)
+    }
+
+    /// Frees a page level and all directories below it. The pages they map are left alone.
+    unsafe fn free_level<P: PageAllocator>(level_head: PhysAddr, level: usize) {
+        if level > 0 {
+            let entries: *const PageTableEntry = level_head.as_hhdm();
+            for index in 0..1 << arch::virt::get_level_bits() {
+                let pte = unsafe { entries.add(index).read_volatile() };
+                if pte.is_present() && pte.is_directory(level) {
+                    unsafe { Self::free_level::<P>(pte.address(), level - 1) };
+                }
+            }
+        }
+        unsafe { P::dealloc(level_head, 1) };
+    }
+
+    /// Returns the leaf PTE which maps `virt`, for a change to `length` bytes starting there.
+    /// Large pages which are only partially affected by the change are split up first.
+    /// Returns the PTE and its level.
+    fn leaf_pte<P: PageAllocator>(
+        &self,
+        virt: VirtAddr,
+        length: usize,
+    ) -> Result<(*mut PageTableEntry, usize), PageTableError> {
+        let (pte, level) = self.walk::<P>(virt, 0, false)?;
+        let size = arch::virt::get_level_size(level);
+        if level > 0 && (virt.0 % size != 0 || length < size) {
+            return self.walk::<P>(virt, 0, true);
+        }
+        Ok((pte, level))
+    }
+
+    /// Returns the highest level on which `length` bytes from `phys` can be mapped at `virt`
+    /// with a single PTE.
+    fn leaf_level(&self, virt: VirtAddr, phys: PhysAddr, length: usize) -> usize {
+        (1..=arch::virt::get_max_leaf_level().min(self.root_level - 1))
+            .rev()
+            .find(|&level| {
+                let size = arch::virt::get_level_size(level);
+                virt.0 % size == 0 && phys.0 % size == 0 && length >= size
+            })
+            .unwrap_or(0)
     }
 
     /// Establishes a new mapping in this page table.
@@ -180,6 +414,7 @@ impl PageTable {
         virt: VirtAddr,
         phys: PhysAddr,
         flags: VmFlags,
+        cache: VmCacheType,
     ) -> Result<(), PageTableError> {
         let pte = self.get_pte::<P>(virt, true)?;
 
@@ -187,6 +422,7 @@ impl PageTable {
             *pte = PageTableEntry::new(
                 phys,
                 flags.as_pte()
+                    | cache.as_pte()
                     | if self.is_user {
                         PteFlags::User
                     } else {
@@ -199,34 +435,111 @@ impl PageTable {
         return Ok(());
     }
 
+    /// Maps a large page on `level`. Both addresses have to be aligned to its size.
+    /// Smaller pages which were mapped in its range before are replaced.
+    pub fn map_large<P: PageAllocator>(
+        &self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        flags: VmFlags,
+        cache: VmCacheType,
+        level: usize,
+    ) -> Result<(), PageTableError> {
+        let (pte, _) = self.walk::<P>(virt, level, true)?;
+
+        let old = unsafe { pte.read_volatile() };
+        unsafe {
+            pte.write_volatile(PageTableEntry::new(
+                phys,
+                flags.as_pte()
+                    | cache.as_pte()
+                    | PteFlags::Large
+                    | if self.is_user {
+                        PteFlags::User
+                    } else {
+                        PteFlags::empty()
+                    },
+                level,
+            ))
+        };
+
+        if old.is_present() {
+            self.flush_range(virt, arch::virt::get_level_size(level));
+            // The old level can only be freed once no CPU can walk it anymore.
+            if old.is_directory(level) {
+                unsafe { Self::free_level::<P>(old.address(), level - 1) };
+            }
+        }
+
+        return Ok(());
+    }
+
     /// Changes the permissions on a mapping.
     pub fn remap_single<P: PageAllocator>(
         &self,
         virt: VirtAddr,
         flags: VmFlags,
     ) -> Result<(), PageTableError> {
-        let pte = self.get_pte::<P>(virt, false)?;
+        self.remap_pte::<P>(virt, flags, arch::virt::get_page_size())?;
+        self.flush_range(virt, arch::virt::get_page_size());
+        return Ok(());
+    }
 
-        unsafe { *pte = PageTableEntry::new((*pte).address(), flags.as_pte(), 0) };
-        crate::arch::virt::flush_tlb(virt);
+    /// Changes the permissions of the PTE at `virt` without flushing the TLB. The caching type
+    /// is kept. Pages which aren't mapped are left alone.
+    /// Returns the amount of bytes which the PTE covers, see [`Self::leaf_pte`].
+    fn remap_pte<P: PageAllocator>(
+        &self,
+        virt: VirtAddr,
+        flags: VmFlags,
+        length: usize,
+    ) -> Result<usize, PageTableError> {
+        let (pte, level) = self.leaf_pte::<P>(virt, length)?;
 
-        return Ok(());
+        unsafe {
+            if !(*pte).is_present() {
+                return Ok(arch::virt::get_level_size(level));
+            }
+
+            let old_flags = (*pte).flags(level);
+            *pte = PageTableEntry::new(
+                (*pte).address(),
+                flags.as_pte()
+                    | (old_flags & (CACHE_FLAGS | PteFlags::Large))
+                    | if self.is_user {
+                        PteFlags::User
+                    } else {
+                        PteFlags::empty()
+                    },
+                level,
+            )
+        };
+
+        return Ok(arch::virt::get_level_size(level));
     }
 
     /// Maps a range of consecutive memory in this page table.
+    /// Parts which are suitably aligned are mapped with large pages.
     pub fn map_range<P: PageAllocator>(
         &self,
         virt: VirtAddr,
         phys: PhysAddr,
         flags: VmFlags,
+        cache: VmCacheType,
         length: usize,
     ) -> Result<(), PageTableError> {
         // TODO: Do transactional mapping.
         let length = align_up(length, arch::virt::get_page_size());
-        let step = arch::virt::get_page_size();
 
-        for offset in (0..length).step_by(step) {
-            self.map_single::<P>(VirtAddr(virt.0 + offset), PhysAddr(phys.0 + offset), flags)?;
+        let mut offset = 0;
+        while offset < length {
+            let (virt, phys) = (VirtAddr(virt.0 + offset), PhysAddr(phys.0 + offset));
+            let level = self.leaf_level(virt, phys, length - offset);
+            match level {
+                0 => self.map_single::<P>(virt, phys, flags, cache)?,
+                _ => self.map_large::<P>(virt, phys, flags, cache, level)?,
+            }
+            offset += arch::virt::get_level_size(level);
         }
         return Ok(());
     }
@@ -242,19 +555,30 @@ impl PageTable {
         let length = align_up(length, arch::virt::get_page_size());
         let step = arch::virt::get_page_size();
 
-        for offset in (0..length).step_by(step) {
-            self.remap_single::<P>(VirtAddr(virt.0 + offset), flags)?;
+        let mut offset = 0;
+        while offset < length {
+            match self.remap_pte::<P>(VirtAddr(virt.0 + offset), flags, length - offset) {
+                Ok(size) => offset += size,
+                // Parts of the range might not have been touched yet.
+                Err(PageTableError::NeedAllocation) => offset += step,
+                Err(x) => {
+                    self.flush_range(virt, offset);
+                    return Err(x);
+                }
+            }
         }
+
+        self.flush_range(virt, length);
         return Ok(());
     }
 
     /// Un-maps a page from this page table.
     pub fn unmap_single<P: PageAllocator>(&self, virt: VirtAddr) -> Result<(), PageTableError> {
-        let pte = self.get_pte::<P>(virt, false)?;
+        let (pte, _) = self.leaf_pte::<P>(virt, arch::virt::get_page_size())?;
         unsafe {
             pte.write_volatile(PageTableEntry::empty());
         };
-        crate::arch::virt::flush_tlb(virt);
+        self.flush_range(virt, arch::virt::get_page_size());
         Ok(())
     }
 
@@ -267,12 +591,176 @@ impl PageTable {
         // TODO: Do transactional mapping.
         let length = align_up(length, arch::virt::get_page_size());
         let step = arch::virt::get_page_size();
-        for offset in (0..length).step_by(step) {
-            self.unmap_single::<P>(VirtAddr(virt.0 + offset))?;
+
+        let mut offset = 0;
+        while offset < length {
+            match self.leaf_pte::<P>(VirtAddr(virt.0 + offset), length - offset) {
+                Ok((pte, level)) => {
+                    unsafe { pte.write_volatile(PageTableEntry::empty()) };
+                    offset += arch::virt::get_level_size(level);
+                }
+                // Parts of the range might not have been touched yet.
+                Err(PageTableError::NeedAllocation) => offset += step,
+                Err(x) => {
+                    self.flush_range(virt, offset);
+                    return Err(x);
+                }
+            }
         }
+
+        self.flush_range(virt, length);
         return Ok(());
     }
 
+    /// Like [`Self::unmap_range`], but calls `dirty` with the address of every page which was
+    /// written to. This happens after the range was flushed, so the pages can't be written to
+    /// anymore.
+    pub fn unmap_range_dirty<P: PageAllocator>(
+        &self,
+        virt: VirtAddr,
+        length: usize,
+        mut dirty: impl FnMut(VirtAddr),
+    ) -> Result<(), PageTableError> {
+        let length = align_up(length, arch::virt::get_page_size());
+        let step = arch::virt::get_page_size();
+        let mut written = Vec::new();
+
+        let mut offset = 0;
+        let result = loop {
+            if offset >= length {
+                break Ok(());
+            }
+            match self.leaf_pte::<P>(VirtAddr(virt.0 + offset), length - offset) {
+                Ok((pte, level)) => {
+                    let old = unsafe { (*pte).take() };
+                    let size = arch::virt::get_level_size(level).min(length - offset);
+                    if old.is_present() && old.is_dirty() {
+                        written.push((offset, size));
+                    }
+                    offset += size;
+                }
+                // Parts of the range might not have been touched yet.
+                Err(PageTableError::NeedAllocation) => offset += step,
+                Err(x) => break Err(x),
+            }
+        };
+
+        self.flush_range(virt, offset.min(length));
+        for (start, size) in written {
+            for page in (start..start + size).step_by(step) {
+                dirty(VirtAddr(virt.0 + page));
+            }
+        }
+        result
+    }
+
+    /// Clears the dirty bits in a range and calls `dirty` with the address of every page which
+    /// was written to. Writes after this call set the bits again.
+    pub fn clean_range(&self, virt: VirtAddr, length: usize, mut dirty: impl FnMut(VirtAddr)) {
+        let length = align_up(length, arch::virt::get_page_size());
+        let step = arch::virt::get_page_size();
+        let mut found = false;
+
+        let mut offset = 0;
+        while offset < length {
+            let page = VirtAddr(virt.0 + offset);
+            // Parts of the range might not have been touched yet.
+            let Ok((pte, level)) = self.walk::<KernelAlloc>(page, 0, false) else {
+                offset += step;
+                continue;
+            };
+
+            // A large page has a single dirty bit for all pages in it.
+            let size = arch::virt::get_level_size(level);
+            let end = (align_down(page.0, size) + size).min(virt.0 + length);
+            if unsafe { (*pte).is_present() && (*pte).clear_dirty() } {
+                for addr in (page.0..end).step_by(step) {
+                    dirty(VirtAddr(addr));
+                }
+                found = true;
+            }
+            offset = end - virt.0;
+        }
+
+        // Other CPUs have to walk the page table again to mark the pages as dirty.
+        if found {
+            self.flush_range(virt, length);
+        }
+    }
+
+    /// Clears the accessed bit of a page and returns whether it was set.
+    /// Returns [`None`] if the page isn't mapped.
+    pub fn clear_accessed(&self, virt: VirtAddr) -> Option<bool> {
+        let pte = self.get_pte::<KernelAlloc>(virt, false).ok()?;
+        unsafe { (*pte).is_present().then(|| (*pte).clear_accessed()) }
+    }
+
+    /// Records in the PTE of an unmapped page that it was moved to swap space.
+    /// Pages which are mapped are left alone. Faults read the page back through the object which
+    /// owns it, since copies of the object share its swap slots. The PTE only lets page table
+    /// walks like [`Self::count_user_pages`] see the page.
+    pub fn set_swap_entry(&self, virt: VirtAddr, entry: usize) {
+        let Ok(pte) = self.get_pte::<KernelAlloc>(virt, false) else {
+            return;
+        };
+        unsafe {
+            if !(*pte).is_present() {
+                pte.write_volatile(PageTableEntry::new_swap(entry));
+            }
+        }
+    }
+
+    /// Returns the amount of user pages which are present and the amount of pages which were
+    /// moved to swap space.
+    pub fn count_user_pages(&self) -> (usize, usize) {
+        let head = self.head.lock();
+        // The upper half of the root level belongs to the kernel.
+        let entries = (1 << arch::virt::get_level_bits()) / 2;
+        unsafe { Self::count_level(head.as_hhdm(), self.root_level - 1, entries) }
+    }
+
+    /// Counts the pages below the first `entries` PTEs of a page level.
+    unsafe fn count_level(
+        level_head: *const PageTableEntry,
+        level: usize,
+        entries: usize,
+    ) -> (usize, usize) {
+        let mut present = 0;
+        let mut swapped = 0;
+        for index in 0..entries {
+            let pte = unsafe { level_head.add(index).read_volatile() };
+            if !pte.is_present() {
+                swapped += pte.swap_entry().is_some() as usize;
+            } else if level > 0 && pte.is_directory(level) {
+                let (p, s) = unsafe {
+                    Self::count_level(
+                        pte.address().as_hhdm(),
+                        level - 1,
+                        1 << arch::virt::get_level_bits(),
+                    )
+                };
+                present += p;
+                swapped += s;
+            } else {
+                // A large page covers all pages of the levels below it.
+                present += 1 << (arch::virt::get_level_bits() * level);
+            }
+        }
+        (present, swapped)
+    }
+
+    /// Translates a virtual address to the physical address it is mapped to.
+    pub fn translate(&self, virt: VirtAddr) -> Option<PhysAddr> {
+        let (pte, level) = self.walk::<KernelAlloc>(virt, 0, false).ok()?;
+        let pte = unsafe { pte.read_volatile() };
+        if !pte.is_present() {
+            return None;
+        }
+
+        let size = arch::virt::get_level_size(level);
+        Some(PhysAddr(pte.address().0 + virt.0 % size))
+    }
+
     /// Checks if the address (may be unaligned) is mapped in this page table.
     pub fn is_mapped(&self, virt: VirtAddr) -> bool {
         self.get_pte::<KernelAlloc>(virt, false)
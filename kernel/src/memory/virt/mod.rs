@@ -1,29 +1,40 @@
+pub mod aslr;
 pub mod fault;
 pub mod mmu;
+pub mod stack;
+pub mod vmalloc;
 
 use super::{VirtAddr, pmm::AllocFlags};
 use crate::{
     arch::{self},
-    memory::{cache::MemoryObject, pmm::KernelAlloc, virt::mmu::PageTable},
+    memory::{
+        cache::MemoryObject,
+        pmm::KernelAlloc,
+        swap::{self, Eviction, SwapEntry},
+        virt::mmu::PageTable,
+    },
     posix::errno::{EResult, Errno},
+    process::Process,
     uapi,
-    util::{divide_up, once::Once},
+    util::{divide_up, mutex::spin::SpinMutex, once::Once},
 };
 use alloc::{collections::btree_set::BTreeSet, sync::Arc, vec::Vec};
 use bitflags::bitflags;
 use core::{
     fmt::Debug,
+    mem,
     num::NonZeroUsize,
-    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    ptr,
+    sync::atomic::{AtomicU8, Ordering},
 };
 
-// TODO: Kernel stacks should be mapped, not just on the HHDM. Otherwise we can't check for overflows.
+/// Size of a kernel stack, see [`stack::KernelStack`].
 pub const KERNEL_STACK_SIZE: usize = 0x8000;
 
 bitflags! {
     /// PTE protection flags.
     #[derive(Debug, Copy, Clone)]
-    pub struct PteFlags: u8 {
+    pub struct PteFlags: u16 {
         /// Page can be read from.
         const Read = 1 << 0;
         /// Page can be written to.
@@ -36,6 +47,12 @@ bitflags! {
         const Large = 1 << 4;
         /// Page is a directory to the next level.
         const Directory = 1 << 5;
+        /// Page uses [`VmCacheType::WriteCombining`].
+        const WriteCombining = 1 << 6;
+        /// Page uses [`VmCacheType::Uncached`].
+        const Uncached = 1 << 7;
+        /// Page uses [`VmCacheType::WriteThrough`].
+        const WriteThrough = 1 << 8;
     }
 
     /// Page protection flags.
@@ -51,10 +68,21 @@ bitflags! {
         const Shared = 1 << 3;
         /// This page is to be copied on write.
         const CopyOnWrite = 1 << 4;
+        /// The mapping may be writable and executable at the same time.
+        const WriteExec = 1 << 5;
+        /// The pages of the mapping stay in memory, see [`AddressSpace::lock`].
+        const Locked = 1 << 6;
     }
 }
 
 impl VmFlags {
+    /// Returns false if the flags make a page writable and executable without allowing it.
+    /// Such a page would let an attacker inject code with a single write.
+    fn is_write_xor_exec(self) -> bool {
+        !self.contains(VmFlags::Write | VmFlags::Exec) || self.contains(VmFlags::WriteExec)
+    }
+
+    /// Pages without [`VmFlags::Exec`] are mapped as non-executable.
     fn as_pte(self) -> PteFlags {
         let mut result = PteFlags::empty();
         if self.contains(VmFlags::Read) {
@@ -70,8 +98,46 @@ impl VmFlags {
     }
 }
 
+/// How a range of memory is going to be used, see [`AddressSpace::advise`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAdvice {
+    /// The range will be accessed soon, so its pages should be fetched ahead of time.
+    WillNeed,
+    /// The range won't be accessed soon. Private pages are dropped and read back as zeroes.
+    DontNeed,
+    /// The contents of the range don't matter anymore. Only allowed on private memory.
+    Free,
+}
+
 /// Page caching types.
-pub enum VmCacheType {}
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VmCacheType {
+    /// Accesses are fully cached. This is the default for regular memory.
+    #[default]
+    WriteBack,
+    /// Reads are not cached, writes are collected and sent out in bursts. Used for framebuffers.
+    WriteCombining,
+    /// Every access goes straight to the device, in order. Used for MMIO registers.
+    Uncached,
+    /// Reads are cached, but writes go to memory immediately.
+    WriteThrough,
+}
+
+impl VmCacheType {
+    fn as_pte(self) -> PteFlags {
+        // Without support from the MMU, everything is mapped with the default type.
+        if !arch::virt::supports_cache_types() {
+            return PteFlags::empty();
+        }
+
+        match self {
+            VmCacheType::WriteBack => PteFlags::empty(),
+            VmCacheType::WriteCombining => PteFlags::WriteCombining,
+            VmCacheType::Uncached => PteFlags::Uncached,
+            VmCacheType::WriteThrough => PteFlags::WriteThrough,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum PageTableError {
@@ -82,13 +148,18 @@ pub enum PageTableError {
 
 pub(crate) static KERNEL_PAGE_TABLE: Once<Arc<PageTable>> = Once::new();
 
-// TODO: Replace with allocator.
-pub static KERNEL_MMAP_BASE_ADDR: AtomicUsize = AtomicUsize::new(0);
-
 pub struct AddressSpace {
     pub table: Arc<PageTable>,
     /// A map that translates global page offsets (virt / page_size) to a physical page and the flags of the mapping.
     pub mappings: BTreeSet<MappedObject>,
+    /// If set, new mappings are locked as soon as they're created, see [`AddressSpace::lock_all`].
+    pub lock_new: bool,
+    /// Amount of ranges which are being faulted in to be locked. The swap reclaimer leaves the
+    /// address space alone meanwhile, see [`AddressSpace::lock`].
+    pub populating: usize,
+    /// Object ranges of removed shared mappings which still have to be written back, see
+    /// [`AddressSpace::write_back_removed`].
+    pub writeback: Vec<(Arc<dyn MemoryObject>, usize, usize)>,
 }
 
 /// Represents a mapped object.
@@ -101,6 +172,8 @@ pub struct MappedObject {
     pub offset_page: usize,
     /// The mapped object.
     pub object: Arc<dyn MemoryObject>,
+    /// How the pages of the mapping are cached.
+    pub cache: VmCacheType,
     /// A [`VmFlags`] object, but stored as an atomic value.
     flags: AtomicU8,
 }
@@ -122,6 +195,7 @@ impl Clone for MappedObject {
             end_page: self.end_page,
             offset_page: self.offset_page,
             object: self.object.clone(),
+            cache: self.cache,
             flags: AtomicU8::new(self.flags.load(Ordering::SeqCst)),
         }
     }
@@ -156,6 +230,9 @@ impl AddressSpace {
         Self {
             table: Arc::new(PageTable::new_user::<KernelAlloc>(AllocFlags::empty())),
             mappings: BTreeSet::new(),
+            lock_new: false,
+            populating: 0,
+            writeback: Vec::new(),
         }
     }
 
@@ -166,6 +243,7 @@ impl AddressSpace {
         addr: VirtAddr,
         len: NonZeroUsize,
         prot: VmFlags,
+        cache: VmCacheType,
         offset: uapi::off_t,
     ) -> EResult<()> {
         // `addr + len` may not overflow if the mapping is fixed.
@@ -178,9 +256,341 @@ impl AddressSpace {
             return Err(Errno::EINVAL);
         }
 
+        if !prot.is_write_xor_exec() {
+            return Err(Errno::EACCES);
+        }
+
         let start_page = addr.value() / page_size;
         let end_page = start_page + divide_up(len.into(), page_size);
 
+        // The new mapping shadows everything in its range.
+        self.remove_range(start_page, end_page);
+
+        self.mappings.insert(MappedObject {
+            start_page,
+            end_page,
+            offset_page: offset as usize / page_size,
+            object: object.clone(),
+            cache,
+            flags: AtomicU8::new(prot.bits()),
+        });
+
+        Ok(())
+    }
+
+    pub fn protect(&mut self, addr: VirtAddr, len: NonZeroUsize, prot: VmFlags) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        if !self.is_mapped(addr, len.get()) {
+            return Err(Errno::ENOMEM);
+        }
+
+        // Sharing, copy on write, permission for W+X and locking are properties of the mapping,
+        // not of its protection.
+        let kept = VmFlags::Shared | VmFlags::CopyOnWrite | VmFlags::WriteExec | VmFlags::Locked;
+        let prot = prot & !kept;
+
+        // Nothing may change if any of the mappings can't take the new protection.
+        if self
+            .mappings
+            .iter()
+            .filter(|mapping| start_page < mapping.end_page && mapping.start_page < end_page)
+            .any(|mapping| !(prot | (mapping.get_flags() & kept)).is_write_xor_exec())
+        {
+            return Err(Errno::EACCES);
+        }
+
+        let page_size = arch::virt::get_page_size();
+        self.split_at(start_page);
+        self.split_at(end_page);
+
+        for mapping in self
+            .mappings
+            .iter()
+            .filter(|mapping| start_page < mapping.end_page && mapping.start_page < end_page)
+        {
+            let flags = prot | (mapping.get_flags() & kept);
+            mapping.set_flags(flags);
+
+            let pte_flags = match flags.contains(VmFlags::CopyOnWrite) {
+                true => flags & !VmFlags::Write,
+                false => flags,
+            };
+            // Remapping resets the dirty bits, so they have to be saved first.
+            self.collect_dirty(mapping, mapping.start_page, mapping.end_page);
+            self.table
+                .remap_range::<KernelAlloc>(
+                    (mapping.start_page * page_size).into(),
+                    pte_flags,
+                    (mapping.end_page - mapping.start_page) * page_size,
+                )
+                .map_err(|_| Errno::ENOMEM)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the amount of pages in locked mappings.
+    pub fn locked_pages(&self) -> usize {
+        self.mappings
+            .iter()
+            .filter(|x| x.get_flags().contains(VmFlags::Locked))
+            .map(|x| x.end_page - x.start_page)
+            .sum()
+    }
+
+    /// Faults in all pages in a range and keeps them in memory, so they are never moved to swap
+    /// space. Fails with [`Errno::ENOMEM`] if more than `limit` pages would be locked afterwards.
+    /// The pages are faulted in one by one, so `space` must not be locked by the caller.
+    pub fn lock(
+        space: &SpinMutex<Self>,
+        addr: VirtAddr,
+        len: NonZeroUsize,
+        limit: Option<usize>,
+    ) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        if !space.lock().is_mapped(addr, len.get()) {
+            return Err(Errno::ENOMEM);
+        }
+        Self::lock_pages(space, start_page, end_page, limit)
+    }
+
+    /// Faults in all pages in a range, as if each of them was accessed.
+    /// Like with [`Self::lock`], `space` must not be locked by the caller.
+    pub fn populate(space: &SpinMutex<Self>, addr: VirtAddr, len: NonZeroUsize) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        fault::populate(space, start_page, end_page)
+    }
+
+    /// Keeps all pages in a range in memory once they were accessed, without faulting them in.
+    /// This can't fail, so the caller has to check the limit beforehand.
+    pub fn set_locked_range(&mut self, addr: VirtAddr, len: NonZeroUsize) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        self.set_locked(start_page, end_page, true);
+        Ok(())
+    }
+
+    /// Allows the pages in a range to be moved to swap space again.
+    pub fn unlock(&mut self, addr: VirtAddr, len: NonZeroUsize) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        if !self.is_mapped(addr, len.get()) {
+            return Err(Errno::ENOMEM);
+        }
+        self.set_locked(start_page, end_page, false);
+        Ok(())
+    }
+
+    /// Locks all current mappings if `current` is set, and all mappings created later on if
+    /// `future` is set. See [`Self::lock`].
+    pub fn lock_all(
+        space: &SpinMutex<Self>,
+        current: bool,
+        future: bool,
+        limit: Option<usize>,
+    ) -> EResult<()> {
+        if current {
+            Self::lock_pages(space, 0, usize::MAX, limit)?;
+        }
+        space.lock().lock_new = future;
+        Ok(())
+    }
+
+    /// Unlocks all mappings, including the ones created later on.
+    pub fn unlock_all(&mut self) {
+        self.set_locked(0, usize::MAX, false);
+        self.lock_new = false;
+    }
+
+    fn lock_pages(
+        space: &SpinMutex<Self>,
+        start_page: usize,
+        end_page: usize,
+        limit: Option<usize>,
+    ) -> EResult<()> {
+        {
+            let mut space = space.lock();
+            space.check_lock_limit(start_page, end_page, limit)?;
+            space.populating += 1;
+        }
+
+        // The reclaimer can't swap the pages out between faulting them in and locking them.
+        let result = fault::populate(space, start_page, end_page);
+
+        let mut space = space.lock();
+        space.populating -= 1;
+        result?;
+
+        // Other threads might have locked pages in the meantime.
+        space.check_lock_limit(start_page, end_page, limit)?;
+        space.set_locked(start_page, end_page, true);
+        Ok(())
+    }
+
+    /// Fails with [`Errno::ENOMEM`] if more than `limit` pages would be locked after locking
+    /// all mappings between two pages.
+    fn check_lock_limit(
+        &self,
+        start_page: usize,
+        end_page: usize,
+        limit: Option<usize>,
+    ) -> EResult<()> {
+        let new_pages: usize = self
+            .mappings
+            .iter()
+            .filter(|x| {
+                start_page < x.end_page
+                    && x.start_page < end_page
+                    && !x.get_flags().contains(VmFlags::Locked)
+            })
+            .map(|x| end_page.min(x.end_page) - start_page.max(x.start_page))
+            .sum();
+        match limit.is_some_and(|x| self.locked_pages() + new_pages > x) {
+            true => Err(Errno::ENOMEM),
+            false => Ok(()),
+        }
+    }
+
+    /// Sets or clears [`VmFlags::Locked`] on all mappings between two pages.
+    fn set_locked(&mut self, start_page: usize, end_page: usize, locked: bool) {
+        self.split_at(start_page);
+        self.split_at(end_page);
+        for mapping in self
+            .mappings
+            .iter()
+            .filter(|x| start_page < x.end_page && x.start_page < end_page)
+        {
+            let mut flags = mapping.get_flags();
+            flags.set(VmFlags::Locked, locked);
+            mapping.set_flags(flags);
+        }
+    }
+
+    /// Removes all mappings in a range. Pages which nothing refers to anymore are freed.
+    /// Modified pages of shared mappings are written back by [`Self::write_back_removed`].
+    pub fn unmap(&mut self, addr: VirtAddr, len: NonZeroUsize) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        self.remove_range(start_page, end_page);
+        Ok(())
+    }
+
+    /// Changes the size of the mapping at `addr` from `old_len` to `new_len` bytes without moving it.
+    /// Fails with [`Errno::ENOMEM`] if the pages after the mapping are already in use.
+    pub fn resize(
+        &mut self,
+        addr: VirtAddr,
+        old_len: NonZeroUsize,
+        new_len: NonZeroUsize,
+    ) -> EResult<()> {
+        let (start_page, old_end) = Self::page_range(addr, old_len)?;
+        let (_, new_end) = Self::page_range(addr, new_len)?;
+        let mapping = self.containing(start_page, old_end)?;
+
+        if new_end <= old_end {
+            self.remove_range(new_end, old_end);
+            return Ok(());
+        }
+
+        // Growing only works at the end of a mapping, and only into free pages.
+        if mapping.end_page != old_end
+            || self
+                .mappings
+                .iter()
+                .any(|x| old_end < x.end_page && x.start_page < new_end)
+        {
+            return Err(Errno::ENOMEM);
+        }
+
+        self.mappings.remove(&mapping);
+        self.mappings.insert(MappedObject {
+            end_page: new_end,
+            ..mapping
+        });
+
+        Ok(())
+    }
+
+    /// Moves the mapping at `addr` with a size of `old_len` bytes to `target` and resizes it to
+    /// `new_len` bytes. The pages are not copied, they stay in the mapped object.
+    /// Anything which was mapped at `target` before gets unmapped.
+    pub fn move_mapping(
+        &mut self,
+        addr: VirtAddr,
+        old_len: NonZeroUsize,
+        target: VirtAddr,
+        new_len: NonZeroUsize,
+    ) -> EResult<()> {
+        let (start_page, old_end) = Self::page_range(addr, old_len)?;
+        let (target_start, target_end) = Self::page_range(target, new_len)?;
+        if target_start < old_end && start_page < target_end {
+            return Err(Errno::EINVAL);
+        }
+        self.containing(start_page, old_end)?;
+
+        // Cut out the part that is being moved.
+        self.split_at(start_page);
+        self.split_at(old_end);
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|x| x.start_page == start_page)
+            .cloned()
+            .ok_or(Errno::EFAULT)?;
+        self.mappings.remove(&mapping);
+
+        // Unmapping loses the dirty bits, but the pages stay in the object.
+        self.collect_dirty(&mapping, start_page, old_end);
+        let page_size = arch::virt::get_page_size();
+        _ = self.table.unmap_range::<KernelAlloc>(
+            (start_page * page_size).into(),
+            (old_end - start_page) * page_size,
+        );
+
+        self.remove_range(target_start, target_end);
+        let moved = MappedObject {
+            start_page: target_start,
+            end_page: target_end,
+            ..mapping
+        };
+        self.mappings.insert(moved.clone());
+
+        // Pages which were cut off by shrinking can't be reached anymore.
+        let new_pages = target_end - target_start;
+        let old_pages = old_end - start_page;
+        if new_pages < old_pages {
+            self.release(
+                &moved,
+                moved.offset_page + new_pages,
+                old_pages - new_pages,
+                1,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns the mapping which contains all pages between `start_page` and `end_page`.
+    fn containing(&self, start_page: usize, end_page: usize) -> EResult<MappedObject> {
+        self.mappings
+            .iter()
+            .find(|x| x.start_page <= start_page && end_page <= x.end_page)
+            .cloned()
+            .ok_or(Errno::EFAULT)
+    }
+
+    /// Tells the kernel how a range of memory is going to be used.
+    /// Shared mappings dropped by [`MemoryAdvice::DontNeed`] are written back by
+    /// [`Self::write_back_removed`].
+    pub fn advise(
+        &mut self,
+        addr: VirtAddr,
+        len: NonZeroUsize,
+        advice: MemoryAdvice,
+    ) -> EResult<()> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        if !self.is_mapped(addr, len.get()) {
+            return Err(Errno::ENOMEM);
+        }
+
+        let page_size = arch::virt::get_page_size();
         let overlapping = self
             .mappings
             .iter()
@@ -188,213 +598,496 @@ impl AddressSpace {
             .cloned()
             .collect::<Vec<_>>();
 
-        // Split any mappings that got shadowed.
-        for mapping in overlapping.iter() {
-            self.mappings.remove(mapping);
-            // If new mapping completely shadows the old mapping.
-            if start_page <= mapping.start_page && end_page >= mapping.end_page {
-                for p in mapping.start_page..mapping.end_page {
-                    let page_addr = (p * page_size).into();
-                    _ = self.table.unmap_single::<KernelAlloc>(page_addr);
+        match advice {
+            MemoryAdvice::WillNeed => {
+                for mapping in overlapping.iter() {
+                    for page in start_page.max(mapping.start_page)..end_page.min(mapping.end_page) {
+                        _ = mapping
+                            .object
+                            .try_get_page(page - mapping.start_page + mapping.offset_page);
+                    }
                 }
             }
-            // If new mapping partially shadows the old mapping.
-            else {
-                for p in start_page.max(mapping.start_page)..end_page.min(mapping.end_page) {
-                    let page_addr = (p * page_size).into();
-                    _ = self.table.unmap_single::<KernelAlloc>(page_addr);
+            MemoryAdvice::DontNeed | MemoryAdvice::Free => {
+                // Locked pages have to stay.
+                if overlapping
+                    .iter()
+                    .any(|x| x.get_flags().contains(VmFlags::Locked))
+                {
+                    return Err(Errno::EINVAL);
                 }
 
-                let head_pages = start_page.saturating_sub(mapping.start_page);
+                // Only private memory can be freed, shared memory still has to be readable by others.
+                if advice == MemoryAdvice::Free
+                    && overlapping
+                        .iter()
+                        .any(|x| x.get_flags().contains(VmFlags::Shared))
+                {
+                    return Err(Errno::EINVAL);
+                }
 
-                let tail_pages = mapping.end_page.saturating_sub(end_page);
+                // Writes to shared mappings must not get lost with their page table entries.
+                for mapping in overlapping.iter() {
+                    let first = start_page.max(mapping.start_page);
+                    let last = end_page.min(mapping.end_page);
+                    let start = (first * page_size).into();
+                    let len = (last - first) * page_size;
+                    if !mapping.get_flags().contains(VmFlags::Shared) {
+                        self.table
+                            .unmap_range::<KernelAlloc>(start, len)
+                            .map_err(|_| Errno::ENOMEM)?;
+                        continue;
+                    }
 
-                // Insert the leftmost pages.
-                if head_pages > 0 {
-                    self.mappings.insert(MappedObject {
-                        start_page: mapping.start_page,
-                        end_page: mapping.start_page + head_pages,
-                        offset_page: mapping.offset_page,
-                        object: mapping.object.clone(),
-                        flags: AtomicU8::new(mapping.flags.load(Ordering::SeqCst)),
-                    });
+                    self.table
+                        .unmap_range_dirty::<KernelAlloc>(start, len, |addr| {
+                            mapping.object.mark_dirty(
+                                addr.value() / page_size - mapping.start_page + mapping.offset_page,
+                            )
+                        })
+                        .map_err(|_| Errno::ENOMEM)?;
+                    self.writeback.push((
+                        mapping.object.clone(),
+                        first - mapping.start_page + mapping.offset_page,
+                        last - first,
+                    ));
                 }
 
-                // Insert the rightmost pages.
-                if tail_pages > 0 {
-                    self.mappings.insert(MappedObject {
-                        start_page: mapping.end_page - tail_pages,
-                        end_page: mapping.end_page,
-                        offset_page: mapping.offset_page + head_pages + (end_page - start_page),
-                        object: mapping.object.clone(),
-                        flags: AtomicU8::new(mapping.flags.load(Ordering::SeqCst)),
-                    });
+                // The pages fault in again on the next access, zero-filled for private memory.
+                for mapping in overlapping.iter() {
+                    let first = start_page.max(mapping.start_page);
+                    let last = end_page.min(mapping.end_page);
+                    self.release(
+                        mapping,
+                        first - mapping.start_page + mapping.offset_page,
+                        last - first,
+                        overlapping
+                            .iter()
+                            .filter(|x| Arc::ptr_eq(&x.object, &mapping.object))
+                            .count(),
+                    );
                 }
             }
         }
 
-        self.mappings.insert(MappedObject {
-            start_page,
-            end_page,
-            offset_page: offset as usize / page_size,
-            object: object.clone(),
-            flags: AtomicU8::new(prot.bits()),
-        });
-
         Ok(())
     }
 
-    pub fn protect(&mut self, addr: VirtAddr, len: NonZeroUsize, prot: VmFlags) -> EResult<()> {
-        // `addr + len` may not overflow if the mapping is fixed.
-        if addr.value().checked_add(len.into()).is_none() {
+    /// Moves the dirty bits of all shared mappings in a range to their objects.
+    /// Returns the object ranges which were affected, so the caller can write them back with
+    /// [`MemoryObject::sync`] once it doesn't hold the address space anymore.
+    pub fn collect_dirty_range(
+        &self,
+        addr: VirtAddr,
+        len: NonZeroUsize,
+    ) -> EResult<Vec<(Arc<dyn MemoryObject>, usize, usize)>> {
+        let (start_page, end_page) = Self::page_range(addr, len)?;
+        if !self.is_mapped(addr, len.get()) {
             return Err(Errno::ENOMEM);
         }
 
+        let mut result = Vec::new();
+        for mapping in self.mappings.iter().filter(|mapping| {
+            start_page < mapping.end_page
+                && mapping.start_page < end_page
+                && mapping.get_flags().contains(VmFlags::Shared)
+        }) {
+            let first = start_page.max(mapping.start_page);
+            let last = end_page.min(mapping.end_page);
+            self.collect_dirty(mapping, first, last);
+            result.push((
+                mapping.object.clone(),
+                first - mapping.start_page + mapping.offset_page,
+                last - first,
+            ));
+        }
+
+        Ok(result)
+    }
+
+    /// Moves the dirty bits of the shared mappings of `object` in every address space to the
+    /// object, so the next [`MemoryObject::sync`] sees changes made through any of them.
+    /// This locks every address space, so the caller must not hold one.
+    pub fn collect_dirty_everywhere(object: &dyn MemoryObject) {
+        Process::for_each(|proc| proc.address_space.lock().collect_dirty_of(object));
+    }
+
+    /// Moves the dirty bits of all shared mappings of `object` to the object.
+    pub fn collect_dirty_of(&self, object: &dyn MemoryObject) {
+        for mapping in self.mappings.iter().filter(|mapping| {
+            ptr::addr_eq(Arc::as_ptr(&mapping.object), object)
+                && mapping.get_flags().contains(VmFlags::Shared)
+        }) {
+            self.collect_dirty(mapping, mapping.start_page, mapping.end_page);
+        }
+    }
+
+    /// Marks all pages of a shared mapping between two pages as dirty in its object, if they
+    /// were written to through this address space.
+    fn collect_dirty(&self, mapping: &MappedObject, start_page: usize, end_page: usize) {
+        if !mapping.get_flags().contains(VmFlags::Shared) {
+            return;
+        }
+
+        let first = start_page.max(mapping.start_page);
+        let last = end_page.min(mapping.end_page);
+        if first >= last {
+            return;
+        }
+
+        let page_size = arch::virt::get_page_size();
+        self.table.clean_range(
+            (first * page_size).into(),
+            (last - first) * page_size,
+            |addr| {
+                mapping
+                    .object
+                    .mark_dirty(addr.value() / page_size - mapping.start_page + mapping.offset_page)
+            },
+        );
+    }
+
+    /// Converts a user supplied range to page numbers. The start has to be page aligned.
+    fn page_range(addr: VirtAddr, len: NonZeroUsize) -> EResult<(usize, usize)> {
         let page_size = arch::virt::get_page_size();
         if !addr.value().is_multiple_of(page_size) {
             return Err(Errno::EINVAL);
         }
 
-        let start_page = addr.value() / page_size;
-        let end_page = start_page + divide_up(len.into(), page_size);
+        // `addr + len` may not overflow if the mapping is fixed.
+        let end = addr.value().checked_add(len.get()).ok_or(Errno::ENOMEM)?;
+        Ok((addr.value() / page_size, divide_up(end, page_size)))
+    }
 
-        let overlapping = self
+    /// Splits the mapping which contains `page`, so that no mapping crosses it.
+    fn split_at(&mut self, page: usize) {
+        let Some(mapping) = self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.start_page < page && page < mapping.end_page)
+            .cloned()
+        else {
+            return;
+        };
+
+        self.mappings.remove(&mapping);
+        self.mappings.insert(MappedObject {
+            end_page: page,
+            ..mapping.clone()
+        });
+        self.mappings.insert(MappedObject {
+            start_page: page,
+            offset_page: mapping.offset_page + (page - mapping.start_page),
+            ..mapping
+        });
+    }
+
+    /// Removes all mappings between two pages and frees the pages which nothing refers to anymore.
+    /// Shared mappings are written back by [`Self::write_back_removed`].
+    fn remove_range(&mut self, start_page: usize, end_page: usize) {
+        let page_size = arch::virt::get_page_size();
+        self.split_at(start_page);
+        self.split_at(end_page);
+
+        let removed = self
             .mappings
             .iter()
             .filter(|mapping| start_page < mapping.end_page && mapping.start_page < end_page)
             .cloned()
             .collect::<Vec<_>>();
+        for mapping in removed.iter() {
+            self.mappings.remove(mapping);
+        }
 
-        // Split any mappings that got shadowed.
-        for mapping in overlapping {
-            // If new mapping completely shadows the old mapping.
-            if start_page <= mapping.start_page && end_page >= mapping.end_page {
-                self.mappings.remove(&mapping);
-                self.mappings.insert(MappedObject {
-                    flags: AtomicU8::new(prot.bits()),
-                    ..mapping
-                });
-                self.table
-                    .remap_range::<KernelAlloc>(
-                        (mapping.start_page * page_size).into(),
-                        prot,
-                        (mapping.end_page - mapping.start_page) * page_size,
-                    )
-                    .map_err(|_| Errno::ENOMEM)?;
+        // This also makes sure that no CPU can still access the pages before they're freed.
+        // Only then it's known which pages of shared mappings were written to.
+        for mapping in removed.iter() {
+            let start = (mapping.start_page * page_size).into();
+            let len = (mapping.end_page - mapping.start_page) * page_size;
+            if !mapping.get_flags().contains(VmFlags::Shared) {
+                _ = self.table.unmap_range::<KernelAlloc>(start, len);
+                continue;
             }
-            // If new mapping partially shadows the old mapping.
-            else {
-                // TODO
-                self.mappings.remove(&mapping);
-                self.table
-                    .unmap_range::<KernelAlloc>(
-                        (start_page.max(mapping.start_page) * page_size).into(),
-                        (end_page.min(mapping.end_page) - start_page) * page_size,
+
+            _ = self
+                .table
+                .unmap_range_dirty::<KernelAlloc>(start, len, |addr| {
+                    mapping.object.mark_dirty(
+                        addr.value() / page_size - mapping.start_page + mapping.offset_page,
                     )
-                    .map_err(|_| Errno::ENOMEM)?;
+                });
+            self.writeback.push((
+                mapping.object.clone(),
+                mapping.offset_page,
+                mapping.end_page - mapping.start_page,
+            ));
+        }
 
-                let head_pages = start_page.saturating_sub(mapping.start_page);
+        for mapping in removed.iter() {
+            self.release(
+                mapping,
+                mapping.offset_page,
+                mapping.end_page - mapping.start_page,
+                removed
+                    .iter()
+                    .filter(|x| Arc::ptr_eq(&x.object, &mapping.object))
+                    .count(),
+            );
+        }
+    }
 
-                let tail_pages = mapping.end_page.saturating_sub(end_page);
+    /// Frees a range of pages of a private mapping if nothing else can reach them.
+    /// `extra` is the amount of references to the object which are held by the caller.
+    fn release(&self, mapping: &MappedObject, page_index: usize, num_pages: usize, extra: usize) {
+        if mapping.get_flags().contains(VmFlags::Shared) {
+            return;
+        }
+
+        let references = self
+            .mappings
+            .iter()
+            .filter(|x| Arc::ptr_eq(&x.object, &mapping.object))
+            .count();
+        if Arc::strong_count(&mapping.object) == references + extra {
+            mapping.object.discard(page_index, num_pages);
+        }
+    }
+
+    /// Advances the clock hand of the swap reclaimer over the private mappings of this address
+    /// space, starting at `start_page`. Pages which were accessed since the hand last passed them
+    /// get another chance, up to `target` of the others are unmapped to be moved to swap space.
+    /// The caller writes them out without holding the address space and completes each with
+    /// [`Self::finish_eviction`]. Also returns the page to continue at next time, or [`None`] if
+    /// the hand went past the last mapping.
+    pub fn reclaim(&self, start_page: usize, target: usize) -> (Vec<Eviction>, Option<usize>) {
+        let page_size = arch::virt::get_page_size();
+        let mut result = Vec::new();
+
+        // Pages which are about to be locked have to stay.
+        if self.populating != 0 {
+            return (result, None);
+        }
+
+        for mapping in self.mappings.iter().filter(|x| {
+            start_page < x.end_page && !x.get_flags().intersects(VmFlags::Shared | VmFlags::Locked)
+        }) {
+            // Objects which are referenced from elsewhere might be mapped in other page tables.
+            let references = self
+                .mappings
+                .iter()
+                .filter(|x| Arc::ptr_eq(&x.object, &mapping.object))
+                .count();
+            if Arc::strong_count(&mapping.object) != references {
+                continue;
+            }
 
-                // Insert the leftmost pages.
-                if head_pages > 0 {
-                    self.mappings.insert(MappedObject {
-                        start_page: mapping.start_page,
-                        end_page: mapping.start_page + head_pages,
-                        offset_page: mapping.offset_page,
-                        object: mapping.object.clone(),
-                        flags: AtomicU8::new(mapping.flags.load(Ordering::SeqCst)),
-                    });
+            for page in start_page.max(mapping.start_page)..mapping.end_page {
+                if result.len() == target {
+                    return (result, Some(page));
                 }
 
-                // Insert the rightmost pages.
-                if tail_pages > 0 {
-                    self.mappings.insert(MappedObject {
-                        start_page: mapping.end_page - tail_pages,
-                        end_page: mapping.end_page,
-                        offset_page: mapping.offset_page + head_pages + (end_page - start_page),
-                        object: mapping.object.clone(),
-                        flags: AtomicU8::new(mapping.flags.load(Ordering::SeqCst)),
-                    });
+                let addr = VirtAddr::from(page * page_size);
+                if self.table.clear_accessed(addr) != Some(false) {
+                    continue;
                 }
 
-                // Insert the new mapping.
-                self.mappings.insert(MappedObject {
-                    flags: AtomicU8::new(prot.bits()),
-                    ..mapping
+                let page_index = page - mapping.start_page + mapping.offset_page;
+                let Some(phys) = mapping.object.prepare_swap_out(page_index) else {
+                    continue;
+                };
+
+                // Nobody may write to the page while it's being written to swap space.
+                // Large pages are split up for this, which can fail.
+                if self.table.unmap_single::<KernelAlloc>(addr).is_err() {
+                    mapping.object.finish_swap_out(page_index, phys, None);
+                    continue;
+                }
+                result.push(Eviction {
+                    object: mapping.object.clone(),
+                    page_index,
+                    addr,
+                    page: phys,
                 });
             }
         }
 
-        Ok(())
+        (result, None)
     }
 
-    pub fn unmap(&self, addr: VirtAddr, len: NonZeroUsize) -> EResult<()> {
-        let _ = (len, addr);
-        // TODO
-        warn!("AddressSpace::unmap() is a stub!");
-        Ok(())
+    /// Completes an eviction started by [`Self::reclaim`]. `entry` is where the page was written
+    /// to, or [`None`] if that failed. Returns true if the page was freed. If the page was faulted
+    /// in again or the mapping changed in the meantime, the page stays and `entry` is released.
+    pub fn finish_eviction(&self, eviction: Eviction, entry: Option<SwapEntry>) -> bool {
+        let page_size = arch::virt::get_page_size();
+        let page = eviction.addr.value() / page_size;
+        let unchanged = !self.table.is_mapped(eviction.addr)
+            && self.mappings.iter().any(|x| {
+                Arc::ptr_eq(&x.object, &eviction.object)
+                    && (x.start_page..x.end_page).contains(&page)
+                    && page - x.start_page + x.offset_page == eviction.page_index
+            });
+
+        let entry = entry.filter(|_| unchanged);
+        if !eviction
+            .object
+            .finish_swap_out(eviction.page_index, eviction.page, entry)
+        {
+            if let Some(entry) = entry {
+                entry.drop_ref();
+            }
+            return false;
+        }
+
+        let entry = entry.unwrap();
+        self.table.set_swap_entry(eviction.addr, entry.value());
+        swap::track(&eviction.object);
+        true
     }
 
     /// Checks if the entire range is mapped in this address space.
     pub fn is_mapped(&self, addr: VirtAddr, len: usize) -> bool {
+        self.is_accessible(addr, len, VmFlags::empty())
+    }
+
+    /// Checks if the entire range is mapped in this address space and every page in it allows
+    /// accesses with the given `flags`.
+    pub fn is_accessible(&self, addr: VirtAddr, len: usize, flags: VmFlags) -> bool {
         let page_size = arch::virt::get_page_size();
-        let num_pages = divide_up(len, page_size);
         let start_page = addr.value() / page_size;
+        let end_page = match addr.value().checked_add(len) {
+            Some(x) => divide_up(x, page_size),
+            None => return false,
+        };
 
-        let mut prev = None;
-
-        for mapping in self.mappings.iter().filter(|mapping| {
-            start_page < mapping.end_page && mapping.start_page < start_page + num_pages
-        }) {
-            if let Some(e) = prev
-                && e + 1 != mapping.start_page
-            {
+        // The mappings are sorted by their start page, so every mapping has to start exactly
+        // where the previous one ended.
+        let mut next_page = start_page;
+        for mapping in self
+            .mappings
+            .iter()
+            .filter(|mapping| start_page < mapping.end_page && mapping.start_page < end_page)
+        {
+            if mapping.start_page > next_page || !mapping.get_flags().contains(flags) {
                 return false;
             }
-
-            prev = Some(mapping.end_page);
+            next_page = mapping.end_page;
         }
 
-        // If the filter didn't contain any matches, the range is completely outside of any
-        // mapped memory. This would fall through and skip the gap check, so we need to check
-        // if there was at least one iteration.
-        prev.is_some()
+        next_page >= end_page
     }
 
+    /// Removes all mappings and frees their pages.
     pub fn clear(&mut self) {
-        self.mappings.clear();
+        let ranges = self
+            .mappings
+            .iter()
+            .map(|x| (x.start_page, x.end_page))
+            .collect::<Vec<_>>();
+        for (start_page, end_page) in ranges {
+            self.remove_range(start_page, end_page);
+        }
+    }
+
+    /// Writes back the shared mappings which were removed since the last call. This does I/O, so
+    /// `space` must not be locked by the caller.
+    pub fn write_back_removed(space: &SpinMutex<Self>) {
+        let removed = mem::take(&mut space.lock().writeback);
+        // There is nobody to report a failed writeback to.
+        for (object, page_index, num_pages) in removed {
+            _ = object.sync(page_index, num_pages);
+        }
+    }
+
+    /// Writes back all modified pages of shared mappings.
+    fn write_back(&self) {
+        for (object, page_index, num_pages) in self.writeback.iter() {
+            _ = object.sync(*page_index, *num_pages);
+        }
+
+        for mapping in self
+            .mappings
+            .iter()
+            .filter(|x| x.get_flags().contains(VmFlags::Shared))
+        {
+            self.collect_dirty(mapping, mapping.start_page, mapping.end_page);
+            _ = mapping
+                .object
+                .sync(mapping.offset_page, mapping.end_page - mapping.start_page);
+        }
     }
 
     pub fn fork(&self) -> EResult<Self> {
         let page_size = arch::virt::get_page_size();
         let mut result = Self::new();
 
-        // Copy over existing mappings, but make a copy of private mappings.
+        // Map private mappings as read only in order to handle CoW.
+        // This has to happen first, so no thread can write to a page after it has been shared.
         for obj in self.mappings.iter() {
-            if obj.get_flags().contains(VmFlags::Shared) {
-                result.mappings.insert(obj.clone());
-            } else {
+            if !obj.get_flags().contains(VmFlags::Shared) {
                 obj.set_flags(obj.get_flags() | VmFlags::CopyOnWrite);
+                self.table
+                    .remap_range::<KernelAlloc>(
+                        (obj.start_page * page_size).into(),
+                        obj.get_flags() & !VmFlags::Write,
+                        (obj.end_page - obj.start_page) * page_size,
+                    )
+                    .unwrap();
+            }
+        }
 
+        // Copy over existing mappings. Private objects are copied, but share their pages until
+        // either side writes to them. A split mapping still refers to a single copy.
+        let mut copies: Vec<(Arc<dyn MemoryObject>, Arc<dyn MemoryObject>)> = Vec::new();
+        for obj in self.mappings.iter() {
+            if obj.get_flags().contains(VmFlags::Shared) {
                 result.mappings.insert(obj.clone());
+                continue;
+            }
 
-                // Map the object as read only in order to handle CoW.
-                for p in obj.start_page..obj.end_page {
-                    if self.table.is_mapped((p * page_size).into()) {
-                        self.table
-                            .remap_single::<KernelAlloc>((p * page_size).into(), VmFlags::Read)
-                            .unwrap();
-                    }
+            let copy = match copies.iter().find(|(x, _)| Arc::ptr_eq(x, &obj.object)) {
+                Some((_, copy)) => Some(copy.clone()),
+                None => obj.object.clone_cow().inspect(|copy| {
+                    copies.push((obj.object.clone(), copy.clone()));
+                }),
+            };
+
+            match copy {
+                Some(object) => result.mappings.insert(MappedObject {
+                    object,
+                    ..obj.clone()
+                }),
+                // Objects which can't be copied are shared instead. Their pages would never be
+                // unshared on a write fault, so they must not be treated as CoW on either side.
+                None => {
+                    obj.set_flags(obj.get_flags() & !VmFlags::CopyOnWrite);
+                    self.table
+                        .remap_range::<KernelAlloc>(
+                            (obj.start_page * page_size).into(),
+                            obj.get_flags(),
+                            (obj.end_page - obj.start_page) * page_size,
+                        )
+                        .unwrap();
+                    result.mappings.insert(obj.clone())
                 }
-            }
+            };
+        }
+
+        // Locks are not inherited.
+        for mapping in result.mappings.iter() {
+            mapping.set_flags(mapping.get_flags() & !VmFlags::Locked);
         }
 
         Ok(result)
     }
 }
 
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // Changes to shared mappings are only recorded in the page table.
+        self.write_back();
+    }
+}
+
 unsafe extern "C" {
     pub unsafe static LD_KERNEL_START: u8;
     pub unsafe static LD_TEXT_START: u8;
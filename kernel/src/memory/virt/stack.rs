@@ -0,0 +1,78 @@
+//! Virtually mapped kernel stacks.
+//!
+//! Kernel stacks get their address space from [`vmalloc`], which leaves an unmapped guard page
+//! below every area. A stack which overflows runs into that page and faults, instead of silently
+//! overwriting whatever comes next in memory.
+
+use super::{KERNEL_STACK_SIZE, VmCacheType, VmFlags, mmu::PageTable, vmalloc};
+use crate::{
+    arch,
+    memory::{
+        PhysAddr, VirtAddr,
+        pmm::{AllocFlags, KernelAlloc, PageAllocator},
+    },
+};
+use alloc::{alloc::AllocError, vec::Vec};
+
+/// A kernel stack of [`KERNEL_STACK_SIZE`] bytes with a guard page below it.
+#[derive(Debug)]
+pub struct KernelStack {
+    bottom: VirtAddr,
+}
+
+impl KernelStack {
+    /// Allocates and maps a new stack.
+    pub fn new() -> Result<Self, AllocError> {
+        let result = Self {
+            bottom: vmalloc::alloc(KERNEL_STACK_SIZE)?,
+        };
+
+        let page_size = arch::virt::get_page_size();
+        for offset in (0..KERNEL_STACK_SIZE).step_by(page_size) {
+            // Dropping the stack frees the pages which were mapped so far.
+            let phys = KernelAlloc::alloc(1, AllocFlags::Zeroed)?;
+            if PageTable::get_kernel()
+                .map_single::<KernelAlloc>(
+                    result.bottom + offset,
+                    phys,
+                    VmFlags::Read | VmFlags::Write,
+                    VmCacheType::WriteBack,
+                )
+                .is_err()
+            {
+                unsafe { KernelAlloc::dealloc(phys, 1) };
+                return Err(AllocError);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Returns the lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Returns the address right above the stack, which is the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.bottom + KERNEL_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let table = PageTable::get_kernel();
+        let pages = (0..KERNEL_STACK_SIZE)
+            .step_by(arch::virt::get_page_size())
+            .filter_map(|offset| table.translate(self.bottom + offset))
+            .collect::<Vec<PhysAddr>>();
+
+        // Nothing may refer to the pages anymore when they're freed.
+        _ = table.unmap_range::<KernelAlloc>(self.bottom, KERNEL_STACK_SIZE);
+        for page in pages {
+            unsafe { KernelAlloc::dealloc(page, 1) };
+        }
+
+        vmalloc::free(self.bottom);
+    }
+}
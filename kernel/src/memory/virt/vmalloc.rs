@@ -0,0 +1,111 @@
+//! Allocation of kernel address space.
+//!
+//! Mappings outside of the HHDM, like MMIO regions, loaded modules and kernel stacks, get their
+//! addresses from a region starting at [`arch::virt::get_map_base`]. The first usable address is
+//! moved by a random amount, see [`aslr`]. Freed ranges are merged with their neighbors and handed
+//! out again.
+//!
+//! Every area is preceded by an unmapped guard page. Running off the start of an area faults
+//! instead of silently writing to the area below it. This is what catches kernel stack overflows.
+
+use super::{aslr, mmu::PageTable};
+use crate::{
+    arch,
+    memory::{VirtAddr, pmm::KernelAlloc},
+    util::{align_up, mutex::spin::SpinMutex},
+};
+use alloc::{alloc::AllocError, collections::btree_map::BTreeMap};
+
+/// Size of the region. It has to be covered by a single entry of the top page level.
+const REGION_SIZE: usize = 1 << 39;
+
+struct Areas {
+    /// Ranges which aren't in use, by start address.
+    free: BTreeMap<usize, usize>,
+    /// Areas which are in use, by start address. Guard pages are not included.
+    used: BTreeMap<usize, usize>,
+}
+
+static AREAS: SpinMutex<Areas> = SpinMutex::new(Areas {
+    free: BTreeMap::new(),
+    used: BTreeMap::new(),
+});
+
+/// Sets up the region. This has to happen before the first user page table is created, because
+/// those only copy the top level of the kernel page table.
+pub fn init(table: &PageTable) {
+    let base = arch::virt::get_map_base();
+    table
+        .get_pte::<KernelAlloc>(base, true)
+        .expect("Unable to create the kernel mapping region");
+
+    // Randomize within the lower half, which leaves the upper half for allocations.
+    let offset = aslr::kernel_offset(REGION_SIZE / 2, arch::virt::get_page_size());
+    AREAS
+        .lock()
+        .free
+        .insert(base.value() + offset, REGION_SIZE - offset);
+}
+
+/// Reserves `len` bytes of kernel address space. The returned area is page aligned and not mapped.
+pub fn alloc(len: usize) -> Result<VirtAddr, AllocError> {
+    let page_size = arch::virt::get_page_size();
+    let len = align_up(len.max(1), page_size);
+    let needed = len + page_size;
+
+    let mut areas = AREAS.lock();
+    let (&start, &free_len) = areas
+        .free
+        .iter()
+        .find(|(_, x)| **x >= needed)
+        .ok_or(AllocError)?;
+
+    areas.free.remove(&start);
+    if free_len > needed {
+        areas.free.insert(start + needed, free_len - needed);
+    }
+
+    let addr = start + page_size;
+    areas.used.insert(addr, len);
+    Ok(VirtAddr::new(addr))
+}
+
+/// Returns an area from [`alloc`]. Its pages have to be unmapped already.
+pub fn free(addr: VirtAddr) {
+    let page_size = arch::virt::get_page_size();
+    let mut areas = AREAS.lock();
+    let Some(len) = areas.used.remove(&addr.value()) else {
+        panic!(
+            "Attempted to free unknown kernel area at {:#x}",
+            addr.value()
+        );
+    };
+
+    let mut start = addr.value() - page_size;
+    let mut len = len + page_size;
+
+    // Merge with the neighbors on both sides.
+    if let Some((&prev, &prev_len)) = areas.free.range(..start).next_back()
+        && prev + prev_len == start
+    {
+        areas.free.remove(&prev);
+        start = prev;
+        len += prev_len;
+    }
+    if let Some(next_len) = areas.free.remove(&(start + len)) {
+        len += next_len;
+    }
+
+    areas.free.insert(start, len);
+}
+
+/// Returns true if `addr` lies in the guard page of an area.
+/// This is called from exception handlers, so it gives up if the allocator is busy.
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let page_size = arch::virt::get_page_size();
+    let Some(areas) = AREAS.try_lock() else {
+        return false;
+    };
+    let guard = addr.value() & !(page_size - 1);
+    areas.used.contains_key(&guard.wrapping_add(page_size))
+}
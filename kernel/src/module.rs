@@ -1,12 +1,12 @@
 use crate::arch;
 use crate::memory::pmm::{AllocFlags, KernelAlloc, PageAllocator};
-use crate::memory::virt::{self, KERNEL_MMAP_BASE_ADDR, VmFlags, mmu::PageTable};
+use crate::memory::virt::{self, VmCacheType, VmFlags, mmu::PageTable, vmalloc};
 use crate::memory::{PhysAddr, VirtAddr};
 use crate::posix::errno::{EResult, Errno};
 use crate::util::{align_down, align_up, mutex::spin::SpinMutex};
 use crate::vfs::exec::elf::{self, ElfHashTable, ElfHdr, ElfPhdr, ElfRela, ElfSym};
 use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, string::String, vec::Vec};
-use core::{ffi::CStr, slice, sync::atomic::Ordering};
+use core::{ffi::CStr, slice};
 
 // TODO: This can use RwLocks.
 pub(crate) static SYMBOL_TABLE: SpinMutex<BTreeMap<String, (elf::ElfSym, Option<&ModuleInfo>)>> =
@@ -113,7 +113,16 @@ pub fn load(data: &[u8]) -> EResult<()> {
     )
     .map_err(|_| Errno::ENOEXEC)?;
 
-    let mut load_base = 0;
+    // Reserve address space for all segments at once, so they keep their distances.
+    // Segments which don't start on a page boundary may take up one more page.
+    let image_size = phdrs
+        .iter()
+        .filter(|x| x.p_type == elf::PT_LOAD)
+        .map(|x| (x.p_vaddr + x.p_memsz) as usize + arch::virt::get_page_size())
+        .max()
+        .ok_or(Errno::ENOEXEC)?;
+    let load_base = vmalloc::alloc(image_size).map_err(|_| Errno::ENOMEM)?;
+
     let mut info = ModuleInfo {
         version: String::new(),
         description: String::new(),
@@ -122,6 +131,36 @@ pub fn load(data: &[u8]) -> EResult<()> {
         mappings: Vec::new(),
     };
 
+    match load_image(data, elf_hdr, phdrs, load_base.value(), &mut info) {
+        Ok(name) => {
+            MODULE_TABLE.lock().insert(name, info);
+            Ok(())
+        }
+        Err(e) => {
+            release(load_base, &info.mappings);
+            Err(e)
+        }
+    }
+}
+
+/// Frees the memory and address space of a module image.
+fn release(load_base: VirtAddr, mappings: &[(PhysAddr, VirtAddr, usize, VmFlags)]) {
+    let page_table = PageTable::get_kernel();
+    for (phys, virt, length, _) in mappings {
+        _ = page_table.unmap_range::<KernelAlloc>(*virt, *length);
+        unsafe { KernelAlloc::dealloc_bytes(*phys, *length) };
+    }
+    vmalloc::free(load_base);
+}
+
+/// Maps, relocates and starts a module at `load_base`. Returns the name of the module.
+fn load_image(
+    data: &[u8],
+    elf_hdr: &ElfHdr,
+    phdrs: &[ElfPhdr],
+    load_base: usize,
+    info: &mut ModuleInfo,
+) -> EResult<String> {
     // Variables read from the dynamic segment.
     let mut dt_strtab = None;
     let mut dt_strsz = None;
@@ -139,11 +178,6 @@ pub fn load(data: &[u8]) -> EResult<()> {
         match phdr.p_type {
             // Load the segment into memory.
             elf::PT_LOAD => {
-                // Record where the first PHDR was loaded at.
-                if load_base == 0 {
-                    load_base = KERNEL_MMAP_BASE_ADDR.load(Ordering::Acquire);
-                }
-
                 let mut memsz = phdr.p_memsz as usize;
 
                 // Fix potentially unaligned addresses.
@@ -159,16 +193,20 @@ pub fn load(data: &[u8]) -> EResult<()> {
                 let page_table = PageTable::get_kernel();
 
                 // Map memory with RW permissions.
-                for page in (0..memsz).step_by(arch::virt::get_page_size()) {
-                    page_table
-                        .map_single::<KernelAlloc>(
-                            (load_base + aligned_virt + page).into(),
-                            phys + page,
-                            VmFlags::Read | VmFlags::Write,
-                        )
-                        .map_err(|_| Errno::ENOMEM)?;
-
-                    KERNEL_MMAP_BASE_ADDR.fetch_add(arch::virt::get_page_size(), Ordering::AcqRel);
+                if page_table
+                    .map_range::<KernelAlloc>(
+                        (load_base + aligned_virt).into(),
+                        phys,
+                        VmFlags::Read | VmFlags::Write,
+                        VmCacheType::WriteBack,
+                        memsz,
+                    )
+                    .is_err()
+                {
+                    _ = page_table
+                        .unmap_range::<KernelAlloc>((load_base + aligned_virt).into(), memsz);
+                    unsafe { KernelAlloc::dealloc_bytes(phys, memsz) };
+                    return Err(Errno::ENOMEM);
                 }
 
                 let virt = load_base + phdr.p_vaddr as usize;
@@ -398,9 +436,7 @@ pub fn load(data: &[u8]) -> EResult<()> {
         (entry_point)();
     }
 
-    MODULE_TABLE.lock().insert(name.to_owned(), info);
-
-    return Ok(());
+    return Ok(name.to_owned());
 }
 
 #[doc(hidden)]
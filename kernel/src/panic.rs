@@ -5,7 +5,10 @@ use super::log::GLOBAL_LOGGERS;
 use crate::{
     arch,
     {
-        memory::{VirtAddr, virt::mmu::PageTable},
+        memory::{
+            VirtAddr,
+            virt::{self, mmu::PageTable},
+        },
         percpu::CpuData,
         vfs::exec::elf::ElfAddr,
     },
@@ -45,9 +48,15 @@ fn panic_handler(info: &PanicInfo) -> ! {
         log_panic!("at {}", location);
     }
 
+    // The kernel image and modules may be placed at random addresses.
+    log_panic!("----------");
+    log_panic!(
+        "Kernel image at {:#x}",
+        &raw const virt::LD_KERNEL_START as usize
+    );
+
+    let modules = super::module::MODULE_TABLE.lock();
     {
-        log_panic!("----------");
-        let modules = super::module::MODULE_TABLE.lock();
         log_panic!("{} linked module(s):", modules.len());
         for (name, module) in modules.iter() {
             log_panic!(
@@ -81,6 +90,21 @@ fn panic_handler(info: &PanicInfo) -> ! {
             });
             let (name, offset) = symbol
                 .map(|(name, (sym, _))| (name.as_str(), addr - sym.st_value))
+                .or_else(|| {
+                    // Symbols of modules aren't in the table, so show the offset into the module.
+                    modules.iter().find_map(|(name, module)| {
+                        module
+                            .mappings
+                            .iter()
+                            .any(|(_, virt, len, _)| {
+                                (virt.value()..virt.value() + len).contains(&(addr as usize))
+                            })
+                            .then(|| {
+                                let base = module.mappings.first().unwrap().1.value();
+                                (name.as_str(), addr - base as ElfAddr)
+                            })
+                    })
+                })
                 .unwrap_or(("???", 0));
 
             if addr == 0 {
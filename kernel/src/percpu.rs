@@ -7,7 +7,7 @@ use crate::{
         memory::{
             self,
             pmm::{AllocFlags, KernelAlloc, PageAllocator},
-            virt::{VmFlags, mmu::PageTable},
+            virt::{VmCacheType, VmFlags, mmu::PageTable},
         },
         posix::errno::{EResult, Errno},
     },
@@ -21,7 +21,7 @@ pub struct CpuData {
     pub this: AtomicPtr<CpuData>,
     /// The ID of this CPU.
     pub id: usize,
-    /// Stack pointer for kernel mode. Only used for task switching.
+    /// Top of the kernel stack of the running task, where entries from user mode start.
     pub kernel_stack: AtomicUsize,
     /// Stack pointer for user mode.
     pub user_stack: AtomicUsize,
@@ -149,6 +149,7 @@ pub(crate) fn allocate_cpu() -> EResult<&'static CpuData> {
             VirtAddr::from(percpu_new),
             phys,
             VmFlags::Read | VmFlags::Write,
+            VmCacheType::WriteBack,
             percpu_size,
         )
         .map_err(|_| Errno::ENOMEM)?;
@@ -1,3 +1,4 @@
 pub mod errno;
 pub mod resource;
+pub mod time;
 pub mod utsname;
@@ -1,6 +1,10 @@
-use crate::uapi::resource::*;
+use crate::{
+    posix::errno::Errno,
+    uapi::{self, resource::*},
+};
 
 #[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Resource {
     Cpu = RLIMIT_CPU,
     FileSize = RLIMIT_FSIZE,
@@ -20,22 +24,84 @@ pub enum Resource {
     RtTime = RLIMIT_RTTIME,
 }
 
-pub struct Limits {
-    pub open_max: rlimit,
-    pub core_size: rlimit,
+impl TryFrom<u32> for Resource {
+    type Error = Errno;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            RLIMIT_CPU => Self::Cpu,
+            RLIMIT_FSIZE => Self::FileSize,
+            RLIMIT_DATA => Self::Data,
+            RLIMIT_STACK => Self::Stack,
+            RLIMIT_CORE => Self::Core,
+            RLIMIT_RSS => Self::Rss,
+            RLIMIT_NPROC => Self::NumProc,
+            RLIMIT_NOFILE => Self::NumFiles,
+            RLIMIT_MEMLOCK => Self::MemLock,
+            RLIMIT_AS => Self::AddressSpace,
+            RLIMIT_LOCKS => Self::Locks,
+            RLIMIT_SIGPENDING => Self::SigPending,
+            RLIMIT_MSGQUEUE => Self::MsgQueue,
+            RLIMIT_NICE => Self::Nice,
+            RLIMIT_RTPRIO => Self::RtPrio,
+            RLIMIT_RTTIME => Self::RtTime,
+            _ => return Err(Errno::EINVAL),
+        })
+    }
+}
+
+/// Resource limits of a process. Every resource can be queried and changed, but only some of
+/// them are enforced.
+#[derive(Clone)]
+pub struct Limits([rlimit; RLIM_NLIMITS as usize]);
+
+impl Limits {
+    pub fn get(&self, resource: Resource) -> rlimit {
+        self.0[resource as usize]
+    }
+
+    pub fn set(&mut self, resource: Resource, limit: rlimit) {
+        self.0[resource as usize] = limit;
+    }
+
+    /// Returns the soft limit of `resource`, or [`None`] if it is unlimited.
+    pub fn get_cur(&self, resource: Resource) -> Option<uapi::rlim_t> {
+        match self.get(resource).rlim_cur {
+            RLIM_INFINITY => None,
+            x => Some(x),
+        }
+    }
 }
 
 impl Default for Limits {
     fn default() -> Self {
-        Self {
-            open_max: rlimit {
+        let mut result = Self(
+            [rlimit {
+                rlim_cur: RLIM_INFINITY,
+                rlim_max: RLIM_INFINITY,
+            }; _],
+        );
+        result.set(
+            Resource::NumFiles,
+            rlimit {
                 rlim_cur: 1024,
                 rlim_max: 1024,
             },
-            core_size: rlimit {
+        );
+        result.set(
+            Resource::Core,
+            rlimit {
                 rlim_cur: 0,
                 rlim_max: 0,
             },
-        }
+        );
+        result.set(
+            Resource::MemLock,
+            rlimit {
+                rlim_cur: 8 * 1024 * 1024,
+                rlim_max: 8 * 1024 * 1024,
+            },
+        );
+        result
     }
 }
@@ -0,0 +1,36 @@
+use crate::{
+    memory::{VirtAddr, user::UserPtr},
+    posix::errno::{EResult, Errno},
+    uapi::time::timespec,
+};
+
+pub const NS_PER_SEC: usize = 1_000_000_000;
+
+/// Converts a relative [`timespec`] to nanoseconds.
+pub fn timespec_to_ns(value: &timespec) -> EResult<usize> {
+    if value.tv_sec < 0 || !(0..NS_PER_SEC as isize).contains(&value.tv_nsec) {
+        return Err(Errno::EINVAL);
+    }
+
+    (value.tv_sec as usize)
+        .checked_mul(NS_PER_SEC)
+        .and_then(|x| x.checked_add(value.tv_nsec as usize))
+        .ok_or(Errno::EINVAL)
+}
+
+pub fn ns_to_timespec(value: usize) -> timespec {
+    timespec {
+        tv_sec: (value / NS_PER_SEC) as _,
+        tv_nsec: (value % NS_PER_SEC) as _,
+    }
+}
+
+/// Reads a relative timeout from user memory. A null pointer means that there is no timeout.
+pub fn read_timeout(addr: VirtAddr) -> EResult<Option<usize>> {
+    if addr == VirtAddr::null() {
+        return Ok(None);
+    }
+
+    let value = UserPtr::<timespec>::new(addr).read().ok_or(Errno::EFAULT)?;
+    timespec_to_ns(&value).map(Some)
+}
@@ -2,14 +2,23 @@ pub mod signal;
 pub mod task;
 
 use crate::{
-    arch::sched::Context,
-    memory::{VirtAddr, virt::AddressSpace},
+    arch::{self, sched::Context},
+    memory::{
+        VirtAddr, oom,
+        virt::{AddressSpace, aslr, mmu},
+    },
     percpu::CpuData,
-    posix::errno::{EResult, Errno},
-    process::task::Task,
+    posix::{
+        errno::{EResult, Errno},
+        resource::Limits,
+    },
+    process::{
+        signal::{PendingSignals, Signal, SignalActions, SignalInfo},
+        task::Task,
+    },
     sched::Scheduler,
     uapi,
-    util::{mutex::spin::SpinMutex, once::Once},
+    util::{event::Event, mutex::spin::SpinMutex, once::Once},
     vfs::{
         self,
         cache::PathNode,
@@ -24,7 +33,10 @@ use alloc::{
     sync::{Arc, Weak},
     vec::Vec,
 };
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{
+    hint, mem,
+    sync::atomic::{AtomicI32, AtomicUsize, Ordering},
+};
 
 /// A unique process ID.
 pub type Pid = usize;
@@ -33,9 +45,22 @@ pub type Pid = usize;
 pub enum ProcessState {
     Running,
     Exited(u8),
+    /// The process was terminated by a signal.
+    Killed(Signal),
     // TODO: SIGSTOP
 }
 
+impl ProcessState {
+    /// Returns the status as reported to a waiting parent, or [`None`] if the process is still running.
+    pub fn wait_status(&self) -> Option<i32> {
+        match self {
+            ProcessState::Running => None,
+            ProcessState::Exited(code) => Some((*code as i32) << 8),
+            ProcessState::Killed(sig) => Some(sig.number() as i32 & 0x7f),
+        }
+    }
+}
+
 pub struct Process {
     /// The unique identifier of this process.
     id: Pid,
@@ -55,12 +80,23 @@ pub struct Process {
     pub status: SpinMutex<ProcessState>,
     /// Child processes owned by this process.
     pub children: SpinMutex<Vec<Arc<Process>>>,
+    /// Signaled when a child process terminated.
+    pub child_event: Event,
     /// The user identity of this process.
     pub identity: SpinMutex<Identity>,
     /// A table of open file descriptors.
     pub open_files: SpinMutex<FdTable>,
     /// A pointer to the next free memory region.
     pub mmap_head: SpinMutex<VirtAddr>,
+    /// How this process reacts to signals.
+    pub signal_actions: SpinMutex<SignalActions>,
+    /// Signals sent to the process as a whole.
+    pub pending_signals: SpinMutex<PendingSignals>,
+    /// Added to the score of this process when the OOM killer looks for a victim.
+    /// [`uapi::oom::OOM_SCORE_ADJ_MIN`] exempts the process.
+    pub oom_score_adj: AtomicI32,
+    /// Resource limits, inherited by child processes.
+    pub limits: SpinMutex<Limits>,
 }
 
 impl Process {
@@ -70,6 +106,12 @@ impl Process {
         self.id
     }
 
+    /// Returns the ID of the process group this process belongs to.
+    // TODO: Process groups. For now, every process is the leader of its own group.
+    pub const fn get_pgid(&self) -> Pid {
+        self.id
+    }
+
     pub fn get_name(&self) -> &str {
         &self.name
     }
@@ -85,10 +127,25 @@ impl Process {
         })
     }
 
-    pub fn new(name: String, parent: Option<Arc<Self>>) -> EResult<Self> {
+    pub fn new(name: String, parent: Option<Arc<Self>>) -> EResult<Arc<Self>> {
         Self::new_with_space(name, parent, AddressSpace::new())
     }
 
+    /// Looks up a living process by its ID.
+    pub fn get_by_pid(pid: Pid) -> Option<Arc<Self>> {
+        PROCESS_TABLE.lock().get(&pid).and_then(|x| x.upgrade())
+    }
+
+    /// Calls `f` for every living process.
+    pub fn for_each(mut f: impl FnMut(&Arc<Self>)) {
+        let procs = PROCESS_TABLE
+            .lock()
+            .values()
+            .filter_map(|x| x.upgrade())
+            .collect::<Vec<_>>();
+        procs.iter().for_each(|x| f(x));
+    }
+
     pub fn fork(self: Arc<Self>, context: &Context) -> EResult<(Arc<Self>, Arc<Task>)> {
         let forked = Arc::new(Self {
             id: PID_COUNTER.fetch_add(1, Ordering::Acquire),
@@ -100,10 +157,18 @@ impl Process {
             working_dir: SpinMutex::new(self.working_dir.lock().clone()),
             status: SpinMutex::new(ProcessState::Running),
             children: SpinMutex::new(Vec::new()),
+            child_event: Event::new(),
             identity: SpinMutex::new(self.identity.lock().clone()),
             open_files: SpinMutex::new(self.open_files.lock().clone()),
             mmap_head: SpinMutex::new(*self.mmap_head.lock()),
+            signal_actions: SpinMutex::new(self.signal_actions.lock().clone()),
+            pending_signals: SpinMutex::new(PendingSignals::new()),
+            oom_score_adj: AtomicI32::new(self.oom_score_adj.load(Ordering::Relaxed)),
+            limits: SpinMutex::new(self.limits.lock().clone()),
         });
+        PROCESS_TABLE
+            .lock()
+            .insert(forked.id, Arc::downgrade(&forked));
 
         // Create a heap allocated context that we can pass to the entry point.
         let mut forked_ctx = Box::new(*context);
@@ -112,17 +177,60 @@ impl Process {
 
         // Create the main thread.
         let forked_thread = Arc::new(Task::new(to_user_context, raw_ctx as _, 0, &forked, true)?);
+        let current = Scheduler::get_current();
+        *forked_thread.signal_mask.lock() = *current.signal_mask.lock();
+        forked_thread.inherit_scheduling(&current);
         forked.threads.lock().push(forked_thread.clone());
         self.children.lock().push(forked.clone());
 
         Ok((forked, forked_thread))
     }
 
+    /// Creates a new thread in this process which enters user mode at `ip` with the stack set to `sp`.
+    /// `arg` is passed as the first argument and `tls` becomes the thread pointer.
+    pub fn create_thread(
+        self: &Arc<Self>,
+        ip: VirtAddr,
+        sp: VirtAddr,
+        arg: usize,
+        tls: VirtAddr,
+    ) -> EResult<Arc<Task>> {
+        // Create a heap allocated context that we can pass to the entry point.
+        let ctx = Box::new(arch::sched::new_user_context(ip, sp, arg));
+        let raw_ctx = Box::into_raw(ctx);
+
+        let thread = Arc::new(Task::new(to_user_context, raw_ctx as _, 0, self, true)?);
+        arch::sched::set_tls(&mut thread.task_context.lock(), tls);
+        let current = Scheduler::get_current();
+        *thread.signal_mask.lock() = *current.signal_mask.lock();
+        thread.inherit_scheduling(&current);
+        self.threads.lock().push(thread.clone());
+
+        Ok(thread)
+    }
+
+    /// Terminates the calling thread without affecting the other threads of this process.
+    /// If it was the last thread, the entire process exits with `code`.
+    pub fn exit_thread(&self, code: u8) -> ! {
+        let is_last = {
+            let task = Scheduler::get_current();
+            let mut threads = self.threads.lock();
+            threads.retain(|x| !Arc::ptr_eq(x, &task));
+            threads.is_empty()
+        };
+
+        if is_last {
+            self.terminate(ProcessState::Exited(code));
+        }
+
+        Scheduler::kill_current();
+    }
+
     fn new_with_space(
         name: String,
         parent: Option<Arc<Self>>,
         space: AddressSpace,
-    ) -> EResult<Self> {
+    ) -> EResult<Arc<Self>> {
         let (root, cwd, identity) = match &parent {
             Some(x) => (
                 x.root_dir.lock().clone(),
@@ -132,26 +240,45 @@ impl Process {
             None => (vfs::get_root(), vfs::get_root(), Identity::default()),
         };
 
-        // Save the child in the parent process.
-        if let Some(x) = &parent {
-            x.children.lock().push(x.clone())
-        }
-
-        Ok(Self {
+        let result = Arc::new(Self {
             id: PID_COUNTER.fetch_add(1, Ordering::Relaxed),
             name,
-            parent: parent.map(|x| Arc::downgrade(&x)),
+            parent: parent.as_ref().map(Arc::downgrade),
             threads: SpinMutex::new(Vec::new()),
             address_space: Arc::new(SpinMutex::new(space)),
             status: SpinMutex::new(ProcessState::Running),
             children: SpinMutex::new(Vec::new()),
+            child_event: Event::new(),
             root_dir: SpinMutex::new(root),
             working_dir: SpinMutex::new(cwd),
             identity: SpinMutex::new(identity),
             open_files: SpinMutex::new(FdTable::new()),
-            // TODO: This address should be determined from the highest loaded segment.
-            mmap_head: SpinMutex::new(VirtAddr::new(0x1_0000_0000)),
-        })
+            // The executable loader picks the actual address, see [`ExecInfo::mmap_base`].
+            mmap_head: SpinMutex::new(VirtAddr::new(aslr::MMAP_BASE)),
+            signal_actions: SpinMutex::new(SignalActions::new()),
+            pending_signals: SpinMutex::new(PendingSignals::new()),
+            oom_score_adj: AtomicI32::new(
+                parent
+                    .as_ref()
+                    .map_or(0, |x| x.oom_score_adj.load(Ordering::Relaxed)),
+            ),
+            limits: SpinMutex::new(
+                parent
+                    .as_ref()
+                    .map_or_else(Limits::default, |x| x.limits.lock().clone()),
+            ),
+        });
+
+        // Save the child in the parent process.
+        if let Some(x) = &parent {
+            x.children.lock().push(result.clone())
+        }
+
+        PROCESS_TABLE
+            .lock()
+            .insert(result.id, Arc::downgrade(&result));
+
+        Ok(result)
     }
 
     /// Returns the kernel process.
@@ -173,6 +300,7 @@ impl Process {
             executable: file.clone(),
             interpreter: None,
             space: AddressSpace::new(),
+            mmap_base: VirtAddr::new(aslr::MMAP_BASE),
             argv,
             envp,
         };
@@ -180,6 +308,9 @@ impl Process {
         let format = vfs::exec::identify(&file).ok_or(Errno::ENOEXEC)?;
         let init = Arc::try_new(format.load(&self, &mut info)?)?;
 
+        // The signal mask is preserved across an execve.
+        *init.signal_mask.lock() = *Scheduler::get_current().signal_mask.lock();
+
         // If we get here, then the loading of the executable was successful.
         {
             let mut threads = self.threads.lock();
@@ -188,8 +319,12 @@ impl Process {
 
             let mut space = self.address_space.lock();
             *space = info.space;
+            *self.mmap_head.lock() = info.mmap_base;
 
             self.open_files.lock().close_exec();
+
+            // Handlers don't exist in the new image anymore.
+            self.signal_actions.lock().reset_caught();
         }
 
         CpuData::get().scheduler.add_task(init);
@@ -199,22 +334,83 @@ impl Process {
     }
 
     pub fn exit(self: Arc<Self>, code: u8) {
-        {
+        self.terminate(ProcessState::Exited(code));
+    }
+
+    /// Terminates all threads of this process and records the reason for waiting parents.
+    /// This must be called from a thread of this process.
+    pub fn terminate(&self, reason: ProcessState) -> ! {
+        // There is nobody left to reap orphans if init dies.
+        if self.id <= 1 {
+            panic!("Attempted to kill init ({reason:?})");
+        }
+
+        let child_info = match reason {
+            ProcessState::Exited(code) => SignalInfo {
+                status: code as i32,
+                ..SignalInfo::new(Signal::SIGCHLD, uapi::signal::CLD_EXITED as _)
+            },
+            ProcessState::Killed(sig) => SignalInfo {
+                status: sig.number() as i32,
+                ..SignalInfo::new(Signal::SIGCHLD, uapi::signal::CLD_KILLED as _)
+            },
+            ProcessState::Running => unreachable!("A process can't terminate as running"),
+        };
+
+        let current = Scheduler::get_current();
+        let threads = {
             let mut open_files = self.open_files.lock();
             let mut threads = self.threads.lock();
             let mut status = self.status.lock();
 
-            // Kill all threads.
+            // Kill all threads. Threads running on other CPUs stop on their next reschedule.
             for thread in threads.iter() {
                 *thread.state.lock() = task::TaskState::Dead;
+                if !Arc::ptr_eq(thread, &current)
+                    && thread.on_cpu.load(Ordering::Acquire)
+                    && let Some(cpu) = CpuData::get_for(thread.last_cpu.load(Ordering::Acquire))
+                {
+                    cpu.scheduler.request_reschedule();
+                }
             }
-            threads.clear();
 
             // Close all files.
             open_files.close_all();
 
-            *status = ProcessState::Exited(code);
+            *status = reason;
+            mem::take(&mut *threads)
+        };
+
+        // No other thread may touch the address space while it's torn down.
+        // They might wait for a shootdown from this CPU before they can be switched away from.
+        for thread in threads.iter().filter(|x| !Arc::ptr_eq(x, &current)) {
+            while thread.on_cpu.load(Ordering::Acquire) {
+                mmu::handle_shootdown();
+                hint::spin_loop();
+            }
+        }
+        drop(threads);
+        drop(current);
+
+        // Free the memory right away instead of when the parent reaps the process.
+        // The OOM killer relies on this to get memory back from its victims.
+        self.address_space.lock().clear();
+        oom::memory_released();
+        AddressSpace::write_back_removed(&self.address_space);
+
+        // Notify the parent about the state change.
+        if let Some(parent) = self.get_parent() {
+            signal::send_to_process(
+                &parent,
+                SignalInfo {
+                    pid: self.id,
+                    uid: self.identity.lock().user_id,
+                    ..child_info
+                },
+            );
+            parent.child_event.wake_all();
         }
+
         Scheduler::kill_current();
     }
 }
@@ -353,8 +549,16 @@ impl Identity {
     }
 }
 
+impl Drop for Process {
+    fn drop(&mut self) {
+        PROCESS_TABLE.lock().remove(&self.id);
+    }
+}
+
 static PID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 static KERNEL_PROCESS: Once<Arc<Process>> = Once::new();
+/// Maps process IDs to all living processes.
+static PROCESS_TABLE: SpinMutex<BTreeMap<Pid, Weak<Process>>> = SpinMutex::new(BTreeMap::new());
 
 #[initgraph::task(
     name = "generic.process",
@@ -363,16 +567,19 @@ static KERNEL_PROCESS: Once<Arc<Process>> = Once::new();
 pub fn PROCESS_STAGE() {
     // Create the kernel process and task.
     unsafe {
-        KERNEL_PROCESS.init(Arc::new(
+        KERNEL_PROCESS.init(
             Process::new_with_space(
                 "kernel".into(),
                 None,
                 AddressSpace {
                     table: super::memory::virt::KERNEL_PAGE_TABLE.get().clone(),
                     mappings: BTreeSet::new(),
+                    lock_new: false,
+                    populating: 0,
+                    writeback: Vec::new(),
                 },
             )
             .expect("Unable to create the main kernel process"),
-        ))
+        )
     };
 }
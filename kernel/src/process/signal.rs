@@ -1,6 +1,15 @@
-use crate::uapi::signal;
+use super::{Pid, Process, ProcessState, task::Task};
+use crate::{
+    arch::{self, sched::Context},
+    memory::VirtAddr,
+    sched::Scheduler,
+    uapi::{self, signal},
+};
+use alloc::sync::Arc;
+use core::fmt::Debug;
 
 #[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Signal {
     SIGHUP = signal::SIGHUP,
     SIGINT = signal::SIGINT,
@@ -34,8 +43,96 @@ pub enum Signal {
     SIGPWR = signal::SIGPWR,
 }
 
+/// What happens to a process if a signal is delivered while its action is [`SignalHandler::Default`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DefaultAction {
+    /// Terminate the process.
+    Terminate,
+    /// Terminate the process and indicate that a core dump would have been made.
+    CoreDump,
+    /// Discard the signal.
+    Ignore,
+    /// Stop the process.
+    Stop,
+    /// Continue the process if it is stopped.
+    Continue,
+}
+
+impl Signal {
+    /// Converts a raw signal number to a [`Signal`].
+    /// Returns [`None`] if the number does not name a valid signal.
+    pub const fn from_raw(value: u32) -> Option<Self> {
+        Some(match value {
+            signal::SIGHUP => Self::SIGHUP,
+            signal::SIGINT => Self::SIGINT,
+            signal::SIGQUIT => Self::SIGQUIT,
+            signal::SIGCONT => Self::SIGCONT,
+            signal::SIGBUS => Self::SIGBUS,
+            signal::SIGABRT => Self::SIGABRT,
+            signal::SIGCHLD => Self::SIGCHLD,
+            signal::SIGFPE => Self::SIGFPE,
+            signal::SIGKILL => Self::SIGKILL,
+            signal::SIGILL => Self::SIGILL,
+            signal::SIGPIPE => Self::SIGPIPE,
+            signal::SIGSEGV => Self::SIGSEGV,
+            signal::SIGSTOP => Self::SIGSTOP,
+            signal::SIGALRM => Self::SIGALRM,
+            signal::SIGTERM => Self::SIGTERM,
+            signal::SIGTSTP => Self::SIGTSTP,
+            signal::SIGTTIN => Self::SIGTTIN,
+            signal::SIGTTOU => Self::SIGTTOU,
+            signal::SIGUSR1 => Self::SIGUSR1,
+            signal::SIGUSR2 => Self::SIGUSR2,
+            signal::SIGIO => Self::SIGIO,
+            signal::SIGPROF => Self::SIGPROF,
+            signal::SIGSYS => Self::SIGSYS,
+            signal::SIGTRAP => Self::SIGTRAP,
+            signal::SIGURG => Self::SIGURG,
+            signal::SIGVTALRM => Self::SIGVTALRM,
+            signal::SIGXCPU => Self::SIGXCPU,
+            signal::SIGXFSZ => Self::SIGXFSZ,
+            signal::SIGWINCH => Self::SIGWINCH,
+            signal::SIGPWR => Self::SIGPWR,
+            _ => return None,
+        })
+    }
+
+    /// Returns the raw signal number.
+    #[inline]
+    pub const fn number(self) -> u32 {
+        self as u32
+    }
+
+    /// Returns true if the action of this signal can neither be caught, blocked or ignored.
+    #[inline]
+    pub const fn is_unblockable(self) -> bool {
+        matches!(self, Self::SIGKILL | Self::SIGSTOP)
+    }
+
+    /// Returns the action which is taken if no handler is installed.
+    pub const fn default_action(self) -> DefaultAction {
+        match self {
+            Self::SIGCHLD | Self::SIGURG | Self::SIGWINCH | Self::SIGPWR => DefaultAction::Ignore,
+            Self::SIGCONT => DefaultAction::Continue,
+            Self::SIGSTOP | Self::SIGTSTP | Self::SIGTTIN | Self::SIGTTOU => DefaultAction::Stop,
+            Self::SIGQUIT
+            | Self::SIGABRT
+            | Self::SIGBUS
+            | Self::SIGFPE
+            | Self::SIGILL
+            | Self::SIGSEGV
+            | Self::SIGSYS
+            | Self::SIGTRAP
+            | Self::SIGXCPU
+            | Self::SIGXFSZ => DefaultAction::CoreDump,
+            _ => DefaultAction::Terminate,
+        }
+    }
+}
+
 /// Wrapper around a set of signals.
 #[repr(transparent)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 pub struct SignalSet {
     inner: signal::sigset_t,
 }
@@ -45,6 +142,16 @@ impl SignalSet {
         Self { inner: 0 }
     }
 
+    /// Creates a set from its user space representation.
+    pub const fn from_raw(inner: signal::sigset_t) -> Self {
+        Self { inner }
+    }
+
+    /// Returns the user space representation of this set.
+    pub const fn into_raw(self) -> signal::sigset_t {
+        self.inner
+    }
+
     pub fn set_signal(&mut self, idx: usize, state: bool) {
         if state {
             self.inner |= 1 << idx;
@@ -52,4 +159,477 @@ impl SignalSet {
             self.inner &= !(1 << idx);
         }
     }
+
+    /// Adds a signal to the set.
+    pub fn add(&mut self, sig: Signal) {
+        self.set_signal(sig.number() as usize - 1, true);
+    }
+
+    /// Removes a signal from the set.
+    pub fn remove(&mut self, sig: Signal) {
+        self.set_signal(sig.number() as usize - 1, false);
+    }
+
+    /// Returns a copy of this set with an additional signal.
+    pub fn with(mut self, sig: Signal) -> Self {
+        self.add(sig);
+        self
+    }
+
+    pub const fn contains(&self, sig: Signal) -> bool {
+        self.inner & (1 << (sig.number() - 1)) != 0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.inner == 0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self {
+            inner: self.inner | other.inner,
+        }
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self {
+            inner: self.inner & other.inner,
+        }
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self {
+            inner: self.inner & !other.inner,
+        }
+    }
+
+    /// Removes the signals that may never be blocked from this set.
+    pub fn without_unblockable(mut self) -> Self {
+        self.remove(Signal::SIGKILL);
+        self.remove(Signal::SIGSTOP);
+        self
+    }
+
+    /// Returns the lowest valid signal contained in this set.
+    pub fn first(&self) -> Option<Signal> {
+        let mut bits = self.inner;
+        while bits != 0 {
+            let idx = bits.trailing_zeros();
+            if let Some(sig) = Signal::from_raw(idx + 1) {
+                return Some(sig);
+            }
+            bits &= !(1 << idx);
+        }
+        None
+    }
+}
+
+impl Debug for SignalSet {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_fmt(format_args!("{:#x}", self.inner))
+    }
+}
+
+/// Describes what to do when a signal is delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignalHandler {
+    /// Perform the [`DefaultAction`] of the signal.
+    Default,
+    /// Discard the signal.
+    Ignore,
+    /// Call a user space function.
+    Function(VirtAddr),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SignalAction {
+    pub handler: SignalHandler,
+    /// The function to return to after the handler has finished. It has to perform a sigreturn.
+    pub restorer: VirtAddr,
+    /// Signals to block while the handler is running.
+    pub mask: SignalSet,
+    /// `SA_*` flags.
+    pub flags: u32,
+}
+
+impl SignalAction {
+    pub const fn new() -> Self {
+        Self {
+            handler: SignalHandler::Default,
+            restorer: VirtAddr::null(),
+            mask: SignalSet::from_raw(0),
+            flags: 0,
+        }
+    }
+
+    /// Converts the user space representation of an action.
+    pub fn from_user(action: &signal::sigaction) -> Self {
+        let handler = match action.sa_sigaction.map_or(0, |x| x as usize) {
+            signal::SIG_DFL => SignalHandler::Default,
+            signal::SIG_IGN => SignalHandler::Ignore,
+            x => SignalHandler::Function(x.into()),
+        };
+
+        Self {
+            handler,
+            restorer: action.sa_restorer.map_or(0, |x| x as usize).into(),
+            mask: SignalSet::from_raw(action.sa_mask).without_unblockable(),
+            flags: action.sa_flags as u32,
+        }
+    }
+
+    /// Converts this action to its user space representation.
+    pub fn to_user(&self) -> signal::sigaction {
+        let handler = match self.handler {
+            SignalHandler::Default => signal::SIG_DFL,
+            SignalHandler::Ignore => signal::SIG_IGN,
+            SignalHandler::Function(x) => x.value(),
+        };
+
+        signal::sigaction {
+            // SAFETY: A function pointer has the same representation as a usize.
+            // The value is never called from kernel mode, only handed back to the user.
+            sa_sigaction: unsafe { core::mem::transmute::<usize, _>(handler) },
+            sa_restorer: unsafe { core::mem::transmute::<usize, _>(self.restorer.value()) },
+            sa_mask: self.mask.into_raw(),
+            sa_flags: self.flags as i32,
+        }
+    }
+
+    /// Returns true if the signal should be discarded when it gets delivered with this action.
+    pub fn is_ignored(&self, sig: Signal) -> bool {
+        match self.handler {
+            SignalHandler::Ignore => true,
+            SignalHandler::Default => matches!(
+                sig.default_action(),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            SignalHandler::Function(_) => false,
+        }
+    }
+}
+
+/// The signal actions of a process. These are shared between all threads.
+#[derive(Clone, Debug)]
+pub struct SignalActions {
+    actions: [SignalAction; signal::NSIG as usize],
+}
+
+impl SignalActions {
+    pub const fn new() -> Self {
+        Self {
+            actions: [SignalAction::new(); signal::NSIG as usize],
+        }
+    }
+
+    pub fn get(&self, sig: Signal) -> &SignalAction {
+        &self.actions[sig.number() as usize]
+    }
+
+    pub fn set(&mut self, sig: Signal, action: SignalAction) {
+        self.actions[sig.number() as usize] = action;
+    }
+
+    /// Resets all caught signals to their default action. Ignored signals stay ignored.
+    /// This is used when a process image is replaced.
+    pub fn reset_caught(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let SignalHandler::Function(_) = action.handler {
+                *action = SignalAction::new();
+            }
+        }
+    }
+}
+
+/// Information about the origin of a signal.
+#[derive(Clone, Copy, Debug)]
+pub struct SignalInfo {
+    pub signal: Signal,
+    /// One of the `SI_*` or signal specific codes.
+    pub code: i32,
+    /// The process which sent the signal.
+    pub pid: Pid,
+    /// The real user ID of the sending process.
+    pub uid: uapi::uid_t,
+    /// The faulting address for hardware generated signals.
+    pub addr: VirtAddr,
+    /// The exit value or signal for [`Signal::SIGCHLD`].
+    pub status: i32,
+}
+
+impl SignalInfo {
+    /// Creates a new [`SignalInfo`] without any origin.
+    pub const fn new(signal: Signal, code: i32) -> Self {
+        Self {
+            signal,
+            code,
+            pid: 0,
+            uid: 0,
+            addr: VirtAddr::null(),
+            status: 0,
+        }
+    }
+
+    /// Converts this info to its user space representation.
+    pub fn to_user(&self) -> signal::siginfo_t {
+        signal::siginfo_t {
+            si_signo: self.signal.number() as _,
+            si_code: self.code,
+            si_errno: 0,
+            si_pid: self.pid,
+            si_uid: self.uid,
+            si_addr: self.addr.value().into(),
+            si_status: self.status,
+            si_value: signal::sigval { sival_int: 0 },
+        }
+    }
+}
+
+/// A set of signals waiting for delivery.
+/// Standard signals are not queued, so at most one instance of a signal can be pending.
+#[derive(Clone)]
+pub struct PendingSignals {
+    set: SignalSet,
+    info: [Option<SignalInfo>; signal::NSIG as usize],
+}
+
+impl PendingSignals {
+    pub const fn new() -> Self {
+        Self {
+            set: SignalSet::from_raw(0),
+            info: [None; signal::NSIG as usize],
+        }
+    }
+
+    /// Returns the set of all pending signals.
+    pub fn set(&self) -> SignalSet {
+        self.set
+    }
+
+    /// Marks a signal as pending. If it was already pending, the new information is discarded.
+    pub fn push(&mut self, info: SignalInfo) {
+        if self.set.contains(info.signal) {
+            return;
+        }
+        self.set.add(info.signal);
+        self.info[info.signal.number() as usize] = Some(info);
+    }
+
+    /// Removes the lowest pending signal which is not contained in `blocked`.
+    pub fn pop(&mut self, blocked: SignalSet) -> Option<SignalInfo> {
+        let sig = self.set.difference(blocked).first()?;
+        self.take(sig)
+    }
+
+    /// Removes a specific signal from the pending set.
+    pub fn take(&mut self, sig: Signal) -> Option<SignalInfo> {
+        if !self.set.contains(sig) {
+            return None;
+        }
+        self.set.remove(sig);
+        self.info[sig.number() as usize].take()
+    }
+
+    /// Removes all pending instances of the signals in `set`.
+    pub fn discard(&mut self, set: SignalSet) {
+        while let Some(sig) = self.set.intersection(set).first() {
+            self.take(sig);
+        }
+    }
+}
+
+impl Debug for PendingSignals {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&self.set, f)
+    }
+}
+
+/// Sends a signal to a process. The signal is delivered to any thread which does not block it.
+pub fn send_to_process(proc: &Arc<Process>, info: SignalInfo) {
+    // Ignored signals are discarded immediately and never become pending.
+    if !info.signal.is_unblockable()
+        && proc
+            .signal_actions
+            .lock()
+            .get(info.signal)
+            .is_ignored(info.signal)
+    {
+        return;
+    }
+
+    // A continue signal discards any pending stop signals and vice versa.
+    let mut pending = proc.pending_signals.lock();
+    match info.signal.default_action() {
+        DefaultAction::Continue => pending.discard(stop_signals()),
+        DefaultAction::Stop => pending.discard(SignalSet::new().with(Signal::SIGCONT)),
+        _ => (),
+    }
+    pending.push(info);
+    drop(pending);
+
+    // Interrupt any thread which could handle the signal.
+    for thread in proc.threads.lock().iter() {
+        if info.signal.is_unblockable() || !thread.signal_mask.lock().contains(info.signal) {
+            Scheduler::wake(thread);
+        }
+    }
+}
+
+/// Sends a signal to a specific thread.
+pub fn send_to_task(task: &Arc<Task>, info: SignalInfo) {
+    if !info.signal.is_unblockable()
+        && task
+            .get_process()
+            .signal_actions
+            .lock()
+            .get(info.signal)
+            .is_ignored(info.signal)
+    {
+        return;
+    }
+
+    task.pending_signals.lock().push(info);
+
+    if info.signal.is_unblockable() || !task.signal_mask.lock().contains(info.signal) {
+        Scheduler::wake(task);
+    }
+}
+
+/// Sends a synchronous signal caused by the current task, e.g. because of a CPU exception.
+/// Such a signal can't be ignored or blocked. If it is, the default action is taken instead.
+pub fn send_fault(signal: Signal, code: i32, addr: VirtAddr) {
+    let task = Scheduler::get_current();
+    let proc = task.get_process();
+
+    {
+        let mut actions = proc.signal_actions.lock();
+        let mut mask = task.signal_mask.lock();
+        if mask.contains(signal) || actions.get(signal).is_ignored(signal) {
+            actions.set(signal, SignalAction::new());
+            mask.remove(signal);
+        }
+    }
+
+    task.pending_signals.lock().push(SignalInfo {
+        addr,
+        ..SignalInfo::new(signal, code)
+    });
+}
+
+fn stop_signals() -> SignalSet {
+    SignalSet::new()
+        .with(Signal::SIGSTOP)
+        .with(Signal::SIGTSTP)
+        .with(Signal::SIGTTIN)
+        .with(Signal::SIGTTOU)
+}
+
+/// Returns true if the current task has a signal pending which is not blocked.
+pub fn has_pending() -> bool {
+    let task = Scheduler::get_current();
+    let blocked = *task.signal_mask.lock();
+    let proc = task.get_process();
+
+    !task
+        .pending_signals
+        .lock()
+        .set()
+        .union(proc.pending_signals.lock().set())
+        .difference(blocked)
+        .is_empty()
+}
+
+/// Removes the next deliverable signal of the current task.
+/// Thread-directed signals take precedence over process-directed ones.
+fn next_pending(task: &Task, proc: &Process, blocked: SignalSet) -> Option<SignalInfo> {
+    if let Some(info) = task.pending_signals.lock().pop(blocked) {
+        return Some(info);
+    }
+    proc.pending_signals.lock().pop(blocked)
+}
+
+/// Delivers pending signals to the current task before it returns to user mode.
+/// This has to be called by the architecture specific code on every transition to user mode.
+/// Returns true if `context` was modified to run a signal handler.
+pub fn dispatch_pending(context: &mut Context) -> bool {
+    let task = Scheduler::get_current();
+    if !task.is_user() {
+        return false;
+    }
+
+    let proc = task.get_process();
+
+    loop {
+        let blocked = *task.signal_mask.lock();
+        let info = match next_pending(&task, &proc, blocked) {
+            Some(x) => x,
+            None => {
+                // If sigsuspend was interrupted by a signal without a handler, the mask has to be restored here.
+                if let Some(mask) = task.saved_signal_mask.lock().take() {
+                    *task.signal_mask.lock() = mask;
+                }
+                return false;
+            }
+        };
+
+        let action = *proc.signal_actions.lock().get(info.signal);
+        let handler = match action.handler {
+            _ if info.signal.is_unblockable() => SignalHandler::Default,
+            x => x,
+        };
+
+        match handler {
+            SignalHandler::Ignore => continue,
+            SignalHandler::Default => match info.signal.default_action() {
+                DefaultAction::Ignore | DefaultAction::Continue => continue,
+                // TODO: Actually stop the process once job control exists.
+                DefaultAction::Stop => continue,
+                DefaultAction::Terminate | DefaultAction::CoreDump => {
+                    proc.terminate(ProcessState::Killed(info.signal));
+                }
+            },
+            SignalHandler::Function(func) => {
+                // If sigsuspend temporarily replaced the mask, the handler has to restore the original one.
+                let old_mask = task.saved_signal_mask.lock().take().unwrap_or(blocked);
+
+                if arch::sched::push_signal_frame(
+                    context,
+                    func,
+                    action.restorer,
+                    &info.to_user(),
+                    old_mask.into_raw(),
+                )
+                .is_err()
+                {
+                    // We couldn't set up a frame on the user stack, so there's no way to continue.
+                    proc.terminate(ProcessState::Killed(Signal::SIGSEGV));
+                }
+
+                let mut new_mask = blocked.union(action.mask);
+                if action.flags & signal::SA_NODEFER == 0 {
+                    new_mask.add(info.signal);
+                }
+                *task.signal_mask.lock() = new_mask.without_unblockable();
+
+                if action.flags & signal::SA_RESETHAND != 0 {
+                    proc.signal_actions
+                        .lock()
+                        .set(info.signal, SignalAction::new());
+                }
+
+                return true;
+            }
+        }
+    }
+}
+
+/// Returns from a signal handler by restoring the context saved by [`dispatch_pending`].
+/// If the saved context is corrupted, the process is killed.
+pub fn sigreturn(context: &mut Context) {
+    let task = Scheduler::get_current();
+    match arch::sched::pop_signal_frame(context) {
+        Ok(mask) => *task.signal_mask.lock() = SignalSet::from_raw(mask).without_unblockable(),
+        Err(_) => task
+            .get_process()
+            .terminate(ProcessState::Killed(Signal::SIGSEGV)),
+    }
 }
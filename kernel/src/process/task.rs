@@ -1,14 +1,18 @@
-use super::Process;
+use super::{
+    Process,
+    signal::{PendingSignals, SignalSet},
+};
 use crate::{
     arch::{self},
-    {memory::virt::KERNEL_STACK_SIZE, posix::errno::EResult, util::mutex::spin::SpinMutex},
+    irq::lock::IrqLock,
+    sched::{Policy, cpuset::CpuSet},
+    {memory::virt::stack::KernelStack, posix::errno::EResult, util::mutex::spin::SpinMutex},
 };
-use alloc::sync::{Arc, Weak};
-use core::{
-    alloc::Layout,
-    panic,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+use alloc::{
+    string::String,
+    sync::{Arc, Weak},
 };
+use core::sync::atomic::{AtomicBool, AtomicI8, AtomicU32, AtomicUsize, Ordering};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TaskState {
@@ -33,28 +37,43 @@ pub struct Task {
     id: Tid,
     /// The process which this task belongs to.
     process: Weak<Process>,
+    /// The display name of this task.
+    pub name: SpinMutex<String>,
     /// If this task is a user task. `false` forbids this task to ever enter user mode.
     is_user: bool,
     /// The current state of the thread.
     pub state: SpinMutex<TaskState>,
+    /// The ID of the CPU which this task last ran on.
+    pub last_cpu: AtomicUsize,
+    /// Whether this task is running on a CPU or still being switched away from.
+    pub on_cpu: AtomicBool,
+    /// Whether this task is in a run queue.
+    pub queued: AtomicBool,
     /// The saved context of a task while it is not running.
     pub task_context: SpinMutex<arch::sched::TaskContext>,
     /// The kernel stack for this task.
-    pub kernel_stack: AtomicUsize,
+    pub kernel_stack: KernelStack,
     /// The user stack for this task.
     pub user_stack: AtomicUsize,
-    /// The amount of time that this task can live on.
-    pub ticks: usize,
-    /// A value between -20 and 19, where -20 is the highest priority and 0 is a neutral priority.
-    pub priority: i8,
-    pub catch_fault: AtomicBool,
+    /// The amount of timer ticks left in the time slice of this task.
+    pub ticks: AtomicUsize,
+    /// The nice value of this task. A value between -20 and 19, where -20 is the highest priority
+    /// and 0 is a neutral priority. See [`crate::sched::fair::nice_to_weight`] for the weights.
+    pub priority: AtomicI8,
+    /// The weighted execution time of this task, used by the fair scheduling class.
+    pub vruntime: AtomicUsize,
+    /// The scheduling policy of this task. Use [`Self::policy`] instead.
+    policy: AtomicU32,
+    /// The CPUs this task may run on. Use [`Self::affinity`] instead.
+    affinity: SpinMutex<CpuSet>,
+    /// Signals which are blocked from delivery to this task.
+    pub signal_mask: SpinMutex<SignalSet>,
+    /// Signals which were sent to this specific task.
+    pub pending_signals: SpinMutex<PendingSignals>,
+    /// The signal mask to restore after a signal handler ran, if it was temporarily replaced.
+    pub saved_signal_mask: SpinMutex<Option<SignalSet>>,
 }
 
-const STACK_LAYOUT: Layout = match Layout::from_size_align(KERNEL_STACK_SIZE, 0x1000) {
-    Ok(x) => x,
-    Err(_) => panic!("Layout error"),
-};
-
 impl Task {
     /// Creates a new task.
     pub fn new(
@@ -64,20 +83,28 @@ impl Task {
         parent: &Arc<Process>,
         is_user: bool,
     ) -> EResult<Self> {
-        let kernel_stack =
-            AtomicUsize::new(unsafe { alloc::alloc::alloc_zeroed(STACK_LAYOUT) as usize });
+        let kernel_stack = KernelStack::new()?;
 
         let result = Self {
             id: TASK_ID_COUNTER.fetch_add(1, Ordering::Acquire),
             is_user,
             process: Arc::downgrade(parent),
-            catch_fault: AtomicBool::new(false),
+            name: SpinMutex::new(parent.get_name().into()),
             state: SpinMutex::new(TaskState::Ready),
+            last_cpu: AtomicUsize::new(0),
+            on_cpu: AtomicBool::new(false),
+            queued: AtomicBool::new(false),
             task_context: SpinMutex::new(arch::sched::TaskContext::default()),
             kernel_stack,
             user_stack: AtomicUsize::new(0),
-            ticks: 0,
-            priority: 0,
+            ticks: AtomicUsize::new(0),
+            priority: AtomicI8::new(0),
+            vruntime: AtomicUsize::new(0),
+            policy: AtomicU32::new(Self::encode_policy(Policy::Normal)),
+            affinity: SpinMutex::new(CpuSet::all()),
+            signal_mask: SpinMutex::new(SignalSet::new()),
+            pending_signals: SpinMutex::new(PendingSignals::new()),
+            saved_signal_mask: SpinMutex::new(None),
         };
 
         {
@@ -87,7 +114,7 @@ impl Task {
                 entry,
                 arg1,
                 arg2,
-                result.kernel_stack.load(Ordering::Relaxed).into(),
+                result.kernel_stack.top(),
                 is_user,
             )?;
         }
@@ -116,6 +143,51 @@ impl Task {
             todo!()
         }
     }
+
+    /// Returns the scheduling policy of this task.
+    pub fn policy(&self) -> Policy {
+        let raw = self.policy.load(Ordering::Relaxed);
+        Policy::from_user(raw >> 8, (raw & 0xFF) as _).expect("Invalid policy in task")
+    }
+
+    /// Changes the scheduling policy of this task.
+    /// Takes effect the next time this task is queued.
+    pub fn set_policy(&self, policy: Policy) {
+        self.policy
+            .store(Self::encode_policy(policy), Ordering::Relaxed);
+    }
+
+    // The policy is stored atomically, because the scheduler reads it from interrupt context.
+    fn encode_policy(policy: Policy) -> u32 {
+        let (policy, priority) = policy.to_user();
+        policy << 8 | priority as u32
+    }
+
+    /// Returns the set of CPUs this task may run on.
+    pub fn affinity(&self) -> CpuSet {
+        // The scheduler reads the affinity from interrupt context.
+        let _irq = IrqLock::lock();
+        *self.affinity.lock()
+    }
+
+    /// Restricts the CPUs this task may run on.
+    /// If the task is currently running on a CPU that is not in `affinity`, it gets moved away
+    /// the next time it is rescheduled.
+    pub fn set_affinity(&self, affinity: CpuSet) {
+        let _irq = IrqLock::lock();
+        *self.affinity.lock() = affinity;
+    }
+
+    /// Copies the scheduling parameters of `parent` to this task.
+    pub fn inherit_scheduling(&self, parent: &Task) {
+        self.set_policy(parent.policy());
+        self.set_affinity(parent.affinity());
+        self.priority
+            .store(parent.priority.load(Ordering::Relaxed), Ordering::Relaxed);
+        // Starting at the runtime of the parent keeps tasks from gaining time by forking.
+        self.vruntime
+            .store(parent.vruntime.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
 }
 
 /// Global counter to provide new task IDs.
@@ -0,0 +1,50 @@
+//! Kernel random numbers.
+//!
+//! Numbers come from a xoshiro256** generator. It is seeded on first use from the hardware random
+//! number generator if there is one, and from the clock otherwise. This is good enough to
+//! randomize memory layouts, but must not be used for cryptography.
+
+use crate::{arch, clock, memory::virt, util::mutex::spin::SpinMutex};
+
+/// State of the generator. All zeroes means it hasn't been seeded yet.
+static STATE: SpinMutex<[u64; 4]> = SpinMutex::new([0; 4]);
+
+/// SplitMix64, which spreads a seed over the generator state.
+fn split_mix(x: &mut u64) -> u64 {
+    *x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *x;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn seed() -> [u64; 4] {
+    if arch::core::get_entropy().is_none() {
+        warn!("No hardware random number generator, seeding from the clock");
+    }
+
+    // The bootloader may have randomized the kernel image, so its address adds a bit of entropy.
+    let mut x = clock::get_elapsed() as u64 ^ (&raw const virt::LD_KERNEL_START as u64);
+    core::array::from_fn(|_| {
+        x ^= arch::core::get_entropy().unwrap_or(0);
+        split_mix(&mut x)
+    })
+}
+
+/// Returns a random 64-bit number.
+pub fn next_u64() -> u64 {
+    let mut s = STATE.lock();
+    if *s == [0; 4] {
+        *s = seed();
+    }
+
+    let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = s[1] << 17;
+    s[2] ^= s[0];
+    s[3] ^= s[1];
+    s[1] ^= s[2];
+    s[0] ^= s[3];
+    s[2] ^= t;
+    s[3] = s[3].rotate_left(45);
+    result
+}
@@ -0,0 +1,93 @@
+//! Sets of CPUs, used for affinity masks.
+
+use crate::percpu::CpuData;
+use core::sync::atomic::Ordering;
+
+/// Maximum amount of CPUs which can be represented in a [`CpuSet`].
+pub const MAX_CPUS: usize = 256;
+
+/// A set of CPU IDs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuSet {
+    bits: [u64; MAX_CPUS / 64],
+}
+
+impl CpuSet {
+    /// Size of the set in bytes, as seen by user space.
+    pub const BYTES: usize = MAX_CPUS / 8;
+
+    pub const fn empty() -> Self {
+        Self {
+            bits: [0; MAX_CPUS / 64],
+        }
+    }
+
+    pub const fn all() -> Self {
+        Self {
+            bits: [u64::MAX; MAX_CPUS / 64],
+        }
+    }
+
+    /// Returns the set of all CPUs which are online.
+    pub fn online() -> Self {
+        let mut result = Self::empty();
+        for cpu in CpuData::iter().filter(|x| x.online.load(Ordering::Acquire)) {
+            result.insert(cpu.id);
+        }
+        result
+    }
+
+    /// Creates a set which only contains `cpu`.
+    pub fn single(cpu: usize) -> Self {
+        let mut result = Self::empty();
+        result.insert(cpu);
+        result
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.bits[cpu / 64] & (1 << (cpu % 64)) != 0
+    }
+
+    pub fn insert(&mut self, cpu: usize) {
+        if cpu < MAX_CPUS {
+            self.bits[cpu / 64] |= 1 << (cpu % 64);
+        }
+    }
+
+    pub fn remove(&mut self, cpu: usize) {
+        if cpu < MAX_CPUS {
+            self.bits[cpu / 64] &= !(1 << (cpu % 64));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|x| *x == 0)
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut result = *self;
+        for (a, b) in result.bits.iter_mut().zip(other.bits.iter()) {
+            *a &= b;
+        }
+        result
+    }
+
+    /// Reads a set from a byte mask where bit `n` stands for CPU `n`.
+    /// Bits for CPUs above [`MAX_CPUS`] are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut result = Self::empty();
+        for (i, byte) in bytes.iter().take(Self::BYTES).enumerate() {
+            result.bits[i / 8] |= (*byte as u64) << ((i % 8) * 8);
+        }
+        result
+    }
+
+    /// Writes the set as a byte mask where bit `n` stands for CPU `n`.
+    pub fn to_bytes(&self) -> [u8; Self::BYTES] {
+        let mut result = [0; Self::BYTES];
+        for (i, byte) in result.iter_mut().enumerate() {
+            *byte = (self.bits[i / 8] >> ((i % 8) * 8)) as u8;
+        }
+        result
+    }
+}
@@ -0,0 +1,115 @@
+//! The fair scheduling class.
+//!
+//! Every task accumulates virtual runtime while it runs. The amount is scaled by the weight of its
+//! nice value, so tasks with a higher priority age slower. The task with the least virtual runtime
+//! runs next.
+
+use crate::process::task::{Task, Tid};
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+use core::sync::atomic::Ordering;
+
+/// Lowest possible nice value, which is the highest priority.
+pub const NICE_MIN: i8 = -20;
+/// Highest possible nice value, which is the lowest priority.
+pub const NICE_MAX: i8 = 19;
+
+/// Weights of the nice values from [`NICE_MIN`] to [`NICE_MAX`].
+/// Every step changes the share of CPU time by roughly 10%.
+const NICE_TO_WEIGHT: [usize; 40] = [
+    88761, 71755, 56483, 46273, 36291, // -20
+    29154, 23254, 18705, 14949, 11916, // -15
+    9548, 7620, 6100, 4904, 3906, // -10
+    3121, 2501, 1991, 1586, 1277, // -5
+    1024, 820, 655, 526, 423, // 0
+    335, 272, 215, 172, 137, // 5
+    110, 87, 70, 56, 45, // 10
+    36, 29, 23, 18, 15, // 15
+];
+
+/// The weight of a task with a nice value of 0.
+const NICE_0_WEIGHT: usize = NICE_TO_WEIGHT[20];
+
+/// How much virtual runtime a waking task may have less than the queue.
+/// This lets interactive tasks which mostly sleep run ahead of busy ones.
+const SLEEPER_CREDIT_NS: usize = 20_000_000;
+
+/// Minimum difference in virtual runtime before a waking task preempts the running one.
+pub const WAKEUP_GRANULARITY_NS: usize = 5_000_000;
+
+/// Returns the weight of a nice value.
+pub fn nice_to_weight(nice: i8) -> usize {
+    NICE_TO_WEIGHT[(nice.clamp(NICE_MIN, NICE_MAX) - NICE_MIN) as usize]
+}
+
+/// Charges `delta` nanoseconds of execution time to `task`.
+pub fn charge(task: &Task, delta: usize) {
+    let weight = nice_to_weight(task.priority.load(Ordering::Relaxed));
+    task.vruntime
+        .fetch_add(delta * NICE_0_WEIGHT / weight, Ordering::Relaxed);
+}
+
+/// Runnable tasks ordered by their virtual runtime.
+#[derive(Debug)]
+pub struct FairQueue {
+    tasks: BTreeMap<(usize, Tid), Arc<Task>>,
+    /// Monotonically increasing lower bound for the virtual runtime of all tasks in this queue.
+    min_vruntime: usize,
+}
+
+impl FairQueue {
+    pub const fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    pub fn min_vruntime(&self) -> usize {
+        self.min_vruntime
+    }
+
+    /// Returns the smallest virtual runtime of all queued tasks.
+    pub fn first_vruntime(&self) -> Option<usize> {
+        self.tasks.first_key_value().map(|((x, _), _)| *x)
+    }
+
+    /// Adds a task to the queue.
+    pub fn push(&mut self, task: Arc<Task>) {
+        // Tasks which slept for a long time or are new to this queue don't get to catch up
+        // on all the time they didn't run.
+        let floor = self.min_vruntime.saturating_sub(SLEEPER_CREDIT_NS);
+        let vruntime = task.vruntime.load(Ordering::Relaxed).max(floor);
+        task.vruntime.store(vruntime, Ordering::Relaxed);
+
+        self.tasks.insert((vruntime, task.get_id()), task);
+    }
+
+    /// Removes the task with the least virtual runtime from the queue.
+    pub fn pop(&mut self) -> Option<Arc<Task>> {
+        self.tasks.pop_first().map(|(_, task)| task)
+    }
+
+    /// Removes the task with the most virtual runtime which satisfies `filter`.
+    pub fn steal(&mut self, filter: impl Fn(&Task) -> bool) -> Option<Arc<Task>> {
+        let key = *self.tasks.iter().rev().find(|(_, x)| filter(x))?.0;
+        self.tasks.remove(&key)
+    }
+
+    /// Advances the minimum virtual runtime. `current` is the virtual runtime of the running task.
+    pub fn update_min(&mut self, current: Option<usize>) {
+        let min = match (current, self.first_vruntime()) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(x), None) | (None, Some(x)) => x,
+            (None, None) => return,
+        };
+        self.min_vruntime = self.min_vruntime.max(min);
+    }
+}
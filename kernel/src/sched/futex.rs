@@ -0,0 +1,173 @@
+//! Fast user space mutexes.
+//!
+//! A futex in private memory is identified by the address space and the virtual address of the
+//! futex word. A futex in a shared mapping is identified by the mapped object and the offset of
+//! the word in it instead, so it's the same futex for all processes which map it. Neither key
+//! changes when the page is swapped out or copied on write.
+
+use crate::{
+    arch, clock,
+    memory::{MemoryObject, VirtAddr, user::UserPtr, virt::VmFlags},
+    posix::errno::{EResult, Errno},
+    process::signal,
+    sched::Scheduler,
+    util::{
+        event::{Event, EventGuard},
+        mutex::spin::SpinMutex,
+    },
+};
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
+use core::cmp::Ordering;
+
+/// Number of buckets in the futex hash table.
+const NUM_BUCKETS: usize = 64;
+
+/// Identifies a futex word independently of the page which holds it right now.
+#[derive(Clone)]
+enum FutexKey {
+    /// A word in private memory, by the address of its address space and its virtual address.
+    Private(usize, VirtAddr),
+    /// A word in a shared mapping, by the mapped object and the offset of the word in it.
+    /// Holding the object keeps its address from being reused while a task waits on it.
+    Shared(Arc<dyn MemoryObject>, usize),
+}
+
+impl FutexKey {
+    /// Returns the key as plain numbers, for comparisons and hashing.
+    fn id(&self) -> (bool, usize, usize) {
+        match self {
+            Self::Private(space, addr) => (false, *space, addr.value()),
+            Self::Shared(object, offset) => {
+                (true, Arc::as_ptr(object).cast::<()>().addr(), *offset)
+            }
+        }
+    }
+}
+
+impl PartialEq for FutexKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for FutexKey {}
+
+impl PartialOrd for FutexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FutexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.id().cmp(&other.id())
+    }
+}
+
+/// A bucket in the futex hash table. Maps the key of a futex word to its wait queue.
+type Bucket = SpinMutex<BTreeMap<FutexKey, Arc<Event>>>;
+
+static FUTEX_TABLE: [Bucket; NUM_BUCKETS] = [const { SpinMutex::new(BTreeMap::new()) }; _];
+
+fn get_bucket(key: &FutexKey) -> &'static Bucket {
+    // Futex words are 4 byte aligned, so the lowest bits are always zero.
+    let (_, base, offset) = key.id();
+    &FUTEX_TABLE[((base >> 4) ^ (offset >> 2)) % NUM_BUCKETS]
+}
+
+/// Translates the address of a futex word in the current address space to its key.
+fn get_key(addr: VirtAddr) -> EResult<FutexKey> {
+    if !addr.value().is_multiple_of(size_of::<u32>()) {
+        return Err(Errno::EINVAL);
+    }
+
+    let page_size = arch::virt::get_page_size();
+    let page = addr.value() / page_size;
+    let proc = Scheduler::get_current().get_process();
+    let space = proc.address_space.lock();
+    let mapping = space
+        .mappings
+        .iter()
+        .find(|x| (x.start_page..x.end_page).contains(&page))
+        .ok_or(Errno::EFAULT)?;
+
+    Ok(match mapping.get_flags().contains(VmFlags::Shared) {
+        true => FutexKey::Shared(
+            mapping.object.clone(),
+            (page - mapping.start_page + mapping.offset_page) * page_size
+                + addr.value() % page_size,
+        ),
+        false => FutexKey::Private(Arc::as_ptr(&proc.address_space).addr(), addr),
+    })
+}
+
+/// Removes the wait queue of `key` from the table if nobody is waiting on it anymore.
+fn cleanup(bucket: &Bucket, key: &FutexKey) {
+    let mut queues = bucket.lock();
+    if queues.get(key).is_some_and(|x| x.is_empty()) {
+        queues.remove(key);
+    }
+}
+
+/// Blocks the current task as long as the futex word at `addr` contains `expected`.
+/// If `timeout` is given, the wait is aborted with [`Errno::ETIMEDOUT`] after that many nanoseconds.
+pub fn wait(addr: VirtAddr, expected: u32, timeout: Option<usize>) -> EResult<()> {
+    let deadline = timeout.map(|x| clock::get_elapsed().saturating_add(x));
+    let key = get_key(addr)?;
+    let bucket = get_bucket(&key);
+
+    // Join the queue before checking the value. A waker always takes the bucket lock after changing
+    // the value, so it either finds us in the queue or we see the new value.
+    let mut queues = bucket.lock();
+    let queue = queues
+        .entry(key.clone())
+        .or_insert_with(|| Arc::new(Event::new()))
+        .clone();
+    let guard = queue.guard();
+    drop(queues);
+
+    // The value is read without the bucket lock, since faulting the page in may sleep.
+    let result = match UserPtr::<u32>::new(addr).read() {
+        None => Err(Errno::EFAULT),
+        Some(x) if x != expected => Err(Errno::EAGAIN),
+        // Sleeping in the fault handler resets our state. If we were woken meanwhile, we're done.
+        Some(_) if !guard.rearm() => Ok(()),
+        Some(_) => block(&guard, deadline),
+    };
+    drop(guard);
+
+    cleanup(bucket, &key);
+    result
+}
+
+/// Waits until `guard` is woken up, the deadline has passed or a signal arrives.
+fn block(guard: &EventGuard, deadline: Option<usize>) -> EResult<()> {
+    match deadline {
+        None => guard.wait(),
+        Some(deadline) => {
+            if !guard.wait_until(deadline) {
+                return Err(Errno::ETIMEDOUT);
+            }
+        }
+    }
+
+    match signal::has_pending() {
+        true => Err(Errno::EINTR),
+        false => Ok(()),
+    }
+}
+
+/// Wakes up to `count` tasks waiting on the futex word at `addr`.
+/// Returns the amount of tasks that were woken up.
+pub fn wake(addr: VirtAddr, count: usize) -> EResult<usize> {
+    let key = get_key(addr)?;
+    let bucket = get_bucket(&key);
+
+    let woken = match bucket.lock().get(&key) {
+        Some(queue) => queue.wake(count),
+        None => 0,
+    };
+
+    cleanup(bucket, &key);
+    Ok(woken)
+}
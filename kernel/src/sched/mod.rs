@@ -1,19 +1,173 @@
+pub mod cpuset;
+pub mod fair;
+pub mod futex;
+pub mod realtime;
+
 use crate::{
     arch::{self},
+    clock,
     irq::lock::{IrqGuard, IrqLock},
+    memory::virt::mmu,
     percpu::{CPU_DATA, CpuData},
+    posix::errno::{EResult, Errno},
     process::{
-        Process,
-        task::{Task, TaskState},
+        Process, signal,
+        task::{Task, TaskState, Tid},
     },
+    uapi::sched::{SCHED_FIFO, SCHED_OTHER, SCHED_RR},
     util::mutex::spin::SpinMutex,
 };
-use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+use alloc::{collections::btree_map::BTreeMap, sync::Arc};
 use core::{
+    marker::PhantomData,
     mem,
-    ptr::null_mut,
-    sync::atomic::{AtomicPtr, Ordering},
+    ptr::{self, null_mut},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
 };
+use cpuset::CpuSet;
+use fair::FairQueue;
+use realtime::{RT_PRIORITY_MAX, RT_PRIORITY_MIN, RealtimeQueue};
+
+/// The amount of timer ticks a task may run before it gets preempted.
+pub const TIME_SLICE: usize = 3;
+
+/// The amount of timer ticks between attempts to balance the load with other CPUs.
+const BALANCE_INTERVAL: usize = 10;
+
+/// The scheduling policy of a task.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Shares the CPU with other normal tasks according to the nice value.
+    Normal,
+    /// Real-time task which runs until it blocks or yields.
+    Fifo(u8),
+    /// Real-time task which shares the CPU with tasks of the same priority in time slices.
+    RoundRobin(u8),
+}
+
+impl Policy {
+    /// Converts a policy and priority as passed by user space.
+    pub fn from_user(policy: u32, priority: i32) -> EResult<Self> {
+        let rt_priority = || match u8::try_from(priority) {
+            Ok(x) if (RT_PRIORITY_MIN..=RT_PRIORITY_MAX).contains(&x) => Ok(x),
+            _ => Err(Errno::EINVAL),
+        };
+
+        match policy {
+            SCHED_OTHER if priority == 0 => Ok(Self::Normal),
+            SCHED_FIFO => Ok(Self::Fifo(rt_priority()?)),
+            SCHED_RR => Ok(Self::RoundRobin(rt_priority()?)),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    /// Converts the policy to a policy and priority as seen by user space.
+    pub fn to_user(self) -> (u32, i32) {
+        match self {
+            Self::Normal => (SCHED_OTHER, 0),
+            Self::Fifo(x) => (SCHED_FIFO, x as _),
+            Self::RoundRobin(x) => (SCHED_RR, x as _),
+        }
+    }
+
+    /// Returns the priority of a real-time policy.
+    pub fn rt_priority(self) -> Option<u8> {
+        match self {
+            Self::Normal => None,
+            Self::Fifo(x) | Self::RoundRobin(x) => Some(x),
+        }
+    }
+}
+
+/// The runnable tasks of a CPU, split by scheduling class.
+#[derive(Debug)]
+struct RunQueue {
+    realtime: RealtimeQueue,
+    fair: FairQueue,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            realtime: RealtimeQueue::new(),
+            fair: FairQueue::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.realtime.len() + self.fair.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.realtime.is_empty() && self.fair.is_empty()
+    }
+
+    /// Adds a task to the queue. Returns false if it is queued already, here or on another CPU.
+    fn push(&mut self, task: Arc<Task>) -> bool {
+        if task.queued.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        match task.policy().rt_priority() {
+            Some(priority) => self.realtime.push(task, priority),
+            None => self.fair.push(task),
+        }
+        true
+    }
+
+    /// Removes the next task to run. Real-time tasks always come first.
+    fn pop(&mut self) -> Option<Arc<Task>> {
+        let task = self.realtime.pop().or_else(|| self.fair.pop())?;
+        task.queued.store(false, Ordering::Release);
+        Some(task)
+    }
+
+    /// Removes a task which can be moved to the CPU `cpu`.
+    fn steal(&mut self, cpu: usize) -> Option<Arc<Task>> {
+        let filter = |task: &Task| can_migrate(task, cpu);
+        let task = self
+            .realtime
+            .steal(filter)
+            .or_else(|| self.fair.steal(filter))?;
+        task.queued.store(false, Ordering::Release);
+        Some(task)
+    }
+
+    /// Removes `task` from the queue. Returns false if it wasn't queued here.
+    fn remove(&mut self, task: &Task) -> bool {
+        let filter = |x: &Task| ptr::eq(x, task);
+        let Some(task) = self
+            .realtime
+            .steal(filter)
+            .or_else(|| self.fair.steal(filter))
+        else {
+            return false;
+        };
+        task.queued.store(false, Ordering::Release);
+        true
+    }
+}
+
+/// Returns true if `task` may be moved to the CPU `cpu`.
+fn can_migrate(task: &Task, cpu: usize) -> bool {
+    // A task which is still running can't be moved, or it would run on two CPUs at once.
+    !task.on_cpu.load(Ordering::Acquire)
+        && *task.state.lock() == TaskState::Ready
+        && task.affinity().contains(cpu)
+}
+
+/// Returns true if `new` should preempt `current` when it becomes runnable.
+fn should_preempt(new: &Task, current: &Task) -> bool {
+    match (new.policy().rt_priority(), current.policy().rt_priority()) {
+        (Some(new), Some(current)) => new > current,
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        // Preempt the current task if the new one has run considerably less.
+        (None, None) => {
+            new.vruntime.load(Ordering::Relaxed) + fair::WAKEUP_GRANULARITY_NS
+                < current.vruntime.load(Ordering::Relaxed)
+        }
+    }
+}
 
 /// An instance of a scheduler. Each CPU has one instance running to coordinate task management.
 #[derive(Debug)]
@@ -22,7 +176,19 @@ pub struct Scheduler {
     pub(crate) current: AtomicPtr<Task>,
     pub(crate) idle_task: AtomicPtr<Task>,
     pub(crate) preempt_level: usize,
-    run_queue: SpinMutex<VecDeque<Arc<Task>>>,
+    /// Set if the current task should be preempted as soon as possible.
+    need_resched: AtomicBool,
+    run_queue: SpinMutex<RunQueue>,
+    /// The time at which the current task was last charged for its execution time.
+    exec_start: AtomicUsize,
+    /// Tasks blocked on this CPU with a deadline, ordered by the deadline.
+    timers: SpinMutex<BTreeMap<(usize, Tid), Arc<Task>>>,
+    /// The amount of timer ticks on this CPU.
+    tick_count: AtomicUsize,
+    /// The task which was running before the last task switch.
+    prev_task: AtomicPtr<Task>,
+    /// A task which has to be moved to another CPU once it is switched away from.
+    migrate_prev: SpinMutex<Option<Arc<Task>>>,
 }
 
 impl Scheduler {
@@ -31,23 +197,44 @@ impl Scheduler {
             current: AtomicPtr::new(null_mut()),
             idle_task: AtomicPtr::new(null_mut()),
             preempt_level: 0,
-            run_queue: SpinMutex::new(VecDeque::new()),
+            need_resched: AtomicBool::new(false),
+            run_queue: SpinMutex::new(RunQueue::new()),
+            exec_start: AtomicUsize::new(0),
+            timers: SpinMutex::new(BTreeMap::new()),
+            tick_count: AtomicUsize::new(0),
+            prev_task: AtomicPtr::new(null_mut()),
+            migrate_prev: SpinMutex::new(None),
         };
     }
 
     /// Adds a task to a run queue.
     pub fn add_task(&self, task: Arc<Task>) {
-        self.run_queue.lock().push_back(task);
+        let _irq = IrqLock::lock();
+        if !self.run_queue.lock().push(task.clone()) {
+            return;
+        }
+
+        let current = self.current.load(Ordering::Acquire);
+        if current.is_null() {
+            return;
+        }
+        if current == self.idle_task.load(Ordering::Acquire)
+            || should_preempt(&task, unsafe { &*current })
+        {
+            self.request_reschedule();
+        }
     }
 
-    /// Adds a task to the run queue of the CPU with the lowest load.
+    /// Adds a task to the run queue of the CPU with the lowest load which the task may run on.
     /// This is used for new process creation to balance load across CPUs.
     pub fn add_task_to_best_cpu(task: Arc<Task>) {
+        let _irq = IrqLock::lock();
+        let affinity = task.affinity();
         let mut min_load = usize::MAX;
         let mut least_loaded_cpu = CpuData::get();
 
         // Find the CPU with the minimum runqueue length
-        for cpu_data in CpuData::iter() {
+        for cpu_data in CpuData::iter().filter(|x| affinity.contains(x.id)) {
             let load = cpu_data.scheduler.run_queue.lock().len();
             if load < min_load {
                 min_load = load;
@@ -57,8 +244,107 @@ impl Scheduler {
         least_loaded_cpu.scheduler.add_task(task);
     }
 
+    /// Adds a task that became runnable to the run queue of the CPU that suits it best.
+    /// This is usually the CPU it ran on last, unless another CPU is idle.
+    pub fn enqueue(task: Arc<Task>) {
+        let _irq = IrqLock::lock();
+        let last = CpuData::get_for(task.last_cpu.load(Ordering::Acquire))
+            .expect("Task ran on a CPU that doesn't exist");
+
+        let affinity = task.affinity();
+
+        // The task might still be running on its last CPU if it didn't get to block yet.
+        // Queueing it there guarantees that it's never running on two CPUs at once.
+        if task.on_cpu.load(Ordering::Acquire)
+            || (affinity.contains(last.id) && last.scheduler.is_idle())
+        {
+            last.scheduler.add_task(task);
+            return;
+        }
+
+        // Waiting behind other tasks is worse than losing a warm cache.
+        let allowed = affinity.intersection(&CpuSet::online());
+        let target = CpuData::iter()
+            .find(|x| allowed.contains(x.id) && x.scheduler.is_idle())
+            .or_else(|| allowed.contains(last.id).then_some(last))
+            .or_else(|| CpuData::iter().find(|x| allowed.contains(x.id)))
+            .unwrap_or(last);
+        if !ptr::eq(target, last) {
+            Scheduler::migrate(&task, &last.scheduler, &target.scheduler);
+        }
+        target.scheduler.add_task(task);
+    }
+
+    /// Changes the affinity of `task` and moves it away from its CPU if it may not run there anymore.
+    /// This is also used to bind kernel tasks to a CPU.
+    pub fn set_affinity(task: &Arc<Task>, affinity: CpuSet) {
+        task.set_affinity(affinity);
+
+        // A queued task is moved to a run queue where it may run right away.
+        {
+            let _irq = IrqLock::lock();
+            let queued = CpuData::iter()
+                .filter(|x| !affinity.contains(x.id))
+                .any(|x| x.scheduler.run_queue.lock().remove(task));
+            if queued {
+                Scheduler::enqueue(task.clone());
+                return;
+            }
+        }
+
+        let cpu = task.last_cpu.load(Ordering::Acquire);
+        if affinity.contains(cpu) || !task.on_cpu.load(Ordering::Acquire) {
+            return;
+        }
+
+        if Arc::ptr_eq(task, &Scheduler::get_current()) {
+            CpuData::get().scheduler.reschedule();
+        } else if let Some(cpu) = CpuData::get_for(cpu) {
+            cpu.scheduler.request_reschedule();
+        }
+    }
+
+    /// Returns true if this CPU has nothing to do.
+    fn is_idle(&self) -> bool {
+        self.current.load(Ordering::Acquire) == self.idle_task.load(Ordering::Acquire)
+            && self.run_queue.lock().is_empty()
+    }
+
+    /// Adjusts the virtual runtime of a task moving between the run queues of two CPUs.
+    fn migrate(task: &Task, from: &Scheduler, to: &Scheduler) {
+        let from_min = from.run_queue.lock().fair.min_vruntime();
+        let to_min = to.run_queue.lock().fair.min_vruntime();
+        let vruntime = task
+            .vruntime
+            .load(Ordering::Relaxed)
+            .saturating_sub(from_min);
+        task.vruntime.store(vruntime + to_min, Ordering::Relaxed);
+    }
+
+    /// Takes a task from the busiest CPU if it has at least `threshold` more queued tasks
+    /// than this one. This must only be called on the scheduler of the current CPU.
+    fn steal(&self, threshold: usize) -> Option<Arc<Task>> {
+        let id = CPU_DATA.get().id;
+        let own = self.run_queue.lock().len();
+        let busiest = CpuData::iter()
+            .filter(|x| x.online.load(Ordering::Acquire) && !ptr::eq(&x.scheduler, self))
+            .max_by_key(|x| x.scheduler.run_queue.lock().len())?;
+
+        let task = {
+            let mut queue = busiest.scheduler.run_queue.lock();
+            if queue.len() < own + threshold {
+                return None;
+            }
+            queue.steal(id)?
+        };
+
+        Scheduler::migrate(&task, &busiest.scheduler, self);
+        Some(task)
+    }
+
     /// Returns the task currently running on this CPU.
     pub fn get_current() -> Arc<Task> {
+        let _preempt = PreemptGuard::new();
         let ptr = CPU_DATA.get().scheduler.current.load(Ordering::Acquire);
         debug_assert!(!ptr.is_null());
 
@@ -69,15 +355,56 @@ impl Scheduler {
         result
     }
 
+    /// Removes the next task which may run on this CPU from the run queue.
+    /// Tasks which may not run here right now are moved to another CPU.
     fn next(&self) -> Option<Arc<Task>> {
-        let mut queue = self.run_queue.lock();
-        while let Some(x) = &queue.pop_front() {
-            let inner = x.state.lock();
-            if *inner == TaskState::Ready {
-                return Some(x.clone());
+        let id = CPU_DATA.get().id;
+        loop {
+            let task = {
+                let mut queue = self.run_queue.lock();
+                loop {
+                    let task = queue.pop()?;
+                    if *task.state.lock() == TaskState::Ready {
+                        break task;
+                    }
+                }
+            };
+
+            // A task which is still being switched away from on another CPU can't run here yet.
+            // It's queued on that CPU instead, which picks it up once the switch is done.
+            if task.on_cpu.load(Ordering::Acquire) && task.last_cpu.load(Ordering::Acquire) != id {
+                Scheduler::enqueue(task);
+                continue;
             }
+
+            // A task whose CPUs all went offline has to run somewhere.
+            let affinity = task.affinity();
+            if affinity.contains(id) || affinity.intersection(&CpuSet::online()).is_empty() {
+                return Some(task);
+            }
+            Scheduler::enqueue(task);
+        }
+    }
+
+    /// Charges the time since the last update to the current task.
+    fn update_current(&self) {
+        let now = clock::get_elapsed();
+        let delta = now.saturating_sub(self.exec_start.swap(now, Ordering::Relaxed));
+
+        let current = self.current.load(Ordering::Acquire);
+        let mut queue = self.run_queue.lock();
+        if current.is_null()
+            || current == self.idle_task.load(Ordering::Acquire)
+            || unsafe { (*current).policy() } != Policy::Normal
+        {
+            queue.fair.update_min(None);
+        } else {
+            let task = unsafe { &*current };
+            fair::charge(task, delta);
+            queue
+                .fair
+                .update_min(Some(task.vruntime.load(Ordering::Relaxed)));
         }
-        None
     }
 
     /// Puts the current task back to the run queue and reschedules.
@@ -85,18 +412,184 @@ impl Scheduler {
         let lock = IrqLock::lock();
         let from = self.current.load(Ordering::Acquire);
 
+        // The task is queued according to its runtime, so it has to be up to date.
+        self.update_current();
+
         if from != self.idle_task.load(Ordering::Acquire) {
-            self.add_task(unsafe {
+            let task = unsafe {
                 let task = Arc::from_raw(from);
                 let result = task.clone();
                 mem::forget(task);
                 result
-            });
+            };
+
+            // A task which is about to block stays runnable if it gets preempted.
+            // To the waiter, this looks like a spurious wakeup.
+            {
+                let mut state = task.state.lock();
+                if *state == TaskState::Waiting {
+                    *state = TaskState::Ready;
+                }
+            }
+
+            // If the task may not run here anymore, it's moved once the switch is done.
+            match task.affinity().contains(CPU_DATA.get().id) {
+                true => self.add_task(task),
+                false => *self.migrate_prev.lock() = Some(task),
+            }
+        }
+
+        self.do_reschedule(lock);
+    }
+
+    /// Blocks the current task until it is woken up by [`Scheduler::wake`].
+    /// The caller has to set the state of the current task to [`TaskState::Waiting`] first.
+    /// Returns immediately if the task was already woken up in the meantime.
+    pub fn block(&self) {
+        let lock = IrqLock::lock();
+        if *Scheduler::get_current().state.lock() != TaskState::Waiting {
+            return;
         }
 
         self.do_reschedule(lock);
     }
 
+    /// Like [`Scheduler::block`], but also wakes the task up once `deadline` has passed.
+    /// The deadline is given in nanoseconds since boot.
+    /// Returns true if the deadline has passed.
+    pub fn block_until(&self, deadline: usize) -> bool {
+        let task = Scheduler::get_current();
+        let key = (deadline, task.get_id());
+
+        let lock = IrqLock::lock();
+        {
+            let mut state = task.state.lock();
+            if *state != TaskState::Waiting {
+                return clock::get_elapsed() >= deadline;
+            }
+            if clock::get_elapsed() >= deadline {
+                *state = TaskState::Ready;
+                return true;
+            }
+        }
+
+        self.timers.lock().insert(key, task);
+        self.do_reschedule(lock);
+
+        // If we were woken up before the deadline, the timer is still queued.
+        {
+            let _irq = IrqLock::lock();
+            self.timers.lock().remove(&key);
+        }
+
+        clock::get_elapsed() >= deadline
+    }
+
+    /// Puts the current task to sleep until `deadline` has passed.
+    /// The deadline is given in nanoseconds since boot.
+    /// Returns [`Errno::EINTR`] if the sleep was interrupted by a signal.
+    pub fn sleep_until(deadline: usize) -> EResult<()> {
+        let task = Scheduler::get_current();
+        while clock::get_elapsed() < deadline {
+            // Check for signals after changing the state, so a signal sent in between can't get lost.
+            *task.state.lock() = TaskState::Waiting;
+            if signal::has_pending() {
+                *task.state.lock() = TaskState::Ready;
+                return Err(Errno::EINTR);
+            }
+
+            CpuData::get().scheduler.block_until(deadline);
+        }
+        Ok(())
+    }
+
+    /// Wakes up all tasks on this CPU whose deadline has passed.
+    /// This is called by the timer interrupt.
+    pub fn expire_timers(&self) {
+        let now = clock::get_elapsed();
+        let mut timers = self.timers.lock();
+        while let Some(entry) = timers.first_entry()
+            && entry.key().0 <= now
+        {
+            Scheduler::wake(&entry.remove());
+        }
+    }
+
+    /// Charges a timer tick to the current task and requests a reschedule once its time slice is
+    /// used up. This is called by the timer interrupt.
+    pub fn tick(&self) {
+        self.expire_timers();
+        self.update_current();
+
+        // Periodically pull work from CPUs which have a lot more to do.
+        if self.tick_count.fetch_add(1, Ordering::Relaxed) % BALANCE_INTERVAL == 0
+            && let Some(task) = self.steal(2)
+        {
+            self.add_task(task);
+        }
+
+        let current = self.current.load(Ordering::Acquire);
+        if current.is_null() {
+            return;
+        }
+        if current == self.idle_task.load(Ordering::Acquire) {
+            // Leave the idle task as soon as there is something to do.
+            if !self.run_queue.lock().is_empty() {
+                self.request_reschedule();
+            }
+            return;
+        }
+
+        let task = unsafe { &*current };
+        // FIFO tasks don't have a time slice.
+        if let Policy::Fifo(_) = task.policy() {
+            return;
+        }
+
+        let ticks = task.ticks.load(Ordering::Relaxed).saturating_sub(1);
+        task.ticks.store(ticks, Ordering::Relaxed);
+        if ticks == 0 {
+            self.request_reschedule();
+        }
+    }
+
+    /// Requests a reschedule on the next return from an interrupt.
+    pub fn request_reschedule(&self) {
+        self.need_resched.store(true, Ordering::Release);
+    }
+
+    /// Reschedules if it was requested and preemption is enabled.
+    /// This has to be called on return from an interrupt or system call. `to_user` tells whether
+    /// it returns to user mode. Kernel code might hold spinlocks, so it's only preempted while idle.
+    pub fn preempt(&self, to_user: bool) {
+        if !to_user
+            && self.current.load(Ordering::Acquire) != self.idle_task.load(Ordering::Acquire)
+        {
+            return;
+        }
+
+        unsafe { arch::sched::preempt_disable() };
+        if unsafe { arch::sched::preempt_enable() } && self.need_resched.load(Ordering::Acquire) {
+            self.reschedule();
+        }
+    }
+
+    /// Wakes up a task which is in the [`TaskState::Waiting`] state.
+    /// Returns false if the task wasn't waiting.
+    pub fn wake(task: &Arc<Task>) -> bool {
+        let _irq = IrqLock::lock();
+        {
+            let mut state = task.state.lock();
+            if *state != TaskState::Waiting {
+                return false;
+            }
+            *state = TaskState::Ready;
+        }
+
+        Scheduler::enqueue(task.clone());
+        true
+    }
+
     /// Reschedules without adding the current task back to the run queue.
     pub fn do_yield(&self) {
         let lock = IrqLock::lock();
@@ -105,47 +598,78 @@ impl Scheduler {
 
     /// Runs the scheduler.
     fn do_reschedule(&self, irq_guard: IrqGuard) {
+        self.update_current();
+
         let from = self.current.load(Ordering::Acquire);
+        // Rather than going idle, try to take over work from another CPU.
         let to = self
             .next()
+            .or_else(|| self.steal(1))
             .map(|task| Arc::into_raw(task) as *mut _)
             .unwrap_or(self.idle_task.load(Ordering::Acquire));
 
+        // The next task starts with a fresh time slice.
+        self.need_resched.store(false, Ordering::Release);
+        unsafe { (*to).ticks.store(TIME_SLICE, Ordering::Relaxed) };
+
         if from == to {
             return;
         }
 
         self.current.store(to, Ordering::Relaxed);
+        self.prev_task.store(from, Ordering::Release);
+        unsafe {
+            (*to).on_cpu.store(true, Ordering::Release);
+            (*to).last_cpu.store(CPU_DATA.get().id, Ordering::Release);
+        }
 
         unsafe {
             let to_proc = (*to).get_process();
 
             // If we are switching between address spaces, we need to update the page table.
+            // Interrupts are disabled here, so another CPU might wait for us to handle a shootdown.
             // TODO: This is very ugly.
-            to_proc.address_space.lock().table.set_active();
+            mmu::lock_responsive(&to_proc.address_space)
+                .table
+                .activate();
 
             let cpu = CPU_DATA.get();
 
             {
-                // Save the current kernel and user stack pointers to the old task.
-                (*from)
-                    .kernel_stack
-                    .store(cpu.kernel_stack.load(Ordering::Acquire), Ordering::Release);
+                // Save the current user stack pointer to the old task.
                 (*from)
                     .user_stack
                     .store(cpu.user_stack.load(Ordering::Acquire), Ordering::Release);
 
                 // Get the kernel and user stack pointers from the new task and write them to the per-CPU data.
-                cpu.kernel_stack.store(
-                    (*to).kernel_stack.load(Ordering::Acquire),
-                    Ordering::Release,
-                );
+                // Every entry into the kernel starts at the top of the kernel stack.
+                cpu.kernel_stack
+                    .store((*to).kernel_stack.top().value(), Ordering::Release);
                 cpu.user_stack
                     .store((*to).user_stack.load(Ordering::Acquire), Ordering::Release);
             }
 
             arch::sched::switch(from, to, irq_guard);
         }
+
+        // We might have been moved to another CPU in the meantime.
+        CPU_DATA.get().scheduler.finish_switch();
+    }
+
+    /// Completes a task switch. This runs on the stack of the task that was switched to.
+    fn finish_switch(&self) {
+        // The previous task is now completely off this CPU and may run somewhere else.
+        let prev = self.prev_task.swap(null_mut(), Ordering::AcqRel);
+        if !prev.is_null() {
+            unsafe { (*prev).on_cpu.store(false, Ordering::Release) };
+        }
+
+        if let Some(task) = self.migrate_prev.lock().take() {
+            Scheduler::enqueue(task);
+        }
+
+        // Interrupts were kept disabled by the switch, see [`arch::sched::switch`].
+        unsafe { IrqLock::force_unlock() };
     }
 
     /// Kills the currently running task.
@@ -165,8 +689,31 @@ impl Scheduler {
     }
 }
 
+/// Disables preemption on the current CPU as long as the guard is alive.
+/// A reschedule requested in the meantime is performed on the next return from an interrupt.
+pub struct PreemptGuard {
+    // The guard must not leave the CPU it was created on.
+    _p: PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        unsafe { arch::sched::preempt_disable() };
+        Self { _p: PhantomData }
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        unsafe { arch::sched::preempt_enable() };
+    }
+}
+
 /// Generic task entry point. This is to be called by an implementing [`crate::arch::sched::init_task`].
 pub extern "C" fn task_entry(entry: extern "C" fn(usize, usize), arg1: usize, arg2: usize) -> ! {
+    // New tasks don't return from a switch, so they have to finish it here.
+    CPU_DATA.get().scheduler.finish_switch();
+
     (entry)(arg1, arg2);
 
     // The task function is over, kill the task.
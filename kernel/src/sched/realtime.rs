@@ -0,0 +1,61 @@
+//! The real-time scheduling classes.
+//!
+//! Real-time tasks have a fixed priority and always run before tasks of the fair class.
+//! Among themselves, the task with the highest priority runs first.
+
+use crate::process::task::Task;
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+
+/// Lowest priority of a real-time task.
+pub const RT_PRIORITY_MIN: u8 = 1;
+/// Highest priority of a real-time task.
+pub const RT_PRIORITY_MAX: u8 = 99;
+
+/// Runnable real-time tasks, with one FIFO queue per priority level.
+#[derive(Debug)]
+pub struct RealtimeQueue {
+    queues: [VecDeque<Arc<Task>>; RT_PRIORITY_MAX as usize],
+    len: usize,
+}
+
+impl RealtimeQueue {
+    pub const fn new() -> Self {
+        Self {
+            queues: [const { VecDeque::new() }; _],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a task to the end of the queue for `priority`.
+    pub fn push(&mut self, task: Arc<Task>, priority: u8) {
+        let priority = priority.clamp(RT_PRIORITY_MIN, RT_PRIORITY_MAX);
+        self.queues[(priority - RT_PRIORITY_MIN) as usize].push_back(task);
+        self.len += 1;
+    }
+
+    /// Removes the first task with the highest priority from the queue.
+    pub fn pop(&mut self) -> Option<Arc<Task>> {
+        let task = self.queues.iter_mut().rev().find_map(|x| x.pop_front())?;
+        self.len -= 1;
+        Some(task)
+    }
+
+    /// Removes the first task with the highest priority which satisfies `filter`.
+    pub fn steal(&mut self, filter: impl Fn(&Task) -> bool) -> Option<Arc<Task>> {
+        for queue in self.queues.iter_mut().rev() {
+            if let Some(idx) = queue.iter().position(|x| filter(x)) {
+                self.len -= 1;
+                return queue.remove(idx);
+            }
+        }
+        None
+    }
+}
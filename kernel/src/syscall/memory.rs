@@ -1,14 +1,58 @@
 use crate::{
-    arch::virt::get_page_size,
-    memory::{VirtAddr, virt::VmFlags},
-    posix::errno::{EResult, Errno},
+    arch::virt::{get_level_size, get_page_size},
+    memory::{
+        VirtAddr, swap, user,
+        virt::{AddressSpace, MemoryAdvice, VmFlags},
+    },
+    posix::{
+        errno::{EResult, Errno},
+        resource::Resource,
+    },
+    process::Process,
     sched::Scheduler,
     uapi,
     util::align_up,
-    vfs::file::MmapFlags,
+    vfs::{
+        File,
+        file::{MmapFlags, OpenFlags},
+        inode::Mode,
+    },
 };
-use core::num::NonZeroUsize;
-use uapi::mman::*;
+use alloc::sync::Arc;
+use core::{num::NonZeroUsize, sync::atomic::Ordering};
+use uapi::{limits::PATH_MAX, mman::*, oom::*, swap::*};
+
+/// Takes `length` bytes of free address space from the mmap head. Pages which are already in
+/// use are skipped, fixed mappings and mappings which grew in place can be above the head.
+fn next_address(
+    mmap_head: &mut VirtAddr,
+    space: &AddressSpace,
+    length: usize,
+    align: usize,
+) -> VirtAddr {
+    let page_size = get_page_size();
+    let mut cur = align_up(mmap_head.value(), align);
+    while let Some(end) = space
+        .mappings
+        .iter()
+        .find(|x| x.start_page * page_size < cur + length && cur < x.end_page * page_size)
+        .map(|x| x.end_page * page_size)
+    {
+        cur = align_up(end, align);
+    }
+
+    *mmap_head = align_up(cur + length, page_size).into();
+    VirtAddr::new(cur)
+}
+
+/// Returns the alignment of a new mapping. Large ones start on a huge page boundary, so their
+/// memory can be mapped with huge pages.
+fn mapping_align(length: usize) -> usize {
+    match length >= get_level_size(1) {
+        true => get_level_size(1),
+        false => get_page_size(),
+    }
+}
 
 pub fn mmap(
     addr: VirtAddr,
@@ -33,37 +77,67 @@ pub fn mmap(
     vm_prot.set(VmFlags::Read, prot & PROT_READ != 0);
     vm_prot.set(VmFlags::Write, prot & PROT_WRITE != 0);
     vm_prot.set(VmFlags::Exec, prot & PROT_EXEC != 0);
+    vm_prot.set(VmFlags::Shared, flags.contains(MmapFlags::Shared));
+    vm_prot.set(VmFlags::WriteExec, flags.contains(MmapFlags::Jit));
 
     let proc = Scheduler::get_current().get_process();
+    let file = match flags.contains(MmapFlags::Anonymous) {
+        true => None,
+        false => {
+            // Look up the corresponding fd.
+            Some(proc.open_files.lock().get_fd(fd).ok_or(Errno::EBADF)?)
+        }
+    };
+    let limit = lock_limit(&proc);
+
     let mut mmap_head = proc.mmap_head.lock();
+    let mut space = proc.address_space.lock();
 
     // If MAP_FIXED isn't specified, we must find a suitable address.
     let addr = if !flags.contains(MmapFlags::Fixed) {
-        let cur = *mmap_head;
-        *mmap_head = align_up((cur + length).value(), get_page_size()).into();
-
-        cur
+        let align = match flags.contains(MmapFlags::Anonymous) {
+            true => mapping_align(length),
+            false => get_page_size(),
+        };
+        next_address(&mut mmap_head, &space, length, align)
     } else {
         addr
     };
+    let length = NonZeroUsize::new(length).ok_or(Errno::EINVAL)?;
 
-    let file = match flags.contains(MmapFlags::Anonymous) {
-        true => None,
-        false => {
-            // Look up the corresponding fd.
-            Some(proc.open_files.lock().get_fd(fd).ok_or(Errno::EBADF)?)
-        }
-    };
-    crate::vfs::mmap(
+    // After `mlockall(MCL_FUTURE)`, the mapping has to be locked or not exist at all. The limit is
+    // checked first, because a fixed mapping replaces the old one, which can't be restored later.
+    let lock_new = space.lock_new;
+    if lock_new
+        && limit.is_some_and(|x| space.locked_pages() + length.get().div_ceil(get_page_size()) > x)
+    {
+        return Err(Errno::EAGAIN);
+    }
+
+    let addr = crate::vfs::mmap(
         file.map(|x| x.file.clone()),
-        &mut proc.address_space.lock(),
+        &mut space,
         addr,
-        NonZeroUsize::new(length).ok_or(Errno::EINVAL)?,
+        length,
         vm_prot,
         flags,
         offset,
-    )
-    .map(|x| x.value())
+    )?;
+
+    if lock_new {
+        space.set_locked_range(addr, length)?;
+    }
+    drop((space, mmap_head));
+
+    // A fixed mapping may have replaced shared ones.
+    AddressSpace::write_back_removed(&proc.address_space);
+
+    // Pages which can't be faulted in right now are kept once they're accessed.
+    if lock_new {
+        _ = AddressSpace::populate(&proc.address_space, addr, length);
+    }
+
+    Ok(addr.value())
 }
 
 pub fn mprotect(addr: VirtAddr, size: usize, prot: u32) -> EResult<usize> {
@@ -82,10 +156,270 @@ pub fn mprotect(addr: VirtAddr, size: usize, prot: u32) -> EResult<usize> {
     Ok(0)
 }
 
+/// Returns the amount of pages the process may lock, or [`None`] if there is no limit.
+fn lock_limit(proc: &Process) -> Option<usize> {
+    // Privileged processes may lock as much as they want.
+    if proc.identity.lock().effective_user_id == 0 {
+        return None;
+    }
+
+    proc.limits
+        .lock()
+        .get_cur(Resource::MemLock)
+        .map(|x| x / get_page_size())
+}
+
+pub fn mlock(addr: VirtAddr, size: usize) -> EResult<usize> {
+    // An empty range is not an error.
+    let Some(size) = NonZeroUsize::new(size) else {
+        return Ok(0);
+    };
+
+    let proc = Scheduler::get_current().get_process();
+    let limit = lock_limit(&proc);
+    if limit == Some(0) {
+        return Err(Errno::EPERM);
+    }
+    AddressSpace::lock(&proc.address_space, addr, size, limit)?;
+
+    Ok(0)
+}
+
+pub fn munlock(addr: VirtAddr, size: usize) -> EResult<usize> {
+    let Some(size) = NonZeroUsize::new(size) else {
+        return Ok(0);
+    };
+
+    let proc = Scheduler::get_current().get_process();
+    proc.address_space.lock().unlock(addr, size)?;
+
+    Ok(0)
+}
+
+pub fn mlockall(flags: u32) -> EResult<usize> {
+    if flags == 0 || flags & !(MCL_CURRENT | MCL_FUTURE) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let proc = Scheduler::get_current().get_process();
+    let limit = lock_limit(&proc);
+    if limit == Some(0) {
+        return Err(Errno::EPERM);
+    }
+    AddressSpace::lock_all(
+        &proc.address_space,
+        flags & MCL_CURRENT != 0,
+        flags & MCL_FUTURE != 0,
+        limit,
+    )?;
+
+    Ok(0)
+}
+
+pub fn munlockall() -> EResult<usize> {
+    let proc = Scheduler::get_current().get_process();
+    proc.address_space.lock().unlock_all();
+    Ok(0)
+}
+
 pub fn munmap(addr: VirtAddr, size: usize) -> EResult<usize> {
     let proc = Scheduler::get_current().get_process();
     proc.address_space
         .lock()
-        .unmap(addr, NonZeroUsize::new(size).ok_or(Errno::EINVAL)?)
-        .map(|_| 0)
+        .unmap(addr, NonZeroUsize::new(size).ok_or(Errno::EINVAL)?)?;
+    AddressSpace::write_back_removed(&proc.address_space);
+
+    Ok(0)
+}
+
+pub fn madvise(addr: VirtAddr, size: usize, advice: u32) -> EResult<usize> {
+    let advice = match advice {
+        // Access patterns are only hints.
+        MADV_NORMAL | MADV_RANDOM | MADV_SEQUENTIAL => return Ok(0),
+        MADV_WILLNEED => MemoryAdvice::WillNeed,
+        MADV_DONTNEED => MemoryAdvice::DontNeed,
+        MADV_FREE => MemoryAdvice::Free,
+        _ => return Err(Errno::EINVAL),
+    };
+
+    // An empty range is not an error.
+    let Some(size) = NonZeroUsize::new(size) else {
+        return Ok(0);
+    };
+
+    let proc = Scheduler::get_current().get_process();
+    let result = proc.address_space.lock().advise(addr, size, advice);
+    AddressSpace::write_back_removed(&proc.address_space);
+    result?;
+
+    Ok(0)
+}
+
+pub fn mremap(
+    old_addr: VirtAddr,
+    old_size: usize,
+    new_size: usize,
+    flags: u32,
+    new_addr: VirtAddr,
+) -> EResult<usize> {
+    if flags & !(MREMAP_MAYMOVE | MREMAP_FIXED) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    // A fixed address implies that the mapping is moved.
+    let may_move = flags & MREMAP_MAYMOVE != 0;
+    let fixed = flags & MREMAP_FIXED != 0;
+    if fixed && !may_move {
+        return Err(Errno::EINVAL);
+    }
+
+    // TODO: An old size of 0 duplicates shared mappings.
+    let old_size = NonZeroUsize::new(old_size).ok_or(Errno::EINVAL)?;
+    let new_size = NonZeroUsize::new(new_size).ok_or(Errno::EINVAL)?;
+
+    // Same lock order as in mmap.
+    let proc = Scheduler::get_current().get_process();
+    let mut mmap_head = proc.mmap_head.lock();
+    let mut space = proc.address_space.lock();
+
+    let result = match fixed {
+        true => space
+            .move_mapping(old_addr, old_size, new_addr, new_size)
+            .map(|_| new_addr.value()),
+        false => match space.resize(old_addr, old_size, new_size) {
+            Ok(()) => Ok(old_addr.value()),
+            Err(Errno::ENOMEM) if may_move => {
+                let target = next_address(
+                    &mut mmap_head,
+                    &space,
+                    new_size.get(),
+                    mapping_align(new_size.get()),
+                );
+                space
+                    .move_mapping(old_addr, old_size, target, new_size)
+                    .map(|_| target.value())
+            }
+            Err(x) => Err(x),
+        },
+    };
+    drop((space, mmap_head));
+
+    // Shrinking or replacing mappings may have removed shared ones.
+    AddressSpace::write_back_removed(&proc.address_space);
+    result
+}
+
+/// Writes modified pages of shared mappings in a range back to their files.
+pub fn msync(addr: VirtAddr, size: usize, flags: u32) -> EResult<usize> {
+    if flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == (MS_ASYNC | MS_SYNC)
+    {
+        return Err(Errno::EINVAL);
+    }
+
+    // An empty range is not an error.
+    let Some(size) = NonZeroUsize::new(size) else {
+        return Ok(0);
+    };
+
+    let proc = Scheduler::get_current().get_process();
+    let dirty = proc.address_space.lock().collect_dirty_range(addr, size)?;
+
+    // Mappings always see the page cache, so there is nothing to invalidate.
+    // Asynchronous writeback happens when the mapping goes away.
+    if flags & MS_SYNC != 0 {
+        for (object, page_index, num_pages) in dirty {
+            // Other processes may have written to the same pages.
+            AddressSpace::collect_dirty_everywhere(object.as_ref());
+            object.sync(page_index, num_pages)?;
+        }
+    }
+
+    Ok(0)
+}
+
+/// Opens the swap area at `path`. Only privileged users may change swap areas.
+fn open_swap_area(path: VirtAddr) -> EResult<Arc<File>> {
+    if path == VirtAddr::null() {
+        return Err(Errno::EFAULT);
+    }
+    let path = user::copy_cstr_from_user(path, PATH_MAX)?;
+
+    let proc = Scheduler::get_current().get_process();
+    let identity = proc.identity.lock().clone();
+    if identity.effective_user_id != 0 {
+        return Err(Errno::EPERM);
+    }
+
+    File::open(
+        proc.root_dir.lock().clone(),
+        proc.working_dir.lock().clone(),
+        &path,
+        OpenFlags::ReadWrite,
+        Mode::empty(),
+        &identity,
+    )
+}
+
+/// Starts swapping to the device or file at `path`.
+pub fn swapon(path: VirtAddr, flags: u32) -> EResult<usize> {
+    if flags & !(SWAP_FLAG_PREFER | SWAP_FLAG_PRIO_MASK | SWAP_FLAG_DISCARD) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    // Discarding freed slots is only a hint for the device.
+    let priority = (flags & SWAP_FLAG_PREFER != 0)
+        .then(|| ((flags & SWAP_FLAG_PRIO_MASK) >> SWAP_FLAG_PRIO_SHIFT) as isize);
+
+    swap::enable(open_swap_area(path)?, priority)?;
+    Ok(0)
+}
+
+/// Stops swapping to the device or file at `path`.
+pub fn swapoff(path: VirtAddr) -> EResult<usize> {
+    let file = open_swap_area(path)?;
+    swap::disable(file.inode.as_ref().ok_or(Errno::EINVAL)?)?;
+    Ok(0)
+}
+
+/// Returns the process with ID `pid`, or the current process if it's 0.
+fn get_process(pid: usize) -> EResult<Arc<Process>> {
+    match pid {
+        0 => Ok(Scheduler::get_current().get_process()),
+        _ => Process::get_by_pid(pid).ok_or(Errno::ESRCH),
+    }
+}
+
+/// Returns the OOM score adjustment of a process.
+pub fn get_oom_score_adj(pid: usize) -> EResult<usize> {
+    let target = get_process(pid)?;
+    Ok(target.oom_score_adj.load(Ordering::Relaxed) as isize as usize)
+}
+
+/// Changes how likely the OOM killer is to choose a process.
+pub fn set_oom_score_adj(pid: usize, adj: isize) -> EResult<usize> {
+    let adj = i32::try_from(adj)
+        .ok()
+        .filter(|x| (OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(x))
+        .ok_or(Errno::EINVAL)?;
+    let target = get_process(pid)?;
+
+    let identity = Scheduler::get_current()
+        .get_process()
+        .identity
+        .lock()
+        .clone();
+    if identity.effective_user_id != 0 {
+        let owner = target.identity.lock().user_id;
+        if identity.effective_user_id != owner {
+            return Err(Errno::EPERM);
+        }
+        // Only privileged users may protect a process from the OOM killer.
+        if adj < target.oom_score_adj.load(Ordering::Relaxed) {
+            return Err(Errno::EACCES);
+        }
+    }
+
+    target.oom_score_adj.store(adj, Ordering::Relaxed);
+    Ok(0)
 }
@@ -1,6 +1,8 @@
 mod memory;
 mod numbers;
 mod process;
+mod sched;
+mod signal;
 mod system;
 mod vfs;
 
@@ -15,10 +17,10 @@ macro_rules! sys_unimp {
 }
 
 /// Executes the syscall as identified by `num`.
-/// Returns a tuple of (value, error) to the user. An error code of 0 inidcates success.
+/// Writes a tuple of (value, error) back to `frame`. An error code of 0 inidcates success.
 /// If the error code is not 0, `value` is not valid and indicates failure.
 pub fn dispatch(
-    frame: &Context,
+    frame: &mut Context,
     num: usize,
     a0: usize,
     a1: usize,
@@ -26,7 +28,7 @@ pub fn dispatch(
     a3: usize,
     a4: usize,
     a5: usize,
-) -> (usize, usize) {
+) {
     // Execute a syscall based on the number.
     // Note that the numbers might not be in order, but grouped logically.
     let result = match num {
@@ -39,23 +41,38 @@ pub fn dispatch(
 
         // Mapped memory
         numbers::MMAP => memory::mmap(a0.into(), a1, a2 as _, a3 as _, a4 as _, a5 as _),
-        numbers::MUNMAP => sys_unimp!("munmap", memory::munmap(a0.into(), a1)),
+        numbers::MUNMAP => memory::munmap(a0.into(), a1),
         numbers::MPROTECT => memory::mprotect(a0.into(), a1, a2 as _),
-        numbers::MADVISE => sys_unimp!("madvise", Err(Errno::ENOSYS)),
+        numbers::MADVISE => memory::madvise(a0.into(), a1, a2 as _),
+        numbers::MREMAP => memory::mremap(a0.into(), a1, a2, a3 as _, a4.into()),
+        numbers::MSYNC => memory::msync(a0.into(), a1, a2 as _),
+        numbers::SWAPON => memory::swapon(a0.into(), a1 as _),
+        numbers::SWAPOFF => memory::swapoff(a0.into()),
+        numbers::GET_OOM_SCORE_ADJ => memory::get_oom_score_adj(a0),
+        numbers::SET_OOM_SCORE_ADJ => memory::set_oom_score_adj(a0, a1 as _),
+        numbers::MLOCK => memory::mlock(a0.into(), a1),
+        numbers::MUNLOCK => memory::munlock(a0.into(), a1),
+        numbers::MLOCKALL => memory::mlockall(a0 as _),
+        numbers::MUNLOCKALL => memory::munlockall(),
 
         // Signals
-        numbers::SIGPROCMASK => sys_unimp!("sigprocmask", Err(Errno::ENOSYS)),
-        numbers::SIGSUSPEND => sys_unimp!("sigsuspend", Err(Errno::ENOSYS)),
-        numbers::SIGPENDING => sys_unimp!("sigpending", Err(Errno::ENOSYS)),
-        numbers::SIGACTION => sys_unimp!("sigaction", Err(Errno::ENOSYS)),
-        numbers::SIGTIMEDWAIT => sys_unimp!("sigtimedwait", Err(Errno::ENOSYS)),
+        numbers::SIGPROCMASK => signal::sigprocmask(a0 as _, a1.into(), a2.into()),
+        numbers::SIGSUSPEND => signal::sigsuspend(a0.into()),
+        numbers::SIGPENDING => signal::sigpending(a0.into()),
+        numbers::SIGACTION => signal::sigaction(a0 as _, a1.into(), a2.into()),
+        numbers::SIGTIMEDWAIT => signal::sigtimedwait(a0.into(), a1.into(), a2.into()),
         numbers::SIGALTSTACK => sys_unimp!("sigaltstack", Err(Errno::ENOSYS)),
+        numbers::SIGRETURN => {
+            // This restores the entire context, so the return value must not be written.
+            crate::process::signal::sigreturn(frame);
+            return;
+        }
 
         // Processes
         numbers::EXIT => process::exit(a0),
         numbers::EXECVE => process::execve(a0.into(), a1.into(), a2.into()),
         numbers::FORK => process::fork(frame),
-        numbers::KILL => sys_unimp!("kill", Err(Errno::ENOSYS)),
+        numbers::KILL => signal::kill(a0 as _, a1 as _),
         numbers::GETTID => Ok(process::gettid()),
         numbers::GETPID => Ok(process::getpid()),
         numbers::GETPPID => Ok(process::getppid()),
@@ -63,11 +80,11 @@ pub fn dispatch(
         numbers::WAITPID => process::waitpid(a0 as _, a1.into(), a2 as _),
 
         // Threads
-        numbers::THREAD_CREATE => sys_unimp!("thread_create", Err(Errno::ENOSYS)),
-        numbers::THREAD_KILL => sys_unimp!("thread_kill", Err(Errno::ENOSYS)),
-        numbers::THREAD_EXIT => sys_unimp!("thread_exit", Err(Errno::ENOSYS)),
-        numbers::THREAD_SETNAME => sys_unimp!("thread_setname", Err(Errno::ENOSYS)),
-        numbers::THREAD_GETNAME => sys_unimp!("thread_getname", Err(Errno::ENOSYS)),
+        numbers::THREAD_CREATE => process::thread_create(a0.into(), a1, a2.into(), a3.into()),
+        numbers::THREAD_KILL => process::thread_kill(a0, a1 as _),
+        numbers::THREAD_EXIT => process::thread_exit(),
+        numbers::THREAD_SETNAME => process::thread_setname(a0, a1.into()),
+        numbers::THREAD_GETNAME => process::thread_getname(a0, a1.into(), a2),
 
         // VFS
         numbers::READ => vfs::read(a0 as _, a1.into(), a2).map(|x| x as _),
@@ -109,8 +126,8 @@ pub fn dispatch(
         numbers::DUP => vfs::dup(a0 as _).map(|x| x as _),
         numbers::DUP3 => vfs::dup3(a0 as _, a1 as _, a2).map(|x| x as _),
         numbers::SYNC => sys_unimp!("sync", Err(Errno::ENOSYS)),
-        numbers::FSYNC => sys_unimp!("fsync", Err(Errno::ENOSYS)),
-        numbers::FDATASYNC => sys_unimp!("fdatasync", Err(Errno::ENOSYS)),
+        numbers::FSYNC => vfs::fsync(a0 as _).map(|_| 0),
+        numbers::FDATASYNC => vfs::fsync(a0 as _).map(|_| 0),
         numbers::CHROOT => sys_unimp!("chroot", Err(Errno::ENOSYS)),
         numbers::MOUNT => sys_unimp!("mount", Err(Errno::ENOSYS)),
         numbers::UMOUNT => sys_unimp!("umount", Err(Errno::ENOSYS)),
@@ -168,12 +185,12 @@ pub fn dispatch(
 
         // Limits
         numbers::GETRUSAGE => sys_unimp!("getrusage", Err(Errno::ENOSYS)),
-        numbers::GETRLIMIT => sys_unimp!("getrlimit", Err(Errno::ENOSYS)),
-        numbers::SETRLIMIT => sys_unimp!("setrlimit", Err(Errno::ENOSYS)),
+        numbers::GETRLIMIT => process::getrlimit(a0 as _, a1.into()),
+        numbers::SETRLIMIT => process::setrlimit(a0 as _, a1.into()),
 
         // Futexes
-        numbers::FUTEX_WAIT => sys_unimp!("futex_wait", Ok(0)),
-        numbers::FUTEX_WAKE => sys_unimp!("futex_wake", Ok(0)),
+        numbers::FUTEX_WAIT => process::futex_wait(a0.into(), a1 as _, a2.into()),
+        numbers::FUTEX_WAKE => process::futex_wake(a0.into()),
 
         // Time
         numbers::TIMER_CREATE => sys_unimp!("timer_create", Ok(0)),
@@ -187,10 +204,12 @@ pub fn dispatch(
         // Scheduling
         numbers::SLEEP => system::sleep(a0.into(), a1.into()),
         numbers::YIELD => sys_unimp!("yield", Ok(0)),
-        numbers::GETPRIORITY => sys_unimp!("getpriority", Err(Errno::ENOSYS)),
-        numbers::SETPRIORITY => sys_unimp!("setpriority", Err(Errno::ENOSYS)),
-        numbers::SCHED_GETPARAM => sys_unimp!("sched_getparam", Err(Errno::ENOSYS)),
-        numbers::SCHED_SETPARAM => sys_unimp!("sched_setparam", Err(Errno::ENOSYS)),
+        numbers::GETPRIORITY => sched::getpriority(a0 as _, a1),
+        numbers::SETPRIORITY => sched::setpriority(a0 as _, a1, a2 as _),
+        numbers::SCHED_GETPARAM => sched::sched_getparam(a0, a1.into()),
+        numbers::SCHED_SETPARAM => sched::sched_setparam(a0, a1 as _, a2.into()),
+        numbers::SCHED_GETAFFINITY => sched::sched_getaffinity(a0, a1, a2.into()),
+        numbers::SCHED_SETAFFINITY => sched::sched_setaffinity(a0, a1, a2.into()),
         numbers::GETENTROPY => sys_unimp!("getentropy", Ok(0)),
 
         _ => {
@@ -200,7 +219,7 @@ pub fn dispatch(
     };
 
     match result {
-        Ok(x) => return (x, 0),
-        Err(x) => return (0, x as usize),
+        Ok(x) => frame.set_return(x, 0),
+        Err(x) => frame.set_return(0, x as usize),
     }
 }
@@ -137,3 +137,14 @@ pub const EPOLL_WAIT: usize = 133;
 pub const TIMERFD_CREATE: usize = 134;
 pub const TIMERFD_SETTIME: usize = 135;
 pub const TIMERFD_GETTIME: usize = 136;
+pub const SIGRETURN: usize = 137;
+pub const SCHED_GETAFFINITY: usize = 138;
+pub const SCHED_SETAFFINITY: usize = 139;
+pub const MREMAP: usize = 140;
+pub const MSYNC: usize = 141;
+pub const GET_OOM_SCORE_ADJ: usize = 142;
+pub const SET_OOM_SCORE_ADJ: usize = 143;
+pub const MLOCK: usize = 144;
+pub const MUNLOCK: usize = 145;
+pub const MLOCKALL: usize = 146;
+pub const MUNLOCKALL: usize = 147;
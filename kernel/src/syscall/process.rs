@@ -1,15 +1,29 @@
 use crate::{
     arch::sched::Context,
-    memory::{VirtAddr, user::UserPtr},
-    percpu::CpuData,
-    posix::errno::{EResult, Errno},
-    process::ProcessState,
-    sched::Scheduler,
-    uapi,
+    memory::{
+        VirtAddr,
+        user::{self, UserPtr},
+    },
+    posix::{
+        errno::{EResult, Errno},
+        resource::Resource,
+        time,
+    },
+    process::{
+        Process,
+        signal::{self, Signal, SignalInfo},
+        task::Task,
+    },
+    sched::{Scheduler, futex},
+    uapi::{
+        self,
+        limits::PATH_MAX,
+        resource::rlimit,
+        wait::{WCONTINUED, WNOHANG, WUNTRACED},
+    },
     vfs::{File, file::OpenFlags, inode::Mode},
 };
-use alloc::vec::Vec;
-use core::ffi::{CStr, c_char};
+use alloc::{string::String, sync::Arc, vec::Vec};
 
 pub fn gettid() -> usize {
     Scheduler::get_current().get_id()
@@ -52,7 +66,7 @@ pub fn getpgid(pid: usize) -> EResult<usize> {
     }
 
     let proc = Scheduler::get_current().get_process();
-    Ok(proc.get_pid())
+    Ok(proc.get_pgid())
 }
 
 pub fn exit(error: usize) -> ! {
@@ -77,35 +91,131 @@ pub fn fork(ctx: &Context) -> EResult<usize> {
     Ok(new_proc.get_pid())
 }
 
+pub fn thread_create(
+    entry: VirtAddr,
+    arg: usize,
+    stack: VirtAddr,
+    tls: VirtAddr,
+) -> EResult<usize> {
+    if entry == VirtAddr::null() || stack == VirtAddr::null() {
+        return Err(Errno::EINVAL);
+    }
+
+    let proc = Scheduler::get_current().get_process();
+    let thread = proc.create_thread(entry, stack, arg, tls)?;
+    Scheduler::add_task_to_best_cpu(thread.clone());
+
+    Ok(thread.get_id())
+}
+
+pub fn thread_exit() -> ! {
+    let proc = Scheduler::get_current().get_process();
+    proc.exit_thread(0);
+}
+
+/// Returns the thread with ID `tid` in the current process.
+fn get_thread(tid: usize) -> EResult<Arc<Task>> {
+    let proc = Scheduler::get_current().get_process();
+    let threads = proc.threads.lock();
+    threads
+        .iter()
+        .find(|x| x.get_id() == tid)
+        .cloned()
+        .ok_or(Errno::ESRCH)
+}
+
+pub fn thread_kill(tid: usize, sig: u32) -> EResult<usize> {
+    let thread = get_thread(tid)?;
+
+    // Signal 0 only checks if the thread exists.
+    if sig == 0 {
+        return Ok(0);
+    }
+
+    let proc = thread.get_process();
+    let sig = Signal::from_raw(sig).ok_or(Errno::EINVAL)?;
+    signal::send_to_task(
+        &thread,
+        SignalInfo {
+            pid: proc.get_pid(),
+            uid: proc.identity.lock().user_id,
+            ..SignalInfo::new(sig, uapi::signal::SI_TKILL as _)
+        },
+    );
+
+    Ok(0)
+}
+
+/// Maximum length of a thread name, including the NUL terminator.
+const THREAD_NAME_MAX: usize = 16;
+
+pub fn thread_setname(tid: usize, name: VirtAddr) -> EResult<usize> {
+    let thread = get_thread(tid)?;
+
+    let name = user::copy_cstr_from_user(name, THREAD_NAME_MAX).map_err(|e| match e {
+        Errno::ENAMETOOLONG => Errno::ERANGE,
+        e => e,
+    })?;
+
+    *thread.name.lock() = String::from_utf8_lossy(&name).into_owned();
+    Ok(0)
+}
+
+pub fn thread_getname(tid: usize, buf: VirtAddr, len: usize) -> EResult<usize> {
+    let thread = get_thread(tid)?;
+    // The copy may fault, so it can't happen while the name is locked.
+    let mut name = thread.name.lock().clone().into_bytes();
+    name.push(0);
+
+    // The name has to fit including the NUL terminator.
+    if len < name.len() {
+        return Err(Errno::ERANGE);
+    }
+
+    user::copy_to_user(buf, &name)?;
+
+    Ok(0)
+}
+
+pub fn futex_wait(addr: VirtAddr, expected: u32, timeout: VirtAddr) -> EResult<usize> {
+    // A null timeout waits forever.
+    let timeout = time::read_timeout(timeout)?;
+    futex::wait(addr, expected, timeout)?;
+    Ok(0)
+}
+
+pub fn futex_wake(addr: VirtAddr) -> EResult<usize> {
+    // The libc expects all waiters to be woken up.
+    futex::wake(addr, usize::MAX)
+}
+
+/// Maximum length of a single argument or environment string, including the NUL terminator.
+const ARG_STRLEN_MAX: usize = 32 * 4096;
+
+/// Reads a NULL terminated array of strings, like `argv`, from user memory.
+fn read_string_array(addr: VirtAddr) -> EResult<Vec<Vec<u8>>> {
+    let array = UserPtr::<usize>::new(addr);
+    let mut result = Vec::new();
+    for i in 0.. {
+        match array.offset(i).read().ok_or(Errno::EFAULT)? {
+            0 => break,
+            x => result.push(user::copy_cstr_from_user(x.into(), ARG_STRLEN_MAX)?),
+        }
+    }
+    Ok(result)
+}
+
 pub fn execve(path: VirtAddr, argv: VirtAddr, envp: VirtAddr) -> EResult<usize> {
     let proc = Scheduler::get_current().get_process();
 
-    let path_str = unsafe { CStr::from_ptr(path.as_ptr()) };
-
-    let args: Vec<_> = (0..)
-        .map(|i| unsafe { argv.as_ptr::<usize>().offset(i).read() })
-        .take_while(|&p| p != 0)
-        .map(|p| {
-            unsafe { CStr::from_ptr(p as *const c_char) }
-                .to_bytes()
-                .to_vec()
-        })
-        .collect();
-
-    let envs: Vec<_> = (0..)
-        .map(|i| unsafe { envp.as_ptr::<usize>().offset(i).read() })
-        .take_while(|&p| p != 0)
-        .map(|p| {
-            unsafe { CStr::from_ptr(p as *const c_char) }
-                .to_bytes()
-                .to_vec()
-        })
-        .collect();
+    let path = user::copy_cstr_from_user(path, PATH_MAX)?;
+    let args = read_string_array(argv)?;
+    let envs = read_string_array(envp)?;
 
     let file = File::open(
         proc.root_dir.lock().clone(),
         proc.working_dir.lock().clone(),
-        path_str.to_bytes(),
+        &path,
         OpenFlags::Read | OpenFlags::Executable,
         Mode::empty(),
         &proc.identity.lock(),
@@ -115,53 +225,92 @@ pub fn execve(path: VirtAddr, argv: VirtAddr, envp: VirtAddr) -> EResult<usize>
     unreachable!("fexecve should never return on success");
 }
 
-pub fn waitpid(pid: uapi::pid_t, mut stat_loc: UserPtr<i32>, _options: i32) -> EResult<usize> {
+pub fn getrlimit(resource: u32, mut limit: UserPtr<rlimit>) -> EResult<usize> {
+    let resource = Resource::try_from(resource)?;
     let proc = Scheduler::get_current().get_process();
+    let value = proc.limits.lock().get(resource);
+    if !limit.write(value) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(0)
+}
+
+pub fn setrlimit(resource: u32, limit: UserPtr<rlimit>) -> EResult<usize> {
+    let resource = Resource::try_from(resource)?;
+    let value = limit.read().ok_or(Errno::EFAULT)?;
+    if value.rlim_cur > value.rlim_max {
+        return Err(Errno::EINVAL);
+    }
+
+    let proc = Scheduler::get_current().get_process();
+    let privileged = proc.identity.lock().effective_user_id == 0;
+    let mut limits = proc.limits.lock();
+
+    // Only privileged processes may raise the hard limit.
+    if value.rlim_max > limits.get(resource).rlim_max && !privileged {
+        return Err(Errno::EPERM);
+    }
+
+    limits.set(resource, value);
+    Ok(0)
+}
+
+pub fn waitpid(pid: uapi::pid_t, mut stat_loc: UserPtr<i32>, options: i32) -> EResult<usize> {
+    // Processes can't be stopped or continued, so there is never anything to report for those.
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED) != 0 {
+        return Err(Errno::EINVAL);
+    }
+
+    let proc = Scheduler::get_current().get_process();
+
+    // -1 waits for any child, 0 and other negative values for the children in a process group.
+    let matches = |child: &Process| match pid as isize {
+        -1 => true,
+        0 => child.get_pgid() == proc.get_pgid(),
+        x if x < 0 => child.get_pgid() == x.unsigned_abs(),
+        _ => child.get_pid() == pid,
+    };
 
     loop {
-        let mut inner = proc.children.lock();
-        if inner.is_empty() {
+        // Registering first makes sure that a child which exits in the meantime wakes us up.
+        let guard = proc.child_event.guard();
+        let inner = proc.children.lock();
+        if !inner.iter().any(|x| matches(x)) {
             return Err(Errno::ECHILD);
         }
-        match pid as isize {
-            // Any child process whose process group ID is equal to the absolute value of pid.
-            ..=-2 => {
-                todo!();
-            }
-            -1 | 0 => {
-                let mut waitee = None;
-                for (idx, child) in inner.iter().enumerate() {
-                    let child_inner = child.status.lock();
-                    if let ProcessState::Exited(code) = *child_inner {
-                        stat_loc.write((code as i32) << 8);
-                        waitee = Some(idx);
-                    }
-                }
-
-                if let Some(w) = waitee {
-                    inner.remove(w);
-                }
+
+        let waitee = inner.iter().find_map(|child| {
+            let status = child.status.lock().wait_status();
+            status
+                .filter(|_| matches(child))
+                .map(|x| (child.clone(), x))
+        });
+        drop(inner);
+
+        if let Some((child, status)) = waitee {
+            // Writing to user memory may sleep, so it's done without the lock. The child stays
+            // around if its status can't be reported.
+            if !stat_loc.is_null() && !stat_loc.write(status) {
+                return Err(Errno::EFAULT);
             }
-            _ => {
-                let mut waitee = None;
-                for (idx, child) in inner.iter().enumerate() {
-                    if child.get_pid() != pid {
-                        continue;
-                    }
-
-                    let child_inner = child.status.lock();
-                    if let ProcessState::Exited(code) = *child_inner {
-                        stat_loc.write((code as i32) << 8);
-                        waitee = Some(idx);
-                    }
-                }
-
-                if let Some(w) = waitee {
-                    inner.remove(w);
-                }
+
+            // Another thread may have reaped the child in the meantime.
+            let mut inner = proc.children.lock();
+            if let Some(idx) = inner.iter().position(|x| Arc::ptr_eq(x, &child)) {
+                inner.remove(idx);
+                return Ok(child.get_pid());
             }
+            continue;
         }
-        drop(inner);
-        CpuData::get().scheduler.reschedule();
+
+        if options & WNOHANG != 0 {
+            return Ok(0);
+        }
+
+        // Waiting can be interrupted by signals.
+        if signal::has_pending() {
+            return Err(Errno::EINTR);
+        }
+        guard.wait();
     }
 }
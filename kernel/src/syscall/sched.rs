@@ -0,0 +1,223 @@
+use crate::{
+    memory::{
+        VirtAddr,
+        user::{self, UserPtr},
+    },
+    posix::errno::{EResult, Errno},
+    process::{Identity, Process, task::Task},
+    sched::{
+        Policy, Scheduler,
+        cpuset::CpuSet,
+        fair::{NICE_MAX, NICE_MIN},
+    },
+    uapi::{
+        resource::{PRIO_PGRP, PRIO_PROCESS, PRIO_USER},
+        sched::sched_param,
+    },
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::Ordering;
+
+/// Returns the processes selected by `which` and `who`.
+fn get_targets(which: u32, who: usize) -> EResult<Vec<Arc<Process>>> {
+    let proc = Scheduler::get_current().get_process();
+
+    let targets = match which {
+        // TODO: Process groups. For now, the group ID of a process is equal to its PID.
+        PRIO_PROCESS | PRIO_PGRP => match who {
+            0 => vec![proc],
+            _ => vec![Process::get_by_pid(who).ok_or(Errno::ESRCH)?],
+        },
+        PRIO_USER => {
+            let uid = match who {
+                0 => proc.identity.lock().user_id,
+                _ => who,
+            };
+            let mut targets = vec![];
+            Process::for_each(|x| {
+                if x.get_pid() != 0 && x.identity.lock().user_id == uid {
+                    targets.push(x.clone());
+                }
+            });
+            targets
+        }
+        _ => return Err(Errno::EINVAL),
+    };
+
+    match targets.is_empty() {
+        true => Err(Errno::ESRCH),
+        false => Ok(targets),
+    }
+}
+
+/// Returns the process with ID `pid`, or the current process if it's 0.
+fn get_process(pid: usize) -> EResult<Arc<Process>> {
+    match pid {
+        0 => Ok(Scheduler::get_current().get_process()),
+        _ => Process::get_by_pid(pid).ok_or(Errno::ESRCH),
+    }
+}
+
+/// Returns the thread with ID `tid` of any process, or the current thread if it's 0.
+fn get_task(tid: usize) -> EResult<Arc<Task>> {
+    if tid == 0 {
+        return Ok(Scheduler::get_current());
+    }
+
+    let mut result = None;
+    Process::for_each(|x| {
+        if result.is_none() {
+            result = x.threads.lock().iter().find(|x| x.get_id() == tid).cloned();
+        }
+    });
+    result.ok_or(Errno::ESRCH)
+}
+
+/// Returns true if `caller` is allowed to change the scheduling parameters of `target`.
+fn may_change(caller: &Identity, target: &Process) -> bool {
+    let target = target.identity.lock();
+
+    caller.effective_user_id == 0
+        || caller.effective_user_id == target.user_id
+        || caller.effective_user_id == target.effective_user_id
+}
+
+/// Returns the lowest nice value of all selected processes.
+/// Negative values are returned as is, because errors are reported separately.
+pub fn getpriority(which: u32, who: usize) -> EResult<usize> {
+    let nice = get_targets(which, who)?
+        .iter()
+        .flat_map(|x| x.threads.lock().clone())
+        .map(|x| x.priority.load(Ordering::Relaxed))
+        .min()
+        .unwrap_or(0);
+
+    Ok(nice as isize as usize)
+}
+
+pub fn setpriority(which: u32, who: usize, prio: isize) -> EResult<usize> {
+    let nice = prio.clamp(NICE_MIN as isize, NICE_MAX as isize) as i8;
+    let caller = Scheduler::get_current().get_process();
+    let identity = caller.identity.lock().clone();
+
+    // Either all targets change or none of them, so every one is checked first.
+    let targets = get_targets(which, who)?;
+    for target in targets.iter() {
+        if !may_change(&identity, target) {
+            return Err(Errno::EPERM);
+        }
+
+        // Only privileged users may raise the priority.
+        if identity.effective_user_id != 0
+            && target
+                .threads
+                .lock()
+                .iter()
+                .any(|x| nice < x.priority.load(Ordering::Relaxed))
+        {
+            return Err(Errno::EACCES);
+        }
+    }
+
+    for target in targets.iter() {
+        for thread in target.threads.lock().iter() {
+            thread.priority.store(nice, Ordering::Relaxed);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Returns the scheduling policy of a process and writes its priority to `param`.
+pub fn sched_getparam(pid: usize, param: VirtAddr) -> EResult<usize> {
+    let thread = match pid {
+        0 => Scheduler::get_current(),
+        _ => get_process(pid)?
+            .threads
+            .lock()
+            .first()
+            .cloned()
+            .ok_or(Errno::ESRCH)?,
+    };
+
+    let (policy, priority) = thread.policy().to_user();
+    UserPtr::<sched_param>::new(param).write(sched_param {
+        sched_priority: priority,
+    });
+    Ok(policy as _)
+}
+
+/// Changes the scheduling policy of all threads in a process.
+/// A negative `policy` keeps the current policy and only changes the priority.
+pub fn sched_setparam(pid: usize, policy: isize, param: VirtAddr) -> EResult<usize> {
+    let target = get_process(pid)?;
+    let param = UserPtr::<sched_param>::new(param)
+        .read()
+        .ok_or(Errno::EFAULT)?;
+
+    let caller = Scheduler::get_current().get_process();
+    let identity = caller.identity.lock().clone();
+    if !may_change(&identity, &target) {
+        return Err(Errno::EPERM);
+    }
+
+    let threads = target.threads.lock().clone();
+    let policy = match u32::try_from(policy) {
+        Ok(x) => x,
+        Err(_) => threads.first().ok_or(Errno::ESRCH)?.policy().to_user().0,
+    };
+    let policy = Policy::from_user(policy, param.sched_priority)?;
+
+    // Only privileged users may use real-time policies.
+    if policy != Policy::Normal && identity.effective_user_id != 0 {
+        return Err(Errno::EPERM);
+    }
+
+    for thread in threads.iter() {
+        thread.set_policy(policy);
+    }
+
+    Ok(0)
+}
+
+/// Writes the affinity mask of a thread to `mask` and returns the amount of bytes written.
+pub fn sched_getaffinity(tid: usize, size: usize, mask: VirtAddr) -> EResult<usize> {
+    let task = get_task(tid)?;
+    let bytes = task.affinity().intersection(&CpuSet::online()).to_bytes();
+
+    // The mask has to be large enough to hold every online CPU.
+    let needed = bytes.iter().rposition(|x| *x != 0).map_or(1, |x| x + 1);
+    if size < needed {
+        return Err(Errno::EINVAL);
+    }
+
+    let len = size.min(CpuSet::BYTES);
+    user::copy_to_user(mask, &bytes[..len])?;
+
+    Ok(len)
+}
+
+/// Restricts the CPUs a thread may run on to the ones set in `mask`.
+pub fn sched_setaffinity(tid: usize, size: usize, mask: VirtAddr) -> EResult<usize> {
+    let task = get_task(tid)?;
+    // Bits beyond the CPUs we know about are ignored.
+    let mut buf = [0u8; CpuSet::BYTES];
+    let len = size.min(CpuSet::BYTES);
+    user::copy_from_user(&mut buf[..len], mask)?;
+    let affinity = CpuSet::from_bytes(&buf[..len]);
+
+    let caller = Scheduler::get_current().get_process();
+    let identity = caller.identity.lock().clone();
+    if !may_change(&identity, &task.get_process()) {
+        return Err(Errno::EPERM);
+    }
+
+    // A task has to be able to run somewhere.
+    let affinity = affinity.intersection(&CpuSet::online());
+    if affinity.is_empty() {
+        return Err(Errno::EINVAL);
+    }
+
+    Scheduler::set_affinity(&task, affinity);
+    Ok(0)
+}
@@ -0,0 +1,263 @@
+use crate::{
+    clock,
+    memory::{VirtAddr, user::UserPtr},
+    percpu::CpuData,
+    posix::{
+        errno::{EResult, Errno},
+        time,
+    },
+    process::{
+        Process,
+        signal::{self, Signal, SignalAction, SignalInfo, SignalSet},
+        task::TaskState,
+    },
+    sched::Scheduler,
+    uapi::{
+        self,
+        signal::{SI_USER, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK, sigaction, siginfo_t, sigset_t},
+    },
+};
+use alloc::sync::Arc;
+
+pub fn sigprocmask(how: u32, set: VirtAddr, oldset: VirtAddr) -> EResult<usize> {
+    let task = Scheduler::get_current();
+
+    // Read the new set first, so nothing changes if it's bad. `set` and `oldset` may also overlap.
+    let set = match set {
+        x if x == VirtAddr::null() => None,
+        x => Some(SignalSet::from_raw(
+            UserPtr::<sigset_t>::new(x).read().ok_or(Errno::EFAULT)?,
+        )),
+    };
+
+    let old = {
+        let mut mask = task.signal_mask.lock();
+        let old = *mask;
+        if let Some(set) = set {
+            *mask = match how {
+                SIG_BLOCK => mask.union(set),
+                SIG_UNBLOCK => mask.difference(set),
+                SIG_SETMASK => set,
+                _ => return Err(Errno::EINVAL),
+            }
+            .without_unblockable();
+        }
+        old
+    };
+
+    if oldset != VirtAddr::null() && !UserPtr::<sigset_t>::new(oldset).write(old.into_raw()) {
+        return Err(Errno::EFAULT);
+    }
+
+    Ok(0)
+}
+
+pub fn sigpending(set: VirtAddr) -> EResult<usize> {
+    let task = Scheduler::get_current();
+    let proc = task.get_process();
+
+    let pending = task
+        .pending_signals
+        .lock()
+        .set()
+        .union(proc.pending_signals.lock().set())
+        .intersection(*task.signal_mask.lock());
+
+    if !UserPtr::<sigset_t>::new(set).write(pending.into_raw()) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(0)
+}
+
+pub fn sigaction(sig: u32, act: VirtAddr, oact: VirtAddr) -> EResult<usize> {
+    let sig = Signal::from_raw(sig).ok_or(Errno::EINVAL)?;
+    let proc = Scheduler::get_current().get_process();
+
+    // User memory is only accessed without holding the lock on the actions.
+    let action = match act {
+        x if x == VirtAddr::null() => None,
+        // The action for SIGKILL and SIGSTOP can't be changed.
+        _ if sig.is_unblockable() => return Err(Errno::EINVAL),
+        x => Some(SignalAction::from_user(
+            &UserPtr::<sigaction>::new(x).read().ok_or(Errno::EFAULT)?,
+        )),
+    };
+
+    let old = {
+        let mut actions = proc.signal_actions.lock();
+        let old = *actions.get(sig);
+        if let Some(action) = action {
+            actions.set(sig, action);
+        }
+        old
+    };
+
+    // Setting a signal to be ignored discards any pending instances of it.
+    if let Some(action) = action
+        && action.is_ignored(sig)
+    {
+        let set = SignalSet::new().with(sig);
+        proc.pending_signals.lock().discard(set);
+        for thread in proc.threads.lock().iter() {
+            thread.pending_signals.lock().discard(set);
+        }
+    }
+
+    if oact != VirtAddr::null() && !UserPtr::<sigaction>::new(oact).write(old.to_user()) {
+        return Err(Errno::EFAULT);
+    }
+
+    Ok(0)
+}
+
+pub fn sigsuspend(mask: VirtAddr) -> EResult<usize> {
+    let task = Scheduler::get_current();
+    let mask = SignalSet::from_raw(UserPtr::<sigset_t>::new(mask).read().ok_or(Errno::EFAULT)?);
+
+    // Temporarily replace the signal mask. The old one gets restored after the signal was handled.
+    {
+        let mut current = task.signal_mask.lock();
+        *task.saved_signal_mask.lock() = Some(*current);
+        *current = mask.without_unblockable();
+    }
+
+    // Sending a signal wakes up the task, unless it's blocked.
+    loop {
+        *task.state.lock() = TaskState::Waiting;
+        if signal::has_pending() {
+            *task.state.lock() = TaskState::Ready;
+            break;
+        }
+        CpuData::get().scheduler.block();
+    }
+
+    // sigsuspend always returns with an error.
+    Err(Errno::EINTR)
+}
+
+pub fn sigtimedwait(set: VirtAddr, info: VirtAddr, timeout: VirtAddr) -> EResult<usize> {
+    let task = Scheduler::get_current();
+    let proc = task.get_process();
+
+    let set = SignalSet::from_raw(UserPtr::<sigset_t>::new(set).read().ok_or(Errno::EFAULT)?);
+    // Everything which is not in the set is treated as blocked.
+    let blocked = SignalSet::from_raw(!set.into_raw());
+
+    // A null timeout waits forever.
+    let deadline = time::read_timeout(timeout)?.map(|x| clock::get_elapsed().saturating_add(x));
+
+    // Only signals which aren't blocked wake up the task, so the ones in the set are unblocked
+    // while waiting. They're taken before they could be delivered.
+    let old_mask = {
+        let mut mask = task.signal_mask.lock();
+        let old = *mask;
+        *mask = mask.difference(set);
+        old
+    };
+
+    let result = loop {
+        *task.state.lock() = TaskState::Waiting;
+        let received = {
+            let from_task = task.pending_signals.lock().pop(blocked);
+            from_task.or_else(|| proc.pending_signals.lock().pop(blocked))
+        };
+
+        if let Some(received) = received {
+            *task.state.lock() = TaskState::Ready;
+            break Ok(received);
+        }
+
+        // A signal outside of the set interrupts the wait.
+        if signal::has_pending() {
+            *task.state.lock() = TaskState::Ready;
+            break Err(Errno::EINTR);
+        }
+
+        let scheduler = &CpuData::get().scheduler;
+        match deadline {
+            Some(deadline) => {
+                if scheduler.block_until(deadline) {
+                    break Err(Errno::EAGAIN);
+                }
+            }
+            None => scheduler.block(),
+        }
+    };
+
+    *task.signal_mask.lock() = old_mask;
+    let received = result?;
+    if info != VirtAddr::null() && !UserPtr::<siginfo_t>::new(info).write(received.to_user()) {
+        return Err(Errno::EFAULT);
+    }
+    Ok(received.signal.number() as _)
+}
+
+/// Returns true if `sender` is allowed to send signals to `target`.
+fn may_signal(sender: &Process, target: &Process) -> bool {
+    let sender = sender.identity.lock();
+    let target = target.identity.lock();
+
+    sender.effective_user_id == 0
+        || sender.user_id == target.user_id
+        || sender.user_id == target.set_user_id
+        || sender.effective_user_id == target.user_id
+        || sender.effective_user_id == target.set_user_id
+}
+
+pub fn kill(pid: isize, sig: u32) -> EResult<usize> {
+    // Signal 0 only checks if the target exists.
+    let sig = match sig {
+        0 => None,
+        x => Some(Signal::from_raw(x).ok_or(Errno::EINVAL)?),
+    };
+
+    let proc = Scheduler::get_current().get_process();
+
+    // Zero and negative values address a process group, except -1 which addresses everybody.
+    let mut targets = vec![];
+    match pid {
+        1.. => targets.push(Process::get_by_pid(pid as uapi::pid_t).ok_or(Errno::ESRCH)?),
+        -1 => Process::for_each(|x| {
+            // Never send broadcasts to the kernel, init or the sender itself.
+            if x.get_pid() > 1 && !Arc::ptr_eq(x, &proc) {
+                targets.push(x.clone());
+            }
+        }),
+        _ => {
+            let group = match pid {
+                0 => proc.get_pgid(),
+                _ => pid.unsigned_abs() as uapi::pid_t,
+            };
+            Process::for_each(|x| {
+                if x.get_pgid() == group {
+                    targets.push(x.clone());
+                }
+            });
+        }
+    }
+
+    let mut sent = 0;
+    for target in targets.iter() {
+        if target.get_pid() == 0 || !may_signal(&proc, target) {
+            continue;
+        }
+
+        if let Some(sig) = sig {
+            signal::send_to_process(
+                target,
+                SignalInfo {
+                    pid: proc.get_pid(),
+                    uid: proc.identity.lock().user_id,
+                    ..SignalInfo::new(sig, SI_USER as _)
+                },
+            );
+        }
+        sent += 1;
+    }
+
+    match sent {
+        0 if targets.is_empty() => Err(Errno::ESRCH),
+        0 => Err(Errno::EPERM),
+        _ => Ok(0),
+    }
+}
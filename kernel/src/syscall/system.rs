@@ -1,14 +1,18 @@
 use crate::{
     clock,
-    memory::{UserSlice, VirtAddr, user::UserPtr},
+    memory::{
+        VirtAddr,
+        user::{self, UserPtr},
+    },
     posix::{
         errno::{EResult, Errno},
+        time,
         utsname::UTSNAME,
     },
     sched::Scheduler,
     uapi::{self, reboot::*, time::*, utsname::*},
 };
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 pub fn archctl(cmd: usize, arg: usize) -> EResult<usize> {
     crate::arch::core::archctl(cmd, arg)
 }
@@ -57,7 +61,11 @@ const LOG_INFO: usize = 6;
 const LOG_DEBUG: usize = 7;
 
 pub fn syslog(level: usize, ptr: VirtAddr, len: usize) -> EResult<usize> {
-    let slice: UserSlice<u8> = UserSlice::new(ptr, len);
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| Errno::ENOMEM)?;
+    buf.resize(len, 0);
+    user::copy_from_user(&mut buf, ptr)?;
+
     use ::core::fmt::Write;
     {
         let current_time = crate::clock::get_elapsed();
@@ -80,7 +88,7 @@ pub fn syslog(level: usize, ptr: VirtAddr, len: usize) -> EResult<usize> {
                 LOG_DEBUG => "DEBUG",
                 _ => "?",
             },
-            String::from_utf8_lossy(slice.as_slice().ok_or(Errno::EINVAL)?)
+            String::from_utf8_lossy(&buf)
         ));
         _ = writer.write_fmt(format_args!("\x1b[0m\n"));
     }
@@ -118,11 +126,19 @@ pub fn reboot(magic: u32, cmd: u32) -> EResult<usize> {
 }
 
 pub fn sleep(request: VirtAddr, remainder: VirtAddr) -> EResult<usize> {
-    let request = UserPtr::<timespec>::new(request);
-    // TODO
-    let _remainder = UserPtr::<timespec>::new(remainder);
-
-    clock::block_ns(request.read().ok_or(Errno::EINVAL)?.tv_nsec as usize).unwrap();
+    let request = UserPtr::<timespec>::new(request)
+        .read()
+        .ok_or(Errno::EFAULT)?;
+    let deadline = clock::get_elapsed().saturating_add(time::timespec_to_ns(&request)?);
+
+    if let Err(e) = Scheduler::sleep_until(deadline) {
+        // Report how much time was left when the sleep got interrupted.
+        if remainder != VirtAddr::null() {
+            let left = deadline.saturating_sub(clock::get_elapsed());
+            UserPtr::<timespec>::new(remainder).write(time::ns_to_timespec(left));
+        }
+        return Err(e);
+    }
 
     Ok(0)
 }
@@ -1,9 +1,14 @@
 use crate::{
+    clock,
     memory::{
         VirtAddr,
-        user::{UserPtr, UserSlice},
+        user::{self, UserPtr},
     },
-    posix::errno::{EResult, Errno},
+    posix::{
+        errno::{EResult, Errno},
+        time,
+    },
+    process::signal::{self, SignalSet},
     sched::Scheduler,
     uapi::{
         fcntl::*,
@@ -17,19 +22,35 @@ use crate::{
     vfs::{
         self, File, PathNode,
         cache::LookupFlags,
-        file::{FileDescription, OpenFlags, SeekAnchor},
+        file::{self, FileDescription, OpenFlags, SeekAnchor},
         inode::{INode, Mode, NodeOps},
     },
 };
-use alloc::{borrow::ToOwned, sync::Arc};
+use alloc::{string::String, sync::Arc, vec::Vec};
 use core::{
-    ffi::CStr,
+    mem,
     sync::atomic::{AtomicBool, Ordering},
 };
 
+/// Allocates a kernel buffer for a transfer of `len` bytes from or to user memory at `addr`.
+fn transfer_buffer(addr: VirtAddr, len: usize) -> EResult<Vec<u8>> {
+    if !user::is_user_range(addr, len) {
+        return Err(Errno::EFAULT);
+    }
+
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| Errno::ENOMEM)?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+/// Reads a path from user memory.
+fn read_path(addr: VirtAddr) -> EResult<Vec<u8>> {
+    user::copy_cstr_from_user(addr, PATH_MAX as _)
+}
+
 pub fn read(fd: i32, addr: VirtAddr, len: usize) -> EResult<isize> {
-    let mut user_ptr = UserSlice::new(addr, len);
-    let slice = user_ptr.as_mut_slice().ok_or(Errno::EINVAL)?;
+    let mut buf = transfer_buffer(addr, len)?;
     let file = {
         let proc = Scheduler::get_current().get_process();
         let proc_inner = proc.open_files.lock();
@@ -41,12 +62,13 @@ pub fn read(fd: i32, addr: VirtAddr, len: usize) -> EResult<isize> {
         return Err(Errno::EBADF);
     }
 
-    file.read(slice)
+    let count = file.read(&mut buf)?;
+    user::copy_to_user(addr, &buf[..count as usize])?;
+    Ok(count)
 }
 
 pub fn pread(fd: i32, addr: VirtAddr, len: usize, offset: usize) -> EResult<isize> {
-    let mut user_ptr = UserSlice::new(addr, len);
-    let slice = user_ptr.as_mut_slice().ok_or(Errno::EINVAL)?;
+    let mut buf = transfer_buffer(addr, len)?;
     let file = {
         let proc = Scheduler::get_current().get_process();
         let proc_inner = proc.open_files.lock();
@@ -58,12 +80,14 @@ pub fn pread(fd: i32, addr: VirtAddr, len: usize, offset: usize) -> EResult<isiz
         return Err(Errno::EBADF);
     }
 
-    file.pread(slice, offset as _)
+    let count = file.pread(&mut buf, offset as _)?;
+    user::copy_to_user(addr, &buf[..count as usize])?;
+    Ok(count)
 }
 
 pub fn write(fd: i32, addr: VirtAddr, len: usize) -> EResult<isize> {
-    let user_ptr = UserSlice::new(addr, len);
-    let slice = user_ptr.as_slice().ok_or(Errno::EINVAL)?;
+    let mut buf = transfer_buffer(addr, len)?;
+    user::copy_from_user(&mut buf, addr)?;
     let file = {
         let proc = Scheduler::get_current().get_process();
         let proc_inner = proc.open_files.lock();
@@ -74,12 +98,12 @@ pub fn write(fd: i32, addr: VirtAddr, len: usize) -> EResult<isize> {
     if !flags.contains(OpenFlags::Write) {
         return Err(Errno::EBADF);
     }
-    file.write(slice)
+    file.write(&buf)
 }
 
 pub fn pwrite(fd: i32, addr: VirtAddr, len: usize, offset: usize) -> EResult<isize> {
-    let user_ptr = UserSlice::new(addr, len);
-    let slice = user_ptr.as_slice().ok_or(Errno::EINVAL)?;
+    let mut buf = transfer_buffer(addr, len)?;
+    user::copy_from_user(&mut buf, addr)?;
     let file = {
         let proc = Scheduler::get_current().get_process();
         let proc_inner = proc.open_files.lock();
@@ -91,17 +115,15 @@ pub fn pwrite(fd: i32, addr: VirtAddr, len: usize, offset: usize) -> EResult<isi
         return Err(Errno::EBADF);
     }
 
-    file.pwrite(slice, offset as _)
+    file.pwrite(&buf, offset as _)
 }
 
 pub fn openat(fd: i32, path: VirtAddr, oflag: usize /* mode */) -> EResult<i32> {
-    // TODO: This should really be using UserPtr/a CStr abstraction.
     if path == VirtAddr::null() {
         return Err(Errno::EINVAL);
     }
 
-    let path = unsafe { CStr::from_ptr(path.as_ptr()) };
-    let v = path.to_owned();
+    let v = read_path(path)?;
     let oflag = OpenFlags::from_bits_truncate(oflag as _);
 
     let proc = Scheduler::get_current().get_process();
@@ -122,7 +144,7 @@ pub fn openat(fd: i32, path: VirtAddr, oflag: usize /* mode */) -> EResult<i32>
     let file = File::open(
         proc.root_dir.lock().clone(),
         parent,
-        v.to_bytes(),
+        &v,
         // O_CLOEXEC doesn't apply to a file, but rather its individual FD.
         // This means that dup'ing a file doesn't share this flag.
         oflag & !OpenFlags::CloseOnExec,
@@ -161,6 +183,13 @@ pub fn close(fd: i32) -> EResult<usize> {
     Ok(0)
 }
 
+/// Writes all modified data of a file back to the file system.
+pub fn fsync(fd: i32) -> EResult<()> {
+    let proc = Scheduler::get_current().get_process();
+    let file = proc.open_files.lock().get_fd(fd).ok_or(Errno::EBADF)?.file;
+    file.sync()
+}
+
 pub fn ioctl(fd: i32, request: usize, arg: VirtAddr) -> EResult<usize> {
     let proc = Scheduler::get_current().get_process();
     let proc_inner = proc.open_files.lock();
@@ -170,10 +199,7 @@ pub fn ioctl(fd: i32, request: usize, arg: VirtAddr) -> EResult<usize> {
     file.ioctl(request, arg)
 }
 
-pub fn getcwd(buffer: VirtAddr, len: usize) -> EResult<usize> {
-    let mut user_ptr = UserSlice::new(buffer, len);
-    let buf: &mut [u8] = user_ptr.as_mut_slice().ok_or(Errno::EINVAL)?;
-
+pub fn getcwd(addr: VirtAddr, len: usize) -> EResult<usize> {
     let proc = Scheduler::get_current().get_process();
 
     let mut buffer = vec![0u8; PATH_MAX as _];
@@ -203,18 +229,18 @@ pub fn getcwd(buffer: VirtAddr, len: usize) -> EResult<usize> {
     }
 
     let path_len = buffer.len() - cursor;
-    if path_len + 1 > buf.len() {
+    if path_len + 1 > len {
         return Err(Errno::ERANGE);
     }
 
-    buf[0..path_len].copy_from_slice(&buffer[cursor..]);
-    buf[path_len] = 0; // NUL terminator
+    user::copy_to_user(addr, &buffer[cursor..])?;
+    user::copy_to_user(addr + path_len, &[0])?; // NUL terminator
 
     Ok(path_len)
 }
 
-fn write_stat(inode: &Arc<INode>, mut statbuf: UserPtr<stat>) {
-    statbuf.write(stat {
+fn write_stat(inode: &Arc<INode>, mut statbuf: UserPtr<stat>) -> EResult<()> {
+    let written = statbuf.write(stat {
         st_dev: 0,
         st_ino: inode.id,
         st_mode: inode.mode.lock().bits()
@@ -238,6 +264,11 @@ fn write_stat(inode: &Arc<INode>, mut statbuf: UserPtr<stat>) {
         st_blksize: 0,
         st_blocks: 0,
     });
+
+    match written {
+        true => Ok(()),
+        false => Err(Errno::EFAULT),
+    }
 }
 
 pub fn fstat(fd: i32, statbuf: UserPtr<stat>) -> EResult<usize> {
@@ -247,7 +278,7 @@ pub fn fstat(fd: i32, statbuf: UserPtr<stat>) -> EResult<usize> {
     let file = proc_inner.get_fd(fd).ok_or(Errno::EBADF)?.file;
     let inode = file.inode.as_ref().ok_or(Errno::EINVAL)?;
 
-    write_stat(inode, statbuf);
+    write_stat(inode, statbuf)?;
 
     Ok(0)
 }
@@ -258,8 +289,7 @@ pub fn fstatat(
     statbuf: UserPtr<stat>,
     _flags: usize, // TODO
 ) -> EResult<usize> {
-    let path = unsafe { CStr::from_ptr(path.as_ptr()) };
-    let v = path.to_owned();
+    let v = read_path(path)?;
 
     let proc = Scheduler::get_current().get_process();
     let proc_inner = proc.open_files.lock();
@@ -279,7 +309,7 @@ pub fn fstatat(
     let file = File::open(
         proc.root_dir.lock().clone(),
         parent,
-        v.to_bytes(),
+        &v,
         OpenFlags::Read,
         Mode::empty(),
         &proc.identity.lock(),
@@ -288,7 +318,7 @@ pub fn fstatat(
 
     drop(proc_inner);
 
-    write_stat(inode, statbuf);
+    write_stat(inode, statbuf)?;
 
     Ok(0)
 }
@@ -322,8 +352,7 @@ pub fn dup3(fd1: i32, fd2: i32, flags: usize) -> EResult<i32> {
 }
 
 pub fn mkdirat(fd: i32, path: VirtAddr, mode: mode_t) -> EResult<i32> {
-    let path = unsafe { CStr::from_ptr(path.as_ptr()) };
-    let v = path.to_owned();
+    let v = read_path(path)?;
 
     let proc = Scheduler::get_current().get_process();
     let inner = proc.open_files.lock();
@@ -342,7 +371,7 @@ pub fn mkdirat(fd: i32, path: VirtAddr, mode: mode_t) -> EResult<i32> {
     vfs::mkdir(
         proc.root_dir.lock().clone(),
         parent,
-        v.as_bytes(),
+        &v,
         Mode::from_bits(mode).ok_or(Errno::EINVAL)?,
         &proc.identity.lock(),
     )?;
@@ -351,8 +380,7 @@ pub fn mkdirat(fd: i32, path: VirtAddr, mode: mode_t) -> EResult<i32> {
 }
 
 pub fn chdir(path: VirtAddr) -> EResult<()> {
-    let path = unsafe { CStr::from_ptr(path.as_ptr()) };
-    let v = path.to_owned();
+    let v = read_path(path)?;
 
     let proc = Scheduler::get_current().get_process();
     let root = proc.root_dir.lock();
@@ -360,7 +388,7 @@ pub fn chdir(path: VirtAddr) -> EResult<()> {
     let node = PathNode::lookup(
         root.clone(),
         cwd.clone(),
-        v.as_bytes(),
+        &v,
         &proc.identity.lock(),
         LookupFlags::MustExist,
     )?;
@@ -379,9 +407,6 @@ pub fn fchdir(fd: i32) -> EResult<()> {
 }
 
 pub fn getdents(fd: i32, addr: VirtAddr, len: usize) -> EResult<usize> {
-    let mut user_ptr = UserSlice::new(addr, len);
-    let buf: &mut [u8] = user_ptr.as_mut_slice().ok_or(Errno::EINVAL)?;
-
     let proc = Scheduler::get_current().get_process();
     let inner = proc.open_files.lock();
 
@@ -397,7 +422,7 @@ pub fn getdents(fd: i32, addr: VirtAddr, len: usize) -> EResult<usize> {
     match &node.node_ops {
         NodeOps::Directory(dir_ops) => {
             // TODO: VFS Probably need a getdents callback...
-            _ = (buf, dir_ops);
+            _ = (addr, len, dir_ops);
         }
         _ => return Err(Errno::ENOTDIR),
     }
@@ -488,18 +513,8 @@ pub fn fcntl(fd: i32, cmd: usize, arg: usize) -> EResult<usize> {
     }
 }
 
-pub fn ppoll(
-    fds_ptr: VirtAddr,
-    nfds: usize,
-    timeout_ptr: VirtAddr,
-    sigmask_ptr: VirtAddr,
-) -> EResult<usize> {
-    // Read the pollfd array from userspace
-    let mut fds_slice = UserSlice::new(fds_ptr, nfds * core::mem::size_of::<pollfd>());
-    let fds_bytes = fds_slice.as_mut_slice().ok_or(Errno::EFAULT)?;
-    let fds =
-        unsafe { core::slice::from_raw_parts_mut(fds_bytes.as_mut_ptr() as *mut pollfd, nfds) };
-
+/// Polls each file descriptor in `fds` once. Returns the amount of entries with events.
+fn poll_fds(fds: &mut [pollfd]) -> usize {
     let proc = Scheduler::get_current().get_process();
     let proc_inner = proc.open_files.lock();
 
@@ -541,10 +556,86 @@ pub fn ppoll(
         }
     }
 
-    // For now, we only support non-blocking poll (ignore timeout and sigmask)
-    // TODO: Implement blocking with timeout
-    let _ = (timeout_ptr, sigmask_ptr);
-    Ok(ready_count)
+    ready_count
+}
+
+pub fn ppoll(
+    fds_ptr: VirtAddr,
+    nfds: usize,
+    timeout_ptr: VirtAddr,
+    sigmask_ptr: VirtAddr,
+) -> EResult<usize> {
+    // Read the pollfd array from userspace
+    let fds_len = nfds.checked_mul(size_of::<pollfd>()).ok_or(Errno::EFAULT)?;
+    if !user::is_user_range(fds_ptr, fds_len) {
+        return Err(Errno::EFAULT);
+    }
+    let fds_ptr = UserPtr::<pollfd>::new(fds_ptr);
+    let mut fds = Vec::new();
+    fds.try_reserve_exact(nfds).map_err(|_| Errno::ENOMEM)?;
+    for i in 0..nfds {
+        fds.push(fds_ptr.offset(i).read().ok_or(Errno::EFAULT)?);
+    }
+
+    // Hands the returned events back to userspace.
+    let write_back = |fds: &[pollfd], count: usize| {
+        for (i, fd) in fds.iter().enumerate() {
+            if !fds_ptr.offset(i).write(*fd) {
+                return Err(Errno::EFAULT);
+            }
+        }
+        Ok(count)
+    };
+
+    // A null timeout waits forever.
+    let deadline = time::read_timeout(timeout_ptr)?.map(|x| clock::get_elapsed().saturating_add(x));
+
+    // The signal mask is replaced while waiting.
+    let task = Scheduler::get_current();
+    let old_mask = if sigmask_ptr == VirtAddr::null() {
+        None
+    } else {
+        let mask = UserPtr::<sigset_t>::new(sigmask_ptr)
+            .read()
+            .ok_or(Errno::EFAULT)?;
+        let mut current = task.signal_mask.lock();
+        Some(mem::replace(
+            &mut *current,
+            SignalSet::from_raw(mask).without_unblockable(),
+        ))
+    };
+
+    let result = loop {
+        // Registering first makes sure that no notification between polling and waiting is lost.
+        let guard = file::POLL_EVENT.guard();
+        let ready_count = poll_fds(&mut fds);
+        if ready_count != 0 {
+            break Ok(ready_count);
+        }
+
+        if deadline.is_some_and(|x| clock::get_elapsed() >= x) {
+            break Ok(0);
+        }
+
+        if signal::has_pending() {
+            break Err(Errno::EINTR);
+        }
+
+        match deadline {
+            Some(deadline) => _ = guard.wait_until(deadline),
+            None => guard.wait(),
+        }
+    };
+
+    // A signal which interrupted the wait is still handled with the temporary mask in place.
+    if let Some(old_mask) = old_mask {
+        match result {
+            Err(Errno::EINTR) => *task.saved_signal_mask.lock() = Some(old_mask),
+            _ => *task.signal_mask.lock() = old_mask,
+        }
+    }
+
+    write_back(&fds, result?)
 }
 
 pub fn pselect(
@@ -587,7 +678,9 @@ pub fn pipe(mut filedes: UserPtr<[i32; 2]>) -> EResult<usize> {
         ]
     };
 
-    filedes.write(fds);
+    if !filedes.write(fds) {
+        return Err(Errno::EFAULT);
+    }
     Ok(0)
 }
 
@@ -596,8 +689,7 @@ pub fn faccessat(fd: i32, path: VirtAddr, amode: usize, flag: usize) -> EResult<
         return Err(Errno::EINVAL);
     }
 
-    let path = unsafe { CStr::from_ptr(path.as_ptr()) };
-    let v = path.to_owned();
+    let v = read_path(path)?;
 
     let proc = Scheduler::get_current().get_process();
     let proc_inner = proc.open_files.lock();
@@ -617,7 +709,7 @@ pub fn faccessat(fd: i32, path: VirtAddr, amode: usize, flag: usize) -> EResult<
     let path_node = PathNode::lookup(
         proc.root_dir.lock().clone(),
         parent,
-        v.as_bytes(),
+        &v,
         &proc.identity.lock(),
         LookupFlags::MustExist
             | LookupFlags::FollowSymlinks
@@ -642,13 +734,12 @@ pub fn unlinkat(fd: i32, path: VirtAddr, flags: usize) -> EResult<()> {
         return Err(Errno::EINVAL);
     }
 
-    let path = unsafe { CStr::from_ptr(path.as_ptr()) };
-    let v = path.to_owned();
+    let v = read_path(path)?;
 
     warn!(
         "unlinkat({}, \"{}\", {:#x}) is a stub!",
         fd,
-        v.to_str().unwrap(),
+        String::from_utf8_lossy(&v),
         flags
     );
 
@@ -666,15 +757,15 @@ pub fn linkat(
         return Err(Errno::EINVAL);
     }
 
-    let old_path = unsafe { CStr::from_ptr(old_path.as_ptr()) };
-    let new_path = unsafe { CStr::from_ptr(new_path.as_ptr()) };
+    let old_path = read_path(old_path)?;
+    let new_path = read_path(new_path)?;
 
     warn!(
         "linkat({}, \"{}\", {}, \"{}\", {:#x}) is a stub!",
         old_fd,
-        old_path.to_str().unwrap(),
+        String::from_utf8_lossy(&old_path),
         new_fd,
-        new_path.to_str().unwrap(),
+        String::from_utf8_lossy(&new_path),
         flags,
     );
 
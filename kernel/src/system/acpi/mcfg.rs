@@ -1,7 +1,7 @@
 use crate::{
     memory::{
         pmm::KernelAlloc,
-        virt::{VmFlags, mmu::PageTable},
+        virt::{VmCacheType, VmFlags, mmu::PageTable},
     },
     system::pci::{Access, EcamPciAccess},
 };
@@ -45,6 +45,7 @@ pub fn MCFG_STAGE() {
                 .map_memory::<KernelAlloc>(
                     (entry.address).into(),
                     VmFlags::Read | VmFlags::Write,
+                    VmCacheType::Uncached,
                     ((entry.end_bus - entry.start_bus) as usize + 1) << 20, // + 1 because the range is inclusive
                 )
                 .unwrap();
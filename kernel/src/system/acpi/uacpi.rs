@@ -10,7 +10,7 @@ use crate::{
         memory::{
             free, malloc,
             pmm::KernelAlloc,
-            virt::{VmFlags, mmu::PageTable},
+            virt::{VmCacheType, VmFlags, mmu::PageTable},
         },
         util::{self, spin::SpinLock},
     },
@@ -40,6 +40,9 @@ extern "C" fn uacpi_kernel_map(addr: uacpi_phys_addr, len: uacpi_size) -> *mut c
             .map_memory::<KernelAlloc>(
                 aligned_addr.into(),
                 VmFlags::Read | VmFlags::Write,
+                // This maps both tables and MMIO of operation regions. The default type is
+                // correct for both, because the platform marks device memory as uncached.
+                VmCacheType::WriteBack,
                 aligned_len,
             )
             .unwrap()
@@ -49,7 +52,13 @@ extern "C" fn uacpi_kernel_map(addr: uacpi_phys_addr, len: uacpi_size) -> *mut c
 
 #[unsafe(no_mangle)]
 extern "C" fn uacpi_kernel_unmap(addr: *mut c_void, len: uacpi_size) {
-    // TODO
+    // Undo the alignment done by `uacpi_kernel_map`.
+    let aligned_addr = util::align_down(addr as usize, arch::virt::get_page_size());
+    let difference = addr as usize - aligned_addr;
+    PageTable::get_kernel().unmap_memory::<KernelAlloc>(
+        aligned_addr as *mut u8,
+        util::align_up(len + difference, arch::virt::get_page_size()),
+    );
 }
 
 #[unsafe(no_mangle)]
@@ -363,7 +363,7 @@ pub static DEVICES: Once<Vec<&Node>> = Once::new();
     name = "system.dt.parse-blob",
     depends = [crate::memory::MEMORY_STAGE],
 )]
-fn TREE_STAGE() {
+pub fn TREE_STAGE() {
     let dt = match BootInfo::get().fdt_addr {
         Some(fdt_addr) => unsafe {
             let slice = slice::from_raw_parts_mut(fdt_addr.as_hhdm(), 8);
@@ -10,17 +10,21 @@ pub mod ioctls;
 pub mod limits;
 pub mod mman;
 pub mod mount;
+pub mod oom;
 pub mod poll;
 pub mod reboot;
 pub mod resource;
+pub mod sched;
 pub mod signal;
 pub mod socket;
 pub mod stat;
 pub mod statvfs;
+pub mod swap;
 pub mod termios;
 pub mod time;
 pub mod uio;
 pub mod utsname;
+pub mod wait;
 
 pub type off_t = isize;
 pub type off64_t = isize;
@@ -57,6 +57,22 @@ pub const ILL_BADIADDR: u32 = 9;
 pub const SEGV_MAPERR: u32 = 1;
 pub const SEGV_ACCERR: u32 = 2;
 
+pub const FPE_INTDIV: u32 = 1;
+pub const FPE_INTOVF: u32 = 2;
+pub const FPE_FLTDIV: u32 = 3;
+pub const FPE_FLTOVF: u32 = 4;
+pub const FPE_FLTUND: u32 = 5;
+pub const FPE_FLTRES: u32 = 6;
+pub const FPE_FLTINV: u32 = 7;
+pub const FPE_FLTSUB: u32 = 8;
+
+pub const TRAP_BRKPT: u32 = 1;
+pub const TRAP_TRACE: u32 = 2;
+
+pub const SIG_ERR: usize = -1isize as usize;
+pub const SIG_DFL: usize = -2isize as usize;
+pub const SIG_IGN: usize = -3isize as usize;
+
 pub const SIG_BLOCK: u32 = 1;
 pub const SIG_UNBLOCK: u32 = 2;
 pub const SIG_SETMASK: u32 = 3;
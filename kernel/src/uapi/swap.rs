@@ -0,0 +1,4 @@
+pub const SWAP_FLAG_PREFER: u32 = 0x8000;
+pub const SWAP_FLAG_PRIO_MASK: u32 = 0x7fff;
+pub const SWAP_FLAG_PRIO_SHIFT: u32 = 0;
+pub const SWAP_FLAG_DISCARD: u32 = 0x10000;
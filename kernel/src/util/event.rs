@@ -1,4 +1,9 @@
-use crate::{percpu::CpuData, process::task::Task, sched::Scheduler, util::mutex::spin::SpinMutex};
+use crate::{
+    percpu::CpuData,
+    process::task::{Task, TaskState},
+    sched::Scheduler,
+    util::mutex::spin::SpinMutex,
+};
 use alloc::{boxed::Box, sync::Arc};
 use intrusive_collections::{LinkedList, LinkedListAtomicLink, intrusive_adapter};
 
@@ -10,50 +15,137 @@ struct Waiter {
 
 intrusive_adapter!(WaitersLinkAdapter = Box<Waiter>: Waiter { waiters_link: LinkedListAtomicLink });
 
+/// A queue of tasks waiting for something to happen.
 #[derive(Debug)]
 pub struct Event {
     waiters: SpinMutex<LinkedList<WaitersLinkAdapter>>,
 }
 
 impl Event {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             waiters: SpinMutex::new(LinkedList::new(WaitersLinkAdapter::NEW)),
         }
     }
 
+    /// Registers the current task as a waiter.
+    /// The returned guard has to be obtained *before* checking the condition to wait for,
+    /// otherwise a wakeup between the check and [`EventGuard::wait`] gets lost.
     pub fn guard(&self) -> EventGuard<'_> {
-        let mut waiters = self.waiters.lock();
-        waiters.push_back(Box::new(Waiter {
-            waiters_link: LinkedListAtomicLink::new(),
-            task: Scheduler::get_current(),
-        }));
+        let task = Scheduler::get_current();
+        self.add_waiter(&task);
+        EventGuard { parent: self, task }
+    }
 
-        return EventGuard { _parent: self };
+    /// Returns true if no task is waiting on this event.
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
     }
 
     pub fn wake_one(&self) {
+        self.wake(1);
+    }
+
+    pub fn wake_all(&self) {
+        self.wake(usize::MAX);
+    }
+
+    /// Wakes up to `count` waiters. Returns the amount of tasks that were woken up.
+    pub fn wake(&self, count: usize) -> usize {
         let mut waiters = self.waiters.lock();
-        if let Some(waiter) = waiters.pop_front() {
-            CpuData::get().scheduler.add_task(waiter.task.clone());
+        let mut woken = 0;
+        while woken < count
+            && let Some(waiter) = waiters.pop_front()
+        {
+            Scheduler::wake(&waiter.task);
+            woken += 1;
         }
+        woken
     }
 
-    pub fn wake_all(&self) {
+    fn add_waiter(&self, task: &Arc<Task>) {
         let mut waiters = self.waiters.lock();
-        for waiter in waiters.iter() {
-            CpuData::get().scheduler.add_task(waiter.task.clone());
+        *task.state.lock() = TaskState::Waiting;
+        if !waiters.iter().any(|x| Arc::ptr_eq(&x.task, task)) {
+            waiters.push_back(Box::new(Waiter {
+                waiters_link: LinkedListAtomicLink::new(),
+                task: task.clone(),
+            }));
         }
-        waiters.clear();
+    }
+
+    /// Removes a task from the wait queue. Returns true if it was still waiting.
+    fn remove_waiter(&self, task: &Arc<Task>) -> bool {
+        let mut waiters = self.waiters.lock();
+        let mut cursor = waiters.front_mut();
+        while let Some(waiter) = cursor.get() {
+            if Arc::ptr_eq(&waiter.task, task) {
+                cursor.remove();
+                return true;
+            }
+            cursor.move_next();
+        }
+        false
     }
 }
 
+/// A registration of the current task on an [`Event`].
+/// Dropping the guard removes the task from the wait queue.
 pub struct EventGuard<'n> {
-    _parent: &'n Event, // TODO: Use this
+    parent: &'n Event,
+    task: Arc<Task>,
 }
 
 impl<'n> EventGuard<'n> {
+    /// Blocks until the event is signaled. Spurious wakeups are possible,
+    /// so the condition has to be checked again afterwards.
     pub fn wait(&self) {
-        CpuData::get().scheduler.do_yield();
+        CpuData::get().scheduler.block();
+
+        // Register again so the next check of the condition can't miss a wakeup.
+        self.parent.add_waiter(&self.task);
+    }
+
+    /// Like [`Self::wait`], but gives up once `deadline` (in nanoseconds since boot) has passed.
+    /// Returns false if the deadline has passed without the event being signaled.
+    pub fn wait_until(&self, deadline: usize) -> bool {
+        let timed_out = CpuData::get().scheduler.block_until(deadline);
+        let woken = self.is_woken();
+
+        self.parent.add_waiter(&self.task);
+        woken || !timed_out
+    }
+
+    /// Marks the task as waiting again, after blocking on something else reset its state.
+    /// Returns false if the event has been signaled since the task was registered.
+    pub fn rearm(&self) -> bool {
+        let waiters = self.parent.waiters.lock();
+        if !waiters.iter().any(|x| Arc::ptr_eq(&x.task, &self.task)) {
+            return false;
+        }
+        *self.task.state.lock() = TaskState::Waiting;
+        true
+    }
+
+    /// Returns true if the task has been woken up since it was last registered.
+    pub fn is_woken(&self) -> bool {
+        !self
+            .parent
+            .waiters
+            .lock()
+            .iter()
+            .any(|x| Arc::ptr_eq(&x.task, &self.task))
+    }
+}
+
+impl Drop for EventGuard<'_> {
+    fn drop(&mut self) {
+        self.parent.remove_waiter(&self.task);
+
+        // The task is not waiting for anything anymore.
+        let mut state = self.task.state.lock();
+        if *state == TaskState::Waiting {
+            *state = TaskState::Ready;
+        }
     }
 }
@@ -43,10 +43,10 @@ impl<T: ?Sized> SpinMutex<T> {
     }
 
     pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
-        if self.is_locked() {
-            return None;
-        } else {
-            return Some(self.lock());
+        let inner = unsafe { &mut *self.inner.get() };
+        match inner.spin.try_lock() {
+            true => Some(SpinMutexGuard { parent: self }),
+            false => None,
         }
     }
 
@@ -19,6 +19,12 @@ impl SpinLock {
         }
     }
 
+    /// Acquires the lock if it's free. Returns true on success.
+    #[inline(always)]
+    pub fn try_lock(&mut self) -> bool {
+        !self.0.swap(true, Ordering::Acquire)
+    }
+
     #[inline(always)]
     pub fn unlock(&mut self) {
         self.0.store(false, Ordering::Release);
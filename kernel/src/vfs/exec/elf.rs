@@ -1,7 +1,10 @@
 use super::ExecInfo;
 use crate::{
     arch,
-    memory::{MemoryObject, PagedMemoryObject, virt::VmFlags},
+    memory::{
+        MemoryObject, PagedMemoryObject, VirtAddr,
+        virt::{VmCacheType, VmFlags, aslr},
+    },
     posix::errno::{EResult, Errno},
     process::{Process, task::Task, to_user},
     util::align_down,
@@ -335,6 +338,8 @@ struct ElfInfo {
     at_phnum: usize,
     at_phent: usize,
     at_entry: usize,
+    /// End of the highest loaded segment.
+    end: usize,
 }
 
 impl ElfFormat {
@@ -364,6 +369,7 @@ impl ElfFormat {
 
         let page_size = arch::virt::get_page_size();
         let mut phdr_addr = 0usize;
+        let mut end = 0usize;
 
         // Iterate all PHDRs.
         for i in 0..elf_hdr.e_phnum {
@@ -386,6 +392,10 @@ impl ElfFormat {
                     if phdr.p_flags & PF_EXECUTE != 0 {
                         prot |= VmFlags::Exec;
                     }
+                    // The executable asks for this explicitly, so it counts as the opt-in.
+                    if prot.contains(VmFlags::Write | VmFlags::Exec) {
+                        prot |= VmFlags::WriteExec;
+                    }
 
                     debug_assert!(phdr.p_offset % phdr.p_align == phdr.p_vaddr % phdr.p_align);
                     let misalign = phdr.p_vaddr as usize & (page_size - 1);
@@ -394,6 +404,7 @@ impl ElfFormat {
                         (phdr.p_filesz as usize + misalign + page_size - 1) & !(page_size - 1);
                     let total_map_size =
                         (phdr.p_memsz as usize + misalign + page_size - 1) & !(page_size - 1);
+                    end = end.max(map_address + total_map_size);
 
                     // Copy the file data into its own mapping.
                     file.mmap(
@@ -417,6 +428,7 @@ impl ElfFormat {
                             (map_address + backed_map_size).into(),
                             NonZeroUsize::new(total_map_size - backed_map_size).unwrap(),
                             prot,
+                            VmCacheType::WriteBack,
                             0,
                         )?;
                     }
@@ -446,6 +458,7 @@ impl ElfFormat {
             at_phnum: elf_hdr.e_phnum as usize,
             at_phent: elf_hdr.e_phentsize as usize,
             at_entry: elf_hdr.e_entry as usize + base,
+            end,
         })
     }
 }
@@ -474,8 +487,16 @@ impl ExecFormat for ElfFormat {
         let page_size = arch::virt::get_page_size();
 
         // Load the main executable. The base address only matters if the type is ET_DYN.
-        // Base could technically be 0, but we don't want to get anywhere near the NULL address.
-        let elf = Self::load_file(&info.executable.clone(), proc, info, 0x10000)?;
+        let elf = Self::load_file(
+            &info.executable.clone(),
+            proc,
+            info,
+            aslr::EXEC_BASE + aslr::random_offset(aslr::EXEC_BITS),
+        )?;
+
+        // Anonymous mappings start above the executable.
+        info.mmap_base =
+            VirtAddr::new(elf.end.max(aslr::MMAP_BASE) + aslr::random_offset(aslr::MMAP_BITS));
 
         // If we have an interpreter, we need to use its entry point.
         let entry = if let Some(x) = &info.interpreter {
@@ -483,7 +504,8 @@ impl ExecFormat for ElfFormat {
                 &x.clone(),
                 proc,
                 info,
-                1usize << (arch::virt::get_highest_bit_shift() - 2),
+                (1usize << (arch::virt::get_highest_bit_shift() - 2))
+                    + aslr::random_offset(aslr::INTERP_BITS),
             )?;
             interp.at_entry
         } else {
@@ -492,7 +514,9 @@ impl ExecFormat for ElfFormat {
 
         // Setup stack.
         // Calculate the start of the user address.
-        let highest = (1usize << (arch::virt::get_highest_bit_shift() - 1)) - page_size;
+        let highest = (1usize << (arch::virt::get_highest_bit_shift() - 1))
+            - page_size
+            - aslr::random_offset(aslr::STACK_BITS);
         let stack_size = 2 * 1024 * 1024; // 2MiB stack.
         let stack_start = highest - stack_size;
 
@@ -502,6 +526,7 @@ impl ExecFormat for ElfFormat {
             stack_start.into(),
             NonZeroUsize::new(stack_size).unwrap(),
             VmFlags::Read | VmFlags::Write,
+            VmCacheType::WriteBack,
             0,
         )?;
 
@@ -4,7 +4,7 @@ mod shebang;
 use core::fmt::Debug;
 
 use crate::{
-    memory::virt::AddressSpace,
+    memory::{VirtAddr, virt::AddressSpace},
     posix::errno::EResult,
     process::{Process, task::Task},
     util::mutex::spin::SpinMutex,
@@ -25,6 +25,8 @@ pub struct ExecInfo {
     pub interpreter: Option<Arc<File>>,
     /// An address space for the new process.
     pub space: AddressSpace,
+    /// Where anonymous mappings without an address hint start.
+    pub mmap_base: VirtAddr,
     /// Arguments.
     pub argv: Vec<Vec<u8>>,
     /// Environment variables.
@@ -1,10 +1,10 @@
 use super::inode::INode;
 use crate::{
-    memory::{AddressSpace, VirtAddr, VmFlags},
+    memory::{AddressSpace, MemoryObject, VirtAddr, VmFlags},
     posix::errno::{EResult, Errno},
     process::Identity,
     uapi,
-    util::mutex::Mutex,
+    util::{event::Event, mutex::Mutex},
     vfs::{
         cache::{LookupFlags, PathNode},
         inode::{Mode, NodeOps},
@@ -18,6 +18,15 @@ use core::{
 };
 use uapi::{fcntl::*, mman::*};
 
+/// Signaled whenever a file might have become ready. Tasks waiting for any of their files in
+/// `ppoll` wait on this.
+pub static POLL_EVENT: Event = Event::new();
+
+/// Wakes up all tasks waiting for files, so they poll them again.
+pub fn notify_poll() {
+    POLL_EVENT.wake_all();
+}
+
 bitflags::bitflags! {
     #[derive(Debug, Clone, Copy)]
     pub struct OpenFlags: u32 {
@@ -55,6 +64,7 @@ bitflags::bitflags! {
         const Shared = MAP_SHARED;
         const Private = MAP_PRIVATE;
         const Fixed = MAP_FIXED;
+        const Jit = MAP_JIT;
     }
 }
 
@@ -144,6 +154,7 @@ pub trait FileOps {
     }
 
     /// Polls this file with a mask.
+    /// Implementations which can become ready later have to call [`notify_poll`] when they do.
     fn poll(&self, file: &File, mask: i16) -> EResult<i16> {
         _ = (file, mask);
         Ok(mask)
@@ -386,6 +397,17 @@ impl File {
         }
     }
 
+    /// Writes all modified data of the file back to the file system, including changes made
+    /// through shared mappings in any address space. The caller must not hold an address space.
+    pub fn sync(&self) -> EResult<()> {
+        let inode = self.inode.as_ref().ok_or(Errno::EINVAL)?;
+        if let Some(cache) = &inode.cache {
+            AddressSpace::collect_dirty_everywhere(cache.as_ref());
+            cache.sync(0, usize::MAX)?;
+        }
+        Ok(())
+    }
+
     pub fn mmap(
         &self,
         space: &mut AddressSpace,
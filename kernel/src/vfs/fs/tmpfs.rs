@@ -3,7 +3,10 @@
 use super::{MountFlags, SuperBlock};
 use crate::{
     arch,
-    memory::{AddressSpace, PagedMemoryObject, PhysAddr, VirtAddr, VmFlags, cache::MemoryObject},
+    memory::{
+        AddressSpace, PagedMemoryObject, PhysAddr, VirtAddr, VmCacheType, VmFlags,
+        cache::MemoryObject,
+    },
     posix::errno::{EResult, Errno},
     process::Identity,
     uapi::{self, statvfs::statvfs},
@@ -74,11 +77,18 @@ impl SuperBlock for TmpSuper {
         file_ops: Arc<dyn FileOps>,
         mode: Mode,
     ) -> EResult<Arc<INode>> {
+        // The page cache is the only place where the data of a file lives.
+        let cache = match node_ops {
+            NodeOps::Regular(_) => Some(Arc::try_new(PagedMemoryObject::new_phys())?),
+            _ => None,
+        };
+
         Ok(Arc::try_new(INode {
             id: self.inode_counter.fetch_add(1, Ordering::Acquire),
             node_ops,
             file_ops,
             sb: self,
+            cache,
             mode: SpinMutex::new(mode),
             atime: SpinMutex::default(),
             mtime: SpinMutex::default(),
@@ -166,7 +176,7 @@ impl DirectoryOps for TmpDir {
 
     fn create(&self, self_node: &Arc<INode>, entry: Arc<Entry>, mode: Mode) -> EResult<()> {
         let mut children = entry.children.lock();
-        let new_file = Arc::new(TmpRegular::new());
+        let new_file = Arc::new(TmpRegular);
         let new_node = self_node.sb.clone().create_inode(
             NodeOps::Regular(new_file.clone()),
             new_file,
@@ -226,18 +236,7 @@ impl FileOps for TmpSymlink {}
 impl FileOps for TmpDir {}
 
 #[derive(Debug)]
-struct TmpRegular {
-    /// A mappable page cache for the contents of the node.
-    pub cache: Arc<PagedMemoryObject>,
-}
-
-impl TmpRegular {
-    pub fn new() -> Self {
-        Self {
-            cache: Arc::new(PagedMemoryObject::new_phys()),
-        }
-    }
-}
+struct TmpRegular;
 
 impl RegularOps for TmpRegular {
     fn truncate(&self, node: &INode, length: u64) -> EResult<()> {
@@ -254,19 +253,21 @@ impl FileOps for TmpRegular {
             return Ok(0);
         }
 
+        let cache = inode.cache.as_ref().ok_or(Errno::EINVAL)?;
         let copy_size = buffer.len().min(inode.len() - start as usize);
-        let actual = (self.cache.as_ref() as &dyn MemoryObject)
-            .read(&mut buffer[0..copy_size], start as usize);
+        let actual =
+            (cache.as_ref() as &dyn MemoryObject).read(&mut buffer[0..copy_size], start as usize);
 
         Ok(actual as _)
     }
 
     fn write(&self, file: &File, buffer: &[u8], offset: u64) -> EResult<isize> {
         let inode = file.inode.as_ref().ok_or(Errno::EINVAL)?;
+        let cache = inode.cache.as_ref().ok_or(Errno::EINVAL)?;
         let mut size_lock = inode.size.lock();
         let start = offset;
-        let actual = (self.cache.as_ref() as &dyn MemoryObject).write(buffer, start as usize);
-        *size_lock = actual;
+        let actual = (cache.as_ref() as &dyn MemoryObject).write(buffer, start as usize);
+        *size_lock = (*size_lock).max(start as usize + actual);
 
         Ok(actual as _)
     }
@@ -281,10 +282,15 @@ impl FileOps for TmpRegular {
         flags: MmapFlags,
         offset: uapi::off_t,
     ) -> EResult<VirtAddr> {
+        let cache = file
+            .inode
+            .as_ref()
+            .and_then(|x| x.cache.as_ref())
+            .ok_or(Errno::ENODEV)?;
         let object = if flags.contains(MmapFlags::Private) {
-            self.cache.make_private(len, offset)?
+            cache.make_private(len, offset)?
         } else {
-            self.cache.clone()
+            cache.clone()
         };
 
         let page_size = arch::virt::get_page_size();
@@ -297,6 +303,7 @@ impl FileOps for TmpRegular {
             map_address,
             NonZeroUsize::new(backed_map_size).unwrap(),
             prot,
+            VmCacheType::WriteBack,
             offset - misalign as isize,
         )?;
         Ok(addr)
@@ -1,5 +1,6 @@
 use super::fs::SuperBlock;
 use crate::{
+    memory::PagedMemoryObject,
     posix::errno::{EResult, Errno},
     process::Identity,
     uapi::{self, stat::*, time::timespec},
@@ -21,6 +22,9 @@ pub struct INode {
     pub file_ops: Arc<dyn FileOps>,
     /// The super block which this node is located in.
     pub sb: Arc<dyn SuperBlock>,
+    /// The contents of a regular file. All reads, writes and shared mappings of the file go
+    /// through this cache, which writes modified pages back to the file system.
+    pub cache: Option<Arc<PagedMemoryObject>>,
 
     // The following fields make up `stat`.
     pub id: usize,
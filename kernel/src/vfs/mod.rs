@@ -15,7 +15,7 @@ use crate::uapi;
 use crate::{
     memory::{
         PagedMemoryObject, VirtAddr,
-        virt::{AddressSpace, VmFlags},
+        virt::{AddressSpace, VmCacheType, VmFlags},
     },
     posix::errno::{EResult, Errno},
     process::{Identity, PROCESS_STAGE, Process},
@@ -134,7 +134,7 @@ pub fn mmap(
 ) -> EResult<VirtAddr> {
     if flags.contains(MmapFlags::Anonymous) {
         let anon = Arc::new(PagedMemoryObject::new_phys());
-        space.map_object(anon, addr, len, prot, offset)?;
+        space.map_object(anon, addr, len, prot, VmCacheType::WriteBack, offset)?;
     } else if let Some(f) = file {
         f.ops.mmap(&f, space, addr, len, prot, flags, offset)?;
     } else {
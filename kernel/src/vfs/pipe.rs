@@ -1,11 +1,14 @@
 use crate::{
     memory::{VirtAddr, user::UserPtr},
     posix::errno::{EResult, Errno},
-    uapi,
+    uapi::{
+        self,
+        poll::{POLLERR, POLLHUP, POLLIN, POLLOUT, POLLRDNORM, POLLWRNORM},
+    },
     util::{event::Event, mutex::spin::SpinMutex, ring::RingBuffer},
     vfs::{
         File,
-        file::{FileOps, OpenFlags},
+        file::{self, FileOps, OpenFlags},
     },
 };
 use core::hint::unlikely;
@@ -68,7 +71,12 @@ impl FileOps for PipeBuffer {
         if flags.contains(OpenFlags::Write) {
             inner.writers -= 1;
         }
+        drop(inner);
 
+        // The other end might be waiting for the hangup.
+        self.rd_queue.wake_all();
+        self.wr_queue.wake_all();
+        file::notify_poll();
         Ok(())
     }
 
@@ -84,7 +92,9 @@ impl FileOps for PipeBuffer {
 
             // If there was at least one byte written to the pipe
             if len > 0 {
+                drop(inner);
                 self.wr_queue.wake_one();
+                file::notify_poll();
                 return Ok(len as _);
             }
 
@@ -120,6 +130,7 @@ impl FileOps for PipeBuffer {
             };
             if len > 0 {
                 self.rd_queue.wake_one();
+                file::notify_poll();
                 return Ok(len as _);
             }
 
@@ -131,9 +142,30 @@ impl FileOps for PipeBuffer {
         }
     }
 
-    fn poll(&self, _file: &File, _mask: i16) -> EResult<i16> {
-        // TODO
-        Err(Errno::ENOSYS)
+    fn poll(&self, file: &File, mask: i16) -> EResult<i16> {
+        let flags = *file.flags.lock();
+        let inner = self.inner.lock();
+        let mut result = 0;
+
+        if flags.contains(OpenFlags::Read) {
+            if !inner.buffer.is_empty() {
+                result |= POLLIN | POLLRDNORM;
+            }
+            if inner.writers == 0 {
+                result |= POLLHUP;
+            }
+        }
+        if flags.contains(OpenFlags::Write) {
+            if inner.buffer.get_available_len() != 0 {
+                result |= POLLOUT | POLLWRNORM;
+            }
+            if inner.readers == 0 {
+                result |= POLLERR;
+            }
+        }
+
+        // Errors and hangups are always reported.
+        Ok(result & (mask | POLLERR | POLLHUP))
     }
 
     fn ioctl(&self, _file: &File, request: usize, argp: VirtAddr) -> EResult<usize> {